@@ -0,0 +1,68 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use kaleidrs::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_program_recovering};
+
+// The fixtures are generated rather than committed, each is sized to
+// be a few thousand tokens so that the numbers aren't all noise
+
+// 1 + (2 + (3 + ... )), every level a new nested expression
+fn nested_arithmetic(depth: usize) -> String {
+    let mut src = String::new();
+
+    for i in 0..depth {
+        src.push_str(&format!("{i} + ("));
+    }
+
+    src.push('0');
+    src.push_str(&")".repeat(depth));
+    src.push(';');
+
+    src
+}
+
+// A call given a great many arguments, each a small expression
+fn wide_call(args: usize) -> String {
+    let args = (0..args)
+        .map(|i| format!("x * {i}"))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    format!("def f(x) g({args});")
+}
+
+// The most typical, lots of short definitions one after the other
+fn many_definitions(count: usize) -> String {
+    (0..count)
+        .map(|i| format!("def f{i}(a b) if a < b then a * {i} else f{i}(b, a - 1);\n"))
+        .collect()
+}
+
+fn parsing(c: &mut Criterion) {
+    let fixtures = [
+        ("nested_arithmetic", nested_arithmetic(500)),
+        ("wide_call", wide_call(500)),
+        ("many_definitions", many_definitions(200)),
+    ];
+
+    let mut group = c.benchmark_group("parse");
+
+    for (name, src) in fixtures.iter() {
+        group.throughput(Throughput::Elements(src.lex().count() as u64));
+
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let (program, errors) =
+                    parse_program_recovering(&mut black_box(src).lex().lookahead());
+                assert!(errors.is_empty());
+                program
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, parsing);
+criterion_main!(benches);
@@ -0,0 +1,21 @@
+// Parsing with nothing but core and alloc, as on an embedded target. Cargo
+// builds the examples along with the tests, so this stops compiling if the
+// parser ever starts to need std. To check the library side of that too:
+//
+//     cargo build --lib --no-default-features --target thumbv7em-none-eabihf
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use kaleidrs::frontend::{
+    ast::TopLevel,
+    lexer::Lex,
+    lookahead::Lookahead,
+    parser::{parse_program_recovering, ParserError},
+};
+
+pub fn parse(src: &str) -> (Vec<TopLevel<'_>>, Vec<ParserError<'_>>) {
+    parse_program_recovering(&mut src.lex().lookahead())
+}
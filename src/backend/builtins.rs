@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::module::Module;
+
+// What the JIT calls to get to a host builtin, see builtin_thunk
+pub const THUNK_NAME: &str = "__kaleidrs_builtin_thunk";
+
+// Each builtin called is a global of this name followed by its own, which
+// the JIT maps to where the Builtin is, see map_builtins
+pub const BUILTIN_PREFIX: &str = "__kaleidrs_builtin.";
+
+type HostFn = Box<dyn Fn(&[f64]) -> f64>;
+
+// A function given by the host rather than written in Kaleidoscope, called
+// with exactly as many arguments as its arity
+pub struct Builtin {
+    pub arity: usize,
+    func: HostFn,
+}
+
+impl Builtin {
+    pub fn call(&self, args: &[f64]) -> f64 {
+        (self.func)(args)
+    }
+}
+
+// Builtins the host registers for programs to call, for embedding the
+// language without editing the crate to extend it:
+//
+// let mut builtins = BuiltinRegistry::new();
+// builtins.register("clamp", 3, |args| args[0].max(args[1]).min(args[2]));
+//
+// Both the interpreter and the JIT can be handed one. Cloning it is cheap,
+// the clones share the very same builtins.
+#[derive(Clone, Default)]
+pub struct BuiltinRegistry {
+    builtins: HashMap<String, Rc<Builtin>>,
+}
+
+impl BuiltinRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registering a name again replaces what it was before
+    pub fn register(&mut self, name: &str, arity: usize, func: impl Fn(&[f64]) -> f64 + 'static) {
+        let builtin = Builtin {
+            arity,
+            func: Box::new(func),
+        };
+
+        self.builtins.insert(name.to_string(), Rc::new(builtin));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Builtin> {
+        self.builtins.get(name).map(Rc::as_ref)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.builtins.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.builtins.is_empty()
+    }
+}
+
+// Closures have nothing to print, so just what they're called and take
+impl fmt::Debug for BuiltinRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.builtins.iter().collect();
+        names.sort_by_key(|(name, _)| name.as_str());
+
+        f.debug_map()
+            .entries(
+                names
+                    .into_iter()
+                    .map(|(name, builtin)| (name, builtin.arity)),
+            )
+            .finish()
+    }
+}
+
+// A closure is no function pointer the JIT could call, so calls to builtins
+// go through this one instead. The code passes it the address of the
+// builtin's global, which the JIT maps to the Builtin, along with the
+// arguments spilled to an array for it. That address stays good for as long
+// as the registry the code was generated with, which the LLVMContext keeps,
+// never letting it be replaced.
+//
+// A panic can't unwind out into the generated code, a builtin that panics
+// gives NaN instead.
+//
+// Only ever to be called by generated code, which passes the address of a
+// builtin in a live registry, and an array of exactly arg_cnt doubles.
+unsafe extern "C" fn builtin_thunk(builtin: *const Builtin, args: *const f64, arg_cnt: u64) -> f64 {
+    let args = std::slice::from_raw_parts(args, arg_cnt as usize);
+
+    panic::catch_unwind(AssertUnwindSafe(|| (*builtin).call(args))).unwrap_or(f64::NAN)
+}
+
+// Points the thunk declared in the module at builtin_thunk, and the global of
+// each builtin called at the builtin, for an engine about to run the module
+pub fn map_builtins(engine: &ExecutionEngine, module: &Module, registry: &BuiltinRegistry) {
+    if let Some(thunk) = module.get_function(THUNK_NAME) {
+        engine.add_global_mapping(&thunk, builtin_thunk as *const () as usize);
+    }
+
+    for global in module.get_globals() {
+        let name = global.get_name().to_string_lossy();
+
+        let builtin = name
+            .strip_prefix(BUILTIN_PREFIX)
+            .and_then(|name| registry.get(name));
+
+        if let Some(builtin) = builtin {
+            engine.add_global_mapping(&global, builtin as *const Builtin as usize);
+        }
+    }
+}
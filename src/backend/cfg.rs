@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use inkwell::basic_block::BasicBlock;
+use inkwell::module::Module;
+use inkwell::values::{AnyValue, FunctionValue};
+
+// Renders the control flow graph of a function as a Graphviz DOT graph, the
+// same sort of thing LLVM's -dot-cfg gives. Each basic block is a box with
+// its name and instructions, with an edge to every block its terminator can
+// branch to, so an if shows up as a diamond of then, else and the merge.
+pub fn emit_cfg(function: FunctionValue, out: &mut impl Write) -> io::Result<()> {
+    let blocks = function.get_basic_blocks();
+    let name = function.get_name().to_string_lossy();
+
+    writeln!(out, "digraph \"cfg {}\" {{", escape(&name))?;
+    writeln!(out, "    node [shape=box, fontname=monospace];")?;
+
+    for (id, block) in blocks.iter().enumerate() {
+        writeln!(out, "    b{id} [label=\"{}\"];", escape(&label(id, *block)))?;
+    }
+
+    for (id, block) in blocks.iter().enumerate() {
+        for succ in successors(*block) {
+            if let Some(succ_id) = blocks.iter().position(|block| *block == succ) {
+                writeln!(out, "    b{id} -> b{succ_id};")?;
+            }
+        }
+    }
+
+    writeln!(out, "}}")
+}
+
+// One DOT file per function with a body, named like "<base>.<function>.dot",
+// giving back the paths written in the order the functions are in
+pub fn write_cfgs(module: &Module, base: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+
+    for function in module.get_functions() {
+        if function.count_basic_blocks() == 0 {
+            continue;
+        }
+
+        let mut file_name = base.as_os_str().to_owned();
+        file_name.push(format!(".{}.dot", function.get_name().to_string_lossy()));
+
+        let path = PathBuf::from(file_name);
+        let mut out = BufWriter::new(File::create(&path)?);
+
+        emit_cfg(function, &mut out)?;
+        out.flush()?;
+
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+// A block LLVM was never given a name for goes by its number instead
+fn label(id: usize, block: BasicBlock) -> String {
+    let name = block.get_name().to_string_lossy().into_owned();
+    let mut label = if name.is_empty() {
+        format!("{id}:")
+    } else {
+        format!("{name}:")
+    };
+
+    for insn in block.get_instructions() {
+        label.push('\n');
+        label.push_str(insn.print_to_string().to_str().unwrap_or("<?>").trim());
+    }
+
+    label
+}
+
+// The blocks a terminator names as operands are where it can go next
+fn successors(block: BasicBlock) -> Vec<BasicBlock> {
+    let Some(terminator) = block.get_terminator() else {
+        return vec![];
+    };
+
+    (0..terminator.get_num_operands())
+        .filter_map(|idx| terminator.get_operand(idx)?.right())
+        .collect()
+}
+
+// Labels are quoted, so quotes and backslashes in them need escaping, and a
+// "\l" ends each line left justified, rather than centered
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\l")
+}
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+    use inkwell::targets::{InitializationConfig, Target};
+
+    use super::*;
+    use crate::backend::llvm_backend::{LLVMCodeGen, LLVMContext};
+    use crate::cli::OptLevel;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_definition};
+
+    #[test]
+    fn emitting_cfg() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "def pick(x) if x < 1 then x + 1 else x - 1;";
+        parse_definition(&mut src.lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let module = llvm_ctx.into_module();
+        let mut out = vec![];
+        emit_cfg(module.get_function("pick").unwrap(), &mut out).unwrap();
+
+        let graph = String::from_utf8(out).unwrap();
+
+        assert!(graph.starts_with("digraph \"cfg pick\" {\n"));
+        assert!(graph.matches("[label=").count() >= 3);
+        assert!(graph.contains("b0 [label=\"entry:\\l"));
+
+        // The entry block branches both ways, and each way on to the merge
+        assert!(graph.contains("b0 -> b1;"));
+        assert!(graph.contains("b0 -> b2;"));
+        assert_eq!(graph.matches("->").count(), 4);
+    }
+}
@@ -0,0 +1,154 @@
+use std::cell::Cell;
+use std::path::Path;
+
+use inkwell::builder::Builder;
+use inkwell::context::Context;
+use inkwell::debug_info::{
+    AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DISubprogram, DWARFEmissionKind,
+    DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::llvm_sys::debuginfo::LLVMDIBuilderFinalizeSubprogram;
+use inkwell::module::{FlagBehavior, Module};
+use inkwell::values::FunctionValue;
+
+use crate::frontend::diagnostics::LineStarts;
+
+// DWARF debug info for a module, so compiled Kaleidoscope can be stepped
+// through in gdb. We have no spans in the tree, but every name in it is a
+// slice of the source, so the line and column of a name is found from where
+// its slice points to. Each function gets a subprogram, and instructions
+// get the location of the last name seen while generating them.
+#[derive(Debug)]
+pub struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    compile_unit: DICompileUnit<'ctx>,
+    subprogram: Cell<Option<DISubprogram<'ctx>>>,
+    lines: LineStarts,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    pub fn new(module: &Module<'ctx>, context: &'ctx Context, path: &Path, src_code: &str) -> Self {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let directory = path.parent().unwrap_or(Path::new("")).to_string_lossy();
+
+        // Without this flag, LLVM silently drops all of the debug info
+        module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            context.i32_type().const_int(3, false),
+        );
+
+        let (builder, compile_unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "kaleidrs",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+
+        Self {
+            builder,
+            compile_unit,
+            subprogram: Cell::new(None),
+            lines: LineStarts::new(src_code),
+        }
+    }
+
+    // One based line and column of a slice of the source, line 0 for
+    // anything not from the source, like the name of top level expressions
+    fn line_col(&self, slice: &str) -> (u32, u32) {
+        self.lines
+            .line_col(slice)
+            .map_or((0, 0), |(line, col)| (line as u32, col as u32))
+    }
+
+    // Attach a subprogram to a function we are about to generate the body of,
+    // every instruction from here on is scoped to it
+    pub fn start_function(
+        &self,
+        builder: &Builder<'ctx>,
+        context: &'ctx Context,
+        fn_val: FunctionValue<'ctx>,
+        name: &str,
+        name_slice: &str,
+    ) {
+        let file = self.compile_unit.get_file();
+        let (line, _) = self.line_col(name_slice);
+
+        let f64_type = self
+            .builder
+            .create_basic_type("double", 64, 0x04, DIFlags::PUBLIC) // DW_ATE_float
+            .unwrap()
+            .as_type();
+
+        let param_types = vec![f64_type; fn_val.count_params() as usize];
+        let fn_type = self.builder.create_subroutine_type(
+            file,
+            Some(f64_type),
+            &param_types,
+            DIFlags::PUBLIC,
+        );
+
+        let subprogram = self.builder.create_function(
+            self.compile_unit.as_debug_info_scope(),
+            name,
+            None,
+            file,
+            line,
+            fn_type,
+            false,
+            true,
+            line,
+            DIFlags::PUBLIC,
+            false,
+        );
+
+        fn_val.set_subprogram(subprogram);
+        self.subprogram.set(Some(subprogram));
+        self.set_location(builder, context, name_slice);
+    }
+
+    // Done with the body of the function, its subprogram must be finalized
+    // before LLVM will verify the function
+    pub fn finish_function(&self, builder: &Builder<'ctx>) {
+        if let Some(subprogram) = self.subprogram.take() {
+            unsafe {
+                LLVMDIBuilderFinalizeSubprogram(self.builder.as_mut_ptr(), subprogram.as_mut_ptr());
+            }
+        }
+
+        builder.unset_current_debug_location();
+    }
+
+    // Point the builder at where this name is in the source
+    pub fn set_location(&self, builder: &Builder<'ctx>, context: &'ctx Context, slice: &str) {
+        let Some(subprogram) = self.subprogram.get() else {
+            return;
+        };
+
+        let (line, col) = self.line_col(slice);
+        let location = self.builder.create_debug_location(
+            context,
+            line,
+            col,
+            subprogram.as_debug_info_scope(),
+            None,
+        );
+
+        builder.set_current_debug_location(location);
+    }
+
+    pub fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
@@ -0,0 +1,683 @@
+use std::collections::HashMap;
+
+use crate::backend::builtins::BuiltinRegistry;
+use crate::backend::llvm_backend::{const_builtin, BackendError, DEFAULT_MAX_POW_UNROLL};
+use crate::frontend::{
+    ast::{ASTExpr, Function, TopLevel},
+    lexer::Ops,
+    transform::{eval_const, pow_unroll, unrolled_pow},
+};
+
+type EvalResult<'src> = Result<f64, BackendError<'src>>;
+
+// Ways to run the interpreter, all off by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvalOptions {
+    // Remember what each call gave, so a second call with the same arguments
+    // is just a lookup, naive recursion like fib goes from exponential to
+    // linear. A function can only see its arguments, assigning to those
+    // changes no one else's, so the same arguments always give the same
+    // result and every def is pure as far as this is concerned. Host builtins
+    // may not be, so a call that reached one, however deep down, is never
+    // remembered, it runs again every time.
+    pub memoize: bool,
+
+    // Give up with StepLimitExceeded once this many expressions have been
+    // evaluated, every node counting, those in the bodies of calls too, so
+    // a program that never stops, like "def loop() loop();", can safely be
+    // run anyway. Counted over everything the interpreter evaluates, the
+    // same as calls(). Every step can be a call deeper into the Rust stack
+    // though, so the limit has to be one the thread's stack can take.
+    pub step_limit: Option<u64>,
+
+    // Give up with CallDepthExceeded once calls nest deeper than this, for
+    // recursion that does end, just too deep down for the Rust stack, each
+    // call being a few evals deeper into it. The step limit would stop
+    // those too, but only by also cutting short long running shallow ones.
+    pub max_call_depth: Option<usize>,
+
+    // Multiply out powers up to this, the same as codegen given it as
+    // --max-pow-unroll, None being codegen's default, for ^ to give exactly
+    // what the JIT does, see LLVMContext::build_pow
+    pub max_pow_unroll: Option<u32>,
+}
+
+// A tree walking interpreter, evaluates the AST directly without going
+// anywhere near LLVM. Slow, but handy for checking what a program should
+// give, the results are meant to match those of the JIT exactly.
+//
+// Variables live in a stack of scopes, each var expression pushes a fresh
+// one for its bindings, popped once its body is done, so inner bindings
+// shadow outer ones and never leak out. Calls start a new stack of their own,
+// a function can't see the variables of whoever called it.
+#[derive(Debug, Default)]
+pub struct Interpreter<'a, 'src> {
+    functions: HashMap<String, &'a Function<'src>>,
+    scopes: Vec<HashMap<&'src str, f64>>,
+    options: EvalOptions,
+    memo: HashMap<(String, Vec<u64>), f64>, // The bits of the arguments, f64 isn't Eq
+    calls: usize,
+    builtin_calls: usize,
+    steps: u64,
+    depth: usize,
+    builtins: BuiltinRegistry,
+    consts: HashMap<String, f64>,
+}
+
+impl<'a, 'src> Interpreter<'a, 'src> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: EvalOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    // The host's builtins can be called from now on, unless shadowed by a def
+    pub fn use_builtins(&mut self, builtins: BuiltinRegistry) {
+        self.builtins = builtins;
+    }
+
+    // How many times a function body was evaluated, calls answered from
+    // the memo don't count
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    // Replacing a function forgets every memoized result, not just its own,
+    // the functions calling it would give something else now too
+    pub fn define(&mut self, func: &'a Function<'src>) {
+        if self.functions.insert(func.proto.get_name(), func).is_some() {
+            self.memo.clear();
+        }
+    }
+
+    // Defines every function in the program, evaluating the top level
+    // expressions in order as we go, giving back what each evaluated to.
+    // Externs can't be called, there is nothing to interpret for them.
+    pub fn run(&mut self, program: &'a [TopLevel<'src>]) -> Result<Vec<f64>, BackendError<'src>> {
+        let mut results = vec![];
+
+        for item in program.iter() {
+            match item {
+                TopLevel::Definition(func) => self.define(func),
+                TopLevel::Expression(func) => results.push(self.eval(&func.body)?),
+                TopLevel::Extern(_) => {}
+
+                // Only what codegen can work out at compile time is allowed
+                TopLevel::Const(def) => {
+                    if self.consts.contains_key(def.name) {
+                        return Err(BackendError::ConstRedefined(def.name));
+                    }
+
+                    let value = self
+                        .eval_const(&def.value)
+                        .ok_or(BackendError::NotAConstant(def.name))?;
+
+                    self.consts.insert(def.name.to_string(), value);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn max_pow_unroll(&self) -> u32 {
+        self.options
+            .max_pow_unroll
+            .unwrap_or(DEFAULT_MAX_POW_UNROLL)
+    }
+
+    // See transform::eval_const, with what's defined here
+    fn eval_const(&self, expr: &ASTExpr) -> Option<f64> {
+        eval_const(
+            expr,
+            &self.consts,
+            &|name| self.functions.contains_key(name),
+            self.max_pow_unroll(),
+        )
+    }
+
+    // Power the way codegen builds it, a constant exponent it would multiply
+    // out is multiplied out here too, any other goes to pow, like llvm.pow
+    fn pow(&self, base: f64, exponent: &ASTExpr, value: f64) -> f64 {
+        match self
+            .eval_const(exponent)
+            .and_then(|n| pow_unroll(n, self.max_pow_unroll()))
+        {
+            Some(n) => unrolled_pow(base, n),
+            None => base.powf(value),
+        }
+    }
+
+    pub fn eval(&mut self, expr: &ASTExpr<'src>) -> EvalResult<'src> {
+        use ASTExpr::*;
+
+        self.steps += 1;
+
+        if let Some(limit) = self.options.step_limit {
+            if self.steps > limit {
+                return Err(BackendError::StepLimitExceeded(limit));
+            }
+        }
+
+        match expr {
+            NumberExpr(num) => Ok(*num),
+
+            StringExpr(text) => Err(BackendError::StringNotAValue(text)),
+
+            ParenExpr(inner) => self.eval(inner),
+
+            // Functions here aren't values with an address to call
+            IndirectCallExpr { .. } => Err(BackendError::IndirectCallNotInterpreted),
+
+            ArrayExpr(_) => Err(BackendError::ArrayNotAValue),
+
+            // Every element is evaluated, as in codegen, then the one wanted
+            // picked out. Out of bounds is as undefined as it is compiled,
+            // here it happens to be NaN.
+            IndexExpr { base, index } => {
+                let ArrayExpr(elems) = &**base else {
+                    return Err(BackendError::NotIndexable);
+                };
+
+                let elems = elems
+                    .iter()
+                    .map(|elem| self.eval(elem))
+                    .collect::<Result<Vec<f64>, _>>()?;
+
+                let index = self.eval(index)?;
+
+                Ok(elems.get(index as usize).copied().unwrap_or(f64::NAN))
+            }
+
+            // Like codegen, these never look at what the argument evaluates to
+            CallExpr { callee, args } if const_builtin(callee).is_some() => match args.as_slice() {
+                [text] => match **text {
+                    StringExpr(text) => Ok(const_builtin(callee).unwrap()(text)),
+                    _ => Err(BackendError::ExpectedStringLiteral(callee)),
+                },
+
+                _ => Err(BackendError::IncorrectNumberOfArgs {
+                    func_name: callee,
+                    param_cnt: 1,
+                }),
+            },
+
+            VariableExpr(name) => self
+                .lookup(name)
+                .copied()
+                .or_else(|| self.consts.get(*name).copied())
+                .ok_or(BackendError::UnknownVariable(name)),
+
+            UnaryExpr { op, operand } => {
+                let operand = self.eval(operand)?;
+                let fn_name = format!("unary{}", op.as_str());
+
+                match op {
+                    _ if self.functions.contains_key(&fn_name) => {
+                        self.call_function(&fn_name, vec![operand])
+                    }
+                    Ops::Minus => Ok(-operand),
+                    Ops::Plus => Ok(operand),
+                    _ => Err(BackendError::UndefinedOperator(*op)),
+                }
+            }
+
+            BinaryExpr {
+                op: Ops::Assign,
+                left,
+                right,
+            } => {
+                let ASTExpr::VariableExpr(name) = **left else {
+                    return Err(BackendError::BadAssignment);
+                };
+
+                if self.lookup(name).is_none() {
+                    return Err(BackendError::UnknownVariable(name));
+                }
+
+                let value = self.eval(right)?;
+                *self.lookup(name).unwrap() = value;
+
+                Ok(value)
+            }
+
+            BinaryExpr { op, left, right } => {
+                let lhs = self.eval(left)?;
+                let rhs = self.eval(right)?;
+
+                // Comparisons give 1.0 for true, 0.0 for false
+                let truth = |b: bool| if b { 1.0 } else { 0.0 };
+
+                match op {
+                    Ops::Plus => Ok(lhs + rhs),
+                    Ops::Minus => Ok(lhs - rhs),
+                    Ops::Mult => Ok(lhs * rhs),
+                    Ops::Div => Ok(lhs / rhs),
+                    Ops::Mod => Ok(lhs % rhs),
+                    Ops::Eq => Ok(truth(lhs == rhs)),
+                    Ops::Neq => Ok(truth(lhs != rhs && !lhs.is_nan() && !rhs.is_nan())),
+                    Ops::Lt => Ok(truth(lhs < rhs)),
+                    Ops::Gt => Ok(truth(lhs > rhs)),
+                    Ops::Le => Ok(truth(lhs <= rhs)),
+                    Ops::Ge => Ok(truth(lhs >= rhs)),
+                    Ops::Xor if !self.functions.contains_key("binary^") => {
+                        Ok(self.pow(lhs, right, rhs))
+                    }
+
+                    overloaded_op => {
+                        let fn_name = format!("binary{}", overloaded_op.as_str());
+
+                        if !self.functions.contains_key(&fn_name) {
+                            return Err(BackendError::UndefinedOperator(*overloaded_op));
+                        }
+
+                        self.call_function(&fn_name, vec![lhs, rhs])
+                    }
+                }
+            }
+
+            CallExpr { callee, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<f64>, _>>()?;
+
+                // A definition of the same name wins over the intrinsic, as in codegen
+                let builtin: Option<fn(f64) -> f64> = match *callee {
+                    _ if self.functions.contains_key(*callee) => None,
+                    "floor" => Some(f64::floor),
+                    "ceil" => Some(f64::ceil),
+                    "round" => Some(f64::round),
+                    "trunc" => Some(f64::trunc),
+                    _ => None,
+                };
+
+                match builtin {
+                    Some(builtin) if args.len() == 1 => Ok(builtin(args[0])),
+                    Some(_) => Err(BackendError::IncorrectNumberOfArgs {
+                        func_name: callee,
+                        param_cnt: 1,
+                    }),
+
+                    None if !self.functions.contains_key(*callee) => {
+                        match self.builtins.get(callee) {
+                            Some(builtin) if args.len() == builtin.arity => {
+                                self.builtin_calls += 1;
+                                Ok(builtin.call(&args))
+                            }
+                            Some(builtin) => Err(BackendError::IncorrectNumberOfArgs {
+                                func_name: callee,
+                                param_cnt: builtin.arity as u32,
+                            }),
+                            None => Err(BackendError::UndefinedFunction(callee)),
+                        }
+                    }
+                    None => {
+                        let param_cnt = self.functions[*callee].proto.get_num_params();
+
+                        if args.len() != param_cnt {
+                            return Err(BackendError::IncorrectNumberOfArgs {
+                                func_name: callee,
+                                param_cnt: param_cnt as u32,
+                            });
+                        }
+
+                        self.call_function(callee, args)
+                    }
+                }
+            }
+
+            // Just like the codegen, the condition is only true when exactly 1.0
+            IfExpr {
+                cond,
+                then_branch,
+                elifs,
+                else_branch,
+            } => {
+                if self.eval(cond)? == 1.0 {
+                    return self.eval(then_branch);
+                }
+
+                for (elif_cond, elif_branch) in elifs.iter() {
+                    if self.eval(elif_cond)? == 1.0 {
+                        return self.eval(elif_branch);
+                    }
+                }
+
+                self.eval(else_branch)
+            }
+
+            // Also following the codegen, the body always runs at least once,
+            // and the end condition is checked before the step is applied
+            ForLoopExpr {
+                varname,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let start = self.eval(start)?;
+                self.scopes.push(HashMap::from([(*varname, start)]));
+
+                let looped = self.eval_loop(varname, end, step, body);
+                self.scopes.pop();
+
+                looped.map(|_| 0.0)
+            }
+
+            // True only when exactly 1.0 too
+            WhileExpr { cond, body } => {
+                while self.eval(cond)? == 1.0 {
+                    self.eval(body)?;
+                }
+
+                Ok(0.0)
+            }
+
+            VarExpr { var_names, body } => {
+                self.scopes.push(HashMap::new());
+
+                let result = self.eval_var(var_names, body);
+                self.scopes.pop();
+
+                result
+            }
+        }
+    }
+
+    fn eval_loop(
+        &mut self,
+        varname: &'src str,
+        end: &ASTExpr<'src>,
+        step: &ASTExpr<'src>,
+        body: &ASTExpr<'src>,
+    ) -> EvalResult<'src> {
+        loop {
+            self.eval(body)?;
+
+            let step = self.eval(step)?;
+            let end = self.eval(end)?;
+
+            *self.lookup(varname).unwrap() += step;
+
+            if end != 1.0 {
+                return Ok(0.0);
+            }
+        }
+    }
+
+    // Each binding is in scope for the initializers after it, not before
+    fn eval_var(
+        &mut self,
+        var_names: &[(&'src str, Option<Box<ASTExpr<'src>>>)],
+        body: &ASTExpr<'src>,
+    ) -> EvalResult<'src> {
+        for (name, init) in var_names.iter() {
+            let value = match init {
+                Some(init) => self.eval(init)?,
+                None => 0.0,
+            };
+
+            self.scopes.last_mut().unwrap().insert(name, value);
+        }
+
+        self.eval(body)
+    }
+
+    // The innermost binding of a name
+    fn lookup(&mut self, name: &str) -> Option<&mut f64> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+
+    fn call_function(&mut self, name: &str, args: Vec<f64>) -> EvalResult<'src> {
+        if !self.options.memoize {
+            return self.eval_call(name, args);
+        }
+
+        let key = (
+            name.to_string(),
+            args.iter().map(|arg| arg.to_bits()).collect(),
+        );
+
+        if let Some(result) = self.memo.get(&key) {
+            return Ok(*result);
+        }
+
+        let builtin_calls = self.builtin_calls;
+        let result = self.eval_call(name, args)?;
+
+        if self.builtin_calls == builtin_calls {
+            self.memo.insert(key, result);
+        }
+
+        Ok(result)
+    }
+
+    fn eval_call(&mut self, name: &str, args: Vec<f64>) -> EvalResult<'src> {
+        let func = self.functions[name];
+
+        if let Some(limit) = self.options.max_call_depth {
+            if self.depth >= limit {
+                return Err(BackendError::CallDepthExceeded(limit));
+            }
+        }
+
+        self.calls += 1;
+        self.depth += 1;
+
+        let frame = HashMap::from_iter(func.proto.get_params().into_iter().zip(args));
+
+        let callers_scopes = std::mem::replace(&mut self.scopes, vec![frame]);
+        let result = self.eval(&func.body);
+        self.scopes = callers_scopes;
+        self.depth -= 1;
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_program_recovering};
+    use std::{cell::Cell, rc::Rc};
+
+    fn run(src: &str) -> Result<Vec<f64>, BackendError<'_>> {
+        let (program, errors) = parse_program_recovering(&mut src.lex().lookahead());
+        assert!(errors.is_empty());
+
+        Interpreter::new().run(&program)
+    }
+
+    #[test]
+    fn evaluating_programs() {
+        let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(10);";
+        assert_eq!(run(src), Ok(vec![55.0]));
+
+        let src =
+            "def acc(x) var total = 0 in (for i = 0, i < x in total = total + i) + total; acc(5);";
+        assert_eq!(run(src), Ok(vec![15.0]));
+
+        // A program's own floor is called, not the intrinsic
+        assert_eq!(
+            run("floor(3.7); def floor(x) x + 1; floor(3.7);"),
+            Ok(vec![3.0, 4.7])
+        );
+    }
+
+    #[test]
+    fn memoizing_calls() {
+        let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(30);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        let mut memoized = Interpreter::with_options(EvalOptions {
+            memoize: true,
+            ..EvalOptions::default()
+        });
+        assert_eq!(memoized.run(&program), Ok(vec![832040.0]));
+
+        // Each of fib(0) up to fib(30) is worked out just the once
+        assert_eq!(memoized.calls(), 31);
+
+        let src = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2); fib(15);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        let mut plain = Interpreter::new();
+        assert_eq!(plain.run(&program), Ok(vec![610.0]));
+        assert_eq!(plain.calls(), 1973);
+        // A redefinition is seen by the calls after it, the callers' included
+        let src = "def g(x) x; def f(x) g(x) + 1; f(1); def g(x) x * 10; g(1); f(1);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+        assert_eq!(memoized.run(&program), Ok(vec![2.0, 10.0, 11.0]));
+
+        // Calls reaching a host builtin run every time, it may do something
+        let printed = Rc::new(Cell::new(0));
+        let mut builtins = BuiltinRegistry::new();
+        builtins.register("putchard", 1, {
+            let printed = printed.clone();
+            move |_| {
+                printed.set(printed.get() + 1);
+                0.0
+            }
+        });
+
+        let mut memoized = Interpreter::with_options(EvalOptions {
+            memoize: true,
+            ..EvalOptions::default()
+        });
+        memoized.use_builtins(builtins);
+
+        let src = "def show(x) putchard(x); def twice(x) show(x) + show(x); twice(65); twice(65);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+        assert_eq!(memoized.run(&program), Ok(vec![0.0, 0.0]));
+        assert_eq!(printed.get(), 4);
+    }
+
+    #[test]
+    fn limiting_steps() {
+        let src = "def loop() loop(); loop();";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        let options = EvalOptions {
+            step_limit: Some(100),
+            ..EvalOptions::default()
+        };
+
+        let mut interp = Interpreter::with_options(options);
+        assert_eq!(
+            interp.run(&program),
+            Err(BackendError::StepLimitExceeded(100))
+        );
+
+        // The body is the one call, a step for each time it was entered
+        assert_eq!(interp.calls(), 100);
+
+        // Under the limit, nothing changes
+        let src = "def fact(n) if n < 2 then 1 else n * fact(n - 1); fact(5);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        assert_eq!(
+            Interpreter::with_options(options).run(&program),
+            Ok(vec![120.0])
+        );
+    }
+
+    #[test]
+    fn limiting_call_depth() {
+        let src = "def down(n) if n < 1 then 0 else down(n - 1); down(100000);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        let options = EvalOptions {
+            max_call_depth: Some(50),
+            ..EvalOptions::default()
+        };
+
+        let mut interp = Interpreter::with_options(options);
+        assert_eq!(
+            interp.run(&program),
+            Err(BackendError::CallDepthExceeded(50))
+        );
+        assert_eq!(interp.calls(), 50);
+
+        // However many calls there are, only how deep they go counts
+        let src = "def down(n) if n < 1 then 0 else down(n - 1); \
+                   def many(n) for i = 0, i < n in down(10); many(1000); down(49);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        let mut interp = Interpreter::with_options(options);
+        assert_eq!(interp.run(&program), Ok(vec![0.0, 0.0]));
+    }
+
+    #[test]
+    fn calling_host_builtins() {
+        let mut builtins = BuiltinRegistry::new();
+        builtins.register("clamp", 3, |args| args[0].max(args[1]).min(args[2]));
+
+        let mut interpreter = Interpreter::new();
+        interpreter.use_builtins(builtins);
+
+        let src = "clamp(5, 0, 3); clamp(-1, 0, 3) + clamp(2, 0, 3);";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+        assert_eq!(interpreter.run(&program), Ok(vec![3.0, 2.0]));
+
+        let (program, _) = parse_program_recovering(&mut "clamp(5, 0);".lex().lookahead());
+        assert_eq!(
+            interpreter.run(&program),
+            Err(BackendError::IncorrectNumberOfArgs {
+                func_name: "clamp",
+                param_cnt: 3
+            })
+        );
+    }
+
+    #[test]
+    fn evaluating_consts() {
+        assert_eq!(
+            run("const N = 2 * 3; def f(x) x + N; f(1); var N = 1 in N;"),
+            Ok(vec![7.0, 1.0])
+        );
+
+        assert_eq!(
+            run("const N = 1; const N = 2;"),
+            Err(BackendError::ConstRedefined("N"))
+        );
+    }
+
+    #[test]
+    fn shadowing_vars() {
+        assert_eq!(run("var x = 1 in var x = 2 in x;"), Ok(vec![2.0]));
+
+        // An inner binding doesn't disturb the outer one once its body ends
+        assert_eq!(run("var x = 1 in (var x = 2 in x) + x;"), Ok(vec![3.0]));
+    }
+
+    #[test]
+    fn vars_dont_leak() {
+        assert_eq!(
+            run("(var x = 5 in x + x) + x;"),
+            Err(BackendError::UnknownVariable("x"))
+        );
+
+        // Nor do they leak into the functions called from the body
+        assert_eq!(
+            run("def f() y; var y = 1 in f();"),
+            Err(BackendError::UnknownVariable("y"))
+        );
+    }
+
+    #[test]
+    fn referencing_outer_vars() {
+        assert_eq!(run("var x = 5 in var y = x * 2 in x + y;"), Ok(vec![15.0]));
+
+        // Assigning from an inner body changes the outer binding
+        assert_eq!(run("var x = 5 in (var y = 1 in x = y) + x;"), Ok(vec![2.0]));
+    }
+}
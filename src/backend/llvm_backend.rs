@@ -1,31 +1,78 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-use inkwell::basic_block::BasicBlock;
+use inkwell::attributes::{Attribute, AttributeLoc};
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::execution_engine::JitFunction;
+use inkwell::intrinsics::Intrinsic;
 use inkwell::module::{Linkage, Module};
 use inkwell::passes::PassBuilderOptions;
+use inkwell::support::LLVMString;
 use inkwell::targets::{CodeModel, FileType, RelocMode, Target, TargetMachine};
-use inkwell::types::BasicMetadataTypeEnum;
+use inkwell::types::{BasicMetadataTypeEnum, BasicType};
 use inkwell::values::{
-    AnyValue, AnyValueEnum, BasicMetadataValueEnum, BasicValue, FunctionValue, PointerValue,
+    AnyValue, AnyValueEnum, ArrayValue, AsValueRef, BasicMetadataValueEnum, BasicValue,
+    BasicValueEnum, CallSiteValue, FloatValue, FunctionValue, InstructionOpcode, InstructionValue,
+    PointerValue,
 };
+use inkwell::AddressSpace;
 use inkwell::FloatPredicate;
 use inkwell::OptimizationLevel;
+use llvm_sys::core::{LLVMIsAConstantFP, LLVMIsAFunction};
 use thiserror::Error;
 
+use crate::backend::builtins::{self, BuiltinRegistry, BUILTIN_PREFIX, THUNK_NAME};
+use crate::backend::cfg;
+use crate::backend::debug_info::DebugInfo;
+use crate::backend::passes::{ModuleStats, OptReport, Pgo, Sanitizer};
+use crate::backend::source_map::SourceMap;
+use crate::backend::symbols::SymbolTable;
 use crate::cli::OptLevel;
 use crate::frontend::{
-    ast::{ASTExpr, Function, Prototype},
+    ast::{ASTExpr, Function, Prototype, TopLevel},
     lexer::Ops,
+    transform::{eval_const, pow_unroll},
 };
 
 type IRGenResult<'ir, 'src> = Result<AnyValueEnum<'ir>, BackendError<'src>>;
 type TopLevelSignature = unsafe extern "C" fn() -> f64;
 
+// At most this many copies of functions are made by specialize_calls
+const MAX_SPECIALIZATIONS: usize = 32;
+
+// Powers with a whole exponent up to this are multiplied out, see build_pow
+pub const DEFAULT_MAX_POW_UNROLL: u32 = 8;
+
+// Builtin functions that map straight onto LLVM intrinsics, these need no
+// extern declaration or host symbol. Name in source, name of the intrinsic,
+// and the number of arguments taken.
+pub const INTRINSICS: &[(&str, &str, u32)] = &[
+    ("floor", "llvm.floor", 1),
+    ("ceil", "llvm.ceil", 1),
+    ("round", "llvm.round", 1),
+    ("trunc", "llvm.trunc", 1),
+];
+
+// Builtins evaluated entirely at compile time, each takes a single string
+// literal, there being no strings at runtime to give them otherwise
+pub type ConstBuiltin = fn(&str) -> f64;
+
+pub const CONST_BUILTINS: &[(&str, ConstBuiltin)] = &[("len", |text| text.chars().count() as f64)];
+
+pub fn const_builtin(name: &str) -> Option<ConstBuiltin> {
+    CONST_BUILTINS
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, eval)| *eval)
+}
+
+pub fn is_intrinsic(name: &str) -> bool {
+    INTRINSICS.iter().any(|(builtin, ..)| *builtin == name) || const_builtin(name).is_some()
+}
+
 macro_rules! to_llvm_float {
     ($context:expr, $int_val:expr) => {
         $context
@@ -39,29 +86,118 @@ macro_rules! to_llvm_float {
 // LLVM IR
 #[derive(Error, PartialEq, Debug)]
 pub enum BackendError<'src> {
-    #[error("Unknown variable name {0}")]
+    #[error("error[{code}]: Unknown variable name {0}", code = self.code())]
     UnknownVariable(&'src str),
 
-    #[error("Undefined function {0}")]
+    #[error("error[{code}]: Undefined function {0}", code = self.code())]
     UndefinedFunction(&'src str),
 
-    #[error("Function {0} defined twice")]
+    #[error("error[{code}]: Function {0} defined twice", code = self.code())]
     MultipleFunctionDefs(String),
 
-    #[error("Incorrect number of arguments passed to {func_name}, expected {param_cnt}")]
+    #[error("error[{code}]: Incorrect number of arguments passed to {func_name}, expected {param_cnt}", code = self.code())]
     IncorrectNumberOfArgs {
         func_name: &'src str,
         param_cnt: u32,
     },
 
-    #[error("LLVM failed to verify function {0}")]
+    #[error("error[{code}]: LLVM failed to verify function {0}", code = self.code())]
     FailedToVerifyFunc(String),
 
-    #[error("Undefined operator used: {0:?}")]
+    #[error("error[{code}]: Undefined operator used: {0:?}", code = self.code())]
     UndefinedOperator(Ops),
 
-    #[error("Incorrect assignment of variable, left side must be a string name")]
+    #[error("error[{code}]: Incorrect assignment of variable, left side must be a string name", code = self.code())]
     BadAssignment,
+
+    #[error("error[{code}]: Entry point {0} is not a function defined in the program", code = self.code())]
+    UnknownEntryPoint(String),
+
+    #[error("error[{code}]: Entry point {0} must not take any arguments", code = self.code())]
+    EntryPointWithArgs(String),
+
+    #[error("error[{code}]: Can't JIT call {0}, only functions of up to 4 arguments can be called", code = self.code())]
+    TooManyJitArgs(String),
+
+    #[error("error[{code}]: String \"{0}\" used as a value, strings can only be given to builtins like len", code = self.code())]
+    StringNotAValue(&'src str),
+
+    #[error("error[{code}]: {0} needs a string literal, its value is worked out at compile time", code = self.code())]
+    ExpectedStringLiteral(&'src str),
+
+    #[error("error[{code}]: Array used as a number, only its elements are doubles", code = self.code())]
+    ArrayNotAValue,
+
+    #[error("error[{code}]: Only array literals can be indexed", code = self.code())]
+    NotIndexable,
+
+    #[error("error[{code}]: Calls through a function pointer can only be compiled, not interpreted", code = self.code())]
+    IndirectCallNotInterpreted,
+
+    #[error("error[{code}]: Constant {0} is already defined", code = self.code())]
+    ConstRedefined(&'src str),
+
+    #[error("error[{code}]: The value of constant {0} isn't known at compile time", code = self.code())]
+    NotAConstant(&'src str),
+
+    #[error("error[{code}]: Function {0} is void, its call gives nothing to be an operand of", code = self.code())]
+    VoidUsedAsValue(&'src str),
+
+    #[error("error[{code}]: Gave up after evaluating {0} expressions, the step limit", code = self.code())]
+    StepLimitExceeded(u64),
+
+    #[error("error[{code}]: Gave up on a call nested more than {0} deep, the call depth limit", code = self.code())]
+    CallDepthExceeded(usize),
+
+    #[error("error[{code}]: {0} is defined by more than one of the files linked together", code = self.code())]
+    SymbolDefinedTwice(String),
+
+    #[error("error[{code}]: Can't optimize with the profile {0}", code = self.code())]
+    BadProfile(String),
+
+    #[error("error[{code}]: {0} is a host builtin, only there to be called from JIT compiled code", code = self.code())]
+    BuiltinNotCompiled(String),
+
+    #[error("error[{code}]: The builtins can't be replaced, code generated already may call them", code = self.code())]
+    BuiltinsReplaced,
+
+    #[error("error[{code}]: Calls can't be specialized with debug info on, the copies would have nowhere in the source to point at", code = self.code())]
+    SpecializedWithDebugInfo,
+}
+
+impl BackendError<'_> {
+    // Stable codes, like the parser's, but numbered from E0101
+    pub fn code(&self) -> &'static str {
+        use BackendError::*;
+
+        match self {
+            UnknownVariable(_) => "E0101",
+            UndefinedFunction(_) => "E0102",
+            MultipleFunctionDefs(_) => "E0103",
+            IncorrectNumberOfArgs { .. } => "E0104",
+            FailedToVerifyFunc(_) => "E0105",
+            UndefinedOperator(_) => "E0106",
+            BadAssignment => "E0107",
+            UnknownEntryPoint(_) => "E0108",
+            EntryPointWithArgs(_) => "E0109",
+            TooManyJitArgs(_) => "E0110",
+            StringNotAValue(_) => "E0111",
+            ExpectedStringLiteral(_) => "E0112",
+            ArrayNotAValue => "E0113",
+            NotIndexable => "E0114",
+            IndirectCallNotInterpreted => "E0115",
+            ConstRedefined(_) => "E0116",
+            NotAConstant(_) => "E0117",
+            StepLimitExceeded(_) => "E0118",
+            VoidUsedAsValue(_) => "E0119",
+            CallDepthExceeded(_) => "E0120",
+            SymbolDefinedTwice(_) => "E0121",
+            BadProfile(_) => "E0122",
+            BuiltinNotCompiled(_) => "E0123",
+            BuiltinsReplaced => "E0124",
+            SpecializedWithDebugInfo => "E0125",
+        }
+    }
 }
 
 // Our context object that we will pass to recursive calls of codegen
@@ -73,6 +209,12 @@ pub struct LLVMContext<'ctx> {
     module: Module<'ctx>,
     machine: TargetMachine,
     sym_table: RefCell<HashMap<String, PointerValue<'ctx>>>,
+    debug_info: Option<DebugInfo<'ctx>>,
+    source_map: Option<SourceMap<'ctx>>,
+    sanitizer: Option<Sanitizer>,
+    builtins: BuiltinRegistry,
+    consts: RefCell<HashMap<String, f64>>,
+    max_pow_unroll: u32,
 }
 
 impl<'ctx> LLVMContext<'ctx> {
@@ -89,7 +231,9 @@ impl<'ctx> LLVMContext<'ctx> {
                 "generic",
                 "",
                 opt_level.into(),
-                RelocMode::Default,
+                // Position independent, so objects can be linked into PIE
+                // executables, which most distros' cc makes by default
+                RelocMode::PIC,
                 CodeModel::Default,
             )
             .unwrap();
@@ -100,6 +244,110 @@ impl<'ctx> LLVMContext<'ctx> {
             module,
             machine,
             sym_table: RefCell::new(HashMap::new()),
+            debug_info: None,
+            source_map: None,
+            sanitizer: None,
+            builtins: BuiltinRegistry::new(),
+            consts: RefCell::new(HashMap::new()),
+            max_pow_unroll: DEFAULT_MAX_POW_UNROLL,
+        }
+    }
+
+    // The highest exponent multiplied out rather than given to llvm.pow, 0
+    // has every power call it
+    pub fn set_max_pow_unroll(&mut self, max: u32) {
+        self.max_pow_unroll = max;
+    }
+
+    // Calls to the host's builtins are generated from now on, a definition
+    // or extern of the same name takes precedence. They're only for the JIT,
+    // and given just the once, as the code calls into the registry itself.
+    pub fn use_builtins<'src>(
+        &mut self,
+        builtins: BuiltinRegistry,
+    ) -> Result<(), BackendError<'src>> {
+        if !self.builtins.is_empty() {
+            return Err(BackendError::BuiltinsReplaced);
+        }
+
+        self.builtins = builtins;
+        Ok(())
+    }
+
+    pub(crate) fn builtins(&self) -> &BuiltinRegistry {
+        &self.builtins
+    }
+
+    // Emit DWARF debug info for everything generated from now on, the source
+    // given must be the very one the tree was parsed from
+    pub fn enable_debug_info(&mut self, path: &Path, src_code: &str) {
+        self.debug_info = Some(DebugInfo::new(&self.module, self.context, path, src_code));
+    }
+
+    // Map each instruction generated from now on back to the source, see
+    // SourceMap. Works with or without debug info.
+    pub fn enable_source_map(&mut self, src_code: &str) {
+        self.source_map = Some(SourceMap::new(src_code));
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap<'ctx>> {
+        self.source_map.as_ref()
+    }
+
+    // Mark every function generated from now on for the sanitizer to
+    // instrument, which it does once run_sanitizer_passes is called
+    pub fn enable_sanitizer(&mut self, sanitizer: Sanitizer) {
+        self.sanitizer = Some(sanitizer);
+    }
+
+    // Run last, after the optimizations, like clang does, so there are fewer
+    // loads and stores left to check, those of variables put in registers
+    pub fn run_sanitizer_passes(&self) {
+        if let Some(sanitizer) = self.sanitizer {
+            let ran = sanitizer
+                .passes()
+                .iter()
+                .any(|passes| self.try_run_passes_on(&self.module, passes).is_ok());
+
+            assert!(ran, "FATAL: LLVM has no {sanitizer:?} sanitizer passes");
+        }
+    }
+
+    // Debug info must be finalized once all the code is generated,
+    // before running any passes or writing the module out
+    pub fn finalize_debug_info(&self) {
+        if let Some(ref debug_info) = self.debug_info {
+            debug_info.finalize();
+        }
+    }
+
+    // Removes all debug info from the module, the !dbg attachments, the
+    // compile unit and the debug info version flag, for smaller output.
+    // Does nothing if there wasn't any. Other named metadata is kept, LLVM's
+    // C API has no way to erase it, though we never add any of our own.
+    pub fn strip_debug_info(&self) {
+        self.module.strip_debug_info();
+    }
+
+    fn set_debug_location(&self, slice: &str) {
+        if let Some(ref debug_info) = self.debug_info {
+            debug_info.set_location(&self.builder, self.context, slice);
+        }
+
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent());
+
+        if let (Some(source_map), Some(function)) = (&self.source_map, function) {
+            source_map.set_location(function, slice);
+        }
+    }
+
+    // Done with the body of function, whatever's left in it is mapped
+    fn flush_source_map(&self, function: FunctionValue<'ctx>) {
+        if let Some(ref source_map) = self.source_map {
+            source_map.flush(function);
         }
     }
 
@@ -110,6 +358,144 @@ impl<'ctx> LLVMContext<'ctx> {
         self.module.print_to_stderr();
     }
 
+    // Looks up a builtin in the table of intrinsics, declaring the f64
+    // overload of the intrinsic in our module, e.g. llvm.floor.f64
+    fn get_intrinsic(&self, name: &str) -> Option<(FunctionValue<'ctx>, u32)> {
+        let (_, intrinsic_name, param_cnt) =
+            INTRINSICS.iter().find(|(builtin, ..)| *builtin == name)?;
+
+        let intrinsic = Intrinsic::find(intrinsic_name)?;
+        let declaration = intrinsic
+            .get_declaration(&self.module, &[self.context.f64_type().into()])
+            .expect("FATAL: LLVM failed to declare intrinsic");
+
+        Some((declaration, *param_cnt))
+    }
+
+    // base ^ exponent, for a constant whole exponent up to max_pow_unroll
+    // that's multiplying base by itself, "x ^ 3" being "x * x * x", which is
+    // faster than calling llvm.pow and exact. Any other exponent calls it.
+    fn build_pow(
+        &self,
+        base: FloatValue<'ctx>,
+        exponent: &ASTExpr,
+        exponent_val: FloatValue<'ctx>,
+    ) -> FloatValue<'ctx> {
+        let unrolled = self
+            .eval_const(exponent)
+            .and_then(|n| pow_unroll(n, self.max_pow_unroll));
+
+        if let Some(n) = unrolled {
+            if n == 0 {
+                return self.context.f64_type().const_float(1.0);
+            }
+
+            return (1..n).fold(base, |product, _| {
+                self.builder
+                    .build_float_mul(product, base, "powtmp")
+                    .unwrap()
+            });
+        }
+
+        let pow = Intrinsic::find("llvm.pow")
+            .and_then(|pow| pow.get_declaration(&self.module, &[self.context.f64_type().into()]))
+            .expect("FATAL: LLVM failed to declare intrinsic");
+
+        self.builder
+            .build_call(pow, &[base.into(), exponent_val.into()], "powtmp")
+            .expect("FATAL: LLVM failed to build call!")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_float_value()
+    }
+
+    // See transform::eval_const, minus and plus in front are only builtin
+    // while no one has overloaded them
+    fn eval_const(&self, expr: &ASTExpr) -> Option<f64> {
+        eval_const(
+            expr,
+            &self.consts.borrow(),
+            &|name| self.is_declared(name),
+            self.max_pow_unroll,
+        )
+    }
+
+    // Spills the arguments to an array, then calls the thunk with it and the
+    // address of the builtin's global, declaring each the first time it's needed
+    fn build_builtin_call(&self, name: &str, args: &[FloatValue<'ctx>]) -> CallSiteValue<'ctx> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let args_type = f64_type.ptr_type(AddressSpace::default());
+
+        let thunk = self.module.get_function(THUNK_NAME).unwrap_or_else(|| {
+            let fn_type =
+                f64_type.fn_type(&[ptr_type.into(), args_type.into(), i64_type.into()], false);
+
+            self.module
+                .add_function(THUNK_NAME, fn_type, Some(Linkage::External))
+        });
+
+        let function = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+
+        let array_type = f64_type.array_type(args.len() as u32);
+        let array_ptr = self.create_entry_block_alloca_of(function, array_type, "builtinargs");
+
+        let elem_ptr = |idx: usize| {
+            unsafe {
+                self.builder.build_in_bounds_gep(
+                    array_type,
+                    array_ptr,
+                    &[i64_type.const_zero(), i64_type.const_int(idx as u64, false)],
+                    "argptr",
+                )
+            }
+            .expect("FATAL: LLVM failed to build GEP instruction")
+        };
+
+        for (idx, arg) in args.iter().enumerate() {
+            self.builder
+                .build_store(elem_ptr(idx), *arg)
+                .expect("FATAL: LLVM failed to build store instruction");
+        }
+
+        let global_name = format!("{BUILTIN_PREFIX}{name}");
+        let global = self.module.get_global(&global_name).unwrap_or_else(|| {
+            let global = self
+                .module
+                .add_global(self.context.i8_type(), None, &global_name);
+            global.set_linkage(Linkage::External);
+            global
+        });
+
+        let thunk_args = [
+            global.as_pointer_value().into(),
+            elem_ptr(0).into(),
+            i64_type.const_int(args.len() as u64, false).into(),
+        ];
+
+        self.builder
+            .build_call(thunk, &thunk_args, "calltmp")
+            .expect("Irrecoverable: LLVM failed to build call expression")
+    }
+
+    // Same as dump_module, but gives back the IR as a string instead
+    pub fn module_to_string(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    // Writes the module out as bitcode, which can be loaded back in far
+    // quicker than compiling it all over again
+    pub fn write_bitcode(&self, path: &Path) -> bool {
+        self.module.write_bitcode_to_path(path)
+    }
+
     // Small helper method to remove the top level anonymous expression,
     // needed for REPL so that we don't define top level twice, just delete
     // it and then define it again.
@@ -121,7 +507,15 @@ impl<'ctx> LLVMContext<'ctx> {
 
     // Optimization passes
     pub fn run_passes(&self, passes: &str) {
+        self.run_passes_on(&self.module, passes);
+    }
+
+    fn run_passes_on(&self, module: &Module<'ctx>, passes: &str) {
+        self.try_run_passes_on(module, passes).unwrap();
+    }
 
+    // A pipeline LLVM can't parse is an error, rather than a panic
+    fn try_run_passes_on(&self, module: &Module<'ctx>, passes: &str) -> Result<(), LLVMString> {
         if !passes.is_empty() {
             let pass_options = PassBuilderOptions::create();
 
@@ -138,49 +532,705 @@ impl<'ctx> LLVMContext<'ctx> {
             pass_options.set_call_graph_profile(true);
             pass_options.set_merge_functions(true);
     
+            module.run_passes(passes, &self.machine, pass_options)?;
+        }
+
+        Ok(())
+    }
+
+    // Run before the optimization passes, so those get to see the counters
+    // when instrumenting, or the profile's branch weights when using one
+    pub fn run_pgo_passes<'src>(&self, pgo: &Pgo) -> Result<(), BackendError<'src>> {
+        pgo.with_profile(|| self.run_passes(pgo.passes()))
+            .map_err(BackendError::BadProfile)
+    }
+
+    // A copy of the module as it is now, with the passes run over the copy
+    // only, the module itself is left as it was
+    pub(crate) fn optimized_copy(&self, passes: &str) -> Module<'ctx> {
+        let module = self.module.clone();
+        self.run_passes_on(&module, passes);
+        module
+    }
+
+    pub(crate) fn get_function(&self, name: &str) -> Option<FunctionValue<'ctx>> {
+        self.module.get_function(name)
+    }
+
+    // Runs the passes just like run_passes, but also reports what they changed
+    pub fn run_passes_explained(&self, passes: &str) -> OptReport {
+        let before = self.stats();
+        self.run_passes(passes);
+        let after = self.stats();
+
+        OptReport { before, after }
+    }
+
+    // Runs the passes one at a time rather than as a single pipeline, writing
+    // out the IR of the module after each one, like opt's -print-after-all
+    pub fn run_passes_printing(&self, passes: &str, out: &mut impl Write) -> io::Result<()> {
+        for pass in passes.split(',').filter(|pass| !pass.is_empty()) {
+            self.run_passes(pass);
+
+            writeln!(out, "; *** IR Dump After {pass} ***")?;
+            write!(out, "{}", self.module_to_string())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ModuleStats {
+        ModuleStats::of(&self.module)
+    }
+
+    // Make the named function the only one visible outside the object,
+    // every other function defined here gets internal linkage. Declarations
+    // (externs) are left alone, they are defined somewhere else after all.
+    pub fn set_entry_point(&self, entry: &str) -> Result<(), BackendError<'static>> {
+        let is_defined = |name: &str| {
             self.module
-                .run_passes(passes, &self.machine, pass_options)
-                .unwrap();
+                .get_function(name)
+                .is_some_and(|func| func.count_basic_blocks() > 0)
+        };
+
+        if !is_defined(entry) {
+            return Err(BackendError::UnknownEntryPoint(entry.to_string()));
+        }
+
+        for func in self.module.get_functions() {
+            if func.count_basic_blocks() == 0 {
+                continue;
+            }
+
+            if func.get_name().to_bytes() == entry.as_bytes() {
+                func.set_linkage(Linkage::External);
+            } else {
+                func.set_linkage(Linkage::Internal);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Our functions all return a double, but the C runtime wants main to
+    // return an int exit code. So the main defined in the program is renamed,
+    // and a real main wrapping it is added, converting its result to an int.
+    pub fn add_c_main(&self) -> Result<(), BackendError<'static>> {
+        let main_fn = self
+            .module
+            .get_function("main")
+            .filter(|func| func.count_basic_blocks() > 0)
+            .ok_or_else(|| BackendError::UnknownEntryPoint("main".to_string()))?;
+
+        if main_fn.count_params() != 0 {
+            return Err(BackendError::EntryPointWithArgs("main".to_string()));
+        }
+
+        main_fn.as_global_value().set_name("__kaleidrs_main");
+        main_fn.set_linkage(Linkage::Internal);
+
+        let i32_type = self.context.i32_type();
+        let c_main = self
+            .module
+            .add_function("main", i32_type.fn_type(&[], false), None);
+
+        let entry = self.context.append_basic_block(c_main, "entry");
+        self.builder.position_at_end(entry);
+
+        let res = self
+            .builder
+            .build_call(main_fn, &[], "calltmp")
+            .expect("FATAL: LLVM failed to build call!")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_float_value();
+
+        let exit_code = self
+            .builder
+            .build_float_to_signed_int(res, i32_type, "exitcode")
+            .expect("FATAL: LLVM failed to convert float to int");
+
+        self.builder
+            .build_return(Some(&exit_code))
+            .expect("FATAL: LLVM failed to build return!");
+
+        if !c_main.verify(true) {
+            return Err(BackendError::FailedToVerifyFunc("main".to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Generates a main running the bodies of the given top level expressions
+    // one after another, in the order given, returning what the last gave.
+    // Rather than each becoming its own anonymous function, they all share
+    // this one, so a file of just expressions is a program that can be run.
+    pub fn wrap_main<'src>(&self, exprs: &[&Function<'src>]) -> Result<(), BackendError<'src>> {
+        if self.module.get_function("main").is_some() {
+            return Err(BackendError::MultipleFunctionDefs("main".to_string()));
+        }
+
+        let f64_type = self.context.f64_type();
+        let main_fn = self
+            .module
+            .add_function("main", f64_type.fn_type(&[], false), None);
+
+        let entry = self.context.append_basic_block(main_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        if let Some(ref debug_info) = self.debug_info {
+            debug_info.start_function(&self.builder, self.context, main_fn, "main", "main");
+        }
+
+        // Nowhere in the source until the first expression's first name
+        if let Some(ref source_map) = self.source_map {
+            source_map.set_location(main_fn, "main");
+        }
+
+        self.sym_table.borrow_mut().clear();
+
+        let mut last = f64_type.const_float(0.0);
+
+        for expr in exprs.iter() {
+            last = expr.body.codegen(self)?.into_float_value();
+        }
+
+        self.builder
+            .build_return(Some(&last))
+            .expect("FATAL: LLVM failed to build return!");
+
+        if let Some(ref debug_info) = self.debug_info {
+            debug_info.finish_function(&self.builder);
+        }
+
+        self.flush_source_map(main_fn);
+
+        if !main_fn.verify(true) {
+            return Err(BackendError::FailedToVerifyFunc("main".to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Renames a function, so it's out of the way of another of the same
+    // name, giving back whether there was one to rename
+    pub fn rename_function(&self, from: &str, to: &str) -> bool {
+        match self.module.get_function(from) {
+            Some(function) => {
+                function.as_global_value().set_name(to);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Generates a function calling each of parts one after another, in the
+    // order given, returning what the last gave, like wrap_main does with
+    // expressions. The parts are then internal, only it calls them.
+    pub fn join_functions<'src>(
+        &self,
+        name: &str,
+        parts: &[String],
+    ) -> Result<(), BackendError<'src>> {
+        let f64_type = self.context.f64_type();
+        let joined = self
+            .module
+            .add_function(name, f64_type.fn_type(&[], false), None);
+
+        let entry = self.context.append_basic_block(joined, "entry");
+        self.builder.position_at_end(entry);
+
+        let mut last = f64_type.const_float(0.0);
+
+        for part in parts.iter() {
+            let function = self
+                .module
+                .get_function(part)
+                .expect("FATAL: joining a function that isn't in the module!");
+
+            function.set_linkage(Linkage::Internal);
+
+            last = self
+                .builder
+                .build_call(function, &[], "parttmp")
+                .expect("FATAL: LLVM failed to build call!")
+                .try_as_basic_value()
+                .left()
+                .expect("FATAL: top level code not giving back a double!")
+                .into_float_value();
+        }
+
+        self.builder
+            .build_return(Some(&last))
+            .expect("FATAL: LLVM failed to build return!");
+
+        if !joined.verify(true) {
+            return Err(BackendError::FailedToVerifyFunc(name.to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Every call whose arguments are all numbers gets a copy of its callee
+    // of its own, taking no arguments, those numbers put in place of the
+    // params, like "f(3)" calling "f.spec(3)". The passes can then fold the
+    // copy down to what the call gives, without having to inline it first.
+    // Giving back how many copies were made.
+    //
+    // Only the calls there are before any copies are made get one, so the
+    // calls in the copies don't, and a recursive function doesn't get a
+    // copy for each number down to the base case. Nor do calls a function
+    // makes to itself. No more than MAX_SPECIALIZATIONS copies are made.
+    pub fn specialize_calls<'src>(
+        &self,
+        defs: &[&Function<'src>],
+    ) -> Result<usize, BackendError<'src>> {
+        // A copy would have nowhere in the source to point its debug info at
+        if self.debug_info.is_some() {
+            return Err(BackendError::SpecializedWithDebugInfo);
+        }
+
+        let mut calls = vec![];
+
+        for function in self.module.get_functions() {
+            for block in function.get_basic_blocks() {
+                for insn in block.get_instructions() {
+                    match constant_call(insn) {
+                        Some((callee, args)) if callee != function => {
+                            calls.push((insn, callee, args))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let mut copies = 0;
+
+        for (call, callee, args) in calls {
+            let name = callee.get_name().to_string_lossy().into_owned();
+
+            let Some(def) = defs.iter().find(|def| def.proto.get_name() == name) else {
+                continue;
+            };
+
+            let args_text = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            let spec_name = format!("{name}.spec({})", args_text.join(", "));
+
+            let spec = match self.module.get_function(&spec_name) {
+                Some(spec) => spec,
+                None if copies < MAX_SPECIALIZATIONS => {
+                    copies += 1;
+                    self.codegen_specialized(def, &spec_name, &args)?
+                }
+                None => continue,
+            };
+
+            self.builder.position_before(&call);
+
+            let spec_call = self
+                .builder
+                .build_call(spec, &[], "calltmp")
+                .expect("FATAL: LLVM failed to build call!")
+                .try_as_basic_value()
+                .left()
+                .and_then(|value| value.as_instruction_value())
+                .expect("FATAL: a specialized call gives a double");
+
+            call.replace_all_uses_with(&spec_call);
+            call.erase_from_basic_block();
+        }
+
+        Ok(copies)
+    }
+
+    // The body of def, with its params bound to args rather than passed in,
+    // they're still variables all the same, assigning to them is fine
+    fn codegen_specialized<'src>(
+        &self,
+        def: &Function<'src>,
+        name: &str,
+        args: &[f64],
+    ) -> Result<FunctionValue<'ctx>, BackendError<'src>> {
+        let f64_type = self.context.f64_type();
+        let spec =
+            self.module
+                .add_function(name, f64_type.fn_type(&[], false), Some(Linkage::Private));
+
+        let entry = self.context.append_basic_block(spec, "entry");
+        self.builder.position_at_end(entry);
+
+        if let Some(ref source_map) = self.source_map {
+            source_map.set_location(spec, name);
+        }
+
+        self.sym_table.borrow_mut().clear();
+
+        for (param, arg) in def.proto.get_params().into_iter().zip(args) {
+            let param_ptr = self.create_entry_block_alloca(spec, param);
+
+            self.builder
+                .build_store(param_ptr, f64_type.const_float(*arg))
+                .expect("FATAL: LLVM failed to build store instruction");
+
+            self.sym_table
+                .borrow_mut()
+                .insert(param.to_string(), param_ptr);
+        }
+
+        let body = def.body.codegen(self)?.into_float_value();
+
+        self.builder
+            .build_return(Some(&body))
+            .expect("FATAL: LLVM failed to build return!");
+
+        self.flush_source_map(spec);
+
+        if !spec.verify(true) {
+            return Err(BackendError::FailedToVerifyFunc(name.to_string()));
+        }
+
+        Ok(spec)
+    }
+
+    // Builtins live in this process, so code calling them can't be written out
+    pub fn compile<'src>(
+        &self,
+        path: &Path,
+        file_type: FileType,
+    ) -> Result<(), BackendError<'src>> {
+        let builtin = self.module.get_globals().find_map(|global| {
+            let name = global.get_name().to_string_lossy().into_owned();
+            name.strip_prefix(BUILTIN_PREFIX).map(str::to_string)
+        });
+
+        if let Some(builtin) = builtin {
+            return Err(BackendError::BuiltinNotCompiled(builtin));
+        }
+
+        self.machine.write_to_file(
+            &self.module, 
+            file_type, 
+            path,
+        ).expect("Failed to write object to file");
+
+        Ok(())
+    }
+
+    // Done with generating code, giving up the module, which lives on for
+    // as long as the Context it was made in, not this
+    pub fn into_module(self) -> Module<'ctx> {
+        self.module
+    }
+
+    // Links the module of another file into this one, for compiling a whole
+    // program to one module, so the passes can inline across files. An
+    // extern in one is then resolved by the definition in the other, but
+    // what both define is an error of our own, rather than LLVM's. Those
+    // internal to a file, like the strings, are just renamed by LLVM.
+    pub fn link_in<'src>(&self, other: Module<'ctx>) -> Result<(), BackendError<'src>> {
+        let exported = |linkage| !matches!(linkage, Linkage::Private | Linkage::Internal);
+
+        for func in other.get_functions() {
+            let name = func.get_name().to_string_lossy();
+
+            let Some(ours) = self.module.get_function(&name) else {
+                continue;
+            };
+
+            let both_defined = func.count_basic_blocks() > 0 && ours.count_basic_blocks() > 0;
+
+            if both_defined && exported(func.get_linkage()) && exported(ours.get_linkage()) {
+                return Err(BackendError::SymbolDefinedTwice(name.into_owned()));
+            }
+        }
+
+        for global in other.get_globals() {
+            let name = global.get_name().to_string_lossy();
+
+            let Some(ours) = self.module.get_global(&name) else {
+                continue;
+            };
+
+            let both_defined =
+                global.get_initializer().is_some() && ours.get_initializer().is_some();
+
+            if both_defined && exported(global.get_linkage()) && exported(ours.get_linkage()) {
+                return Err(BackendError::SymbolDefinedTwice(name.into_owned()));
+            }
+        }
+
+        self.module
+            .link_in_module(other)
+            .expect("FATAL: LLVM failed to link the modules");
+
+        Ok(())
+    }
+
+    // See cfg::write_cfgs
+    pub fn write_cfgs(&self, base: &Path) -> io::Result<Vec<PathBuf>> {
+        cfg::write_cfgs(&self.module, base)
+    }
+
+    // The functions and globals of the module as it is right now
+    pub fn symbols(&self) -> SymbolTable {
+        SymbolTable::of(&self.module)
+    }
+
+    // Whether the module has a function of this name yet, with a body or not
+    pub fn is_declared(&self, fn_name: &str) -> bool {
+        self.module.get_function(fn_name).is_some()
+    }
+
+    /// JIT evalution, creates an ExecutionEngine object, JIT compiles the function,
+    /// then attempts to call the function, will return the resulting floating point val.
+    ///
+    /// # Safety
+    ///
+    /// The module's code is run as is. Every extern it calls has to resolve to
+    /// a host symbol really taking and returning doubles, and the module has to
+    /// have passed verification, nothing here can check either.
+    pub unsafe fn jit_eval(&self) -> Result<f64, BackendError> {
+        let exec_engine = self
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .expect("FATAL: Failed to create JIT execution engine!");
+
+        // Before anything is looked up, that's when the code is finalized
+        builtins::map_builtins(&exec_engine, &self.module, &self.builtins);
+
+        let jitted_fn: JitFunction<'ctx, TopLevelSignature> = exec_engine
+            .get_function("__anonymous_expr")
+            .expect("FATAL: symbol '__anonymous_expr' not present in module!");
+
+        let res = jitted_fn.call();
+
+        exec_engine.remove_module(&self.module).unwrap();
+
+        Ok(res)
+    }
+
+    // Generates func, its prototype first if it wasn't declared yet, giving
+    // back the function itself rather than an AnyValueEnum, for hosts to add
+    // attributes to or get a pointer to from their own engine
+    pub fn codegen_function<'src>(
+        &self,
+        func: &Function<'src>,
+    ) -> Result<FunctionValue<'ctx>, BackendError<'src>> {
+        // See if function has been defined, if not, generate prototype
+        // to get the LLVM function value.
+        let fn_val = match self.module.get_function(&func.proto.get_name()) {
+            Some(fn_val) => fn_val,
+            None => func.proto.codegen(self)?.into_function_value(),
+        };
+
+        // To make sure we aren't defining functions twice, I just check if it
+        // has no entry basic block, if it does, then propogate error.
+        if fn_val.get_first_basic_block().is_some() {
+            return Err(BackendError::MultipleFunctionDefs(func.proto.get_name()));
+        }
+
+        // Declared before, the params have the names the declaration gave
+        // them, the body goes by those of the definition though
+        if let Prototype::FunctionProto { args, .. } = &*func.proto {
+            for (param, arg) in fn_val.get_params().iter().zip(args) {
+                param.set_name(arg);
+            }
+        }
+
+        // This sets our cursor for creating instructions to the basic block
+        // for this function
+        let bb_entry = self.context.append_basic_block(fn_val, "entry");
+        self.builder.position_at_end(bb_entry);
+
+        let name_slice = match *func.proto {
+            Prototype::FunctionProto { name, .. } => name,
+            Prototype::OverloadedUnaryOpProto { arg, .. } => arg,
+            Prototype::OverloadedBinaryOpProto { args: (lhs, _), .. } => lhs,
+        };
+
+        if let Some(ref debug_info) = self.debug_info {
+            debug_info.start_function(
+                &self.builder,
+                self.context,
+                fn_val,
+                &func.proto.get_name(),
+                name_slice,
+            );
+        }
+
+        if let Some(ref source_map) = self.source_map {
+            source_map.set_location(fn_val, name_slice);
+        }
+
+        // Update the symbol table with the args names and references
+        // to their LLVM values.
+        self.sym_table.borrow_mut().clear();
+        for param in fn_val.get_params() {
+            // TODO: Change the named value key to a non-owned CStr reference
+            // so I am not copying and cloning to Rust Strings
+            let owned_str = param
+                .into_float_value()
+                .get_name()
+                .to_str()
+                .unwrap()
+                .to_string();
+
+            // The mutable variables chapter, chapter 7, our passed arguments may be mutated.
+            // Store them all on the stack and allow the function inside to mutate them
+            // as memory objects
+
+            // Allocate the argument to stack.
+            let param_ptr = self.create_entry_block_alloca(fn_val, &owned_str);
+
+            // Store the value of this paramter to it's stack copy
+            let _store = self
+                .builder
+                .build_store(param_ptr, param)
+                .expect("FATAL: LLVM failed to build store instruction");
+
+            // Add it to scope
+            self.sym_table.borrow_mut().insert(owned_str, param_ptr);
+        }
+
+        // Generate code for the body of the function as an ASTExpr node
+        let ir_body = func.body.codegen(self)?;
+
+        // We need to add a return at the end so we return from functions we call
+        self.builder
+            .build_return(Some(&ir_body.into_float_value() as &dyn BasicValue))
+            .expect("FATAL: LLVM failed to build a return!");
+
+        // Attributes given in source, e.g. "def [inline] f(x) ..."
+        for attr in func.attrs.iter() {
+            let kind_id = Attribute::get_named_enum_kind_id(attr.llvm_name());
+            let llvm_attr = self.context.create_enum_attribute(kind_id, 0);
+            fn_val.add_attribute(AttributeLoc::Function, llvm_attr);
+        }
+
+        if let Some(sanitizer) = self.sanitizer {
+            let kind_id = Attribute::get_named_enum_kind_id(sanitizer.attribute());
+            let llvm_attr = self.context.create_enum_attribute(kind_id, 0);
+            fn_val.add_attribute(AttributeLoc::Function, llvm_attr);
+        }
+
+        if let Some(ref debug_info) = self.debug_info {
+            debug_info.finish_function(&self.builder);
+        }
+
+        self.flush_source_map(fn_val);
+
+        if !fn_val.verify(true) {
+            return Err(BackendError::FailedToVerifyFunc(func.proto.get_name()));
         }
+
+        Ok(fn_val)
     }
 
-    pub fn compile(&self, path: &Path, file_type: FileType) -> () {
-        self.machine.write_to_file(
-            &self.module, 
-            file_type, 
-            path,
-        ).expect("Failed to write object to file");
+    // Lowers the elements of an array literal to an LLVM array of doubles,
+    // a constant one when they're all constant, "[1, 2, 3]" is then just
+    // [3 x double] [1.0, 2.0, 3.0], otherwise one insertvalue per element
+    pub fn codegen_array<'src>(
+        &self,
+        elems: &[Box<ASTExpr<'src>>],
+    ) -> Result<ArrayValue<'ctx>, BackendError<'src>> {
+        let f64_type = self.context.f64_type();
+
+        let values = elems
+            .iter()
+            .map(|elem| codegen_operand(self, elem).map(AnyValueEnum::into_float_value))
+            .collect::<Result<Vec<FloatValue<'ctx>>, _>>()?;
+
+        if values.iter().all(|value| value.is_const()) {
+            return Ok(f64_type.const_array(&values));
+        }
+
+        let mut array = f64_type.array_type(values.len() as u32).get_undef();
+
+        for (idx, value) in values.into_iter().enumerate() {
+            array = self
+                .builder
+                .build_insert_value(array, value, idx as u32, "arraytmp")
+                .expect("FATAL: LLVM failed to build insertvalue instruction")
+                .into_array_value();
+        }
+
+        Ok(array)
     }
 
-    // JIT evalution, creates an ExecutionEngine object, JIT compiles the function,
-    // then attempts to call the function, will return the resulting floating point val.
-    pub unsafe fn jit_eval(&self) -> Result<f64, BackendError> {
-        let exec_engine = self
-            .module
-            .create_jit_execution_engine(OptimizationLevel::None)
-            .expect("FATAL: Failed to create JIT execution engine!");
+    // Calls a function pointer held in a double. There is nothing to say
+    // what the pointee takes, so it's trusted to take as many doubles as
+    // were passed here
+    fn build_pointer_call(
+        &self,
+        fn_val: FloatValue<'ctx>,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> CallSiteValue<'ctx> {
+        let function = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
 
-        let jitted_fn: JitFunction<'ctx, TopLevelSignature> = exec_engine
-            .get_function("__anonymous_expr")
-            .expect("FATAL: symbol '__anonymous_expr' not present in module!");
+        // The bits are reinterpreted going through memory, which optimizing
+        // turns back into a bitcast. A bitcast straight away can be selected
+        // into a call of the float register the double is in, by the fast
+        // instruction selector the JIT uses on unoptimized code.
+        let fn_slot = self.create_entry_block_alloca(function, "fnslot");
 
-        let res = jitted_fn.call();
+        self.builder
+            .build_store(fn_slot, fn_val)
+            .expect("FATAL: LLVM failed to build store instruction");
 
-        exec_engine.remove_module(&self.module).unwrap();
+        let fn_addr = self
+            .builder
+            .build_load(self.context.i64_type(), fn_slot, "fnaddr")
+            .expect("FATAL: LLVM failed to build load instruction")
+            .into_int_value();
 
-        Ok(res)
+        let f64_type = self.context.f64_type();
+        let param_types = vec![BasicMetadataTypeEnum::FloatType(f64_type); args.len()];
+        let fn_type = f64_type.fn_type(&param_types, false);
+
+        let fn_ptr = self
+            .builder
+            .build_int_to_ptr(fn_addr, fn_type.ptr_type(AddressSpace::default()), "fnptr")
+            .expect("FATAL: LLVM failed to build inttoptr instruction");
+
+        self.builder
+            .build_indirect_call(fn_type, fn_ptr, args, "calltmp")
+            .expect("Irrecoverable: LLVM failed to build call expression")
     }
 
     fn create_entry_block_alloca(
         &self,
         function: FunctionValue<'ctx>,
         var_name: &str,
+    ) -> PointerValue<'ctx> {
+        self.create_entry_block_alloca_of(function, self.context.f64_type(), var_name)
+    }
+
+    // Same, for something other than a double, like an array
+    fn create_entry_block_alloca_of(
+        &self,
+        function: FunctionValue<'ctx>,
+        ty: impl BasicType<'ctx>,
+        var_name: &str,
     ) -> PointerValue<'ctx> {
         let ir_builder = self.context.create_builder();
-        ir_builder.position_at_end(function.get_first_basic_block().unwrap());
+        let entry = function.get_first_basic_block().unwrap();
+
+        // Before anything else in it, a var in a loop's body comes after
+        // the entry has already branched off to the loop
+        match entry.get_first_instruction() {
+            Some(first) => ir_builder.position_before(&first),
+            None => ir_builder.position_at_end(entry),
+        }
 
         let alloca_insn = ir_builder
-            .build_alloca(self.context.f64_type(), var_name)
+            .build_alloca(ty, var_name)
             .expect("FATAL: LLVM failed to build alloca instruction");
 
         alloca_insn
@@ -204,6 +1254,74 @@ where
     fn codegen(&self, context: &LLVMContext<'ctx>) -> IRGenResult<'ir, 'src>;
 }
 
+// The function a call instruction calls, and its arguments, if it has some
+// and they're all constant doubles
+fn constant_call(insn: InstructionValue<'_>) -> Option<(FunctionValue<'_>, Vec<f64>)> {
+    if insn.get_opcode() != InstructionOpcode::Call || insn.get_num_operands() < 2 {
+        return None;
+    }
+
+    // The callee is the last operand, after the arguments. Calling through
+    // a pointer, it's the inttoptr the pointer was cast with, which isn't a
+    // function, whatever it happens to be named.
+    let callee_idx = insn.get_num_operands() - 1;
+    let callee = insn.get_operand(callee_idx)?.left()?;
+
+    if unsafe { LLVMIsAFunction(callee.as_value_ref()) }.is_null() {
+        return None;
+    }
+
+    let callee = unsafe { CallSiteValue::new(insn.as_value_ref()) }.get_called_fn_value();
+
+    // A function passed along is constant too, its address cast to a
+    // double, but there's no number to get out of it
+    let args = (0..callee_idx)
+        .map(|idx| match insn.get_operand(idx)?.left()? {
+            BasicValueEnum::FloatValue(arg)
+                if !unsafe { LLVMIsAConstantFP(arg.as_value_ref()) }.is_null() =>
+            {
+                arg.get_constant().map(|(arg, _)| arg)
+            }
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((callee, args))
+}
+
+// A call to a void extern is fine as a statement, on its own, as the last
+// thing in a body or in a loop's, but anything using its value, an operator
+// or an argument, would be computing with nothing
+fn check_operand<'src>(
+    context: &LLVMContext,
+    operand: &ASTExpr<'src>,
+) -> Result<(), BackendError<'src>> {
+    match operand {
+        ASTExpr::CallExpr { callee, .. }
+            if context
+                .module
+                .get_function(callee)
+                .is_some_and(|function| function.get_type().get_return_type().is_none()) =>
+        {
+            Err(BackendError::VoidUsedAsValue(callee))
+        }
+
+        ASTExpr::ParenExpr(inner) => check_operand(context, inner),
+
+        _ => Ok(()),
+    }
+}
+
+// Generates an expression whose value is used, an argument, an initializer,
+// a condition or a bound, which a void call can't give
+fn codegen_operand<'ctx, 'src>(
+    context: &LLVMContext<'ctx>,
+    operand: &ASTExpr<'src>,
+) -> IRGenResult<'ctx, 'src> {
+    check_operand(context, operand)?;
+    operand.codegen(context)
+}
+
 impl<'ctx, 'ir, 'src> LLVMCodeGen<'ctx, 'ir, 'src> for ASTExpr<'src>
 where
     'ctx: 'ir,
@@ -220,9 +1338,70 @@ where
                 Ok(float_type.const_float(*num).as_any_value_enum())
             }
 
+            StringExpr(text) => Err(BackendError::StringNotAValue(text)),
+
+            // The parens are only there for the formatter, nothing to generate for them
+            ParenExpr(inner) => inner.codegen(context),
+
+            // Everything else works on doubles, an array is only meaningful
+            // to whatever asks for one with codegen_array
+            ArrayExpr(_) => Err(BackendError::ArrayNotAValue),
+
+            // The array is put on the stack, then the element is loaded from
+            // it. The index is truncated, and not checked against the length,
+            // out of bounds is undefined, whatever's in memory there is read.
+            IndexExpr { base, index } => {
+                let ArrayExpr(elems) = &**base else {
+                    return Err(BackendError::NotIndexable);
+                };
+
+                let array = context.codegen_array(elems)?;
+                let index = codegen_operand(context, index)?.into_float_value();
+
+                let function = context
+                    .builder
+                    .get_insert_block()
+                    .unwrap()
+                    .get_parent()
+                    .unwrap();
+
+                let array_type = array.get_type();
+                let array_ptr =
+                    context.create_entry_block_alloca_of(function, array_type, "arrayptr");
+
+                context
+                    .builder
+                    .build_store(array_ptr, array)
+                    .expect("FATAL: LLVM failed to build store instruction");
+
+                let i64_type = context.context.i64_type();
+                let index = context
+                    .builder
+                    .build_float_to_signed_int(index, i64_type, "idxtmp")
+                    .expect("FATAL: LLVM failed to convert float to int");
+
+                let elem_ptr = unsafe {
+                    context.builder.build_in_bounds_gep(
+                        array_type,
+                        array_ptr,
+                        &[i64_type.const_zero(), index],
+                        "elemptr",
+                    )
+                }
+                .expect("FATAL: LLVM failed to build GEP instruction");
+
+                Ok(context
+                    .builder
+                    .build_load(context.context.f64_type(), elem_ptr, "elemtmp")
+                    .expect("FATAL: LLVM failed to build load instruction")
+                    .as_any_value_enum())
+            }
+
             // To handle variable case, make sure the variable exists in symbol table,
             // if it doesn't return error, otherwise, fetch the LLVM Value for that variable
             VariableExpr(varname) => {
+                context.set_debug_location(varname);
+
                 if let Some(pointer_val) = context.sym_table.borrow().get(*varname) {
                     let load_insn = context
                         .builder
@@ -230,6 +1409,27 @@ where
                         .expect("FATAL: LLVM failed to build load instruction");
 
                     Ok(load_insn.as_any_value_enum())
+                } else if let Some(value) = context.consts.borrow().get(*varname) {
+                    // A constant is just its number, there is nothing to load
+                    let f64_type = context.context.f64_type();
+                    Ok(f64_type.const_float(*value).as_any_value_enum())
+                } else if let Some(function) = context.module.get_function(varname) {
+                    // Not a local, but the name of a function, so the value is a pointer
+                    // to it. Every value is a double, so the pointer has to be smuggled
+                    // through one, its bits reinterpreted, not converted
+                    let fn_ptr = function.as_global_value().as_pointer_value();
+
+                    let fn_addr = context
+                        .builder
+                        .build_ptr_to_int(fn_ptr, context.context.i64_type(), "fnaddr")
+                        .expect("FATAL: LLVM failed to build ptrtoint instruction");
+
+                    let fn_val = context
+                        .builder
+                        .build_bitcast(fn_addr, context.context.f64_type(), "fnval")
+                        .expect("FATAL: LLVM failed to build bitcast instruction");
+
+                    Ok(fn_val.as_any_value_enum())
                 } else {
                     Err(BackendError::UnknownVariable(varname))
                 }
@@ -237,6 +1437,8 @@ where
 
             // Unary Expressions, all fall into the category of overloaded operators
             UnaryExpr { op, operand } => {
+                check_operand(context, operand)?;
+
                 let fn_name = format!("unary{}", op.as_str());
 
                 if let Some(unary_overload_fn) = context.module.get_function(&fn_name) {
@@ -251,13 +1453,33 @@ where
 
                     Ok(unary_op_call.as_any_value_enum())
                 } else {
-                    Err(BackendError::UndefinedOperator(*op))
+                    // No user overload, fall back on the builtin lowering,
+                    // which only exists for the signs
+                    match op {
+                        Ops::Minus => {
+                            let operand_genval = operand.codegen(context)?.into_float_value();
+
+                            let neg = context
+                                .builder
+                                .build_float_neg(operand_genval, "negtmp")
+                                .expect("FATAL: LLVM failed to build fneg!");
+
+                            Ok(neg.as_any_value_enum())
+                        }
+
+                        Ops::Plus => operand.codegen(context),
+
+                        _ => Err(BackendError::UndefinedOperator(*op)),
+                    }
                 }
             }
 
             // Generate the left and right code first, then build the correct
             // instruction depending on the operator.
             BinaryExpr { op, left, right } => {
+                check_operand(context, left)?;
+                check_operand(context, right)?;
+
                 // Assignments are special cases, we only want to codegen the right
                 // then treat the left as a named symbol to store as variable name
                 if let Ops::Assign = op {
@@ -329,6 +1551,15 @@ where
                             Ok(div.as_any_value_enum())
                         }
 
+                        Ops::Mod => {
+                            let rem = context
+                                .builder
+                                .build_float_rem(left_genval, right_genval, "remtmp")
+                                .unwrap();
+
+                            Ok(rem.as_any_value_enum())
+                        }
+
                         // For the comparison operators, map() a conversion back to float, Kaleidoscope only works with floating point nums!
                         Ops::Eq => {
                             let cmp = context
@@ -390,6 +1621,43 @@ where
                             Ok(cmp.as_any_value_enum())
                         }
 
+                        Ops::Le => {
+                            let cmp = context
+                                .builder
+                                .build_float_compare(
+                                    FloatPredicate::OLE,
+                                    left_genval,
+                                    right_genval,
+                                    "letmp",
+                                )
+                                .map(|int_val| to_llvm_float!(context, int_val))
+                                .unwrap();
+
+                            Ok(cmp.as_any_value_enum())
+                        }
+
+                        Ops::Ge => {
+                            let cmp = context
+                                .builder
+                                .build_float_compare(
+                                    FloatPredicate::OGE,
+                                    left_genval,
+                                    right_genval,
+                                    "getmp",
+                                )
+                                .map(|int_val| to_llvm_float!(context, int_val))
+                                .unwrap();
+
+                            Ok(cmp.as_any_value_enum())
+                        }
+
+                        // Power, while no one has overloaded it
+                        Ops::Xor if context.module.get_function("binary^").is_none() => {
+                            let pow = context.build_pow(left_genval, right, right_genval);
+
+                            Ok(pow.as_any_value_enum())
+                        }
+
                         overloaded_op => {
                             // First, we have to check if the operator has been defined, if not, then
                             // we return error, because we cannot apply an operator that has not been defined
@@ -418,17 +1686,105 @@ where
             }
 
             // This one is the most complex expression to handle...
-            CallExpr { ref callee, args } => {
-                // First, see if the function is defined in LLVM module, if not, we have
-                // an undefined function trying to be called
-                let function = context
-                    .module
-                    .get_function(callee)
-                    .ok_or(BackendError::UndefinedFunction(callee))?;
+            // The callee is a local holding a function pointer, rather than a function
+            // itself, locals shadow functions of the same name like they do anywhere else
+            CallExpr { callee, args } if context.sym_table.borrow().contains_key(*callee) => {
+                let llvm_val_args = args
+                    .iter()
+                    .map(|arg| {
+                        codegen_operand(context, arg)
+                            .map(|val| BasicMetadataValueEnum::FloatValue(val.into_float_value()))
+                    })
+                    .collect::<Result<Vec<_>, BackendError>>()?;
+
+                context.set_debug_location(callee);
+
+                let fn_val = VariableExpr(callee).codegen(context)?.into_float_value();
+
+                Ok(context
+                    .build_pointer_call(fn_val, &llvm_val_args)
+                    .as_any_value_enum())
+            }
+
+            // Same as calling a local, the function pointer just comes from
+            // somewhere else, worked out before any of the arguments
+            IndirectCallExpr { callee, args } => {
+                let fn_val = codegen_operand(context, callee)?.into_float_value();
+
+                let llvm_val_args = args
+                    .iter()
+                    .map(|arg| {
+                        codegen_operand(context, arg)
+                            .map(|val| BasicMetadataValueEnum::FloatValue(val.into_float_value()))
+                    })
+                    .collect::<Result<Vec<_>, BackendError>>()?;
+
+                Ok(context
+                    .build_pointer_call(fn_val, &llvm_val_args)
+                    .as_any_value_enum())
+            }
+
+            // Folded at compile time, nothing of the literal itself makes it into the IR
+            CallExpr { callee, args } if const_builtin(callee).is_some() => {
+                let builtin = const_builtin(callee).unwrap();
+
+                match args.as_slice() {
+                    [text] => match **text {
+                        StringExpr(text) => {
+                            let float_type = context.context.f64_type();
+                            Ok(float_type.const_float(builtin(text)).as_any_value_enum())
+                        }
+                        _ => Err(BackendError::ExpectedStringLiteral(callee)),
+                    },
+
+                    _ => Err(BackendError::IncorrectNumberOfArgs {
+                        func_name: callee,
+                        param_cnt: 1,
+                    }),
+                }
+            }
 
-                let param_cnt = function.count_params();
+            // One of the host's, so long as the program has nothing of that name
+            CallExpr { callee, args }
+                if !context.is_declared(callee) && context.builtins.contains(callee) =>
+            {
+                let builtin = context.builtins.get(callee).unwrap();
 
-                if param_cnt != args.len() as u32 {
+                if args.len() != builtin.arity {
+                    return Err(BackendError::IncorrectNumberOfArgs {
+                        func_name: callee,
+                        param_cnt: builtin.arity as u32,
+                    });
+                }
+
+                let args = args
+                    .iter()
+                    .map(|arg| codegen_operand(context, arg).map(AnyValueEnum::into_float_value))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                context.set_debug_location(callee);
+
+                Ok(context
+                    .build_builtin_call(callee, &args)
+                    .as_any_value_enum())
+            }
+
+            CallExpr { ref callee, args } => {
+                // First, see if the function is defined in LLVM module, so a program's
+                // own floor isn't swapped for the intrinsic, then see if it's a builtin
+                // intrinsic, if not, we have an undefined function trying to be called
+                let (function, param_cnt) = match context.module.get_function(callee) {
+                    Some(function) => (function, function.count_params()),
+                    None => context
+                        .get_intrinsic(callee)
+                        .ok_or(BackendError::UndefinedFunction(callee))?,
+                };
+
+                // Variadic functions can take more args than they have params
+                let arg_cnt = args.len() as u32;
+                let variadic = function.get_type().is_var_arg();
+
+                if arg_cnt < param_cnt || (arg_cnt > param_cnt && !variadic) {
                     // verify parameter counts
                     return Err(BackendError::IncorrectNumberOfArgs {
                         func_name: callee,
@@ -440,7 +1796,7 @@ where
                 // Any of the arguments could also produce a backend error, so propogate up
                 let llvm_val_args = args
                     .iter()
-                    .map(|arg| arg.codegen(context))
+                    .map(|arg| codegen_operand(context, arg))
                     .collect::<Result<Vec<_>, BackendError>>()?;
 
                 let llvm_val_args: Vec<BasicMetadataValueEnum> = llvm_val_args
@@ -450,34 +1806,36 @@ where
 
                 // Building a call requires arguments be of type BasicMetadataValueEnum,
                 // as a slice of them, had to convert, but does produce LLVM call instruction.
+                context.set_debug_location(callee);
+
+                // LLVM won't have a name on a call that gives no value
+                let call_name = match function.get_type().get_return_type() {
+                    Some(_) => "calltmp",
+                    None => "",
+                };
+
                 let call = context
                     .builder
-                    .build_call(function, llvm_val_args.as_slice(), &"calltmp")
+                    .build_call(function, llvm_val_args.as_slice(), call_name)
                     .expect("Irrecoverable: LLVM failed to build call expression");
 
-                Ok(call.as_any_value_enum())
+                // Whatever calls to void functions are in, the value is 0.0
+                match call.try_as_basic_value().left() {
+                    Some(_) => Ok(call.as_any_value_enum()),
+                    None => Ok(context
+                        .context
+                        .f64_type()
+                        .const_float(0.0)
+                        .as_any_value_enum()),
+                }
             }
 
             IfExpr {
                 cond,
                 then_branch,
+                elifs,
                 else_branch,
             } => {
-                let cond_codegen = cond.codegen(context)?;
-
-                let one = context.context.f64_type().const_float(1.0);
-
-                // Compute the truth of the condition by comparing value of expression to one
-                let cond_bool = context
-                    .builder
-                    .build_float_compare(
-                        FloatPredicate::OEQ,
-                        cond_codegen.into_float_value(),
-                        one,
-                        &"iftemp",
-                    )
-                    .expect("FATAL: LLVM failed to build float compare!");
-
                 let function = context
                     .builder
                     .get_insert_block()
@@ -485,47 +1843,78 @@ where
                     .get_parent()
                     .unwrap();
 
-                // Basic blocks to be added for this branch
-                // true path - 1, not true - 2, merge - 3
-                let bbs = [&"then", &"else", &"ifcont"]
-                    .into_iter()
-                    .map(|bb_name| context.context.append_basic_block(function, bb_name))
-                    .collect::<Vec<BasicBlock<'ctx>>>();
+                let one = context.context.f64_type().const_float(1.0);
 
-                context
-                    .builder
-                    .build_conditional_branch(cond_bool, bbs[0], bbs[1])
-                    .expect("FATAL: LLVM failed to build br instruction!");
+                // Every branch that ran, the value it gave and the block it
+                // ended up in, which with nested ifs isn't the one it began in
+                let mut incoming = vec![];
 
-                // IMPORTANT: Be sure you set the builder cursor to the appropriate block
-                // before calling codegen() methods on then and else expressions, otherwise
-                // we would generate code in wrong basic block and mess everything up.
-                context.builder.position_at_end(bbs[0]);
-                let then_v = then_branch.codegen(context)?;
+                // An elif chain is lowered to one conditional branch after another,
+                // each condition is checked in the else block of the one before it
+                let conds = std::iter::once((cond, then_branch)).chain(
+                    elifs
+                        .iter()
+                        .map(|(elif_cond, elif_branch)| (elif_cond, elif_branch)),
+                );
 
-                // Don't forget to branch back to merge basic block!!!
-                context
-                    .builder
-                    .build_unconditional_branch(bbs[2])
-                    .expect("FATAL: LLVM failed to build branch!");
+                for (idx, (cond, branch)) in conds.enumerate() {
+                    let cond_codegen = codegen_operand(context, cond)?;
 
-                context.builder.position_at_end(bbs[1]);
-                let else_v = else_branch.codegen(context)?;
-                context
-                    .builder
-                    .build_unconditional_branch(bbs[2])
-                    .expect("FATAL: LLVM failed to build branch!");
+                    // Compute the truth of the condition by comparing value of expression to one
+                    let cond_bool = context
+                        .builder
+                        .build_float_compare(
+                            FloatPredicate::OEQ,
+                            cond_codegen.into_float_value(),
+                            one,
+                            "iftemp",
+                        )
+                        .expect("FATAL: LLVM failed to build float compare!");
+
+                    // Basic blocks to be added for this branch, true path first
+                    let then_bb = context.context.append_basic_block(function, "then");
+                    let else_name = if idx < elifs.len() { "elif" } else { "else" };
+                    let else_bb = context.context.append_basic_block(function, else_name);
+
+                    context
+                        .builder
+                        .build_conditional_branch(cond_bool, then_bb, else_bb)
+                        .expect("FATAL: LLVM failed to build br instruction!");
+
+                    // IMPORTANT: Be sure you set the builder cursor to the appropriate block
+                    // before calling codegen() methods on then and else expressions, otherwise
+                    // we would generate code in wrong basic block and mess everything up.
+                    context.builder.position_at_end(then_bb);
+                    let then_v = codegen_operand(context, branch)?;
+                    incoming.push((then_v, context.builder.get_insert_block().unwrap()));
+
+                    context.builder.position_at_end(else_bb);
+                }
+
+                let else_v = codegen_operand(context, else_branch)?;
+                incoming.push((else_v, context.builder.get_insert_block().unwrap()));
 
-                context.builder.position_at_end(bbs[2]);
+                // Don't forget to branch every path back to the merge basic block!!!
+                let merge_bb = context.context.append_basic_block(function, "ifcont");
+
+                for (_, block) in incoming.iter() {
+                    context.builder.position_at_end(*block);
+                    context
+                        .builder
+                        .build_unconditional_branch(merge_bb)
+                        .expect("FATAL: LLVM failed to build branch!");
+                }
+
+                context.builder.position_at_end(merge_bb);
                 let phi_node = context
                     .builder
-                    .build_phi(context.context.f64_type(), &"iftmp")
+                    .build_phi(context.context.f64_type(), "iftmp")
                     .expect("LLVM failed to create PHI!");
 
-                phi_node.add_incoming(&[
-                    (&then_v.into_float_value() as &dyn BasicValue<'ctx>, bbs[0]),
-                    (&else_v.into_float_value() as &dyn BasicValue<'ctx>, bbs[1]),
-                ]);
+                for (value, block) in incoming.iter() {
+                    let value = value.into_float_value();
+                    phi_node.add_incoming(&[(&value as &dyn BasicValue<'ctx>, *block)]);
+                }
 
                 Ok(phi_node.as_any_value_enum())
             }
@@ -562,7 +1951,7 @@ where
                 // Create alloca for loop variable at entry block of function before start expression
                 let loop_var_ptr = context.create_entry_block_alloca(function, varname);
 
-                let start_genval = start.codegen(context)?;
+                let start_genval = codegen_operand(context, start)?;
 
                 // Store start expression into stack pointer of loop variable
                 context
@@ -595,10 +1984,10 @@ where
 
                 // Generate the step, the parser will supply the default of 1.0 if one
                 // was not given, otherwise we generate user defined
-                let step_genval = step.codegen(context)?;
+                let step_genval = codegen_operand(context, step)?;
 
                 // The end condition check, generated after step
-                let end_codegen = end.codegen(context)?;
+                let end_codegen = codegen_operand(context, end)?;
 
                 // Following three statements, we load the stack variable, apply step
                 // then store it back to the stack
@@ -615,43 +2004,101 @@ where
 
                 context
                     .builder
-                    .build_store(loop_var_ptr, next_val)
-                    .expect("FATAL: LLVM failed to build store instruction");
+                    .build_store(loop_var_ptr, next_val)
+                    .expect("FATAL: LLVM failed to build store instruction");
+
+                // Build the comparison, which will be the check to see if we branch out of the
+                // loop or continue
+                let cmp_val = context
+                    .builder
+                    .build_float_compare(
+                        FloatPredicate::OEQ,
+                        end_codegen.into_float_value(),
+                        context.context.f64_type().const_float(1.0),
+                        &"loopcond",
+                    )
+                    .expect("FATAL: LLVM failed to build comparison instruction");
+
+                let afterloop_bb = context.context.append_basic_block(function, "afterloop");
+
+                context.builder.position_at_end(loop_bb);
+
+                context
+                    .builder
+                    .build_conditional_branch(cmp_val, loop_bb, afterloop_bb)
+                    .unwrap();
+
+                context.builder.position_at_end(afterloop_bb);
+
+                // Above we collected a possible shadowed variable from the map
+                // of our variable symbols. If there was something that was shadowed
+                // restore it here, else, clear the loop variable from our scope
+                if let Some(variable) = shadowed_var {
+                    context
+                        .sym_table
+                        .borrow_mut()
+                        .insert(varname.to_string(), variable);
+                } else {
+                    context.sym_table.borrow_mut().remove(*varname);
+                }
+
+                Ok(context.context.f64_type().const_float(0.0).as_any_value_enum())
+            }
+
+            // Output while-loop as:
+            //   br whilecond
+            // whilecond:
+            //   cond = condexpr
+            //   br cond, whilebody, afterwhile
+            // whilebody:
+            //   bodyexpr
+            //   br whilecond
+            // afterwhile:
+            WhileExpr { cond, body } => {
+                let function = context
+                    .builder
+                    .get_insert_block()
+                    .and_then(|bb| bb.get_parent())
+                    .unwrap();
+
+                let cond_bb = context.context.append_basic_block(function, "whilecond");
+                let body_bb = context.context.append_basic_block(function, "whilebody");
+                let after_bb = context.context.append_basic_block(function, "afterwhile");
+
+                context
+                    .builder
+                    .build_unconditional_branch(cond_bb)
+                    .expect("FATAL: LLVM failed to build branch!");
 
-                // Build the comparison, which will be the check to see if we branch out of the
-                // loop or continue
+                context.builder.position_at_end(cond_bb);
+
+                let cond_val = codegen_operand(context, cond)?;
                 let cmp_val = context
                     .builder
                     .build_float_compare(
                         FloatPredicate::OEQ,
-                        end_codegen.into_float_value(),
+                        cond_val.into_float_value(),
                         context.context.f64_type().const_float(1.0),
-                        &"loopcond",
+                        "whilecond",
                     )
                     .expect("FATAL: LLVM failed to build comparison instruction");
 
-                let afterloop_bb = context.context.append_basic_block(function, "afterloop");
+                context
+                    .builder
+                    .build_conditional_branch(cmp_val, body_bb, after_bb)
+                    .expect("FATAL: LLVM failed to build branch!");
 
-                context.builder.position_at_end(loop_bb);
+                // The body can end in a block of its own, like an if's merge,
+                // the branch back goes wherever it left off
+                context.builder.position_at_end(body_bb);
+                body.codegen(context)?;
 
                 context
                     .builder
-                    .build_conditional_branch(cmp_val, loop_bb, afterloop_bb)
-                    .unwrap();
-
-                context.builder.position_at_end(afterloop_bb);
+                    .build_unconditional_branch(cond_bb)
+                    .expect("FATAL: LLVM failed to build branch!");
 
-                // Above we collected a possible shadowed variable from the map
-                // of our variable symbols. If there was something that was shadowed
-                // restore it here, else, clear the loop variable from our scope
-                if let Some(variable) = shadowed_var {
-                    context
-                        .sym_table
-                        .borrow_mut()
-                        .insert(varname.to_string(), variable);
-                } else {
-                    context.sym_table.borrow_mut().remove(*varname);
-                }
+                context.builder.position_at_end(after_bb);
 
                 Ok(context.context.f64_type().const_float(0.0).as_any_value_enum())
             }
@@ -673,8 +2120,7 @@ where
                     let var_init_codegen = init.as_ref().map_or_else(
                         || Ok(context.context.f64_type().const_zero()),
                         |initializer| {
-                            initializer
-                                .codegen(context)
+                            codegen_operand(context, initializer)
                                 .map(AnyValueEnum::into_float_value)
                         },
                     )?;
@@ -738,14 +2184,28 @@ where
             self.get_num_params()
         ];
 
-        let fn_type = context
-            .context
-            .f64_type()
-            .fn_type(param_types.as_slice(), false);
+        let fn_type = match self.is_void() {
+            true => context
+                .context
+                .void_type()
+                .fn_type(param_types.as_slice(), self.is_variadic()),
+
+            false => context
+                .context
+                .f64_type()
+                .fn_type(param_types.as_slice(), self.is_variadic()),
+        };
+
+        // A decl is for a function of the module's own, so nothing outside it
+        // needs to see it, its definition then stays internal too
+        let linkage = match self.is_decl() {
+            true => Linkage::Internal,
+            false => Linkage::External,
+        };
 
         let fn_val = context
             .module
-            .add_function(&fn_name, fn_type, Some(Linkage::External));
+            .add_function(&fn_name, fn_type, Some(linkage));
 
         match self {
             FunctionProto { args, .. } => {
@@ -778,67 +2238,722 @@ where
     'ctx: 'ir,
 {
     fn codegen(&self, context: &LLVMContext<'ctx>) -> IRGenResult<'ir, 'src> {
-        // See if function has been defined, if not, generate prototype
-        // to get the LLVM function value.
-        let fn_val = match context.module.get_function(&self.proto.get_name()) {
-            Some(fn_val) => fn_val,
-            None => self.proto.codegen(context)?.into_function_value(),
+        context
+            .codegen_function(self)
+            .map(|fn_val| fn_val.as_any_value_enum())
+    }
+}
+
+// Top level items just dispatch to the node they wrap
+impl<'ctx, 'ir, 'src> LLVMCodeGen<'ctx, 'ir, 'src> for TopLevel<'src>
+where
+    'ctx: 'ir,
+{
+    fn codegen(&self, context: &LLVMContext<'ctx>) -> IRGenResult<'ir, 'src> {
+        match self {
+            TopLevel::Definition(func) | TopLevel::Expression(func) => func.codegen(context),
+            TopLevel::Extern(proto) => proto.codegen(context),
+
+            TopLevel::Const(def) => {
+                if context.consts.borrow().contains_key(def.name) {
+                    return Err(BackendError::ConstRedefined(def.name));
+                }
+
+                let value = context
+                    .eval_const(&def.value)
+                    .ok_or(BackendError::NotAConstant(def.name))?;
+
+                context
+                    .consts
+                    .borrow_mut()
+                    .insert(def.name.to_string(), value);
+
+                let f64_type = context.context.f64_type();
+                Ok(f64_type.const_float(value).as_any_value_enum())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use inkwell::targets::InitializationConfig;
+
+    use super::*;
+    use crate::backend::passes::DEFAULT_PASSES;
+    use crate::frontend::{
+        lexer::{Lex, Token},
+        lookahead::Lookahead,
+        parser::{parse_definition, parse_extern, parse_program_recovering, parse_top_level_expr},
+    };
+
+    #[test]
+    fn lowering_array_literals() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let array_of = |src: &'static str| {
+            let func = parse_top_level_expr(&mut src.lex().lookahead()).unwrap();
+            let ASTExpr::ArrayExpr(ref elems) = *func.body else {
+                panic!("FATAL: not an array literal");
+            };
+
+            llvm_ctx.codegen_array(elems).unwrap()
         };
 
-        // To make sure we aren't defining functions twice, I just check if it
-        // has no entry basic block, if it does, then propogate error.
-        if fn_val.get_first_basic_block().is_some() {
-            return Err(BackendError::MultipleFunctionDefs(self.proto.get_name()));
+        let array = array_of(" [1, 2.5, 3] ");
+        assert!(array.is_const());
+        assert_eq!(array.get_type().len(), 3);
+        assert!(array
+            .print_to_string()
+            .to_string()
+            .contains("[double 1.000000e+00, double 2.500000e+00, double 3.000000e+00]"));
+
+        assert_eq!(array_of(" [] ").get_type().len(), 0);
+
+        // Anywhere a double is wanted, it's an error
+        let func = parse_top_level_expr(&mut " [1] + 2 ".lex().lookahead()).unwrap();
+        assert_eq!(
+            func.codegen(&llvm_ctx).err(),
+            Some(BackendError::ArrayNotAValue)
+        );
+    }
+
+    #[test]
+    fn applying_function_attributes() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "def [inline] hot(x) x*x; def [noinline, cold] chilly(x) x;";
+        let mut tokens = src.lex().lookahead();
+
+        for _ in 0..2 {
+            parse_definition(&mut tokens)
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+            tokens.next_if(|t| matches!(t, Token::Semicolon));
         }
 
-        // This sets our cursor for creating instructions to the basic block
-        // for this function
-        let bb_entry = context.context.append_basic_block(fn_val, "entry");
-        context.builder.position_at_end(bb_entry);
+        let has_attr = |fn_name: &str, attr_name: &str| {
+            let kind_id = Attribute::get_named_enum_kind_id(attr_name);
+            llvm_ctx
+                .module
+                .get_function(fn_name)
+                .unwrap()
+                .get_enum_attribute(AttributeLoc::Function, kind_id)
+                .is_some()
+        };
 
-        // Update the symbol table with the args names and references
-        // to their LLVM values.
-        context.sym_table.borrow_mut().clear();
-        for param in fn_val.get_params() {
-            // TODO: Change the named value key to a non-owned CStr reference
-            // so I am not copying and cloning to Rust Strings
-            let owned_str = param
+        assert!(has_attr("hot", "alwaysinline"));
+        assert!(!has_attr("hot", "noinline"));
+        assert!(has_attr("chilly", "noinline"));
+        assert!(has_attr("chilly", "cold"));
+    }
+
+    #[test]
+    fn calling_intrinsics() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        parse_top_level_expr(&mut "floor(3.7);".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let ir = llvm_ctx.module.print_to_string().to_string();
+        assert!(ir.contains("call double @llvm.floor.f64"));
+
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(3.0));
+
+        // Arity is still checked for builtins
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let res = parse_top_level_expr(&mut "ceil(1, 2);".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx);
+
+        assert_eq!(
+            res,
+            Err(BackendError::IncorrectNumberOfArgs {
+                func_name: "ceil",
+                param_cnt: 1
+            })
+        );
+
+        // A program's own definition is called rather than the intrinsic
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        parse_definition(&mut "def floor(x) x + 1;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+        parse_top_level_expr(&mut "floor(3.7);".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let ir = llvm_ctx.module.print_to_string().to_string();
+        assert!(!ir.contains("@llvm.floor"));
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(4.7));
+    }
+
+    #[test]
+    fn explaining_optimizations() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "def f(x) var y = (1 + 2) * x in y + 0 * x;";
+        parse_definition(&mut src.lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let report =
+            llvm_ctx.run_passes_explained("instcombine,reassociate,gvn,simplifycfg,mem2reg");
+
+        assert!(report.instructions_removed() > 0);
+        assert!(report.to_string().starts_with("Instructions: "));
+    }
+
+    #[test]
+    fn declaring_variadic_externs() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        parse_extern(&mut "extern printf(fmt, ...)".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let printf = llvm_ctx.module.get_function("printf").unwrap();
+        assert!(printf.get_type().is_var_arg());
+        assert_eq!(printf.count_params(), 1);
+
+        // Extra args are fine, too few are not
+        let call = parse_top_level_expr(&mut "printf(1, 2, 3);".lex().lookahead()).unwrap();
+        assert!(call.codegen(&llvm_ctx).is_ok());
+
+        llvm_ctx.delete_top_level_expr();
+
+        let call = parse_top_level_expr(&mut "printf();".lex().lookahead()).unwrap();
+        assert!(call.codegen(&llvm_ctx).is_err());
+    }
+
+    #[test]
+    fn generating_function_values() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let def = parse_definition(&mut "def sq(x) x*x".lex().lookahead()).unwrap();
+        let sq = llvm_ctx.codegen_function(&def).unwrap();
+
+        assert_eq!(sq.get_name().to_str(), Ok("sq"));
+        assert_eq!(sq.count_params(), 1);
+        assert_eq!(
+            sq.get_nth_param(0)
+                .unwrap()
                 .into_float_value()
                 .get_name()
-                .to_str()
+                .to_str(),
+            Ok("x")
+        );
+        assert_eq!(sq.count_basic_blocks(), 1);
+
+        assert_eq!(
+            llvm_ctx.codegen_function(&def),
+            Err(BackendError::MultipleFunctionDefs("sq".to_string()))
+        );
+    }
+
+    #[test]
+    fn forward_declaring_functions() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        // f calls g before g is defined, which names its param differently
+        let src = "decl g(x); def f(x) g(x); def g(y) y + 1; f(5);";
+        let (program, errors) = parse_program_recovering(&mut src.lex().lookahead());
+        assert!(errors.is_empty());
+
+        for item in program.iter() {
+            item.codegen(&llvm_ctx).unwrap();
+        }
+
+        let g = llvm_ctx.module.get_function("g").unwrap();
+        assert_eq!(g.get_linkage(), Linkage::Internal);
+        assert_eq!(g.count_basic_blocks(), 1);
+
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(6.0));
+    }
+
+    #[test]
+    fn declaring_void_externs() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let proto = parse_extern(&mut "extern void print(x)".lex().lookahead()).unwrap();
+        assert!(proto.is_void());
+        proto.codegen(&llvm_ctx).unwrap();
+
+        let print = llvm_ctx.module.get_function("print").unwrap();
+        assert_eq!(print.get_type().get_return_type(), None);
+        assert!(print
+            .print_to_string()
+            .to_string()
+            .starts_with("declare void @print(double)"));
+
+        // The call on its own is fine, the expression gives 0.0
+        let call = parse_top_level_expr(&mut "print(1);".lex().lookahead()).unwrap();
+        call.codegen(&llvm_ctx).unwrap();
+
+        let expr = llvm_ctx.module.get_function("__anonymous_expr").unwrap();
+        assert!(expr.verify(false));
+        assert!(expr
+            .print_to_string()
+            .to_string()
+            .contains("ret double 0.0"));
+
+        llvm_ctx.delete_top_level_expr();
+
+        // Nor is anywhere else its value would be used
+        let sin = parse_extern(&mut "extern sin(x)".lex().lookahead()).unwrap();
+        sin.codegen(&llvm_ctx).unwrap();
+
+        for src in [
+            "print(1) + 1;",
+            "sin(print(1));",
+            "var a = print(1) in a + 1;",
+            "if print(1) then 1 else 2;",
+            "if 1 then print(1) else 2;",
+            "for i = 0, print(1) in 1;",
+            "while print(1) in 1;",
+            "[1, 2][print(1)];",
+        ] {
+            llvm_ctx.delete_top_level_expr();
+
+            let call = parse_top_level_expr(&mut src.lex().lookahead()).unwrap();
+            assert_eq!(
+                call.codegen(&llvm_ctx),
+                Err(BackendError::VoidUsedAsValue("print")),
+                "{src}"
+            );
+        }
+
+        // A loop's body is only there for what it does
+        llvm_ctx.delete_top_level_expr();
+
+        let call = parse_top_level_expr(&mut "for i = 0, i < 3 in print(i);".lex().lookahead());
+        call.unwrap().codegen(&llvm_ctx).unwrap();
+    }
+
+    #[test]
+    fn printing_each_pass() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        parse_definition(&mut "def f(x) x * 2 + x * 2;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let mut out = vec![];
+        llvm_ctx
+            .run_passes_printing("mem2reg,gvn", &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let headers = out
+            .lines()
+            .filter(|line| line.starts_with("; *** IR Dump After"))
+            .collect::<Vec<&str>>();
+
+        assert_eq!(
+            headers,
+            vec![
+                "; *** IR Dump After mem2reg ***",
+                "; *** IR Dump After gvn ***"
+            ]
+        );
+        assert_eq!(out.matches("define double @f").count(), 2);
+    }
+
+    #[test]
+    fn mutating_arguments() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        // Arguments live in stack slots, so they can be assigned to, here x
+        // accumulates 0 + 1 + 2 + 3 + 4, the loop runs until i < 4 is false
+        let src = "def binary : 1 (x y) y; def acc(x) (for i = 0, i < 4 in x = x + i) : x;";
+        let mut tokens = src.lex().lookahead();
+
+        for _ in 0..2 {
+            parse_definition(&mut tokens)
                 .unwrap()
-                .to_string();
+                .codegen(&llvm_ctx)
+                .unwrap();
+            tokens.next_if(|t| matches!(t, Token::Semicolon));
+        }
 
-            // The mutable variables chapter, chapter 7, our passed arguments may be mutated.
-            // Store them all on the stack and allow the function inside to mutate them
-            // as memory objects
+        let acc_ir = llvm_ctx
+            .module
+            .get_function("acc")
+            .unwrap()
+            .print_to_string();
+        assert!(acc_ir.to_string().contains("alloca double"));
+
+        parse_top_level_expr(&mut "acc(10);".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
 
-            // Allocate the argument to stack.
-            let param_ptr = context.create_entry_block_alloca(fn_val, &owned_str);
+        // Passes with mem2reg, promoting the slots back to registers, mustn't change anything
+        llvm_ctx.run_passes(DEFAULT_PASSES);
 
-            // Store the value of this paramter to it's stack copy
-            let _store = context
-                .builder
-                .build_store(param_ptr, param)
-                .expect("FATAL: LLVM failed to build store instruction");
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(20.0));
+    }
 
-            // Add it to scope
-            context.sym_table.borrow_mut().insert(owned_str, param_ptr);
+    #[test]
+    fn resolving_unary_overloads() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "def unary ! (v) if v then 0 else 1; def not(x) ! x; def neg(x) -x;";
+        let mut tokens = src.lex().lookahead();
+
+        for _ in 0..3 {
+            parse_definition(&mut tokens)
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+            tokens.next_if(|t| matches!(t, Token::Semicolon));
         }
 
-        // Generate code for the body of the function as an ASTExpr node
-        let ir_body = self.body.codegen(context)?;
+        // With an overload, the user's function is called
+        let not_ir = llvm_ctx
+            .module
+            .get_function("not")
+            .unwrap()
+            .print_to_string();
+        assert!(not_ir
+            .to_string()
+            .contains("call double @\"unary!\"(double"));
+
+        // Without one, minus is lowered to the builtin fneg
+        let neg_ir = llvm_ctx
+            .module
+            .get_function("neg")
+            .unwrap()
+            .print_to_string();
+        assert!(neg_ir.to_string().contains("fneg double"));
+
+        // Other operators have nothing to fall back on
+        let undefined = parse_definition(&mut "def and_it(x) & x;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx);
+        assert_eq!(undefined, Err(BackendError::UndefinedOperator(Ops::And)));
+    }
 
-        // We need to add a return at the end so we return from functions we call
-        context
-            .builder
-            .build_return(Some(&ir_body.into_float_value() as &dyn BasicValue))
-            .expect("FATAL: LLVM failed to build a return!");
+    #[test]
+    fn lowering_powers() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
 
-        if !fn_val.verify(true) {
-            return Err(BackendError::FailedToVerifyFunc(self.proto.get_name()));
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "def cube(x) x ^ 3; def fourth(x) x ^ 4; def one(x) x ^ 0;
+                   def power(x y) x ^ y; def root(x) x ^ 0.5;";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        for item in program.iter() {
+            item.codegen(&llvm_ctx).unwrap();
         }
 
-        Ok(fn_val.as_any_value_enum())
+        let ir_of = |name| {
+            let func = llvm_ctx.module.get_function(name).unwrap();
+            func.print_to_string().to_string()
+        };
+
+        // x ^ 3 is x * x * x, no call to be seen
+        assert_eq!(ir_of("cube").matches("fmul").count(), 2);
+        assert_eq!(ir_of("fourth").matches("fmul").count(), 3);
+        assert!(!ir_of("cube").contains("llvm.pow"));
+        assert!(ir_of("one").contains("ret double 1.000000e+00"));
+
+        // An exponent that isn't a constant whole number is left to llvm.pow
+        assert!(ir_of("power").contains("call double @llvm.pow.f64(double %x"));
+        assert!(ir_of("root").contains("call double @llvm.pow.f64(double %x"));
+        assert_eq!(ir_of("power").matches("fmul").count(), 0);
+
+        // As is one past the limit
+        let mut limited_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+        limited_ctx.set_max_pow_unroll(2);
+
+        let cube = parse_definition(&mut "def cube(x) x ^ 3;".lex().lookahead()).unwrap();
+        let cube = cube.codegen(&limited_ctx).unwrap().print_to_string();
+        assert!(cube.to_string().contains("call double @llvm.pow.f64"));
+    }
+
+    #[test]
+    fn lowering_elif_chains() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        // The nested if leaves its branch in a different block than it began
+        let src = "def classify(x) if x < 0 then 1 elif x < 10 then (if x < 5 then 2 else 3) elif x < 100 then 4 else 5;";
+        parse_definition(&mut src.lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        for (arg, expected) in [
+            (-1.0, 1.0),
+            (3.0, 2.0),
+            (7.0, 3.0),
+            (50.0, 4.0),
+            (500.0, 5.0),
+        ] {
+            parse_top_level_expr(&mut format!("classify({arg});").lex().lookahead())
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+
+            assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(expected));
+            llvm_ctx.delete_top_level_expr();
+        }
+    }
+
+    #[test]
+    fn calling_through_function_pointers() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        for src in [
+            "def sq(x) x * x;",
+            "def add(a b) a + b;",
+            "def apply(f x) f(x);",
+            "def fold(f a b) f(a, b);",
+        ] {
+            parse_definition(&mut src.lex().lookahead())
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+        }
+
+        for (src, expected) in [("apply(sq, 5);", 25.0), ("fold(add, 2, 3);", 5.0)] {
+            parse_top_level_expr(&mut src.lex().lookahead())
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+
+            assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(expected));
+            llvm_ctx.delete_top_level_expr();
+        }
+    }
+
+    #[test]
+    fn calling_expressions() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        for src in ["def inc(x) x + 1;", "def dbl(x) x * 2;", "def id(f) f;"] {
+            parse_definition(&mut src.lex().lookahead())
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+        }
+
+        // What a call returns, what's in an array, and a plain name in parens
+        for (src, expected) in [
+            ("id(dbl)(5);", 10.0),
+            ("[inc, dbl][0](5);", 6.0),
+            ("(inc)(0);", 1.0),
+        ] {
+            parse_top_level_expr(&mut src.lex().lookahead())
+                .unwrap()
+                .codegen(&llvm_ctx)
+                .unwrap();
+
+            assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(expected));
+            llvm_ctx.delete_top_level_expr();
+        }
+    }
+
+    #[test]
+    fn calling_host_builtins() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let mut builtins = BuiltinRegistry::new();
+        builtins.register("clamp", 3, |args| args[0].max(args[1]).min(args[2]));
+        builtins.register("fail", 0, |_| panic!("a builtin gone wrong"));
+
+        let ctx = Context::create();
+        let mut llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+        llvm_ctx.use_builtins(builtins.clone()).unwrap();
+
+        // The code calls into the registry, so it's there to stay
+        assert_eq!(
+            llvm_ctx.use_builtins(builtins),
+            Err(BackendError::BuiltinsReplaced)
+        );
+
+        parse_top_level_expr(&mut "clamp(5, 0, 3) + clamp(-1, 0, 3);".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(3.0));
+        llvm_ctx.delete_top_level_expr();
+
+        assert_eq!(
+            parse_top_level_expr(&mut "clamp(1);".lex().lookahead())
+                .unwrap()
+                .codegen(&llvm_ctx),
+            Err(BackendError::IncorrectNumberOfArgs {
+                func_name: "clamp",
+                param_cnt: 3
+            })
+        );
+
+        // A panic can't unwind into the JIT's code, the call gives NaN
+        llvm_ctx.delete_top_level_expr();
+        parse_top_level_expr(&mut "fail() + 1;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        assert!(unsafe { llvm_ctx.jit_eval() }.unwrap().is_nan());
+
+        // The builtin's address is nowhere in the IR, only its global, which
+        // means nothing outside this process, so can't be compiled ahead of time
+        let ir = llvm_ctx.module_to_string();
+        assert!(ir.contains("@__kaleidrs_builtin.clamp = external global i8"));
+
+        let object = std::env::temp_dir().join("kaleidrs-builtins.o");
+        assert_eq!(
+            llvm_ctx.compile(&object, FileType::Object),
+            Err(BackendError::BuiltinNotCompiled("clamp".to_string()))
+        );
+    }
+
+    #[test]
+    fn compound_assigning() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        // Like plain assignment each gives back the new value, 15 then 3
+        parse_top_level_expr(&mut "var x = 10 in (x += 5) + (x %= 4) + x;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(21.0));
+    }
+
+    #[test]
+    fn evaluating_len_at_compile_time() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        parse_top_level_expr(&mut "len(\"hello\");".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(5.0));
+        llvm_ctx.delete_top_level_expr();
+
+        // Anything but a literal can't be known until runtime
+        let not_literal = parse_definition(&mut "def f(x) len(x);".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx);
+
+        assert_eq!(not_literal, Err(BackendError::ExpectedStringLiteral("len")));
+
+        let stray = parse_definition(&mut "def g(x) \"x\" + x;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx);
+
+        assert_eq!(stray, Err(BackendError::StringNotAValue("x")));
+    }
+
+    #[test]
+    fn error_codes() {
+        let errors = [
+            BackendError::UnknownVariable("x"),
+            BackendError::UndefinedFunction("f"),
+            BackendError::MultipleFunctionDefs("f".to_string()),
+            BackendError::IncorrectNumberOfArgs {
+                func_name: "f",
+                param_cnt: 1,
+            },
+            BackendError::FailedToVerifyFunc("f".to_string()),
+            BackendError::UndefinedOperator(Ops::Xor),
+            BackendError::BadAssignment,
+            BackendError::UnknownEntryPoint("main".to_string()),
+            BackendError::EntryPointWithArgs("main".to_string()),
+            BackendError::TooManyJitArgs("f".to_string()),
+            BackendError::StringNotAValue("abc"),
+            BackendError::ExpectedStringLiteral("len"),
+            BackendError::ArrayNotAValue,
+            BackendError::NotIndexable,
+            BackendError::IndirectCallNotInterpreted,
+            BackendError::ConstRedefined(""),
+            BackendError::NotAConstant(""),
+            BackendError::StepLimitExceeded(1),
+            BackendError::VoidUsedAsValue("print"),
+            BackendError::CallDepthExceeded(1),
+            BackendError::SymbolDefinedTwice("f".to_string()),
+            BackendError::BadProfile("f.profdata".to_string()),
+            BackendError::BuiltinNotCompiled("clamp".to_string()),
+            BackendError::BuiltinsReplaced,
+        ];
+
+        let codes = errors.iter().map(|err| err.code()).collect::<HashSet<_>>();
+        assert_eq!(codes.len(), errors.len());
+
+        for err in errors.iter() {
+            assert!(!err.code().is_empty());
+            assert!(err
+                .to_string()
+                .starts_with(&format!("error[{}]: ", err.code())));
+        }
     }
 }
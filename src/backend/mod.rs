@@ -1 +1,9 @@
+pub mod builtins;
+pub mod cfg;
+pub mod debug_info;
+pub mod interpreter;
 pub mod llvm_backend;
+pub mod passes;
+pub mod source_map;
+pub mod symbols;
+pub mod tiered_jit;
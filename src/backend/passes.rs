@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, PoisonError};
+
+use clap::ValueEnum;
+use inkwell::module::Module;
+use inkwell::values::InstructionOpcode;
+use llvm_sys::support::LLVMParseCommandLineOptions;
+
+// The LLVM passes accepted by --passes, along with a short description of
+// each. This is nowhere near every pass LLVM has, just a good set of the
+// classic scalar/interprocedural ones to experiment with when learning.
+// See https://www.llvm.org/docs/Passes.html for the full story.
+pub const AVAILABLE_PASSES: &[(&str, &str)] = &[
+    (
+        "instcombine",
+        "Combine redundant instructions, peephole optimizations",
+    ),
+    (
+        "reassociate",
+        "Reassociate commutative expressions to help constant folding",
+    ),
+    (
+        "gvn",
+        "Global value numbering, removes redundant computations and loads",
+    ),
+    (
+        "simplifycfg",
+        "Simplify the control flow graph, merging and removing blocks",
+    ),
+    ("mem2reg", "Promote stack slots (allocas) to SSA registers"),
+    (
+        "sroa",
+        "Scalar replacement of aggregates, a stronger mem2reg",
+    ),
+    ("early-cse", "Quick common subexpression elimination"),
+    ("sccp", "Sparse conditional constant propagation"),
+    ("dce", "Dead code elimination"),
+    ("adce", "Aggressive dead code elimination"),
+    ("dse", "Dead store elimination"),
+    (
+        "jump-threading",
+        "Thread branches over blocks with predictable conditions",
+    ),
+    (
+        "correlated-propagation",
+        "Propagate values known from dominating conditions",
+    ),
+    ("tailcallelim", "Turn self recursive tail calls into loops"),
+    (
+        "loop-simplify",
+        "Canonicalize loops into a simpler form for other loop passes",
+    ),
+    ("loop-unroll", "Unroll loops with known iteration counts"),
+    ("indvars", "Canonicalize loop induction variables"),
+    ("inline", "Inline function calls"),
+    (
+        "globaldce",
+        "Remove unused functions and globals from the module",
+    ),
+];
+
+// What --passes is when not given
+pub const DEFAULT_PASSES: &str = "instcombine,reassociate,gvn,simplifycfg,mem2reg";
+
+// Clap value parser for --passes, every name in the comma separated list
+// must be one we know about. An empty list is fine, and means no passes.
+pub fn validate_passes(passes: &str) -> Result<String, String> {
+    if passes.is_empty() {
+        return Ok(String::new());
+    }
+
+    for pass in passes.split(',') {
+        if !AVAILABLE_PASSES.iter().any(|(name, _)| *name == pass) {
+            return Err(format!(
+                "unknown pass '{pass}', use --list-passes to see what is available"
+            ));
+        }
+    }
+
+    Ok(passes.to_string())
+}
+
+// Profile guided optimization, either instrumenting the program so running
+// it writes out a .profraw profile, or optimizing with one already collected
+// (merged into .profdata by llvm-profdata). One or the other, never both.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Pgo {
+    Instrument,
+    Use(PathBuf),
+}
+
+impl Pgo {
+    // What gets run ahead of the optimization passes, instrumenting means
+    // inserting the counters, then lowering them to the calls and globals
+    // the profile runtime (__llvm_profile_*) works with
+    pub fn passes(&self) -> &'static str {
+        match self {
+            Pgo::Instrument => "pgo-instr-gen,instrprof",
+            Pgo::Use(_) => "pgo-instr-use",
+        }
+    }
+
+    // Runs the passes given, with the profile in place for them when using
+    // one. The pass to use a profile can't be handed the path from the
+    // textual pipeline, it reads LLVM's own -pgo-test-profile-file option
+    // instead, which can only be set once in a process. So it's set to a
+    // file of ours, that each profile is copied to while its passes run.
+    //
+    // LLVM exits the whole process on a profile it can't read, rather than
+    // reporting it, so the profile is checked to be one here, first.
+    pub(crate) fn with_profile<T>(&self, run: impl FnOnce() -> T) -> Result<T, String> {
+        let Pgo::Use(profile) = self else {
+            return Ok(run());
+        };
+
+        let contents = fs::read(profile).map_err(|err| format!("{}: {err}", profile.display()))?;
+
+        if !contents.starts_with(PROFDATA_MAGIC) {
+            return Err(format!(
+                "{} is no indexed profile, merge the .profraw with llvm-profdata first",
+                profile.display()
+            ));
+        }
+
+        // Compiles on other threads would swap their profile in under us
+        let _lock = PROFILE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let used_profile = used_profile_path();
+
+        fs::write(&used_profile, &contents)
+            .map_err(|err| format!("{}: {err}", used_profile.display()))?;
+
+        PROFILE_OPTION.call_once(|| set_profile_option(&used_profile));
+
+        let ran = run();
+        let _ = fs::remove_file(&used_profile);
+
+        Ok(ran)
+    }
+}
+
+// What an indexed profile starts with, as written by llvm-profdata merge
+const PROFDATA_MAGIC: &[u8] = b"\xfflprofi\x81";
+
+static PROFILE_OPTION: Once = Once::new();
+static PROFILE_LOCK: Mutex<()> = Mutex::new(());
+
+// Where LLVM reads profiles from, one file for each process
+fn used_profile_path() -> PathBuf {
+    std::env::temp_dir().join(format!("kaleidrs-{}.profdata", std::process::id()))
+}
+
+fn set_profile_option(profile: &Path) {
+    let args = [
+        CString::new("kaleidrs").unwrap(),
+        CString::new(format!("-pgo-test-profile-file={}", profile.display())).unwrap(),
+    ];
+
+    let argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+
+    unsafe {
+        LLVMParseCommandLineOptions(argv.len() as i32, argv.as_ptr(), std::ptr::null());
+    }
+}
+
+// Sanitizers that --sanitize can build into the code, each an instrumenting
+// pass run after the optimizations, along with a runtime library that checks
+// and reports what the instrumented code did wrong, which the program has
+// to be linked with. Only the functions given the sanitizer's attribute are
+// instrumented, so it must be known before generating any IR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Sanitizer {
+    /// AddressSanitizer, catching out of bounds accesses, needs the ASan runtime (link with -fsanitize=address)
+    Address,
+}
+
+impl Sanitizer {
+    // The pipelines to try in turn, for more than one version of LLVM. Up to
+    // LLVM 15, ASan was a function pass needing an analysis run ahead of it,
+    // plus a module pass, after that it's all the one module pass. The old
+    // spelling goes first, as an old LLVM takes the new one, but then aborts.
+    pub fn passes(&self) -> &'static [&'static str] {
+        match self {
+            Sanitizer::Address => &[
+                "require<asan-globals-md>,function(asan),asan-module",
+                "asan",
+            ],
+        }
+    }
+
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            Sanitizer::Address => "sanitize_address",
+        }
+    }
+
+    // What cc or clang need when linking, to pull in the runtime
+    pub fn link_arg(&self) -> &'static str {
+        match self {
+            Sanitizer::Address => "-fsanitize=address",
+        }
+    }
+}
+
+// Clap value parser for --pgo, "instrument" or "use=<profdata>", the profile
+// has to exist, LLVM would rather exit than report a missing one
+pub fn parse_pgo(mode: &str) -> Result<Pgo, String> {
+    match mode.split_once('=') {
+        None if mode == "instrument" => Ok(Pgo::Instrument),
+
+        Some(("use", profile)) => {
+            let profile = PathBuf::from(profile);
+
+            if profile.is_file() {
+                Ok(Pgo::Use(profile))
+            } else {
+                Err(format!("profile '{}' not found", profile.display()))
+            }
+        }
+
+        _ => Err("expected 'instrument' or 'use=<profdata>'".to_string()),
+    }
+}
+
+// Print every available pass with its description, for --list-passes
+pub fn list_passes(out: &mut impl Write) -> io::Result<()> {
+    let width = AVAILABLE_PASSES
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+
+    for (name, description) in AVAILABLE_PASSES.iter() {
+        writeln!(out, "  {name:<width$}  {description}")?;
+    }
+
+    Ok(())
+}
+
+// A few numbers about a function's IR, enough to tell roughly what the
+// optimization passes did to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStats {
+    pub name: String,
+    pub instructions: usize,
+    pub blocks: usize,
+    pub calls: BTreeMap<String, usize>, // callee name to number of call sites, sorted by name
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleStats {
+    pub functions: Vec<FunctionStats>,
+}
+
+impl ModuleStats {
+    // Snapshot the stats of every function with a body in the module
+    pub fn of(module: &Module) -> Self {
+        let functions = module
+            .get_functions()
+            .filter(|func| func.count_basic_blocks() > 0)
+            .map(|func| {
+                let mut instructions = 0;
+                let mut calls = BTreeMap::new();
+
+                for block in func.get_basic_blocks() {
+                    for insn in block.get_instructions() {
+                        instructions += 1;
+
+                        // The callee is always the last operand of a call
+                        if insn.get_opcode() == InstructionOpcode::Call {
+                            let callee = insn
+                                .get_operand(insn.get_num_operands() - 1)
+                                .and_then(|callee| callee.left())
+                                .map(|callee| {
+                                    callee
+                                        .into_pointer_value()
+                                        .get_name()
+                                        .to_string_lossy()
+                                        .into_owned()
+                                });
+
+                            if let Some(callee) = callee {
+                                *calls.entry(callee).or_insert(0) += 1;
+                            }
+                        }
+                    }
+                }
+
+                FunctionStats {
+                    name: func.get_name().to_string_lossy().into_owned(),
+                    instructions,
+                    blocks: func.count_basic_blocks() as usize,
+                    calls,
+                }
+            })
+            .collect();
+
+        Self { functions }
+    }
+
+    pub fn instructions(&self) -> usize {
+        self.functions.iter().map(|func| func.instructions).sum()
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.functions.iter().map(|func| func.blocks).sum()
+    }
+
+    fn get(&self, name: &str) -> Option<&FunctionStats> {
+        self.functions.iter().find(|func| func.name == name)
+    }
+}
+
+// What --explain-opt prints, the IR stats from before and after running
+// the pass pipeline. Not a real diff of the IR, just the counts and a few
+// highlights, but enough to get a feel for what each pass does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptReport {
+    pub before: ModuleStats,
+    pub after: ModuleStats,
+}
+
+impl OptReport {
+    // Positive when the passes made the IR smaller
+    pub fn instructions_removed(&self) -> i64 {
+        self.before.instructions() as i64 - self.after.instructions() as i64
+    }
+
+    pub fn blocks_removed(&self) -> i64 {
+        self.before.blocks() as i64 - self.after.blocks() as i64
+    }
+
+    // Functions that one or more calls to went away, to functions that
+    // have a body of their own, so are most likely inlined
+    pub fn inlined(&self) -> Vec<String> {
+        let mut inlined = vec![];
+
+        for func in self.before.functions.iter() {
+            for (callee, count) in func.calls.iter() {
+                let count_after = self
+                    .after
+                    .get(&func.name)
+                    .and_then(|func| func.calls.get(callee))
+                    .copied()
+                    .unwrap_or(0);
+
+                let has_body = self.before.get(callee).is_some();
+
+                if count_after < *count && has_body && !inlined.contains(callee) {
+                    inlined.push(callee.clone());
+                }
+            }
+        }
+
+        inlined
+    }
+}
+
+impl fmt::Display for OptReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Instructions: {} -> {} ({} removed)",
+            self.before.instructions(),
+            self.after.instructions(),
+            self.instructions_removed()
+        )?;
+
+        writeln!(
+            f,
+            "Basic blocks: {} -> {} ({} removed)",
+            self.before.blocks(),
+            self.after.blocks(),
+            self.blocks_removed()
+        )?;
+
+        let inlined = self.inlined();
+        if !inlined.is_empty() {
+            writeln!(f, "Calls inlined to: {}", inlined.join(", "))?;
+        }
+
+        for before in self.before.functions.iter() {
+            match self.after.get(&before.name) {
+                Some(after) if after.instructions != before.instructions => writeln!(
+                    f,
+                    "  {}: {} -> {} instructions",
+                    before.name, before.instructions, after.instructions
+                )?,
+                Some(_unchanged) => {}
+                None => writeln!(f, "  {}: removed", before.name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_passes() {
+        let mut out = vec![];
+        list_passes(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        for pass in ["instcombine", "reassociate", "gvn", "simplifycfg"] {
+            assert!(out.contains(pass));
+        }
+
+        // Everything listed must be accepted by validation, one line per pass
+        assert_eq!(out.lines().count(), AVAILABLE_PASSES.len());
+        for line in out.lines() {
+            let name = line.split_whitespace().next().unwrap();
+            assert!(validate_passes(name).is_ok());
+        }
+    }
+
+    #[test]
+    fn validating_passes() {
+        assert!(validate_passes(DEFAULT_PASSES).is_ok());
+        assert!(validate_passes("").is_ok());
+        assert!(validate_passes("gvn,notapass").is_err());
+        assert!(validate_passes("gvn,,dce").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use inkwell::values::{AnyValue, FunctionValue, InstructionValue};
+
+use crate::frontend::diagnostics::{json_string, LineStarts};
+
+// Where in the source an instruction was generated from, as it was
+// generated, so before any passes. The value name usually survives them,
+// which is what ties an instruction of the optimized IR back to here.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub function: String,
+    pub value: Option<String>, // None for instructions that give no value, or have no name
+    pub instruction: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+// A lighter alternative to DWARF (see DebugInfo) for reading IR against the
+// program, mapping each instruction to a line and column of the source. The
+// locations are found the same way debug info's are, from the last name in
+// the tree seen before the instruction was generated, so the fadd of "x + 1"
+// is put at the x, the + being no slice of the source.
+//
+// Codegen tells us whenever it sees a name, each instruction generated since
+// the last time is then mapped to where that last name was.
+#[derive(Debug)]
+pub struct SourceMap<'ctx> {
+    lines: LineStarts,
+    location: Cell<(u32, u32)>,
+    mapped: RefCell<HashSet<InstructionValue<'ctx>>>,
+    mappings: RefCell<Vec<Mapping>>,
+}
+
+impl<'ctx> SourceMap<'ctx> {
+    pub fn new(src_code: &str) -> Self {
+        Self {
+            lines: LineStarts::new(src_code),
+            location: Cell::new((0, 0)),
+            mapped: RefCell::new(HashSet::new()),
+            mappings: RefCell::new(vec![]),
+        }
+    }
+
+    // One based line and column of a slice of the source, line 0 for
+    // anything not from the source, like the name of top level expressions
+    fn line_col(&self, slice: &str) -> (u32, u32) {
+        self.lines
+            .line_col(slice)
+            .map_or((0, 0), |(line, col)| (line as u32, col as u32))
+    }
+
+    // Everything in function generated up to now was generated where the
+    // last name was, what's generated from here on is at this one
+    pub fn set_location(&self, function: FunctionValue<'ctx>, slice: &str) {
+        self.flush(function);
+        self.location.set(self.line_col(slice));
+    }
+
+    // Every instruction of function not mapped yet. Codegen only ever adds
+    // to the end of a block, or allocas to the start of the entry block, so
+    // only the ends of each block are walked, up to what was mapped before.
+    fn unmapped(&self, function: FunctionValue<'ctx>) -> Vec<InstructionValue<'ctx>> {
+        let mapped = self.mapped.borrow();
+        let is_new = |insn: &InstructionValue<'ctx>| !mapped.contains(insn);
+        let mut unmapped = vec![];
+
+        for (idx, block) in function.get_basic_blocks().into_iter().enumerate() {
+            let head = match idx {
+                0 => std::iter::successors(block.get_first_instruction(), |insn| {
+                    insn.get_next_instruction()
+                })
+                .take_while(is_new)
+                .collect(),
+                _ => vec![],
+            };
+
+            let mut tail = std::iter::successors(block.get_last_instruction(), |insn| {
+                insn.get_previous_instruction()
+            })
+            .take_while(|insn| is_new(insn) && !head.contains(insn))
+            .collect::<Vec<_>>();
+            tail.reverse();
+
+            unmapped.extend(head.into_iter().chain(tail));
+        }
+
+        unmapped
+    }
+
+    // Maps the instructions of function not mapped yet, to be called once
+    // its body is done. Those generated for nothing in the source are left out.
+    pub fn flush(&self, function: FunctionValue<'ctx>) {
+        let (line, col) = self.location.get();
+        let name = function.get_name().to_string_lossy();
+
+        for insn in self.unmapped(function) {
+            self.mapped.borrow_mut().insert(insn);
+
+            if line == 0 {
+                continue;
+            }
+
+            let value = insn
+                .get_name()
+                .map(|value| value.to_string_lossy().into_owned())
+                .filter(|value| !value.is_empty());
+
+            self.mappings.borrow_mut().push(Mapping {
+                function: name.to_string(),
+                value,
+                instruction: insn.print_to_string().to_string().trim().to_string(),
+                line,
+                col,
+            });
+        }
+    }
+
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.mappings.borrow().clone()
+    }
+
+    // Each mapping on a line of its own, like:
+    //
+    // {"mappings":[
+    // {"function":"f","value":"addtmp","instruction":"%addtmp = fadd ...","line":1,"col":10}
+    // ]}
+    pub fn to_json(&self) -> String {
+        let mappings = self
+            .mappings
+            .borrow()
+            .iter()
+            .map(|mapping| {
+                format!(
+                    "{{\"function\":{},\"value\":{},\"instruction\":{},\"line\":{},\"col\":{}}}",
+                    json_string(&mapping.function),
+                    mapping
+                        .value
+                        .as_deref()
+                        .map_or_else(|| "null".to_string(), json_string),
+                    json_string(&mapping.instruction),
+                    mapping.line,
+                    mapping.col
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!("{{\"mappings\":[\n{}\n]}}\n", mappings.join(",\n"))
+    }
+
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+    use inkwell::targets::{InitializationConfig, Target};
+
+    use crate::backend::llvm_backend::{LLVMCodeGen, LLVMContext};
+    use crate::cli::OptLevel;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_program_recovering};
+
+    #[test]
+    fn mapping_instructions() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let mut llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "# adds one\ndef f(x)\n  x + 1";
+        llvm_ctx.enable_source_map(src);
+
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        for item in program.iter() {
+            item.codegen(&llvm_ctx).unwrap();
+        }
+
+        let mappings = llvm_ctx.source_map().unwrap().mappings();
+        let fadd = mappings
+            .iter()
+            .find(|mapping| mapping.instruction.contains("fadd"))
+            .unwrap();
+
+        // Put at the x, the last name before it, on the line of the +
+        assert_eq!(fadd.function, "f");
+        assert_eq!(fadd.value.as_deref(), Some("addtmp"));
+        assert_eq!((fadd.line, fadd.col), (3, 3));
+
+        // The param's alloca and store are there for the prototype
+        assert!(mappings
+            .iter()
+            .any(|mapping| mapping.instruction.starts_with("store") && mapping.line == 2));
+
+        let json = llvm_ctx.source_map().unwrap().to_json();
+        assert!(json.starts_with("{\"mappings\":[\n{\"function\":\"f\","));
+        assert!(json.contains(
+            "\"value\":\"addtmp\",\"instruction\":\"%addtmp = fadd double %x2, 1.000000e+00\","
+        ));
+        assert!(json.contains("\"line\":3,\"col\":3}"));
+
+        // Only the ends of blocks are looked at for what's new, allocas put
+        // at the top of the entry block and every branch are still found
+        let ctx = Context::create();
+        let mut llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "def g(x)\n  if x then (var y = x in y) else (var z = 2 in z * x)";
+        llvm_ctx.enable_source_map(src);
+
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        for item in program.iter() {
+            item.codegen(&llvm_ctx).unwrap();
+        }
+
+        let g = llvm_ctx.get_function("g").unwrap();
+        let generated = g
+            .get_basic_blocks()
+            .iter()
+            .map(|block| block.get_instructions().count())
+            .sum::<usize>();
+
+        let mappings = llvm_ctx.source_map().unwrap().mappings();
+        assert_eq!(mappings.len(), generated);
+        assert!(mappings
+            .iter()
+            .any(|mapping| mapping.instruction.contains("%z = alloca")));
+    }
+}
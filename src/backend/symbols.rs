@@ -0,0 +1,129 @@
+use std::fmt;
+
+use inkwell::module::{Linkage, Module};
+
+// A function or global in the module, as LLVM has it after codegen, so an
+// extern shows up as a function without a body, and --entry's effect on
+// every other function can be seen in their linkage
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub params: Option<u32>, // Globals have none
+    pub linkage: Linkage,
+    pub defined: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolTable {
+    pub functions: Vec<Symbol>,
+    pub globals: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    // Each sorted by name, rather than in the order they were generated in
+    pub fn of(module: &Module) -> Self {
+        let mut functions: Vec<_> = module
+            .get_functions()
+            .map(|func| Symbol {
+                name: func.get_name().to_string_lossy().into_owned(),
+                params: Some(func.count_params()),
+                linkage: func.get_linkage(),
+                defined: func.count_basic_blocks() > 0,
+            })
+            .collect();
+
+        let mut globals: Vec<_> = module
+            .get_globals()
+            .map(|global| Symbol {
+                name: global.get_name().to_string_lossy().into_owned(),
+                params: None,
+                linkage: global.get_linkage(),
+                defined: global.get_initializer().is_some(),
+            })
+            .collect();
+
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+        globals.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self { functions, globals }
+    }
+}
+
+// One symbol a line, functions first, like:
+//
+// function fib          1  external  defined
+// function sin          1  external  declared
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for symbol in self.functions.iter().chain(self.globals.iter()) {
+            let (kind, params) = match symbol.params {
+                Some(params) => ("function", params.to_string()),
+                None => ("global", String::new()),
+            };
+
+            let linkage = format!("{:?}", symbol.linkage).to_lowercase();
+            let defined = match symbol.defined {
+                true => "defined",
+                false => "declared",
+            };
+
+            write!(f, "{kind:<8} {:<12} {params:>2}  ", symbol.name)?;
+            writeln!(f, "{linkage:<8}  {defined}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+    use inkwell::targets::{InitializationConfig, Target};
+
+    use super::*;
+    use crate::backend::llvm_backend::{LLVMCodeGen, LLVMContext};
+    use crate::cli::OptLevel;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_program_recovering};
+
+    #[test]
+    fn listing_symbols() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        let src = "extern sin(x); def twice(x y) (x + y) * 2;";
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+
+        for item in program.iter() {
+            item.codegen(&llvm_ctx).unwrap();
+        }
+
+        let symbols = llvm_ctx.symbols();
+
+        assert_eq!(
+            symbols.functions,
+            vec![
+                Symbol {
+                    name: "sin".to_string(),
+                    params: Some(1),
+                    linkage: Linkage::External,
+                    defined: false,
+                },
+                Symbol {
+                    name: "twice".to_string(),
+                    params: Some(2),
+                    linkage: Linkage::External,
+                    defined: true,
+                },
+            ]
+        );
+        assert!(symbols.globals.is_empty());
+
+        assert_eq!(
+            symbols.to_string(),
+            "function sin           1  external  declared\n\
+             function twice         2  external  defined\n"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::OptimizationLevel;
+
+use crate::backend::builtins;
+use crate::backend::llvm_backend::{BackendError, LLVMContext};
+use crate::backend::passes::DEFAULT_PASSES;
+
+type Fn0 = unsafe extern "C" fn() -> f64;
+type Fn1 = unsafe extern "C" fn(f64) -> f64;
+type Fn2 = unsafe extern "C" fn(f64, f64) -> f64;
+type Fn3 = unsafe extern "C" fn(f64, f64, f64) -> f64;
+type Fn4 = unsafe extern "C" fn(f64, f64, f64, f64) -> f64;
+
+type RecompileHook<'ctx> = Box<dyn FnMut(&str) + 'ctx>;
+
+// A JIT with two tiers, every function starts out compiled at -O0, which is
+// quick to get going. Calls are counted per function, once one is called past
+// the threshold it is considered hot, and recompiled with the optimization
+// passes at -O2, later calls to it go to the optimized code instead.
+//
+// Rather than patching code in place like ORC would, each hot function gets
+// its own execution engine, over an optimized copy of the module.
+pub struct TieredJit<'ctx> {
+    llvm_ctx: LLVMContext<'ctx>,
+    threshold: usize,
+    call_counts: HashMap<String, usize>,
+    baseline: ExecutionEngine<'ctx>,
+    optimized: HashMap<String, ExecutionEngine<'ctx>>,
+    on_recompile: Option<RecompileHook<'ctx>>,
+}
+
+impl<'ctx> TieredJit<'ctx> {
+    // Takes the context once everything has been generated into it
+    pub fn new(llvm_ctx: LLVMContext<'ctx>, threshold: usize) -> Self {
+        let module = llvm_ctx.optimized_copy("");
+        let baseline = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .expect("FATAL: Failed to create JIT execution engine!");
+
+        builtins::map_builtins(&baseline, &module, llvm_ctx.builtins());
+
+        Self {
+            llvm_ctx,
+            threshold,
+            call_counts: HashMap::new(),
+            baseline,
+            optimized: HashMap::new(),
+            on_recompile: None,
+        }
+    }
+
+    // Called with the name of every function as it gets recompiled
+    pub fn on_recompile(&mut self, hook: impl FnMut(&str) + 'ctx) {
+        self.on_recompile = Some(Box::new(hook));
+    }
+
+    pub fn is_optimized(&self, name: &str) -> bool {
+        self.optimized.contains_key(name)
+    }
+
+    pub fn call<'a>(&mut self, name: &'a str, args: &[f64]) -> Result<f64, BackendError<'a>> {
+        let param_cnt = self
+            .llvm_ctx
+            .get_function(name)
+            .ok_or(BackendError::UndefinedFunction(name))?
+            .count_params();
+
+        if args.len() != param_cnt as usize {
+            return Err(BackendError::IncorrectNumberOfArgs {
+                func_name: name,
+                param_cnt,
+            });
+        }
+
+        let count = self.call_counts.entry(name.to_string()).or_insert(0);
+        *count += 1;
+
+        if *count > self.threshold && !self.optimized.contains_key(name) {
+            self.recompile(name);
+        }
+
+        let engine = self.optimized.get(name).unwrap_or(&self.baseline);
+
+        // Every function takes and returns doubles, so only the
+        // number of arguments decides what type to call it as
+        unsafe {
+            let res = match *args {
+                [] => engine.get_function::<Fn0>(name).map(|f| f.call()),
+                [a] => engine.get_function::<Fn1>(name).map(|f| f.call(a)),
+                [a, b] => engine.get_function::<Fn2>(name).map(|f| f.call(a, b)),
+                [a, b, c] => engine.get_function::<Fn3>(name).map(|f| f.call(a, b, c)),
+                [a, b, c, d] => engine.get_function::<Fn4>(name).map(|f| f.call(a, b, c, d)),
+                _ => return Err(BackendError::TooManyJitArgs(name.to_string())),
+            };
+
+            res.map_err(|_| BackendError::UndefinedFunction(name))
+        }
+    }
+
+    fn recompile(&mut self, name: &str) {
+        let module = self.llvm_ctx.optimized_copy(DEFAULT_PASSES);
+        let engine = module
+            .create_jit_execution_engine(OptimizationLevel::Default)
+            .expect("FATAL: Failed to create JIT execution engine!");
+
+        builtins::map_builtins(&engine, &module, self.llvm_ctx.builtins());
+
+        self.optimized.insert(name.to_string(), engine);
+
+        if let Some(hook) = self.on_recompile.as_mut() {
+            hook(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use inkwell::context::Context;
+    use inkwell::targets::{InitializationConfig, Target};
+
+    use super::*;
+    use crate::backend::llvm_backend::LLVMCodeGen;
+    use crate::cli::OptLevel;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_definition};
+
+    #[test]
+    fn recompiling_hot_functions() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O0);
+
+        parse_definition(&mut "def sq(x) x * x;".lex().lookahead())
+            .unwrap()
+            .codegen(&llvm_ctx)
+            .unwrap();
+
+        let recompiled = Rc::new(Cell::new(0));
+        let mut jit = TieredJit::new(llvm_ctx, 3);
+
+        let counter = recompiled.clone();
+        jit.on_recompile(move |_| counter.set(counter.get() + 1));
+
+        for i in 0..3 {
+            assert_eq!(jit.call("sq", &[i as f64]), Ok((i * i) as f64));
+        }
+
+        assert!(!jit.is_optimized("sq"));
+        assert_eq!(recompiled.get(), 0);
+
+        // Past the threshold, it's recompiled once, and still gives the same answers
+        for _ in 0..3 {
+            assert_eq!(jit.call("sq", &[4.0]), Ok(16.0));
+        }
+
+        assert!(jit.is_optimized("sq"));
+        assert_eq!(recompiled.get(), 1);
+
+        assert_eq!(
+            jit.call("nope", &[]),
+            Err(BackendError::UndefinedFunction("nope"))
+        );
+    }
+}
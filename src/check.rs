@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+
+use crate::cli::ErrorFormat;
+use crate::compile::{parse_diagnosed, report_diagnostics};
+use crate::frontend::diagnostics::Diagnostic;
+
+// A dry run of the frontend only, lex and parse the whole program
+// collecting every error along the way. No IR is generated and LLVM is
+// never touched, so this is quick enough for editors to run on save.
+// Only the first max_errors are kept, also gives back how many more there were.
+pub fn check_src(src_code: &str, max_errors: usize) -> (Vec<Diagnostic>, usize) {
+    let (_program, errors, suppressed) = parse_diagnosed(src_code, max_errors);
+
+    (errors, suppressed)
+}
+
+// Print each error found, followed by a short summary line, which isn't a
+// diagnostic, so it's left out of the JSON
+pub fn report_errors(
+    src_code: &str,
+    errors: &[Diagnostic],
+    suppressed: usize,
+    format: ErrorFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if format == ErrorFormat::Json {
+        return report_diagnostics(src_code, errors, format, out);
+    }
+
+    for err in errors.iter() {
+        writeln!(out, "{}", err)?;
+    }
+
+    if suppressed > 0 {
+        writeln!(out, "... and {suppressed} more errors suppressed")?;
+    }
+
+    match errors.len() + suppressed {
+        1 => writeln!(out, "1 error found"),
+        n => writeln!(out, "{n} errors found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checking_clean_program() {
+        let (errors, _) = check_src("def square(x) x * x; extern sin(x); square(sin(2));", 20);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn checking_program_with_errors() {
+        let src = "def square(x) x * ; def (x) 1; square(3);";
+        let (errors, suppressed) = check_src(src, 20);
+        assert_eq!(errors.len(), 2);
+
+        let mut out = vec![];
+        report_errors(src, &errors, suppressed, ErrorFormat::Human, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().filter(|l| l.starts_with("error[")).count(), 2);
+        assert!(out.ends_with("2 errors found\n"));
+
+        let mut out = vec![];
+        report_errors(src, &errors, suppressed, ErrorFormat::Json, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().count(), 2);
+        assert!(out
+            .lines()
+            .all(|l| l.starts_with("{\"severity\":\"error\",\"code\":\"E0")));
+        assert!(out.contains("\"line\":1,\"col\":19"));
+    }
+
+    #[test]
+    fn capping_errors() {
+        let src = "def (x) 1;".repeat(50);
+
+        let (errors, suppressed) = check_src(&src, 5);
+        assert_eq!((errors.len(), suppressed), (5, 45));
+
+        let mut out = vec![];
+        report_errors(&src, &errors, suppressed, ErrorFormat::Human, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().filter(|l| l.starts_with("error[")).count(), 5);
+        assert!(out.ends_with("... and 45 more errors suppressed\n50 errors found\n"));
+    }
+}
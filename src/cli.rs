@@ -6,20 +6,102 @@ use clap::{
 };
 use inkwell;
 
+use crate::backend::llvm_backend::DEFAULT_MAX_POW_UNROLL;
+use crate::backend::passes::{parse_pgo, validate_passes, Pgo, Sanitizer, DEFAULT_PASSES};
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     /// A positional file containing Kaleidoscope code to compile to object/assembly, if not given, starts interpreter instead
     pub file: Option<PathBuf>,
 
+    /// More files of the same program, each compiled to a module of its own, then linked into the first's before optimizing
+    pub linked_files: Vec<PathBuf>,
+
     /// What optimization level to pass to LLVM
     #[arg(long, value_enum, default_value = OptLevel::O2)]
     pub opt_level: OptLevel,
 
-    /// Comma separated list of LLVM passes (use opt for a list, also see https://www.llvm.org/docs/Passes.html)
-    #[arg(short, long, default_value = "instcombine,reassociate,gvn,simplifycfg,mem2reg")]
+    /// Comma separated list of LLVM passes (use opt for a list, also see https://www.llvm.org/docs/Passes.html), "" to run none at all
+    #[arg(short, long, default_value = DEFAULT_PASSES, value_parser = validate_passes)]
     pub passes: String,
 
+    /// When AOT compiling, the only function left visible outside the object, all others become internal
+    #[arg(long)]
+    pub entry: Option<String>,
+
+    /// Gather every top level expression, in order, into a generated main, which returns the value of the last
+    #[arg(long)]
+    pub wrap_main: bool,
+
+    /// When AOT compiling, emit DWARF debug info so the output can be stepped through in a debugger
+    #[arg(long)]
+    pub debug_info: bool,
+
+    /// Profile guided optimization, "instrument" to build a program that writes out a profile when run (link with clang), or "use=<profdata>" to optimize with one
+    #[arg(long, value_parser = parse_pgo)]
+    pub pgo: Option<Pgo>,
+
+    /// Instrument the code with a sanitizer, the program must then be linked with its runtime (--emit=exe does so)
+    #[arg(long, value_enum)]
+    pub sanitize: Option<Sanitizer>,
+
+    /// Powers with a constant whole exponent up to this are multiplied out, like x ^ 3 as x * x * x, the rest call llvm.pow
+    #[arg(long, default_value_t = DEFAULT_MAX_POW_UNROLL)]
+    pub max_pow_unroll: u32,
+
+    /// When AOT compiling, strip any debug info from the output after optimizing
+    #[arg(long)]
+    pub strip: bool,
+
+    /// Treat warnings, like unused variables, as errors that stop compilation
+    #[arg(long)]
+    pub deny_warnings: bool,
+
+    /// Warn about every function defined but never called, other than main and the --entry
+    #[arg(long)]
+    pub warn_unused_functions: bool,
+
+    /// Remove unused var bindings from the tree before generating any IR
+    #[arg(long)]
+    pub ast_dce: bool,
+
+    /// Lower every for loop in the tree into a while loop before generating any IR
+    #[arg(long)]
+    pub desugar_for: bool,
+
+    /// Give each call with only numbers for arguments its own copy of the callee, for the passes to fold
+    #[arg(long)]
+    pub specialize: bool,
+
+    /// When AOT compiling, run the passes one at a time, printing the IR to stderr after each
+    #[arg(long)]
+    pub print_each_pass: bool,
+
+    /// Print a summary of what the optimization passes changed in the IR, instruction counts, blocks, inlining
+    #[arg(long)]
+    pub explain_opt: bool,
+
+    /// Print each function and global of the generated module, with its linkage and whether it has a body
+    #[arg(long)]
+    pub dump_symbols: bool,
+
+    /// Write a JSON map of each generated instruction to the line and column of the source it came from
+    #[arg(long, value_name = "FILE")]
+    pub source_map: Option<PathBuf>,
+
+    /// Print out the LLVM passes that can be given to --passes, then exit
+    #[arg(long)]
+    pub list_passes: bool,
+
+    /// Print the operator precedence table, with the overloads of the given file if any, then exit
+    #[arg(long)]
+    pub show_precedence: bool,
+
+    /// Let binary overloads change the precedence of builtin operators, like "def binary+ 60 (a b)", rather than rejecting them
+    #[arg(long)]
+    pub allow_override: bool,
+
     /// When AOT compiling, specifies an output file to write to
     #[arg(short, long, default_value = "a.out")]
     pub output: PathBuf,
@@ -28,6 +110,18 @@ pub struct Cli {
     #[arg(short = 'S', long = "assembly")]
     pub asm_p: bool,
 
+    /// When AOT compiling, what kind of file to write, an executable needs a main, taking no arguments
+    #[arg(long, value_enum, default_value = "obj")]
+    pub emit: Emit,
+
+    /// With --emit=cfg, where the graphs go, one file per function named <OUTPUT_BASE>.<function>.dot
+    #[arg(long, default_value = "cfg")]
+    pub output_base: PathBuf,
+
+    /// When emitting an executable, the program invoked to link the object into it
+    #[arg(long, default_value = "cc")]
+    pub linker: PathBuf,
+
     /// When JIT compiling, prints out AST to stdout after every line entered into interpreter
     #[arg(long)]
     pub inspect_tree: bool,
@@ -36,10 +130,66 @@ pub struct Cli {
     #[arg(long)]
     pub inspect_asm: bool,
 
+    /// Only lex and parse the given file, reporting all syntax errors found, no code is generated
+    #[arg(long, requires = "file")]
+    pub check: bool,
+
+    /// Write errors and warnings for people to read, or as a JSON object a line for tools
+    #[arg(long, value_enum, default_value = "human")]
+    pub error_format: ErrorFormat,
+
+    /// How the REPL prints what expressions evaluate to, auto picks scientific notation for very big or small numbers
+    #[arg(long, value_enum, default_value = "auto")]
+    pub repl_format: ReplFormat,
+
+    /// When JIT compiling, take each line entered on its own, rather than continuing onto the next one a line that ends mid expression
+    #[arg(long)]
+    pub repl_single_line: bool,
+
+    /// Stop reporting syntax errors after this many, the rest are only counted
+    #[arg(long, default_value_t = 20)]
+    pub max_errors: usize,
+
+    /// Print the given file out formatted in a canonical style, no code is generated
+    #[arg(long, requires = "file")]
+    pub fmt: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Emit {
+    /// Object file
+    Obj,
+    /// Assembly, same as -S
+    Asm,
+    /// Executable, linked by --linker
+    Exe,
+    /// Graphviz DOT of the parsed tree, printed to stdout, no code is generated
+    AstDot,
+    /// Graphviz DOT of each function's control flow graph as generated, before any passes, see --output-base
+    Cfg,
+    /// JSON of counts from each stage, tokens, tree nodes of each kind, IR instructions before and after the passes, printed to stdout
+    Metrics,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// "line:col: error[code]: message"
+    Human,
+    /// {"severity", "code", "message", "line", "col", "span": {"start", "end"}}
+    Json,
+}
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ReplFormat {
+    /// Scientific notation only past 1e15 or under 1e-5, like 1e20 and 0.25
+    Auto,
+    /// Always scientific notation, like 1e20 and 2.5e-1
+    Sci,
+    /// Always a plain decimal, like 100000000000000000000 and 0.25
+    Fixed,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum OptLevel {
     O0,
     O1,
@@ -1,65 +1,1657 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use inkwell::targets::FileType;
+use inkwell::context::Context;
+use inkwell::module::Module;
+use inkwell::targets::{FileType, InitializationConfig, Target};
+use thiserror::Error;
 
 use crate::{
-    cli::Cli,
+    backend::passes::{ModuleStats, OptReport, Pgo, Sanitizer, DEFAULT_PASSES},
+    cli::{Cli, Emit, ErrorFormat, OptLevel},
     frontend::{
-        lexer::{Lex, Token},
-        parser::{parse_definition, parse_extern, parse_top_level_expr},
+        ast::TopLevel,
+        diagnostics::{json_string, Diagnostic, Severity, Span},
+        dot::emit_dot,
+        lexer::{lex_all_spanned, Lex, Token},
+        lookahead::Lookahead,
+        metrics::count_kinds,
+        parser::{parse_program_recovering, parse_program_reporting, ParserError},
+        sema::{self, SemaError},
+        transform,
     },
 };
-use crate::backend::llvm_backend::{LLVMCodeGen, LLVMContext};
+use crate::backend::llvm_backend::{
+    BackendError, LLVMCodeGen, LLVMContext, DEFAULT_MAX_POW_UNROLL,
+};
 
+// The parts of the Cli that matter when compiling, for using the compiler
+// as a library without going through the command line
+#[derive(Debug, Clone, Hash)]
+pub struct CompileOptions {
+    pub opt_level: OptLevel,
+    pub passes: String,
+    pub ast_dce: bool,
+    pub desugar_for: bool,
+    pub specialize: bool,
+    pub deny_warnings: bool,
+    pub debug_info: bool,
+    pub source_map: bool,
+    pub strip: bool,
+    pub entry: Option<String>,
+    pub wrap_main: bool,
+    pub pgo: Option<Pgo>,
+    pub sanitize: Option<Sanitizer>,
+    pub max_pow_unroll: u32,
+}
 
-pub fn compile_src<'src>(
-    src_code: &'src str, 
-    cli: &Cli
-) -> Result<(), Box<dyn Error + 'src>> {
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::O2,
+            passes: DEFAULT_PASSES.to_string(),
+            ast_dce: false,
+            desugar_for: false,
+            specialize: false,
+            deny_warnings: false,
+            debug_info: false,
+            source_map: false,
+            strip: false,
+            entry: None,
+            wrap_main: false,
+            pgo: None,
+            sanitize: None,
+            max_pow_unroll: DEFAULT_MAX_POW_UNROLL,
+        }
+    }
+}
+
+impl From<&Cli> for CompileOptions {
+    fn from(cli: &Cli) -> Self {
+        Self {
+            opt_level: cli.opt_level,
+            passes: cli.passes.clone(),
+            ast_dce: cli.ast_dce,
+            desugar_for: cli.desugar_for,
+            specialize: cli.specialize,
+            deny_warnings: cli.deny_warnings,
+            debug_info: cli.debug_info,
+            source_map: cli.source_map.is_some(),
+            strip: cli.strip,
+            entry: cli.entry.clone(),
+            wrap_main: cli.wrap_main,
+            pgo: cli.pgo.clone(),
+            sanitize: cli.sanitize,
+            max_pow_unroll: cli.max_pow_unroll,
+        }
+    }
+}
+
+// Anything that can go wrong along the whole pipeline, one error type
+// for each stage of it. Since these borrow from the source, thiserror's
+// #[from] can't be used, so the conversions are written out below.
+#[derive(Error, PartialEq, Debug)]
+pub enum CompileError<'src> {
+    #[error("{0}")]
+    Parser(ParserError<'src>),
+
+    #[error("{0}")]
+    Sema(SemaError<'src>),
+
+    #[error("{0}")]
+    Backend(BackendError<'src>),
+
+    #[error("{0}, warnings are denied")]
+    DeniedWarning(Diagnostic),
+
+    #[error("Internal compiler error: {0}")]
+    InternalError(String),
+
+    #[error("Linking failed:\n{0}")]
+    LinkFailed(String),
+
+    #[error("{0} is declared with decl, but never defined")]
+    DeclNotDefined(String),
+}
+
+impl<'src> From<ParserError<'src>> for CompileError<'src> {
+    fn from(err: ParserError<'src>) -> Self {
+        CompileError::Parser(err)
+    }
+}
+
+impl<'src> From<SemaError<'src>> for CompileError<'src> {
+    fn from(err: SemaError<'src>) -> Self {
+        CompileError::Sema(err)
+    }
+}
+
+impl<'src> From<BackendError<'src>> for CompileError<'src> {
+    fn from(err: BackendError<'src>) -> Self {
+        CompileError::Backend(err)
+    }
+}
+
+// What --emit=metrics prints, a few numbers from each stage of compiling
+// a program, for seeing how it went from source to IR
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileMetrics {
+    pub tokens: usize,
+    pub nodes: BTreeMap<&'static str, usize>, // By ASTExpr::kind
+    pub functions: usize,
+    pub externs: usize,
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+    pub passes: Vec<String>,
+}
+
+impl CompileMetrics {
+    // The IR's stats from before and after passes were run on it
+    pub fn new(
+        src_code: &str,
+        program: &[TopLevel],
+        passes: &str,
+        before: &ModuleStats,
+        after: &ModuleStats,
+    ) -> Self {
+        let mut nodes = BTreeMap::new();
+
+        for item in program.iter() {
+            for child in item.children() {
+                count_kinds(child, &mut nodes);
+            }
+        }
+
+        let count = |pred: fn(&TopLevel) -> bool| program.iter().filter(|item| pred(item)).count();
+
+        Self {
+            tokens: src_code.lex().count(),
+            nodes,
+            functions: count(|item| matches!(item, TopLevel::Definition(_))),
+            externs: count(|item| matches!(item, TopLevel::Extern(proto) if !proto.is_decl())),
+            instructions_before: before.instructions(),
+            instructions_after: after.instructions(),
+            passes: passes
+                .split(',')
+                .filter(|pass| !pass.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    // All on the one line, like:
+    //
+    // {"tokens": 9, "nodes": {"BinaryExpr": 1, "VariableExpr": 2}, "functions": 1, "externs": 0,
+    //  "instructions": {"before": 7, "after": 1}, "passes": ["instcombine", "mem2reg"]}
+    pub fn to_json(&self) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(kind, count)| format!("{}: {count}", json_string(kind)))
+            .collect::<Vec<_>>();
+
+        let passes = self
+            .passes
+            .iter()
+            .map(|pass| json_string(pass))
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"tokens\": {}, \"nodes\": {{{}}}, \"functions\": {}, \"externs\": {}, \
+             \"instructions\": {{\"before\": {}, \"after\": {}}}, \"passes\": [{}]}}\n",
+            self.tokens,
+            nodes.join(", "),
+            self.functions,
+            self.externs,
+            self.instructions_before,
+            self.instructions_after,
+            passes.join(", ")
+        )
+    }
+}
+
+// Generate IR for every item in order, unless wrapping main, then the top
+// level expressions are held back, and become the body of main at the end
+fn codegen_program<'src>(
+    llvm_ctx: &LLVMContext,
+    program: &[TopLevel<'src>],
+    wrap_main: bool,
+) -> Result<(), CompileError<'src>> {
+    let mut exprs = vec![];
+
+    let decls = program
+        .iter()
+        .filter_map(|item| match item {
+            TopLevel::Extern(proto) if proto.is_decl() => Some(&**proto),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
 
-    let ctx = inkwell::context::Context::create();
-    let llvm_ctx = LLVMContext::new(&ctx, cli.opt_level);
+    // A decl promises a definition further on, without one the module
+    // would be left calling a function that can't be linked to
+    for decl in decls.iter() {
+        let name = decl.get_name();
 
-    let mut tokens = src_code.lex().peekable();
+        if !program
+            .iter()
+            .any(|item| matches!(item, TopLevel::Definition(func) if func.proto.get_name() == name))
+        {
+            return Err(CompileError::DeclNotDefined(name));
+        }
+    }
+
+    // Every function is declared before any body is generated, so a call to
+    // one defined further down, or mutual recursion, finds it already there.
+    // The bodies then fill in these declarations, in source order. One with
+    // a decl is declared by it, so it's internal like the decl says.
+    for item in program.iter() {
+        if let TopLevel::Definition(func) = item {
+            let name = func.proto.get_name();
 
-    while let Some(token) = tokens.peek() {
-        match token {
-            Token::Extern => {
-                match parse_extern(&mut tokens) {
-                    Ok(ast) => { ast.codegen(&llvm_ctx)?; }
-                    Err(e) => eprintln!("Error: {}", e),
-                }   
+            if !llvm_ctx.is_declared(&name) {
+                let decl = decls.iter().find(|decl| decl.get_name() == name);
+                decl.copied().unwrap_or(&func.proto).codegen(llvm_ctx)?;
             }
+        }
+    }
+
+    for item in program.iter() {
+        match item {
+            TopLevel::Expression(func) if wrap_main => exprs.push(&**func),
 
-            Token::FuncDef => {
-                match parse_definition(&mut tokens) {
-                    Ok(ast) => { ast.codegen(&llvm_ctx)?; }
-                    Err(e) => eprintln!("Error: {}", e),
-                }   
+            // Already declared by its definition, a second declaration
+            // would only be renamed by LLVM
+            TopLevel::Extern(proto) if llvm_ctx.is_declared(&proto.get_name()) => {}
+
+            item => {
+                item.codegen(llvm_ctx)?;
             }
+        }
+    }
+
+    if wrap_main {
+        llvm_ctx.wrap_main(&exprs)?;
+    }
+
+    Ok(())
+}
+
+// See LLVMContext::specialize_calls, only calls to definitions get copies
+fn specialize_calls<'src>(
+    llvm_ctx: &LLVMContext,
+    program: &[TopLevel<'src>],
+) -> Result<usize, BackendError<'src>> {
+    let defs = program
+        .iter()
+        .filter_map(|item| match item {
+            TopLevel::Definition(func) => Some(&**func),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    llvm_ctx.specialize_calls(&defs)
+}
 
-            // Eat semicolons and move on
-            Token::Semicolon => { tokens.next(); },
+// The transformations opts asks for, run on the checked tree of every
+// function with a body
+fn transform_program(program: &mut [TopLevel], opts: &CompileOptions) {
+    for item in program.iter_mut() {
+        if let TopLevel::Definition(func) | TopLevel::Expression(func) = item {
+            if opts.ast_dce {
+                transform::eliminate_dead_vars(func);
+            }
 
-            _top_level_expr => {
-                match parse_top_level_expr(&mut tokens) {
-                    Ok(ast) => { ast.codegen(&llvm_ctx)?; }
-                    Err(e) => eprintln!("Error: {}", e),
-                }   
+            if opts.desugar_for {
+                transform::desugar_for(func);
             }
+        }
+    }
+}
 
+// Parses a whole program, but as a safety net, any panic from inside the
+// parser is caught and turned into an InternalError, rather than unwinding
+// into and taking down whatever is embedding us. Gives back the first error.
+pub fn catch_parse(src_code: &str) -> Result<Vec<TopLevel<'_>>, CompileError<'_>> {
+    let parsed = panic::catch_unwind(|| {
+        let mut tokens = src_code.lex().lookahead();
+        parse_program_recovering(&mut tokens)
+    });
 
+    match parsed {
+        Ok((program, errors)) => match errors.into_iter().next() {
+            Some(err) => Err(err.into()),
+            None => Ok(program),
+        },
+
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+
+            Err(CompileError::InternalError(msg))
         }
     }
-    
-    // Run the optimization passes on IR in module, output to object/assembly file
-    llvm_ctx.run_passes(&cli.passes);
+}
+
+// Runs the full pipeline, lexing, parsing, checking, generating IR then
+// optimizing it, returning the textual IR of the module. Nothing is written
+// out, so this is handy for embedding the compiler, or testing it. Stops at
+// the first error found.
+pub fn compile_to_string(src_code: &str, opts: CompileOptions) -> Result<String, CompileError<'_>> {
+    let ctx = Context::create();
+    let llvm_ctx = compile_module(&ctx, src_code, &opts)?;
+
+    Ok(llvm_ctx.module_to_string())
+}
+
+// The guts of compile_to_string, leaving the module in the context for
+// whoever wants to do something other than print it. The Context is the
+// caller's, creating one isn't cheap, so a batch of files can all share the
+// one, each compiled to a module of its own tied to it by 'ctx:
+//
+// let ctx = Context::create();
+// for src in sources {
+//     let module = compile_module(&ctx, src, &opts)?.into_module();
+// }
+pub fn compile_module<'ctx, 'src>(
+    ctx: &'ctx Context,
+    src_code: &'src str,
+    opts: &CompileOptions,
+) -> Result<LLVMContext<'ctx>, CompileError<'src>> {
+    compile_modules(ctx, &[src_code], opts)
+}
+
+// Several files as one program, each generated to a module of its own, then
+// linked into the first's, before any passes run, so a call from one file
+// to another can be inlined all the same. A file calls what another defines
+// through an extern, one defining what another does is an error.
+pub fn compile_modules<'ctx, 'src>(
+    ctx: &'ctx Context,
+    srcs: &[&'src str],
+    opts: &CompileOptions,
+) -> Result<LLVMContext<'ctx>, CompileError<'src>> {
+    Target::initialize_native(&InitializationConfig::default())
+        .expect("Failed to initialize native machine target!");
+
+    let files = srcs
+        .iter()
+        .map(|src_code| Ok((*src_code, check_source(src_code, opts)?)))
+        .collect::<Result<Vec<_>, CompileError>>()?;
+
+    let llvm_ctx = generate_modules(ctx, &files, opts, Path::new("<string>"))?;
+
+    if let Some(ref entry) = opts.entry {
+        llvm_ctx.set_entry_point(entry)?;
+    }
+
+    optimize(&llvm_ctx, opts, |llvm_ctx, passes| {
+        llvm_ctx.run_passes(passes);
+        Ok::<_, CompileError>(())
+    })?;
+
+    Ok(llvm_ctx)
+}
+
+// The passes and what has to go with them, PGO's before, on the IR as it was
+// generated, the sanitizer's and stripping the debug info after. Running
+// opts.passes is left to run_passes, for the CLI to print or explain them.
+// Reports what opts.passes changed.
+fn optimize<'src, E: From<BackendError<'src>>>(
+    llvm_ctx: &LLVMContext,
+    opts: &CompileOptions,
+    run_passes: impl FnOnce(&LLVMContext, &str) -> Result<(), E>,
+) -> Result<OptReport, E> {
+    if let Some(ref pgo) = opts.pgo {
+        llvm_ctx.run_pgo_passes(pgo)?;
+    }
+
+    let before = llvm_ctx.stats();
+    run_passes(llvm_ctx, &opts.passes)?;
+    let after = llvm_ctx.stats();
+
+    llvm_ctx.run_sanitizer_passes();
+
+    if opts.strip {
+        llvm_ctx.strip_debug_info();
+    }
+
+    Ok(OptReport { before, after })
+}
+
+// Every file's top level code is a function of the same name, main when
+// wrapping main, so before the others are linked into the first's, each is
+// renamed apart, then one function of that name calls them all, in the
+// order of the files. Just the one file is left as it is.
+fn link_files<'ctx, 'src>(
+    llvm_ctx: &LLVMContext<'ctx>,
+    linked: Vec<LLVMContext<'ctx>>,
+    wrap_main: bool,
+) -> Result<(), BackendError<'src>> {
+    if linked.is_empty() {
+        return Ok(());
+    }
 
-    if cli.asm_p {
-        llvm_ctx.compile(&cli.output.as_path(), FileType::Assembly);
+    let top_level = if wrap_main {
+        "main"
     } else {
-        llvm_ctx.compile(&cli.output.as_path(), FileType::Object);
+        "__anonymous_expr"
+    };
+    let mut parts = vec![];
+
+    for (idx, file_ctx) in std::iter::once(llvm_ctx).chain(linked.iter()).enumerate() {
+        let part = format!("{top_level}.{idx}");
+
+        if file_ctx.rename_function(top_level, &part) {
+            parts.push(part);
+        }
+    }
+
+    for file_ctx in linked {
+        llvm_ctx.link_in(file_ctx.into_module())?;
+    }
+
+    if !parts.is_empty() {
+        llvm_ctx.join_functions(top_level, &parts)?;
+    }
+
+    Ok(())
+}
+
+// A module for a file to be generated into, set up as opts say, the debug
+// info pointing into the file at path
+fn new_module<'ctx>(
+    ctx: &'ctx Context,
+    src_code: &str,
+    opts: &CompileOptions,
+    path: &Path,
+) -> LLVMContext<'ctx> {
+    let mut llvm_ctx = LLVMContext::new(ctx, opts.opt_level);
+    llvm_ctx.set_max_pow_unroll(opts.max_pow_unroll);
+
+    if opts.debug_info {
+        llvm_ctx.enable_debug_info(path, src_code);
+    }
+
+    if opts.source_map {
+        llvm_ctx.enable_source_map(src_code);
+    }
+
+    if let Some(sanitizer) = opts.sanitize {
+        llvm_ctx.enable_sanitizer(sanitizer);
+    }
+
+    llvm_ctx
+}
+
+// A file parsed and checked, stopping at the first error, then transformed
+// as opts say. The CLI reports everything instead, see check_file.
+fn check_source<'src>(
+    src_code: &'src str,
+    opts: &CompileOptions,
+) -> Result<Vec<TopLevel<'src>>, CompileError<'src>> {
+    let mut program = catch_parse(src_code)?;
+
+    if let Some(err) = sema::check_program(&program).into_iter().next() {
+        return Err(err.into());
+    }
+
+    if opts.deny_warnings {
+        let warning = sema::diagnose(src_code, &program)
+            .into_iter()
+            .find(|diag| diag.severity == Severity::Warning);
+
+        if let Some(warning) = warning {
+            return Err(CompileError::DeniedWarning(warning));
+        }
+    }
+
+    transform_program(&mut program, opts);
+
+    Ok(program)
+}
+
+// A checked file up to its module's IR, as generated, none of the passes
+// run yet
+fn generate_module<'ctx, 'src>(
+    ctx: &'ctx Context,
+    src_code: &str,
+    program: &[TopLevel<'src>],
+    opts: &CompileOptions,
+    path: &Path,
+) -> Result<LLVMContext<'ctx>, CompileError<'src>> {
+    let llvm_ctx = new_module(ctx, src_code, opts, path);
+
+    codegen_program(&llvm_ctx, program, opts.wrap_main)?;
+
+    if opts.specialize {
+        specialize_calls(&llvm_ctx, program)?;
+    }
+
+    llvm_ctx.finalize_debug_info();
+
+    Ok(llvm_ctx)
+}
+
+// Each checked file generated to a module of its own, the others then linked
+// into the first's, see link_files. Only the first gets debug info or a
+// source map, path is the first's.
+fn generate_modules<'ctx, 'src>(
+    ctx: &'ctx Context,
+    files: &[(&str, Vec<TopLevel<'src>>)],
+    opts: &CompileOptions,
+    path: &Path,
+) -> Result<LLVMContext<'ctx>, CompileError<'src>> {
+    let mut files = files.iter();
+
+    let llvm_ctx = match files.next() {
+        Some((src_code, program)) => generate_module(ctx, src_code, program, opts, path)?,
+        None => LLVMContext::new(ctx, opts.opt_level),
+    };
+
+    let linked_opts = CompileOptions {
+        debug_info: false,
+        source_map: false,
+        ..opts.clone()
+    };
+
+    let linked = files
+        .map(|(src_code, program)| generate_module(ctx, src_code, program, &linked_opts, path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    link_files(&llvm_ctx, linked, opts.wrap_main)?;
+
+    Ok(llvm_ctx)
+}
+
+// The recovering parser, giving back each syntax error as a diagnostic, only
+// the first max_errors of them, counting the rest. Where the parser had got
+// to is the last token it looked at, which is mostly the one it didn't expect.
+pub(crate) fn parse_diagnosed(
+    src_code: &str,
+    max_errors: usize,
+) -> (Vec<TopLevel<'_>>, Vec<Diagnostic>, usize) {
+    let spanned: Vec<_> = lex_all_spanned(src_code)
+        .into_iter()
+        .filter(|(token, _)| !matches!(token, Token::Comment(_)))
+        .collect();
+
+    let pulled = Cell::new(0);
+    let mut tokens = spanned
+        .iter()
+        .map(|(token, _)| *token)
+        .inspect(|_| pulled.set(pulled.get() + 1))
+        .lookahead();
+
+    let mut diagnostics = vec![];
+    let mut suppressed = 0;
+
+    let program = parse_program_reporting(&mut tokens, |err| {
+        if diagnostics.len() == max_errors {
+            suppressed += 1;
+            return;
+        }
+
+        let span = match pulled.get() {
+            0 => Span { start: 0, end: 0 },
+            n => Span {
+                start: spanned[n - 1].1.start,
+                end: spanned[n - 1].1.end,
+            },
+        };
+
+        // The code is kept apart from the message, rather than in front of it
+        let text = err.to_string();
+        let message = text
+            .strip_prefix(&format!("error[{}]: ", err.code()))
+            .unwrap_or(&text);
+
+        diagnostics.push(Diagnostic::error(message.to_string(), span).with_code(err.code()));
+    });
+
+    (program, diagnostics, suppressed)
+}
+
+// Every diagnostic, one a line, as readable text or JSON
+pub fn report_diagnostics(
+    src_code: &str,
+    diagnostics: &[Diagnostic],
+    format: ErrorFormat,
+    out: &mut impl io::Write,
+) -> io::Result<()> {
+    for diag in diagnostics.iter() {
+        match format {
+            ErrorFormat::Human => writeln!(out, "{}", diag.render(src_code))?,
+            ErrorFormat::Json => writeln!(out, "{}", diag.to_json(src_code))?,
+        }
+    }
+
+    Ok(())
+}
+
+// For editors, rather than stopping at the first problem, everything wrong
+// with the program is given back at once, the syntax errors along with what
+// sema finds, each with where in the source it is. Only with none of them
+// fatal is a module compiled. Whatever might still go wrong generating the
+// IR, sema didn't see coming, knows no place in the source, it's put at the
+// very start.
+pub fn compile_diagnostics<'ctx>(
+    ctx: &'ctx Context,
+    src_code: &str,
+    opts: &CompileOptions,
+) -> (Option<Module<'ctx>>, Vec<Diagnostic>) {
+    let (program, mut diagnostics, _suppressed) = parse_diagnosed(src_code, usize::MAX);
+
+    diagnostics.extend(sema::diagnose(src_code, &program));
+
+    if diagnostics
+        .iter()
+        .any(|diag| diag.is_fatal(opts.deny_warnings))
+    {
+        return (None, diagnostics);
+    }
+
+    match compile_module(ctx, src_code, opts) {
+        Ok(llvm_ctx) => (Some(llvm_ctx.into_module()), diagnostics),
+        Err(err) => {
+            diagnostics.push(Diagnostic::error(
+                err.to_string(),
+                Span { start: 0, end: 0 },
+            ));
+            (None, diagnostics)
+        }
+    }
+}
+
+// Compiled modules kept as bitcode in a directory, named after a hash of
+// the source along with every option, so changing any of them (the opt
+// level, the passes) means a different artifact. Only misses get compiled,
+// these are counted so a build tool can tell how much work was skipped.
+//
+// The hash is std's DefaultHasher, which is stable for a given build of
+// the compiler, but not across Rust versions, at worst that costs a miss.
+#[derive(Debug)]
+pub struct ModuleCache {
+    dir: PathBuf,
+    recompilations: usize,
+}
+
+impl ModuleCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            recompilations: 0,
+        }
+    }
+
+    pub fn recompilations(&self) -> usize {
+        self.recompilations
+    }
+
+    fn artifact_path(&self, src_code: &str, opts: &CompileOptions) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        src_code.hash(&mut hasher);
+        opts.hash(&mut hasher);
+
+        self.dir.join(format!("{:016x}.bc", hasher.finish()))
+    }
+
+    // Same as compile_to_string, but loads the module from the cache when
+    // it's there. Failing to write the artifact out isn't an error, the
+    // compile still succeeded, the next one just won't be a hit.
+    pub fn compile_cached<'src>(
+        &mut self,
+        src_code: &'src str,
+        opts: &CompileOptions,
+    ) -> Result<String, CompileError<'src>> {
+        let path = self.artifact_path(src_code, opts);
+        let ctx = Context::create();
+
+        // An artifact that can't be read back, say left half written, is a miss
+        if let Ok(module) = Module::parse_bitcode_from_path(&path, &ctx) {
+            return Ok(module.print_to_string().to_string());
+        }
+
+        self.recompilations += 1;
+        let llvm_ctx = compile_module(&ctx, src_code, opts)?;
+
+        if fs::create_dir_all(&self.dir).is_ok() {
+            llvm_ctx.write_bitcode(&path);
+        }
+
+        Ok(llvm_ctx.module_to_string())
+    }
+}
+
+// Hands an object file off to the system linker (cc by default), which also
+// pulls in the C runtime that ends up calling our main, along with anything
+// else the object needs, passed on in args
+pub fn link_executable(
+    linker: &Path,
+    object: &Path,
+    output: &Path,
+    args: &[&str],
+) -> Result<(), CompileError<'static>> {
+    let result = Command::new(linker)
+        .arg(object)
+        .arg("-o")
+        .arg(output)
+        .arg("-lm")
+        .args(args)
+        .output()
+        .map_err(|err| CompileError::LinkFailed(format!("{}: {err}", linker.display())))?;
+
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(CompileError::LinkFailed(stderr.into_owned()));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Parses a file and checks it over, reporting whatever is found in it, the
+// program is only given back if none of that is fatal, transformed as opts say
+fn check_file<'src>(
+    src_code: &'src str,
+    cli: &Cli,
+    opts: &CompileOptions,
+) -> Result<Vec<TopLevel<'src>>, Box<dyn Error + 'src>> {
+    // Parse the whole program, reporting any syntax errors, the items that
+    // did parse are still checked and compiled
+    let (mut program, parse_errors, suppressed) = parse_diagnosed(src_code, cli.max_errors);
+    report_diagnostics(src_code, &parse_errors, cli.error_format, &mut io::stderr())?;
+
+    // Not a diagnostic, so left out of the JSON
+    if suppressed > 0 && cli.error_format == ErrorFormat::Human {
+        eprintln!("... and {suppressed} more errors suppressed");
+    }
+
+    // With the whole program parsed, check it over before generating any IR,
+    // report everything we find, not just the first problem. Only errors stop
+    // us, just warnings are fine unless they're denied.
+    let mut diagnostics = sema::diagnose(src_code, &program);
+
+    if cli.warn_unused_functions {
+        let entry = cli.entry.as_deref();
+        diagnostics.extend(sema::find_unused_functions(src_code, &program, entry));
+    }
+
+    report_diagnostics(src_code, &diagnostics, cli.error_format, &mut io::stderr())?;
+
+    if let Some(diag) = diagnostics
+        .into_iter()
+        .find(|diag| diag.is_fatal(opts.deny_warnings))
+    {
+        return Err(Box::new(diag));
+    }
+
+    transform_program(&mut program, opts);
+
+    Ok(program)
+}
+
+// Compiles src_code as cli says, along with linked, the sources of the
+// other files given, see compile_modules
+pub fn compile_src<'src>(
+    src_code: &'src str,
+    linked: &[&'src str],
+    cli: &Cli,
+) -> Result<(), Box<dyn Error + 'src>> {
+    let opts = CompileOptions::from(cli);
+
+    let files = std::iter::once(&src_code)
+        .chain(linked.iter())
+        .map(|file_src| Ok((*file_src, check_file(file_src, cli, &opts)?)))
+        .collect::<Result<Vec<_>, Box<dyn Error + 'src>>>()?;
+
+    // What the parser and sema found was reported as it was found, in the
+    // format asked for, anything else stopping the compile is reported the
+    // same way. It knows no place in the source, like in compile_diagnostics
+    // it's put at the very start.
+    let result = build_program(&files, cli, &opts);
+
+    if let (Err(err), ErrorFormat::Json) = (&result, cli.error_format) {
+        let diag = Diagnostic::error(err.to_string(), Span { start: 0, end: 0 });
+        report_diagnostics(src_code, &[diag], ErrorFormat::Json, &mut io::stderr())?;
+    }
+
+    result
+}
+
+// Everything after checking, the same stages as compile_modules, generating
+// the IR of the checked files and optimizing it, along with writing out
+// whatever cli.emit asks for in between
+fn build_program<'src>(
+    files: &[(&'src str, Vec<TopLevel<'src>>)],
+    cli: &Cli,
+    opts: &CompileOptions,
+) -> Result<(), Box<dyn Error + 'src>> {
+    let (src_code, program) = &files[0];
+
+    // The tree is all there is to show, stop before generating anything
+    if cli.emit == Emit::AstDot {
+        emit_dot(program, &mut io::stdout())?;
+        return Ok(());
+    }
+
+    let ctx = Context::create();
+    let path = cli.file.as_deref().unwrap_or(Path::new("<string>"));
+    let llvm_ctx = generate_modules(&ctx, files, opts, path)?;
+
+    if let (Some(path), Some(source_map)) = (&cli.source_map, llvm_ctx.source_map()) {
+        source_map.write_to(path)?;
+    }
+
+    // The blocks just as they were generated, optimizing would merge them
+    if cli.emit == Emit::Cfg {
+        for path in llvm_ctx.write_cfgs(&cli.output_base)? {
+            eprintln!("Wrote {}", path.display());
+        }
+
+        return Ok(());
+    }
+
+    // An executable always starts at main, unless told otherwise
+    let entry = match cli.emit {
+        Emit::Exe => opts.entry.as_deref().or(Some("main")),
+        _ => opts.entry.as_deref(),
+    };
+
+    if let Some(entry) = entry {
+        llvm_ctx.set_entry_point(entry)?;
+    }
+
+    if cli.emit == Emit::Exe {
+        llvm_ctx.add_c_main()?;
+    }
+
+    // As generated, before the passes get to inline and delete any
+    if cli.dump_symbols {
+        eprint!("{}", llvm_ctx.symbols());
+    }
+
+    // Run the optimization passes on IR in module, output to object/assembly file
+    let report = optimize(&llvm_ctx, opts, |llvm_ctx, passes| {
+        if cli.print_each_pass {
+            llvm_ctx.run_passes_printing(passes, &mut io::stderr())?;
+        } else {
+            llvm_ctx.run_passes(passes);
+        }
+
+        Ok::<_, Box<dyn Error + 'src>>(())
+    })?;
+
+    if cli.explain_opt {
+        eprint!("{report}");
+    }
+
+    let emit = if cli.asm_p { Emit::Asm } else { cli.emit };
+
+    match emit {
+        Emit::Asm => llvm_ctx.compile(cli.output.as_path(), FileType::Assembly)?,
+        Emit::Obj => llvm_ctx.compile(cli.output.as_path(), FileType::Object)?,
+
+        Emit::AstDot | Emit::Cfg => unreachable!("FATAL: the graphs were already emitted"),
+
+        Emit::Metrics => {
+            let after = llvm_ctx.stats();
+            let metrics =
+                CompileMetrics::new(src_code, program, &opts.passes, &report.before, &after);
+            print!("{}", metrics.to_json());
+        }
+
+        // Write out an object next to the executable, link it, then clean up
+        Emit::Exe => {
+            let object = cli.output.with_extension("o");
+            llvm_ctx.compile(&object, FileType::Object)?;
+
+            // An instrumented program needs the profile runtime linked in,
+            // a sanitized one the sanitizer's
+            let mut args = vec![];
+
+            if let Some(Pgo::Instrument) = opts.pgo {
+                args.push("-fprofile-instr-generate");
+            }
+
+            if let Some(sanitizer) = opts.sanitize {
+                args.push(sanitizer.link_arg());
+            }
+
+            let linked = link_executable(&cli.linker, &object, &cli.output, &args);
+            let _ = std::fs::remove_file(&object);
+            linked?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::lexer::Ops;
+    use clap::Parser;
+
+    #[test]
+    fn compiling_to_string() {
+        let ir = compile_to_string("def f(x) x*2;", CompileOptions::default()).unwrap();
+        assert!(ir.contains("define double @f(double %x)"));
+
+        assert_eq!(
+            compile_to_string("def f(x) g(x);", CompileOptions::default()),
+            Err(CompileError::Sema(SemaError::UndefinedFunction("g")))
+        );
+
+        assert!(matches!(
+            compile_to_string("def f(x) x +;", CompileOptions::default()),
+            Err(CompileError::Parser(_))
+        ));
+    }
+
+    #[test]
+    fn compiling_with_ast_dce() {
+        let opts = CompileOptions {
+            ast_dce: true,
+            passes: String::new(),
+            ..Default::default()
+        };
+
+        // Without any passes, the unused var would still get an alloca
+        let ir = compile_to_string("def f(x) var unused = 2 in x;", opts).unwrap();
+        assert!(!ir.contains("%unused"));
+    }
+
+    #[test]
+    fn compiling_with_desugared_for() {
+        let ctx = Context::create();
+        let opts = CompileOptions {
+            desugar_for: true,
+            passes: String::new(),
+            ..Default::default()
+        };
+
+        // The body runs for i = n too, end is checked before i is stepped
+        let src = "def sum(n) var total = 0 in (for i = 1, i < n in total = total + i) + total;
+                   sum(10);";
+        let llvm_ctx = compile_module(&ctx, src, &opts).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(55.0));
+
+        // Nothing left of the for loop, only the while it became
+        let ir = llvm_ctx.module_to_string();
+        assert!(ir.contains("whilecond:") && !ir.contains("afterloop:"));
+    }
+
+    #[test]
+    fn specializing_constant_calls() {
+        let src = "def f(x) x * x; f(3); def g(n) if n < 1 then 0 else g(n - 1); g(2);";
+
+        let opts = CompileOptions {
+            specialize: true,
+            wrap_main: true,
+            passes: "mem2reg,instcombine".to_string(),
+            ..Default::default()
+        };
+
+        let ir = compile_to_string(src, opts.clone()).unwrap();
+
+        // A copy of f for x = 3, folded right down to the answer
+        let spec = ir
+            .split("define private double @\"f.spec(3)\"()")
+            .nth(1)
+            .expect("f was never specialized");
+        let spec = &spec[..spec.find('}').unwrap()];
+        assert!(spec.contains("ret double 9.000000e+00"), "{spec}");
+
+        assert!(ir.contains("call double @\"f.spec(3)\"()"));
+        assert!(!ir.contains("call double @f("));
+
+        // g's own call to itself isn't a constant one, so one copy of g is all
+        assert!(ir.contains("@\"g.spec(2)\""));
+        assert_eq!(ir.matches("define private").count(), 2);
+
+        // Off by default
+        let opts = CompileOptions {
+            specialize: false,
+            ..opts
+        };
+        assert!(!compile_to_string(src, opts.clone())
+            .unwrap()
+            .contains(".spec("));
+
+        // A call through a pointer is never specialized, not even when a
+        // function happens to share the name of the pointer it's cast to
+        let src = "def fnptr(x) x; def h(x) x + 1; def k(f) f(3); k(h);";
+        let opts = CompileOptions {
+            specialize: true,
+            passes: String::new(),
+            ..opts
+        };
+
+        let ir = compile_to_string(src, opts.clone()).unwrap();
+        assert!(ir.contains("%fnptr = inttoptr"), "{ir}");
+        assert!(!ir.contains("fnptr.spec("), "{ir}");
+
+        // Asking for debug info too is an error, not a quiet no-op
+        let opts = CompileOptions {
+            debug_info: true,
+            ..opts
+        };
+        assert_eq!(
+            compile_to_string(src, opts),
+            Err(CompileError::Backend(
+                BackendError::SpecializedWithDebugInfo
+            ))
+        );
+    }
+
+    #[test]
+    fn denying_warnings() {
+        let src = "def f(x) var unused = 2 in x;";
+
+        assert!(compile_to_string(src, CompileOptions::default()).is_ok());
+
+        let opts = CompileOptions {
+            deny_warnings: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            compile_to_string(src, opts),
+            Err(CompileError::DeniedWarning(Diagnostic {
+                severity: Severity::Warning,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn emitting_debug_info() {
+        let opts = CompileOptions {
+            debug_info: true,
+            ..Default::default()
+        };
+
+        let ir = compile_to_string("def g(x) x;\ndef f(x)\n  g(x) * 2;\nf(1);", opts).unwrap();
+
+        assert!(ir.contains("!llvm.dbg.cu"));
+        assert!(ir.contains("!DISubprogram(name: \"f\""));
+        assert!(ir.contains("!DILocation(line: 3, column: 3"));
+    }
+
+    #[test]
+    fn stripping_debug_info() {
+        let src = "def g(x) x;\ndef f(x)\n  g(x) * 2;";
+
+        let opts = CompileOptions {
+            debug_info: true,
+            strip: true,
+            ..Default::default()
+        };
+
+        let ir = compile_to_string(src, opts.clone()).unwrap();
+        assert!(!ir.contains("!llvm.dbg.cu"));
+        assert!(!ir.contains("!dbg"));
+
+        // Nothing to strip without debug info, still fine
+        let opts = CompileOptions {
+            debug_info: false,
+            ..opts
+        };
+
+        assert!(compile_to_string(src, opts).is_ok());
+    }
+
+    #[test]
+    fn choosing_entry_point() {
+        let src = "def bar(x) x; def foo(x) x + 1;";
+
+        let opts = CompileOptions {
+            entry: Some("foo".to_string()),
+            passes: String::new(),
+            ..Default::default()
+        };
+
+        let ir = compile_to_string(src, opts.clone()).unwrap();
+        assert!(ir.contains("define double @foo("));
+        assert!(ir.contains("define internal double @bar("));
+
+        let opts = CompileOptions {
+            entry: Some("baz".to_string()),
+            ..opts
+        };
+
+        assert_eq!(
+            compile_to_string(src, opts),
+            Err(CompileError::Backend(BackendError::UnknownEntryPoint(
+                "baz".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn collecting_diagnostics() {
+        let ctx = Context::create();
+        let opts = CompileOptions::default();
+
+        // The error in f is recovered from, the call to g still checked
+        let src = "def f(x) x * ;\ng(1);";
+        let (module, diagnostics) = compile_diagnostics(&ctx, src, &opts);
+
+        assert!(module.is_none());
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|diag| (diag.severity, diag.span.line_col(src)))
+                .collect::<Vec<_>>(),
+            vec![(Severity::Error, (1, 14)), (Severity::Error, (2, 1))]
+        );
+        assert!(diagnostics[0]
+            .message
+            .contains("Unexpected token: Semicolon"));
+        assert!(diagnostics[1]
+            .message
+            .contains("Call to undefined function g"));
+
+        // Warnings alone still get a module
+        let (module, diagnostics) = compile_diagnostics(&ctx, "def f(x) var y = 1 in x;", &opts);
+
+        assert!(module.unwrap().get_function("f").is_some());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn reporting_json_diagnostics() {
+        let ctx = Context::create();
+        let src = "def f(x)\n  x * ;";
+
+        let (_, diagnostics) = compile_diagnostics(&ctx, src, &CompileOptions::default());
+
+        let mut out = vec![];
+        report_diagnostics(src, &diagnostics, ErrorFormat::Json, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().count(), 1);
+
+        let json: serde_json::Value = serde_json::from_str(out.trim_end()).unwrap();
+
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["code"], "E0001");
+        assert_eq!(json["message"], "Unexpected token: Semicolon");
+        assert_eq!(
+            (json["line"].as_u64(), json["col"].as_u64()),
+            (Some(2), Some(7))
+        );
+        assert_eq!(json["span"]["start"], 15);
+        assert_eq!(json["span"]["end"], 16);
+
+        let mut out = vec![];
+        report_diagnostics(src, &diagnostics, ErrorFormat::Human, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2:7: error[E0001]: Unexpected token: Semicolon\n"
+        );
+    }
+
+    #[test]
+    fn running_no_passes() {
+        let cli = Cli::parse_from(["kaleidrs", "--passes", ""]);
+        assert_eq!(cli.passes, "");
+        assert_eq!(Cli::parse_from(["kaleidrs"]).passes, DEFAULT_PASSES);
+
+        Target::initialize_native(&InitializationConfig::default())
+            .expect("Failed to initialize native machine target!");
+
+        // The IR as it comes out of codegen, the var's alloca still there
+        let src = "def f(x) var y = x in y * 2;";
+        let ctx = Context::create();
+        let llvm_ctx = LLVMContext::new(&ctx, OptLevel::O2);
+
+        for item in catch_parse(src).unwrap().iter() {
+            item.codegen(&llvm_ctx).unwrap();
+        }
+
+        let mut printed = vec![];
+        llvm_ctx
+            .run_passes_printing(&cli.passes, &mut printed)
+            .unwrap();
+        assert!(printed.is_empty());
+
+        let opts = CompileOptions {
+            passes: cli.passes,
+            ..Default::default()
+        };
+
+        let ir = compile_to_string(src, opts).unwrap();
+        assert!(ir.contains("alloca double"));
+        assert_eq!(ir, llvm_ctx.module_to_string());
+    }
+
+    #[test]
+    fn linking_executable() {
+        // Only runs where there's a C compiler around to link with
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("cc not found, skipping");
+            return;
+        }
+
+        Target::initialize_native(&InitializationConfig::default())
+            .expect("Failed to initialize native machine target!");
+
+        let dir = std::env::temp_dir();
+        let exe = dir.join(format!("kaleidrs_exe_test_{}", std::process::id()));
+
+        let cli = Cli::parse_from([
+            "kaleidrs".as_ref(),
+            dir.join("main.kal").as_os_str(),
+            "--emit=exe".as_ref(),
+            "-o".as_ref(),
+            exe.as_os_str(),
+        ]);
+
+        compile_src("def main() 0;", &[], &cli).unwrap();
+
+        let status = Command::new(&exe).status().unwrap();
+        let _ = std::fs::remove_file(&exe);
+
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    fn caching_modules() {
+        let dir = std::env::temp_dir().join(format!("kaleidrs_cache_{}", std::process::id()));
+        let mut cache = ModuleCache::new(&dir);
+
+        let src = "def sq(x) x * x;";
+        let first = cache
+            .compile_cached(src, &CompileOptions::default())
+            .unwrap();
+        let second = cache
+            .compile_cached(src, &CompileOptions::default())
+            .unwrap();
+
+        assert_eq!(cache.recompilations(), 1);
+        assert!(first.contains("define double @sq") && second.contains("define double @sq"));
+
+        // Changing an option, or the source, misses
+        let opts = CompileOptions {
+            opt_level: OptLevel::O0,
+            ..Default::default()
+        };
+
+        cache.compile_cached(src, &opts).unwrap();
+        cache
+            .compile_cached("def sq(x) x * x * 1;", &CompileOptions::default())
+            .unwrap();
+        assert_eq!(cache.recompilations(), 3);
+
+        // Errors aren't cached, each try compiles again
+        assert!(cache
+            .compile_cached("def (", &CompileOptions::default())
+            .is_err());
+        assert!(cache
+            .compile_cached("def (", &CompileOptions::default())
+            .is_err());
+        assert_eq!(cache.recompilations(), 5);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn wrapping_main() {
+        let opts = CompileOptions {
+            wrap_main: true,
+            ..Default::default()
+        };
+
+        let src = "extern putchard(x); putchard(72); def twice(x) x * 2; putchard(twice(2)); putchard(1);";
+        let ir = compile_to_string(src, opts.clone()).unwrap();
+
+        let main = ir.split("define double @main()").nth(1).unwrap();
+        let main = main.split("\n}").next().unwrap();
+
+        assert_eq!(main.matches("call double @putchard").count(), 3);
+        assert!(ir.contains("define double @twice(double %x)"));
+        assert!(!ir.contains("__anonymous_expr"));
+
+        // Not when there's already a main of the program's own
+        assert_eq!(
+            compile_to_string("def main() 1; 2;", opts),
+            Err(CompileError::Backend(BackendError::MultipleFunctionDefs(
+                "main".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn ordering_codegen() {
+        let ctx = Context::create();
+
+        // Called before it's defined, then two calling each other
+        let src = "def a() b(); def b() 1; a();";
+        let llvm_ctx = compile_module(&ctx, src, &CompileOptions::default()).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(1.0));
+
+        let src = "def even(n) if n == 0 then 1 else odd(n - 1);
+                   def odd(n) if n == 0 then 0 else even(n - 1);
+                   extern even(n);
+                   even(10);";
+        let llvm_ctx = compile_module(&ctx, src, &CompileOptions::default()).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(1.0));
+
+        // Declared with decl first, the definition stays internal
+        let ir = compile_to_string("decl g(x); def g(y) y; g(1);", CompileOptions::default());
+        assert!(ir.unwrap().contains("define internal double @g(double %y)"));
+
+        assert_eq!(
+            compile_to_string("decl g(x); g(1);", CompileOptions::default()),
+            Err(CompileError::DeclNotDefined("g".to_string()))
+        );
+    }
+
+    #[test]
+    fn folding_consts() {
+        let src = "const PI = 3.1415; const TAU = 2 * PI; def turns(x) x * TAU;";
+        let ir = compile_to_string(src, CompileOptions::default()).unwrap();
+
+        // Nothing but the number itself, no global for it, nor a load
+        assert!(ir.contains("fmul double %x, 6.283"));
+        assert!(!ir.contains("TAU") && !ir.contains("@PI"));
+        assert!(!ir.contains("load"));
+
+        assert_eq!(
+            compile_to_string("const N = 1; const N = 2;", CompileOptions::default()),
+            Err(CompileError::Backend(BackendError::ConstRedefined("N")))
+        );
+
+        assert_eq!(
+            compile_to_string("def f(x) x; const N = f(1);", CompileOptions::default()),
+            Err(CompileError::Backend(BackendError::NotAConstant("N")))
+        );
+
+        let ir = compile_to_string("const N = 2 ^ 3; def f() N;", CompileOptions::default());
+        assert!(ir.unwrap().contains("ret double 8.0"));
+    }
+
+    #[test]
+    fn calling_without_args() {
+        let ctx = Context::create();
+
+        let src = "def f() 42; f();";
+        let llvm_ctx = compile_module(&ctx, src, &CompileOptions::default()).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(42.0));
+
+        assert_eq!(llvm_ctx.get_function("f").unwrap().count_params(), 0);
+
+        // Giving one anyway is caught, not quietly dropped
+        assert_eq!(
+            compile_to_string("def f() 42; f(1);", CompileOptions::default()),
+            Err(CompileError::Backend(BackendError::IncorrectNumberOfArgs {
+                func_name: "f",
+                param_cnt: 0
+            }))
+        );
+    }
+
+    #[test]
+    fn overloading_negation() {
+        let ctx = Context::create();
+
+        // Without an overload the sign is folded into the constant
+        let ir = compile_to_string("def f() -3;", CompileOptions::default()).unwrap();
+        assert!(ir.contains("ret double -3.0"));
+
+        // With one, -3 is a call to it like any other operand
+        let src = "def unary - (v) 42; def f() -3; f();";
+        let llvm_ctx = compile_module(&ctx, src, &CompileOptions::default()).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(42.0));
+    }
+
+    #[test]
+    fn powers_match_the_interpreter() {
+        use crate::backend::interpreter::Interpreter;
+
+        let ctx = Context::create();
+        let opts = CompileOptions::default();
+
+        // Multiplied out or not, with a constant exponent or not, the JIT and
+        // the interpreter round the same, to the last bit
+        for n in 0..=DEFAULT_MAX_POW_UNROLL + 1 {
+            for base in [1.1, 0.3, -2.7] {
+                let exprs = [
+                    format!("{base} ^ {n}"),
+                    format!("power({base}, {n})"),
+                    "c".to_string(),
+                    format!("{base} ^ {n}.5"),
+                ];
+
+                for expr in exprs {
+                    let src = format!("def power(x y) x ^ y; const c = {base} ^ {n}; {expr};");
+
+                    let program = catch_parse(&src).unwrap();
+                    let interpreted = Interpreter::new().run(&program).unwrap()[0];
+
+                    let llvm_ctx = compile_module(&ctx, &src, &opts).unwrap();
+                    let jitted = unsafe { llvm_ctx.jit_eval() }.unwrap();
+
+                    assert!(
+                        jitted.to_bits() == interpreted.to_bits()
+                            || (jitted.is_nan() && interpreted.is_nan()),
+                        "{expr}: {jitted} != {interpreted}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn indexing_arrays() {
+        let ctx = Context::create();
+        let opts = CompileOptions::default();
+
+        let src = "def pick(i) [10, 20, i * 100][i]; pick(1) + pick(2);";
+        let llvm_ctx = compile_module(&ctx, src, &opts).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(220.0));
+
+        // Elements of an array are doubles, so are variables, neither can be
+        // indexed any further
+        assert_eq!(
+            compile_to_string("[[1]][0];", opts.clone()),
+            Err(CompileError::Backend(BackendError::ArrayNotAValue))
+        );
+        assert_eq!(
+            compile_to_string("[1, 2][0][0];", opts.clone()),
+            Err(CompileError::Backend(BackendError::NotIndexable))
+        );
+        assert_eq!(
+            compile_to_string("def f(a) a[0];", opts),
+            Err(CompileError::Backend(BackendError::NotIndexable))
+        );
+    }
+
+    #[test]
+    fn reusing_context() {
+        let ctx = Context::create();
+        let opts = CompileOptions::default();
+
+        let first = compile_module(&ctx, "def f(x) x + 1;", &opts).unwrap();
+        let second = compile_module(&ctx, "def g(x) x * 2; g(3);", &opts).unwrap();
+
+        // Nothing of one file ends up in the other's module
+        let first = first.into_module();
+        assert!(first.get_function("f").is_some());
+        assert!(first.get_function("g").is_none());
+
+        assert_eq!(unsafe { second.jit_eval() }, Ok(6.0));
+        assert!(second.into_module().get_function("f").is_none());
+
+        // Both still there once the other is done with
+        assert!(first.verify().is_ok());
+    }
+
+    #[test]
+    fn linking_files() {
+        let ctx = Context::create();
+        let opts = CompileOptions {
+            passes: "inline,instcombine".to_string(),
+            ..Default::default()
+        };
+
+        let helper = "def helper(x) x * 2;";
+        let caller = "extern helper(x); def caller() helper(21); caller();";
+
+        let llvm_ctx = compile_modules(&ctx, &[helper, caller], &opts).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(42.0));
+
+        // One module, so helper was inlined into caller
+        assert!(!llvm_ctx.module_to_string().contains("call double @helper"));
+
+        assert_eq!(
+            compile_modules(&ctx, &[helper, helper], &opts).err(),
+            Some(CompileError::Backend(BackendError::SymbolDefinedTwice(
+                "helper".to_string()
+            )))
+        );
+
+        // Top level code in each file, run one file after the other
+        let files = ["def a() 1; a();", "def b() 2; b();"];
+
+        let llvm_ctx = compile_modules(&ctx, &files, &opts).unwrap();
+        assert_eq!(unsafe { llvm_ctx.jit_eval() }, Ok(2.0));
+
+        let opts = CompileOptions {
+            wrap_main: true,
+            passes: String::new(),
+            ..opts
+        };
+
+        let ir = compile_modules(&ctx, &files, &opts)
+            .unwrap()
+            .module_to_string();
+        let main = ir.split("define double @main()").nth(1).unwrap();
+        let main = main.split("\n}").next().unwrap();
+
+        let first = main.find("call double @main.0()").unwrap();
+        let second = main.find("call double @main.1()").unwrap();
+        assert!(first < second, "{main}");
+
+        assert!(ir.contains("define internal double @main.0()"));
+    }
+
+    #[test]
+    fn measuring_compiles() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let ctx = Context::create();
+        let opts = CompileOptions::default();
+
+        let src = "extern sin(x); def f(x) sin(x) + 2 * 3; f(1);";
+        let program = check_source(src, &opts).unwrap();
+        let llvm_ctx = generate_module(&ctx, src, &program, &opts, Path::new("<string>")).unwrap();
+
+        let before = llvm_ctx.stats();
+        llvm_ctx.run_passes(&opts.passes);
+        let after = llvm_ctx.stats();
+
+        let metrics = CompileMetrics::new(src, &program, &opts.passes, &before, &after);
+        assert_eq!(metrics.tokens, 25);
+        assert_eq!(metrics.nodes["CallExpr"], 2);
+        assert_eq!((metrics.functions, metrics.externs), (1, 1));
+        assert!(metrics.instructions_after < metrics.instructions_before);
+        assert_eq!(metrics.passes.len(), 5);
+
+        let json = metrics.to_json();
+        assert!(json.starts_with("{\"tokens\": 25, \"nodes\": {\"BinaryExpr\": 2,"));
+        assert!(json.contains("\"functions\": 1, \"externs\": 1,"));
+        assert!(json.contains("\"passes\": [\"instcombine\", \"reassociate\","));
+
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["functions"], 1);
+    }
+
+    #[test]
+    fn instrumenting_for_pgo() {
+        let opts = CompileOptions {
+            pgo: Some(Pgo::Instrument),
+            ..Default::default()
+        };
+
+        let ir = compile_to_string("def f(x) if x < 1 then 0 else f(x - 1);", opts).unwrap();
+        assert!(ir.contains("__llvm_profile"));
+        assert!(ir.contains("@__profc_f"));
+
+        let ir = compile_to_string("def f(x) x;", CompileOptions::default()).unwrap();
+        assert!(!ir.contains("__llvm_profile"));
+    }
+
+    #[test]
+    fn using_pgo_profiles() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/backend/testdata");
+        let src = "def f(x) if x < 1 then 0 else 1;";
+
+        let using = |profile: PathBuf| CompileOptions {
+            pgo: Some(Pgo::Use(profile)),
+            passes: String::new(),
+            ..Default::default()
+        };
+
+        // One after another in the same process, each with its own counts
+        let ir = compile_to_string(src, using(testdata.join("hot.profdata"))).unwrap();
+        assert!(ir.contains("!{!\"branch_weights\", i32 90, i32 10}"));
+
+        let ir = compile_to_string(src, using(testdata.join("cold.profdata"))).unwrap();
+        assert!(ir.contains("!{!\"branch_weights\", i32 10, i32 90}"));
+
+        // Profiles LLVM can't read are errors, rather than it exiting
+        let missing = compile_to_string(src, using(testdata.join("missing.profdata")));
+        assert!(matches!(
+            missing,
+            Err(CompileError::Backend(BackendError::BadProfile(_)))
+        ));
+
+        let not_indexed = compile_to_string(src, using(testdata.join("hot.proftext")));
+        assert!(matches!(
+            not_indexed,
+            Err(CompileError::Backend(BackendError::BadProfile(_)))
+        ));
+    }
+
+    #[test]
+    fn sanitizing_addresses() {
+        let opts = CompileOptions {
+            sanitize: Some(Sanitizer::Address),
+            ..Default::default()
+        };
+
+        let src = "def f(x) var y = x in y * 2;";
+        let ir = compile_to_string(src, opts).unwrap();
+        assert!(ir.contains("__asan"));
+        assert!(ir.contains("sanitize_address"));
+
+        let ir = compile_to_string(src, CompileOptions::default()).unwrap();
+        assert!(!ir.contains("__asan"));
+    }
+
+    #[test]
+    fn catching_parser_panics() {
+        assert!(catch_parse("def f(x) x * 2; f(1);").is_ok());
+
+        // An operator nothing defined used to panic looking up its
+        // precedence, it's an ordinary parser error now
+        assert!(matches!(
+            catch_parse("def f(x) x & 1;"),
+            Err(CompileError::Parser(ParserError::UndefinedBinaryOperator(
+                Ops::And
+            )))
+        ));
+    }
+}
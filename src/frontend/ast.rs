@@ -1,3 +1,6 @@
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::fmt;
+
 use crate::frontend::lexer::Ops;
 
 // NOTE TO LEARNERS/DEVELOPERS:
@@ -17,9 +20,10 @@ use crate::frontend::lexer::Ops;
 //
 // In the end, the tree is still very much the same, just enum dispatched.
 // I use similar names to the C++ classes for the variants.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum ASTExpr<'src> {
     NumberExpr(f64),
+    StringExpr(&'src str), // Only meaningful as the argument of a builtin like len
     VariableExpr(&'src str),
     UnaryExpr {
         op: Ops,
@@ -34,9 +38,20 @@ pub enum ASTExpr<'src> {
         callee: &'src str,
         args: Vec<Box<ASTExpr<'src>>>,
     },
+    IndirectCallExpr {
+        callee: Box<ASTExpr<'src>>, // Whatever gives the function pointer, like "fns[0]"
+        args: Vec<Box<ASTExpr<'src>>>,
+    },
+    ArrayExpr(Vec<Box<ASTExpr<'src>>>), // [1, 2, 3], a fixed size array of doubles
+    ParenExpr(Box<ASTExpr<'src>>),      // Only kept when parsing with preserve_parens
+    IndexExpr {
+        base: Box<ASTExpr<'src>>,
+        index: Box<ASTExpr<'src>>,
+    },
     IfExpr {
         cond: Box<ASTExpr<'src>>,
         then_branch: Box<ASTExpr<'src>>,
+        elifs: Vec<(Box<ASTExpr<'src>>, Box<ASTExpr<'src>>)>, // condition and branch of each elif, in order
         else_branch: Box<ASTExpr<'src>>,
     },
     ForLoopExpr {
@@ -46,18 +61,295 @@ pub enum ASTExpr<'src> {
         step: Box<ASTExpr<'src>>,
         body: Box<ASTExpr<'src>>,
     },
+    WhileExpr {
+        cond: Box<ASTExpr<'src>>,
+        body: Box<ASTExpr<'src>>,
+    },
     VarExpr {
         var_names: Vec<(&'src str, Option<Box<ASTExpr<'src>>>)>, // var_names is a combination of variable name and (possible) initializer
         body: Box<ASTExpr<'src>>,
     },
 }
 
+// How numbers in the tree are printed by Debug. The derived Debug would
+// write them like Rust does, "3.0", which gets noisy in a dump of a big
+// tree, Clean leaves the ".0" off of whole numbers, handier for teaching.
+// Either way it's the shortest text that still reads back as the same f64.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumberStyle {
+    #[default]
+    Exact,
+    Clean,
+}
+
+#[derive(Clone, Copy)]
+pub struct FormattedNumber(pub f64, pub NumberStyle);
+
+impl fmt::Display for FormattedNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let FormattedNumber(num, style) = *self;
+
+        match style {
+            NumberStyle::Clean if num.is_finite() && num == (num as i64) as f64 => {
+                write!(f, "{}", num as i64)
+            }
+
+            _ => write!(f, "{num:?}"),
+        }
+    }
+}
+
+impl fmt::Debug for FormattedNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// A tree to be printed with its numbers in a given style, all the way down,
+// e.g. println!("{:#?}", expr.styled(NumberStyle::Clean))
+#[derive(Clone, Copy)]
+pub struct StyledExpr<'a, 'src> {
+    expr: &'a ASTExpr<'src>,
+    style: NumberStyle,
+}
+
+impl<'src> ASTExpr<'src> {
+    // The expressions directly under this one, in the order they're written,
+    // for walking the tree without matching on every variant, e.g. counting
+    // the nodes of it:
+    //
+    // fn count(expr: &ASTExpr) -> usize {
+    //     1 + expr.children().into_iter().map(count).sum::<usize>()
+    // }
+    //
+    // Names, like a for loop's variable, are no expressions, so not included
+    pub fn children(&self) -> Vec<&ASTExpr<'src>> {
+        use ASTExpr::*;
+
+        match self {
+            NumberExpr(_) | StringExpr(_) | VariableExpr(_) => vec![],
+
+            UnaryExpr { operand, .. } | ParenExpr(operand) => vec![operand],
+
+            BinaryExpr { left, right, .. } => vec![left, right],
+
+            CallExpr { args, .. } => args.iter().map(|arg| &**arg).collect(),
+
+            IndirectCallExpr { callee, args } => core::iter::once(&**callee)
+                .chain(args.iter().map(|arg| &**arg))
+                .collect(),
+
+            ArrayExpr(elems) => elems.iter().map(|elem| &**elem).collect(),
+
+            IndexExpr { base, index } => vec![base, index],
+
+            IfExpr {
+                cond,
+                then_branch,
+                elifs,
+                else_branch,
+            } => {
+                let mut children = vec![&**cond, &**then_branch];
+
+                for (cond, branch) in elifs.iter() {
+                    children.push(cond);
+                    children.push(branch);
+                }
+
+                children.push(else_branch);
+                children
+            }
+
+            ForLoopExpr {
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => vec![start, end, step, body],
+
+            WhileExpr { cond, body } => vec![cond, body],
+
+            VarExpr { var_names, body } => var_names
+                .iter()
+                .filter_map(|(_, init)| init.as_deref())
+                .chain(core::iter::once(&**body))
+                .collect(),
+        }
+    }
+
+    // The name of the variant, for counting nodes by kind and the like
+    pub fn kind(&self) -> &'static str {
+        use ASTExpr::*;
+
+        match self {
+            NumberExpr(_) => "NumberExpr",
+            StringExpr(_) => "StringExpr",
+            VariableExpr(_) => "VariableExpr",
+            UnaryExpr { .. } => "UnaryExpr",
+            BinaryExpr { .. } => "BinaryExpr",
+            CallExpr { .. } => "CallExpr",
+            IndirectCallExpr { .. } => "IndirectCallExpr",
+            ArrayExpr(_) => "ArrayExpr",
+            ParenExpr(_) => "ParenExpr",
+            IndexExpr { .. } => "IndexExpr",
+            IfExpr { .. } => "IfExpr",
+            ForLoopExpr { .. } => "ForLoopExpr",
+            WhileExpr { .. } => "WhileExpr",
+            VarExpr { .. } => "VarExpr",
+        }
+    }
+
+    pub fn styled(&self, style: NumberStyle) -> StyledExpr<'_, 'src> {
+        StyledExpr { expr: self, style }
+    }
+}
+
+// Prints the same as a derived Debug would, but for the numbers
+impl fmt::Debug for ASTExpr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.styled(NumberStyle::Exact).fmt(f)
+    }
+}
+
+impl<'a, 'src> StyledExpr<'a, 'src> {
+    fn child(&self, expr: &'a ASTExpr<'src>) -> Self {
+        expr.styled(self.style)
+    }
+}
+
+impl fmt::Debug for StyledExpr<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ASTExpr::*;
+
+        let style = self.style;
+
+        match self.expr {
+            NumberExpr(num) => f
+                .debug_tuple("NumberExpr")
+                .field(&FormattedNumber(*num, style))
+                .finish(),
+
+            StringExpr(text) => f.debug_tuple("StringExpr").field(text).finish(),
+
+            VariableExpr(name) => f.debug_tuple("VariableExpr").field(name).finish(),
+
+            ParenExpr(inner) => f
+                .debug_tuple("ParenExpr")
+                .field(&self.child(inner))
+                .finish(),
+
+            UnaryExpr { op, operand } => f
+                .debug_struct("UnaryExpr")
+                .field("op", op)
+                .field("operand", &self.child(operand))
+                .finish(),
+
+            BinaryExpr { op, left, right } => f
+                .debug_struct("BinaryExpr")
+                .field("op", op)
+                .field("left", &self.child(left))
+                .field("right", &self.child(right))
+                .finish(),
+
+            CallExpr { callee, args } => f
+                .debug_struct("CallExpr")
+                .field("callee", callee)
+                .field(
+                    "args",
+                    &args.iter().map(|arg| self.child(arg)).collect::<Vec<_>>(),
+                )
+                .finish(),
+
+            IndirectCallExpr { callee, args } => f
+                .debug_struct("IndirectCallExpr")
+                .field("callee", &self.child(callee))
+                .field(
+                    "args",
+                    &args.iter().map(|arg| self.child(arg)).collect::<Vec<_>>(),
+                )
+                .finish(),
+
+            ArrayExpr(elems) => f
+                .debug_tuple("ArrayExpr")
+                .field(
+                    &elems
+                        .iter()
+                        .map(|elem| self.child(elem))
+                        .collect::<Vec<_>>(),
+                )
+                .finish(),
+
+            IndexExpr { base, index } => f
+                .debug_struct("IndexExpr")
+                .field("base", &self.child(base))
+                .field("index", &self.child(index))
+                .finish(),
+
+            IfExpr {
+                cond,
+                then_branch,
+                elifs,
+                else_branch,
+            } => f
+                .debug_struct("IfExpr")
+                .field("cond", &self.child(cond))
+                .field("then_branch", &self.child(then_branch))
+                .field(
+                    "elifs",
+                    &elifs
+                        .iter()
+                        .map(|(cond, branch)| (self.child(cond), self.child(branch)))
+                        .collect::<Vec<_>>(),
+                )
+                .field("else_branch", &self.child(else_branch))
+                .finish(),
+
+            ForLoopExpr {
+                varname,
+                start,
+                end,
+                step,
+                body,
+            } => f
+                .debug_struct("ForLoopExpr")
+                .field("varname", varname)
+                .field("start", &self.child(start))
+                .field("end", &self.child(end))
+                .field("step", &self.child(step))
+                .field("body", &self.child(body))
+                .finish(),
+
+            WhileExpr { cond, body } => f
+                .debug_struct("WhileExpr")
+                .field("cond", &self.child(cond))
+                .field("body", &self.child(body))
+                .finish(),
+
+            VarExpr { var_names, body } => f
+                .debug_struct("VarExpr")
+                .field(
+                    "var_names",
+                    &var_names
+                        .iter()
+                        .map(|(name, init)| (name, init.as_ref().map(|init| self.child(init))))
+                        .collect::<Vec<_>>(),
+                )
+                .field("body", &self.child(body))
+                .finish(),
+        }
+    }
+}
+
 // Prototype, mimics that off the tutorial C++ class
 #[derive(Debug, PartialEq)]
 pub enum Prototype<'src> {
     FunctionProto {
         name: &'src str,
         args: Vec<&'src str>,
+        variadic: bool, // extern printf(fmt ...), takes any number of args after fmt
+        void: bool,     // extern void print(x), called for what it does, it gives no value
+        decl: bool,     // decl f(x), defined further down the module rather than outside it
     },
     OverloadedUnaryOpProto {
         operator: Ops,
@@ -83,6 +375,18 @@ impl<'src> Prototype<'src> {
         }
     }
 
+    pub fn is_variadic(&self) -> bool {
+        matches!(self, FunctionProto { variadic: true, .. })
+    }
+
+    pub fn is_void(&self) -> bool {
+        matches!(self, FunctionProto { void: true, .. })
+    }
+
+    pub fn is_decl(&self) -> bool {
+        matches!(self, FunctionProto { decl: true, .. })
+    }
+
     pub fn get_num_params(&self) -> usize {
         match self {
             FunctionProto { args, .. } => args.len(),
@@ -92,6 +396,68 @@ impl<'src> Prototype<'src> {
             OverloadedBinaryOpProto { .. } => 2,
         }
     }
+
+    pub fn get_params(&self) -> Vec<&'src str> {
+        match self {
+            FunctionProto { args, .. } => args.clone(),
+
+            OverloadedUnaryOpProto { arg, .. } => vec![*arg],
+
+            OverloadedBinaryOpProto { args, .. } => vec![args.0, args.1],
+        }
+    }
+}
+
+// Attributes that can be given to a definition, e.g. "def [inline] f(x) ...",
+// these give control over how LLVM optimizes a particular function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FnAttr {
+    Inline,
+    NoInline,
+    Cold,
+    Hot,
+}
+
+impl FnAttr {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FnAttr::Inline => "inline",
+            FnAttr::NoInline => "noinline",
+            FnAttr::Cold => "cold",
+            FnAttr::Hot => "hot",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "inline" => Some(FnAttr::Inline),
+            "noinline" => Some(FnAttr::NoInline),
+            "cold" => Some(FnAttr::Cold),
+            "hot" => Some(FnAttr::Hot),
+            _ => None,
+        }
+    }
+
+    // Pairs that ask LLVM for opposite things, like inline and noinline
+    pub fn conflicts_with(&self, other: FnAttr) -> bool {
+        use FnAttr::*;
+
+        matches!(
+            (self, other),
+            (Inline, NoInline) | (NoInline, Inline) | (Hot, Cold) | (Cold, Hot)
+        )
+    }
+
+    // Name of the matching LLVM function attribute, note that "inline"
+    // is a request LLVM must honor, so it maps to alwaysinline
+    pub fn llvm_name(&self) -> &'static str {
+        match self {
+            FnAttr::Inline => "alwaysinline",
+            FnAttr::NoInline => "noinline",
+            FnAttr::Cold => "cold",
+            FnAttr::Hot => "hot",
+        }
+    }
 }
 
 // Function, mimics that off the tutorial C++ class
@@ -99,4 +465,143 @@ impl<'src> Prototype<'src> {
 pub struct Function<'src> {
     pub proto: Box<Prototype<'src>>,
     pub body: Box<ASTExpr<'src>>,
+    pub attrs: Vec<FnAttr>,
+}
+
+impl<'src> Function<'src> {
+    // Just the body, a prototype has no expressions in it
+    pub fn children(&self) -> Vec<&ASTExpr<'src>> {
+        vec![&self.body]
+    }
+}
+
+// A named constant, "const TAU = 6.283;", no global of it is ever
+// generated, every use of the name is replaced by the number itself
+#[derive(Debug, PartialEq)]
+pub struct ConstDef<'src> {
+    pub name: &'src str,
+    pub value: Box<ASTExpr<'src>>,
+}
+
+// The kinds of items that can appear at the top level of a program, the
+// first three mirror the HandleDefinition, HandleExtern, and
+// HandleTopLevelExpression functions of the tutorial's main driver loop.
+#[derive(Debug, PartialEq)]
+pub enum TopLevel<'src> {
+    Definition(Box<Function<'src>>),
+    Extern(Box<Prototype<'src>>), // decls too, see Prototype::is_decl
+    Expression(Box<Function<'src>>),
+    Const(Box<ConstDef<'src>>),
+}
+
+impl<'src> ConstDef<'src> {
+    pub fn children(&self) -> Vec<&ASTExpr<'src>> {
+        vec![&self.value]
+    }
+}
+
+impl<'src> TopLevel<'src> {
+    // An extern has no body, so nothing
+    pub fn children(&self) -> Vec<&ASTExpr<'src>> {
+        match self {
+            TopLevel::Definition(func) | TopLevel::Expression(func) => func.children(),
+            TopLevel::Extern(_) => vec![],
+            TopLevel::Const(def) => def.children(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn formatting_numbers() {
+        let three = ASTExpr::NumberExpr(3.0);
+
+        assert_eq!(format!("{three:?}"), "NumberExpr(3.0)");
+        assert_eq!(
+            format!("{:?}", three.styled(NumberStyle::Clean)),
+            "NumberExpr(3)"
+        );
+
+        // Only whole numbers lose anything, the rest round trip as they are
+        for (num, clean) in [
+            (0.1, "0.1"),
+            (-2.0, "-2"),
+            (1e300, "1e300"),
+            (f64::NAN, "NaN"),
+        ] {
+            assert_eq!(FormattedNumber(num, NumberStyle::Clean).to_string(), clean);
+        }
+
+        // All the way down the tree, pretty printed too
+        let expr = ASTExpr::BinaryExpr {
+            op: Ops::Plus,
+            left: Box::new(ASTExpr::NumberExpr(1.0)),
+            right: Box::new(ASTExpr::NumberExpr(2.5)),
+        };
+
+        let dump = format!("{:#?}", expr.styled(NumberStyle::Clean));
+        assert!(dump.contains("left: NumberExpr(\n        1,\n    ),"));
+        assert!(dump.contains("2.5"));
+        assert!(format!("{expr:#?}").contains("left: NumberExpr(\n        1.0,\n    ),"));
+    }
+
+    #[test]
+    fn listing_children() {
+        use ASTExpr::*;
+
+        let expr = BinaryExpr {
+            op: Ops::Minus,
+            left: Box::new(VariableExpr("x")),
+            right: Box::new(NumberExpr(1.0)),
+        };
+
+        assert_eq!(expr.children(), vec![&VariableExpr("x"), &NumberExpr(1.0)]);
+        assert!(expr.children()[1].children().is_empty());
+
+        // Conditions and branches in the order they're written
+        let expr = IfExpr {
+            cond: Box::new(NumberExpr(1.0)),
+            then_branch: Box::new(NumberExpr(2.0)),
+            elifs: vec![(Box::new(NumberExpr(3.0)), Box::new(NumberExpr(4.0)))],
+            else_branch: Box::new(NumberExpr(5.0)),
+        };
+
+        let nums: Vec<_> = expr
+            .children()
+            .into_iter()
+            .map(|child| match child {
+                NumberExpr(num) => *num,
+                _ => f64::NAN,
+            })
+            .collect();
+        assert_eq!(nums, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        // A var without an initializer has nothing to give but its body
+        let expr = VarExpr {
+            var_names: vec![("a", None), ("b", Some(Box::new(NumberExpr(1.0))))],
+            body: Box::new(VariableExpr("a")),
+        };
+        assert_eq!(expr.children(), vec![&NumberExpr(1.0), &VariableExpr("a")]);
+
+        let func = Function {
+            proto: Box::new(FunctionProto {
+                name: "f",
+                args: vec!["x"],
+                variadic: false,
+                void: false,
+                decl: false,
+            }),
+            body: Box::new(VariableExpr("x")),
+            attrs: vec![],
+        };
+        assert_eq!(func.children(), vec![&VariableExpr("x")]);
+        assert_eq!(
+            TopLevel::Definition(Box::new(func)).children(),
+            vec![&VariableExpr("x")]
+        );
+    }
 }
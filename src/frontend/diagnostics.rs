@@ -0,0 +1,260 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+use core::ops::Range;
+
+use thiserror::Error;
+
+// How bad a diagnostic is, errors stop compilation, warnings are only
+// advice, unless asked to deny them (--deny-warnings)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+// Byte offsets into the source, start inclusive, end exclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // Every name in the tree is a slice of the source, so where it sits
+    // in the source is just the difference of the two pointers. A slice of
+    // anything else has no place in it, that's a bug in the caller.
+    pub fn of(src_code: &str, slice: &str) -> Self {
+        let start = offset_in(&address_range(src_code), slice)
+            .expect("Span::of given a slice that isn't part of the source");
+
+        Self {
+            start,
+            end: start + slice.len(),
+        }
+    }
+
+    // One based line and column of where the span starts, the column
+    // counted in characters, for people reading diagnostics
+    pub fn line_col(&self, src_code: &str) -> (usize, usize) {
+        let (line, line_start) = LineStarts::new(src_code).line_of(self.start);
+        let col = src_code[line_start..self.start].chars().count() + 1;
+
+        (line, col)
+    }
+}
+
+fn address_range(text: &str) -> Range<usize> {
+    let start = text.as_ptr() as usize;
+    start..start + text.len()
+}
+
+// Where slice starts in the text at addresses src, None when it isn't
+// wholly inside it. An empty slice right at the end still counts.
+fn offset_in(src: &Range<usize>, slice: &str) -> Option<usize> {
+    let slice = address_range(slice);
+    (src.start <= slice.start && slice.end <= src.end).then(|| slice.start - src.start)
+}
+
+// The byte offset of every line of some source, for finding the line and
+// column of many slices of it without scanning it again for each. Only the
+// addresses of the source are kept, not the source itself.
+#[derive(Debug, Clone)]
+pub struct LineStarts {
+    src: Range<usize>,
+    starts: Vec<usize>,
+}
+
+impl LineStarts {
+    pub fn new(src_code: &str) -> Self {
+        let starts = core::iter::once(0)
+            .chain(src_code.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+
+        Self {
+            src: address_range(src_code),
+            starts,
+        }
+    }
+
+    // One based line of an offset, and the offset that line starts at
+    fn line_of(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= offset);
+        (line, self.starts[line - 1])
+    }
+
+    // One based line and column of a slice of the source, the column in
+    // bytes, as DWARF wants it. None for a slice of anything else, like the
+    // name given to top level expressions.
+    pub fn line_col(&self, slice: &str) -> Option<(usize, usize)> {
+        let offset = offset_in(&self.src, slice)?;
+        let (line, line_start) = self.line_of(offset);
+
+        Some((line, offset - line_start + 1))
+    }
+}
+
+// Shown like rustc does, "error[E0001]: ..." when there's a code for it
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{severity}{}: {message}", code.map(|code| format!("[{code}]")).unwrap_or_default())]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Span) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: None,
+            message,
+            span,
+        }
+    }
+
+    pub fn warning(message: String, span: Span) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code: None,
+            message,
+            span,
+        }
+    }
+
+    // The stable code of the error behind it, like the parser's E0001
+    pub fn with_code(self, code: &'static str) -> Self {
+        Self {
+            code: Some(code),
+            ..self
+        }
+    }
+
+    // Whether this should stop compilation
+    pub fn is_fatal(&self, deny_warnings: bool) -> bool {
+        self.severity == Severity::Error || deny_warnings
+    }
+
+    // e.g. "3:10: warning: Unused variable b"
+    pub fn render(&self, src_code: &str) -> String {
+        let (line, col) = self.span.line_col(src_code);
+        format!("{line}:{col}: {self}")
+    }
+
+    // One line of JSON for tools to read, like rustc's --error-format=json,
+    // the code is null when there's none
+    //
+    // {"severity":"error","code":"E0001","message":"...","line":1,"col":14,
+    //  "span":{"start":13,"end":14}}
+    pub fn to_json(&self, src_code: &str) -> String {
+        let (line, col) = self.span.line_col(src_code);
+        let code = self.code.map_or_else(|| "null".to_string(), json_string);
+
+        format!(
+            concat!(
+                "{{\"severity\":\"{}\",\"code\":{},\"message\":{},",
+                "\"line\":{},\"col\":{},\"span\":{{\"start\":{},\"end\":{}}}}}"
+            ),
+            self.severity,
+            code,
+            json_string(&self.message),
+            line,
+            col,
+            self.span.start,
+            self.span.end
+        )
+    }
+}
+
+// Quoted, with whatever JSON can't have in a string as is escaped
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locating_spans() {
+        let src = "def f(x)\n  var y = 1 in x;";
+        let y = &src[15..16];
+
+        let span = Span::of(src, y);
+        assert_eq!(span, Span { start: 15, end: 16 });
+        assert_eq!(span.line_col(src), (2, 7));
+
+        let diag = Diagnostic::warning("Unused variable y".to_string(), span);
+        assert_eq!(diag.render(src), "2:7: warning: Unused variable y");
+        assert!(!diag.is_fatal(false) && diag.is_fatal(true));
+
+        // The very end of the source is still in it, an empty span there
+        assert_eq!(
+            Span::of(src, &src[src.len()..]),
+            Span { start: 26, end: 26 }
+        );
+    }
+
+    #[test]
+    fn finding_lines() {
+        let src = "def f(x)\n  x + 1\n";
+        let lines = LineStarts::new(src);
+
+        assert_eq!(lines.line_col(&src[..3]), Some((1, 1)));
+        assert_eq!(lines.line_col(&src[11..12]), Some((2, 3)));
+        assert_eq!(lines.line_col(&src[src.len()..]), Some((3, 1)));
+        assert_eq!(lines.line_col("x"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't part of the source")]
+    fn locating_foreign_slices() {
+        let src = "def f(x) x;";
+        Span::of(src, "x");
+    }
+
+    #[test]
+    fn rendering_json() {
+        let src = "x;\n  \"y\" + 1;";
+        let span = Span { start: 5, end: 8 };
+
+        let diag = Diagnostic::error("String \"y\" used\tas a value".to_string(), span);
+        assert_eq!(
+            diag.to_json(src),
+            r#"{"severity":"error","code":null,"message":"String \"y\" used\u0009as a value","line":2,"col":3,"span":{"start":5,"end":8}}"#
+        );
+
+        let diag = diag.with_code("E0111");
+        assert!(diag.to_json(src).contains(r#""code":"E0111""#));
+        assert_eq!(
+            diag.render(src),
+            "2:3: error[E0111]: String \"y\" used\tas a value"
+        );
+    }
+}
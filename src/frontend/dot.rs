@@ -0,0 +1,244 @@
+use std::io::{self, Write};
+
+use crate::frontend::ast::{ASTExpr, Prototype, TopLevel};
+
+// Renders the tree of a whole program as a Graphviz DOT graph, every node a
+// box labeled with its kind and whatever value it holds, with edges down to
+// its children, left to right in source order. Each top level item is the
+// root of its own tree, try piping the output through "dot -Tpng".
+pub fn emit_dot(program: &[TopLevel], out: &mut impl Write) -> io::Result<()> {
+    let mut graph = DotGraph { out, next_id: 0 };
+
+    writeln!(graph.out, "digraph ast {{")?;
+    writeln!(graph.out, "    node [shape=box, fontname=monospace];")?;
+
+    for item in program.iter() {
+        match item {
+            TopLevel::Definition(func) => {
+                let id = graph.node(&format!("Definition {}", signature(&func.proto)))?;
+                graph.child(id, &func.body)?;
+            }
+
+            TopLevel::Extern(proto) => {
+                let kind = if proto.is_decl() { "Decl" } else { "Extern" };
+                graph.node(&format!("{kind} {}", signature(proto)))?;
+            }
+
+            TopLevel::Expression(func) => {
+                let id = graph.node("Expression")?;
+                graph.child(id, &func.body)?;
+            }
+
+            TopLevel::Const(def) => {
+                let id = graph.node(&format!("Const {}", def.name))?;
+                graph.child(id, &def.value)?;
+            }
+        }
+    }
+
+    writeln!(graph.out, "}}")
+}
+
+// Nodes are numbered in the order they're written, which is all DOT needs
+// to tell apart two nodes that happen to have the same label
+struct DotGraph<'w, W> {
+    out: &'w mut W,
+    next_id: usize,
+}
+
+impl<W: Write> DotGraph<'_, W> {
+    fn node(&mut self, label: &str) -> io::Result<usize> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        writeln!(self.out, "    n{id} [label=\"{}\"];", escape(label))?;
+
+        Ok(id)
+    }
+
+    fn child(&mut self, parent: usize, expr: &ASTExpr) -> io::Result<()> {
+        let id = self.expr(expr)?;
+        writeln!(self.out, "    n{parent} -> n{id};")
+    }
+
+    fn expr(&mut self, expr: &ASTExpr) -> io::Result<usize> {
+        use ASTExpr::*;
+
+        match expr {
+            NumberExpr(num) => self.node(&format!("NumberExpr {num}")),
+
+            StringExpr(text) => self.node(&format!("StringExpr \"{text}\"")),
+
+            VariableExpr(name) => self.node(&format!("VariableExpr {name}")),
+
+            ParenExpr(inner) => {
+                let id = self.node("ParenExpr")?;
+                self.child(id, inner)?;
+
+                Ok(id)
+            }
+
+            UnaryExpr { op, operand } => {
+                let id = self.node(&format!("UnaryExpr {}", op.as_str()))?;
+                self.child(id, operand)?;
+
+                Ok(id)
+            }
+
+            BinaryExpr { op, left, right } => {
+                let id = self.node(&format!("BinaryExpr {}", op.as_str()))?;
+                self.child(id, left)?;
+                self.child(id, right)?;
+
+                Ok(id)
+            }
+
+            CallExpr { callee, args } => {
+                let id = self.node(&format!("CallExpr {callee}"))?;
+
+                for arg in args.iter() {
+                    self.child(id, arg)?;
+                }
+
+                Ok(id)
+            }
+
+            // The first child is what gives the function pointer
+            IndirectCallExpr { callee, args } => {
+                let id = self.node("IndirectCallExpr")?;
+                self.child(id, callee)?;
+
+                for arg in args.iter() {
+                    self.child(id, arg)?;
+                }
+
+                Ok(id)
+            }
+
+            ArrayExpr(elems) => {
+                let id = self.node("ArrayExpr")?;
+
+                for elem in elems.iter() {
+                    self.child(id, elem)?;
+                }
+
+                Ok(id)
+            }
+
+            IndexExpr { base, index } => {
+                let id = self.node("IndexExpr")?;
+                self.child(id, base)?;
+                self.child(id, index)?;
+
+                Ok(id)
+            }
+
+            IfExpr {
+                cond,
+                then_branch,
+                elifs,
+                else_branch,
+            } => {
+                let id = self.node("IfExpr")?;
+                self.child(id, cond)?;
+                self.child(id, then_branch)?;
+
+                for (elif_cond, elif_branch) in elifs.iter() {
+                    self.child(id, elif_cond)?;
+                    self.child(id, elif_branch)?;
+                }
+
+                self.child(id, else_branch)?;
+
+                Ok(id)
+            }
+
+            ForLoopExpr {
+                varname,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let id = self.node(&format!("ForLoopExpr {varname}"))?;
+
+                for child in [start, end, step, body] {
+                    self.child(id, child)?;
+                }
+
+                Ok(id)
+            }
+
+            WhileExpr { cond, body } => {
+                let id = self.node("WhileExpr")?;
+                self.child(id, cond)?;
+                self.child(id, body)?;
+
+                Ok(id)
+            }
+
+            // A binding without an initializer has nothing to point to, it
+            // still shows up in the label
+            VarExpr { var_names, body } => {
+                let names = var_names
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ");
+
+                let id = self.node(&format!("VarExpr {names}"))?;
+
+                for init in var_names.iter().filter_map(|(_, init)| init.as_ref()) {
+                    self.child(id, init)?;
+                }
+
+                self.child(id, body)?;
+
+                Ok(id)
+            }
+        }
+    }
+}
+
+fn signature(proto: &Prototype) -> String {
+    format!("{}({})", proto.get_name(), proto.get_params().join(" "))
+}
+
+// Labels are quoted, so quotes and backslashes in them need escaping
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_program_recovering};
+
+    fn dot(src: &str) -> String {
+        let (program, errors) = parse_program_recovering(&mut src.lex().lookahead());
+        assert!(errors.is_empty());
+
+        let mut out = vec![];
+        emit_dot(&program, &mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn emitting_dot() {
+        let graph = dot("2 + 3;");
+
+        assert!(graph.starts_with("digraph ast {\n"));
+        assert!(graph.contains("n1 [label=\"BinaryExpr +\"];"));
+        assert!(graph.contains("n2 [label=\"NumberExpr 2\"];"));
+        assert!(graph.contains("n3 [label=\"NumberExpr 3\"];"));
+
+        // The two operands, plus the edge from the expression item itself
+        assert!(graph.contains("n1 -> n2;"));
+        assert!(graph.contains("n1 -> n3;"));
+        assert_eq!(graph.matches("->").count(), 3);
+
+        // Quotes of string literals mustn't end the label early
+        assert!(dot("len(\"hi\");").contains("[label=\"StringExpr \\\"hi\\\"\"];"));
+    }
+}
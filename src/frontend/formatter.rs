@@ -0,0 +1,679 @@
+use std::cell::Cell;
+use std::fmt::Write;
+
+use crate::frontend::{
+    ast::{ASTExpr, Function, Prototype, TopLevel},
+    lexer::{Lex, Ops, Token},
+    lookahead::Lookahead,
+    parser::{parse_top_level, Assoc, ParserError, OP_PRECEDENCE},
+};
+
+// How each level of a block is indented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    Tabs,
+}
+
+impl Indent {
+    fn repeat(self, depth: usize) -> String {
+        match self {
+            Indent::Spaces(width) => " ".repeat(width * depth),
+            Indent::Tabs => "\t".repeat(depth),
+        }
+    }
+
+    // How many columns the indentation takes up, a tab counts as 4
+    fn columns(self, depth: usize) -> usize {
+        match self {
+            Indent::Spaces(width) => width * depth,
+            Indent::Tabs => 4 * depth,
+        }
+    }
+}
+
+// A line of a body longer than max_width is wrapped, broken before its
+// binary operators, anything else is left as long as it is. Parens are only
+// put where they're needed, unless preserve_parens, which keeps every one
+// that was written, needed or not, and adds no more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub indent: Indent,
+    pub max_width: usize,
+    pub preserve_parens: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            indent: Indent::Spaces(4),
+            max_width: 80,
+            preserve_parens: false,
+        }
+    }
+}
+
+// A small rustfmt for Kaleidoscope. Parses the whole program and prints it
+// back out in a canonical form, operators spaced, args comma spaced, and
+// bodies indented. The output always parses back to the very same tree,
+// so formatting twice is the same as formatting once.
+//
+// Comments are kept, each is put on its own line just before the item that
+// follows it in the source. Comments at the end of the file stay at the end.
+pub fn format_source(src_code: &str) -> Result<String, ParserError<'_>> {
+    format_source_with(src_code, &FormatOptions::default())
+}
+
+pub fn format_source_with<'src>(
+    src_code: &'src str,
+    opts: &FormatOptions,
+) -> Result<String, ParserError<'src>> {
+    // Lex losslessly, then set the comments aside, remembering the index of
+    // the token each one came before, the parser only sees the rest
+    let mut tokens = vec![];
+    let mut comments = vec![];
+
+    for token in src_code.lex_lossless() {
+        match token {
+            Token::Comment(comment) => comments.push((comment, tokens.len())),
+            token => tokens.push(token),
+        }
+    }
+
+    // Count the tokens pulled from the iterator, so we know the index of
+    // the token each item starts at. Peeking pulls tokens into the buffer,
+    // those haven't been taken yet, so don't count.
+    let consumed = Cell::new(0);
+    let mut tokens = tokens
+        .into_iter()
+        .inspect(|_| consumed.set(consumed.get() + 1))
+        .lookahead()
+        .preserving_parens(opts.preserve_parens);
+
+    let mut comments = comments.into_iter().peekable();
+    let mut out = String::new();
+    let mut prev_is_def = None;
+
+    while let Some(token) = tokens.peek().copied() {
+        if let Token::Semicolon = token {
+            let _ = tokens.next();
+            continue;
+        }
+
+        let start = consumed.get() - tokens.buffered();
+        let item = parse_top_level(&mut tokens)?;
+
+        // Definitions get a blank line on either side, externs and top level
+        // expressions that follow one another are kept together.
+        let is_def = matches!(item, TopLevel::Definition(_));
+        if prev_is_def.is_some_and(|prev_is_def| prev_is_def || is_def) {
+            out.push('\n');
+        }
+
+        while let Some((comment, _)) = comments.next_if(|(_, idx)| *idx <= start) {
+            let _ = writeln!(out, "{comment}");
+        }
+
+        format_item(&item, opts, &mut out);
+        prev_is_def = Some(is_def);
+    }
+
+    for (comment, _) in comments {
+        let _ = writeln!(out, "{comment}");
+    }
+
+    Ok(out)
+}
+
+fn format_item(item: &TopLevel, opts: &FormatOptions, out: &mut String) {
+    match item {
+        TopLevel::Definition(func) => format_definition(func, opts, out),
+
+        TopLevel::Extern(proto) => {
+            let keyword = if proto.is_decl() { "decl" } else { "extern" };
+            let _ = writeln!(out, "{keyword} {};", format_prototype(proto));
+        }
+
+        TopLevel::Expression(func) => {
+            let _ = writeln!(out, "{};", format_block(&func.body, 0, opts));
+        }
+
+        TopLevel::Const(def) => {
+            let _ = writeln!(
+                out,
+                "const {} = {};",
+                def.name,
+                format_block(&def.value, 0, opts)
+            );
+        }
+    }
+}
+
+fn format_definition(func: &Function, opts: &FormatOptions, out: &mut String) {
+    out.push_str("def ");
+
+    if !func.attrs.is_empty() {
+        let attrs = func
+            .attrs
+            .iter()
+            .map(|attr| attr.as_str())
+            .collect::<Vec<&str>>();
+
+        let _ = write!(out, "[{}] ", attrs.join(", "));
+    }
+
+    let _ = writeln!(out, "{}", format_prototype(&func.proto));
+    let _ = writeln!(
+        out,
+        "{}{};",
+        opts.indent.repeat(1),
+        format_block(&func.body, 1, opts)
+    );
+}
+
+fn format_prototype(proto: &Prototype) -> String {
+    match proto {
+        Prototype::FunctionProto {
+            name,
+            args,
+            variadic,
+            void,
+            ..
+        } => {
+            let mut args = args
+                .iter()
+                .map(|arg| format_ident(arg))
+                .collect::<Vec<String>>();
+
+            if *variadic {
+                args.push("...".to_string());
+            }
+
+            let void = if *void { "void " } else { "" };
+
+            format!("{void}{}({})", format_ident(name), args.join(" "))
+        }
+
+        Prototype::OverloadedUnaryOpProto { operator, arg } => {
+            format!("unary {} ({})", operator.as_str(), format_ident(arg))
+        }
+
+        Prototype::OverloadedBinaryOpProto {
+            operator,
+            args: (lhs, rhs),
+            precedence,
+        } => format!(
+            "binary {} {} ({} {})",
+            operator.as_str(),
+            precedence,
+            format_ident(lhs),
+            format_ident(rhs)
+        ),
+    }
+}
+
+// Expressions in "statement" position, the body of a function or a top
+// level expression, or the branches/body of another block. Here if, for,
+// and var are spread over multiple lines, their inner parts indented.
+fn format_block(expr: &ASTExpr, depth: usize, opts: &FormatOptions) -> String {
+    use ASTExpr::*;
+
+    let indent = opts.indent.repeat(depth);
+    let inner = opts.indent.repeat(depth + 1);
+
+    match expr {
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            let elifs = elifs
+                .iter()
+                .map(|(elif_cond, elif_branch)| {
+                    format!(
+                        "\n{indent}elif {} then\n{inner}{}",
+                        format_expr(elif_cond),
+                        format_block(elif_branch, depth + 1, opts),
+                    )
+                })
+                .collect::<String>();
+
+            format!(
+                "if {} then\n{inner}{}{elifs}\n{indent}else\n{inner}{}",
+                format_expr(cond),
+                format_block(then_branch, depth + 1, opts),
+                format_block(else_branch, depth + 1, opts),
+            )
+        }
+
+        ForLoopExpr {
+            varname,
+            start,
+            end,
+            step,
+            body,
+        } => format!(
+            "{} in\n{inner}{}",
+            format_for_header(varname, start, end, step),
+            format_block(body, depth + 1, opts)
+        ),
+
+        WhileExpr { cond, body } => format!(
+            "while {} in\n{inner}{}",
+            format_expr(cond),
+            format_block(body, depth + 1, opts)
+        ),
+
+        VarExpr { var_names, body } => format!(
+            "{} in\n{inner}{}",
+            format_var_header(var_names),
+            format_block(body, depth + 1, opts)
+        ),
+
+        _ => format_wrapped(expr, depth, opts),
+    }
+}
+
+// An expression that doesn't fit in what's left of the line is broken before
+// its operator, the rest indented one level further. Chains of operators that
+// don't need parenthesis, like a + b + c, are broken before each of them.
+fn format_wrapped(expr: &ASTExpr, depth: usize, opts: &FormatOptions) -> String {
+    let line = format_expr(expr);
+
+    let ASTExpr::BinaryExpr { op, left, right } = expr else {
+        return line;
+    };
+
+    if opts.indent.columns(depth) + line.len() <= opts.max_width {
+        return line;
+    }
+
+    let prec = precedence(op);
+
+    let left = match **left {
+        ASTExpr::BinaryExpr { op: left_op, .. } if !needs_parens(&left_op, prec, false) => {
+            format_wrapped(left, depth, opts)
+        }
+        _ => format_operand(left, prec, false),
+    };
+
+    format!(
+        "{left}\n{}{} {}",
+        opts.indent.repeat(depth + 1),
+        op.as_str(),
+        format_operand(right, prec, true)
+    )
+}
+
+// Calls and indexes bind tighter than any operator, "(-a)[0]" isn't "-a[0]"
+fn format_postfix_base(expr: &ASTExpr) -> String {
+    use ASTExpr::*;
+
+    match expr {
+        UnaryExpr { .. }
+        | BinaryExpr { .. }
+        | IfExpr { .. }
+        | ForLoopExpr { .. }
+        | WhileExpr { .. }
+        | VarExpr { .. } => format!("({})", format_expr(expr)),
+        _ => format_expr(expr),
+    }
+}
+
+// Expressions all on the one line
+fn format_expr(expr: &ASTExpr) -> String {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(num) => format!("{num}"),
+
+        StringExpr(text) => format!("\"{text}\""),
+
+        VariableExpr(name) => format_ident(name),
+
+        ParenExpr(inner) => format!("({})", format_expr(inner)),
+
+        UnaryExpr { op, operand } => {
+            let operand = match **operand {
+                BinaryExpr { .. }
+                | IfExpr { .. }
+                | ForLoopExpr { .. }
+                | WhileExpr { .. }
+                | VarExpr { .. } => format!("({})", format_expr(operand)),
+                _ => format_expr(operand),
+            };
+
+            // Operators the lexer doesn't split off on their own, like "!",
+            // need a space or they'd be lexed together with the operand
+            let op = op.as_str();
+            if op.chars().all(Token::is_single_char_token) {
+                format!("{op}{operand}")
+            } else {
+                format!("{op} {operand}")
+            }
+        }
+
+        BinaryExpr { op, left, right } => {
+            let prec = precedence(op);
+
+            format!(
+                "{} {} {}",
+                format_operand(left, prec, false),
+                op.as_str(),
+                format_operand(right, prec, true)
+            )
+        }
+
+        CallExpr { callee, args } => {
+            let args = args
+                .iter()
+                .map(|arg| format_expr(arg))
+                .collect::<Vec<String>>();
+            format!("{}({})", format_ident(callee), args.join(", "))
+        }
+
+        ArrayExpr(elems) => {
+            let elems = elems
+                .iter()
+                .map(|elem| format_expr(elem))
+                .collect::<Vec<String>>();
+            format!("[{}]", elems.join(", "))
+        }
+
+        IndirectCallExpr { callee, args } => {
+            let args = args
+                .iter()
+                .map(|arg| format_expr(arg))
+                .collect::<Vec<String>>();
+            format!("{}({})", format_postfix_base(callee), args.join(", "))
+        }
+
+        IndexExpr { base, index } => {
+            format!("{}[{}]", format_postfix_base(base), format_expr(index))
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            let elifs = elifs
+                .iter()
+                .map(|(elif_cond, elif_branch)| {
+                    format!(
+                        " elif {} then {}",
+                        format_expr(elif_cond),
+                        format_expr(elif_branch)
+                    )
+                })
+                .collect::<String>();
+
+            format!(
+                "if {} then {}{elifs} else {}",
+                format_expr(cond),
+                format_expr(then_branch),
+                format_expr(else_branch)
+            )
+        }
+
+        ForLoopExpr {
+            varname,
+            start,
+            end,
+            step,
+            body,
+        } => format!(
+            "{} in {}",
+            format_for_header(varname, start, end, step),
+            format_expr(body)
+        ),
+
+        WhileExpr { cond, body } => format!("while {} in {}", format_expr(cond), format_expr(body)),
+
+        VarExpr { var_names, body } => {
+            format!("{} in {}", format_var_header(var_names), format_expr(body))
+        }
+    }
+}
+
+// Operands of a binary expression only get parenthesis when needed to keep
+// the same tree. For left associative operators, a left operand needs them
+// when it binds looser than its parent, a right operand when it binds looser
+// or the same, the other way round for right associative ones. Operators of
+// the same precedence always share an associativity.
+fn needs_parens(op: &Ops, parent_prec: i32, is_right: bool) -> bool {
+    let prec = precedence(op);
+    let right_assoc = assoc(op) == Assoc::Right;
+
+    prec < parent_prec || (prec == parent_prec && is_right != right_assoc)
+}
+
+// The keyword expressions always get parenthesis, since their last part
+// would otherwise swallow the rest of the expression
+fn format_operand(expr: &ASTExpr, parent_prec: i32, is_right: bool) -> String {
+    use ASTExpr::*;
+
+    match expr {
+        BinaryExpr { op, .. } => {
+            if needs_parens(op, parent_prec, is_right) {
+                format!("({})", format_expr(expr))
+            } else {
+                format_expr(expr)
+            }
+        }
+
+        IfExpr { .. } | ForLoopExpr { .. } | WhileExpr { .. } | VarExpr { .. } => {
+            format!("({})", format_expr(expr))
+        }
+
+        _ => format_expr(expr),
+    }
+}
+
+fn format_for_header(varname: &str, start: &ASTExpr, end: &ASTExpr, step: &ASTExpr) -> String {
+    let mut header = format!(
+        "for {} = {}, {}",
+        format_ident(varname),
+        format_expr(start),
+        format_expr(end)
+    );
+
+    // A missing step is parsed as a step of 1, so leave it out
+    if *step != ASTExpr::NumberExpr(1.0) {
+        let _ = write!(header, ", {}", format_expr(step));
+    }
+
+    header
+}
+
+fn format_var_header(var_names: &[(&str, Option<Box<ASTExpr>>)]) -> String {
+    let vars = var_names
+        .iter()
+        .map(|(name, init)| match init {
+            Some(init) => format!("{} = {}", format_ident(name), format_expr(init)),
+            None => format_ident(name),
+        })
+        .collect::<Vec<String>>();
+
+    format!("var {}", vars.join(", "))
+}
+
+// Names that wouldn't lex back to the same identifier, keywords for one,
+// must have been backtick escaped in the source, so escape them again
+fn format_ident(name: &str) -> String {
+    let tokens = name.lex().collect::<Vec<Token>>();
+
+    if tokens == [Token::Identifier(name)] {
+        name.to_string()
+    } else {
+        format!("`{name}`")
+    }
+}
+
+fn precedence(op: &Ops) -> i32 {
+    OP_PRECEDENCE
+        .read()
+        .get(op)
+        .map_or(-1, |info| info.precedence)
+}
+
+fn assoc(op: &Ops) -> Assoc {
+    OP_PRECEDENCE
+        .read()
+        .get(op)
+        .map_or(Assoc::Left, |info| info.assoc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::parser::parse_program_recovering;
+
+    // A little corpus of programs, covering every kind of expression
+    const PROGRAMS: &[&str] = &[
+        "def  foo(x   y)x*y+  2;foo( 1,2 );",
+        "extern sin(x); extern cos(x); sin(1)*sin(1) + cos(1)*cos(1);",
+        "extern printf(fmt, ...); extern f(...);",
+        "def fib(n) if n < 3 then 1 else fib(n-1)+fib(n-2); fib(10);",
+        "def f(a b c) (a - (b - c)) * (a + b) / c - a / (b * c);",
+        "def g(x) -x + -(x * 2) - - x;",
+        "def neg(x) -3 * x - -2 + - -1;",
+        "def binary| 5 (a b) if a then 1 else if b then 1 else 0; 1 | 0 | (0 | 1);",
+        "def unary ! (v) if v then 0 else 1; ! 1 + !(2 < 3);",
+        "def loop(n) for i = 1, i < n in foo(i); def loop2(n) for i = 0, i < n, 2 in foo(i) + 1;",
+        "def h(x) var a = 1, b = x * 2, c in a + b + c;",
+        "def k(x) 1 + (if x then 2 else 3) + (var y = x in y * y);",
+        "def [inline, cold] sq(x) x * x;",
+        "def seq(x) x = x + 1;",
+        "def `if`(`then`) `then` + 1; `if`(2);",
+        "def nested(x) if x then for i = 0, i < x in var t = i in t else 0.5;",
+        "def sign(x) if x < 0 then -1 elif x > 0 then 1 else 0; 1 + (if 1 then 2 elif 3 then 4 else 5);",
+        "len(\"hello, world # not a comment\") * 2;",
+        "# Squares\ndef sq(x) # inline comment\n x * x; sq(2); # trailing",
+    ];
+
+    fn parse(src: &str) -> Vec<TopLevel<'_>> {
+        let (program, errors) = parse_program_recovering(&mut src.lex().lookahead());
+        assert_eq!(errors, vec![]);
+
+        program
+    }
+
+    #[test]
+    fn formatting_normalizes_layout() {
+        assert_eq!(
+            format_source("def  foo(x   y)x*y+  2;foo( 1,2 );").unwrap(),
+            "def foo(x y)\n    x * y + 2;\n\nfoo(1, 2);\n"
+        );
+
+        assert_eq!(
+            format_source("def fib(n) if n < 3 then 1 else fib(n-1)+fib(n-2);").unwrap(),
+            "def fib(n)\n    if n < 3 then\n        1\n    else\n        fib(n - 1) + fib(n - 2);\n"
+        );
+
+        assert_eq!(
+            format_source("extern sin(x); extern cos(x);").unwrap(),
+            "extern sin(x);\nextern cos(x);\n"
+        );
+
+        assert!(format_source("def f(x) x + ;").is_err());
+    }
+
+    #[test]
+    fn formatting_keeps_comments() {
+        let src = "# Some math\n# functions\ndef sq(x) # the square\n x*x;\nextern sin(x);\n\n# Use them\nsq(sin(2)); # done\n";
+
+        assert_eq!(
+            format_source(src).unwrap(),
+            "# Some math\n# functions\ndef sq(x)\n    x * x;\n\n# the square\nextern sin(x);\n# Use them\nsq(sin(2));\n# done\n"
+        );
+    }
+
+    #[test]
+    fn formatting_round_trips() {
+        for src in PROGRAMS {
+            let formatted = format_source(src).unwrap();
+            assert_eq!(parse(src), parse(&formatted), "{formatted}");
+        }
+    }
+
+    #[test]
+    fn choosing_indentation() {
+        let src = "def f(x) if x then 1 else 2;";
+
+        let two_spaces = FormatOptions {
+            indent: Indent::Spaces(2),
+            ..Default::default()
+        };
+
+        let tabs = FormatOptions {
+            indent: Indent::Tabs,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_source_with(src, &two_spaces).unwrap(),
+            "def f(x)\n  if x then\n    1\n  else\n    2;\n"
+        );
+
+        assert_eq!(
+            format_source_with(src, &tabs).unwrap(),
+            "def f(x)\n\tif x then\n\t\t1\n\telse\n\t\t2;\n"
+        );
+    }
+
+    #[test]
+    fn preserving_parens() {
+        let src = "def f(a b c) (a + b) * c + (a) + ((b * c));";
+
+        let opts = FormatOptions {
+            preserve_parens: true,
+            ..Default::default()
+        };
+
+        let formatted = format_source_with(src, &opts).unwrap();
+        assert_eq!(
+            formatted,
+            "def f(a b c)\n    (a + b) * c + (a) + ((b * c));\n"
+        );
+        assert_eq!(format_source_with(&formatted, &opts).unwrap(), formatted);
+
+        // Otherwise only the parens needed are left
+        assert_eq!(
+            format_source(src).unwrap(),
+            "def f(a b c)\n    (a + b) * c + a + b * c;\n"
+        );
+    }
+
+    #[test]
+    fn wrapping_long_lines() {
+        let opts = FormatOptions {
+            max_width: 24,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_source_with("def f(x) first(x) + second(x) * 2 - third(x);", &opts).unwrap(),
+            "def f(x)\n    first(x)\n        + second(x) * 2\n        - third(x);\n"
+        );
+
+        // Wrapped or not, it's still the same program, and stays as it is
+        for src in PROGRAMS {
+            let formatted = format_source_with(src, &opts).unwrap();
+
+            assert_eq!(parse(src), parse(&formatted), "{formatted}");
+            assert_eq!(format_source_with(&formatted, &opts).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for src in PROGRAMS {
+            let once = format_source(src).unwrap();
+            let twice = format_source(&once).unwrap();
+            assert_eq!(once, twice);
+        }
+    }
+}
@@ -1,4 +1,14 @@
-use std::str::SplitWhitespace;
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::ops::Range;
+
+use thiserror::Error;
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::frontend::diagnostics::Span;
 
 // Our tokens for the Kaleidoscope language, in the original
 // tutorial, delimiters like commas, parenthesis, semicolons
@@ -28,29 +38,44 @@ pub enum Token<'src> {
     UnaryOverload = 15,
     BinaryOverload = 16,
     Var = 17,
+    OpenBracket = 18,
+    ClosedBracket = 19,
+    Comment(&'src str) = 20, // Only produced when lexing losslessly
+    Ellipsis = 21,
+    Elif = 22,
+    Error(&'src str) = 23,    // Looked like a number, but isn't one
+    Str(&'src str) = 24,      // A string literal, without its quotes
+    CompoundAssign(Ops) = 25, // "+=" and the like, holding the "+"
+    Const = 26,
+    Decl = 27,
+    While = 28,
     Unknown(&'src str) = 255,
 }
 
 // Operators found here, member field of Token::Operator variant
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Ops {
     // General math on floating point values
     Plus = 0,
     Minus = 1,
     Mult = 2,
     Div = 3,
+    Mod = 16, // "%", the remainder of a division, like C's fmod
 
     // Comparison of floating point values
     Eq = 4,  // Let's use the C conventional "=="
     Neq = 5, // Let's use "!="
     Lt = 6,  // "<"
     Gt = 7,  // ">"
+    Le = 14, // "<="
+    Ge = 15, // ">="
 
     // Assignment
     Assign = 8,
 
-    // These operators are left undefined, but can be overloaded by developers
+    // These operators are left undefined, but can be overloaded by developers,
+    // all but "^", which is power, overloading it takes --allow-override
     Or = 9,      // "|"
     And = 10,    // "&"
     Xor = 11,    // "^"
@@ -67,10 +92,13 @@ impl Ops {
             Minus => &"-",
             Mult => &"*",
             Div => &"/",
+            Mod => "%",
             Eq => &"==",
             Neq => &"!=",
             Lt => &"<",
             Gt => &">",
+            Le => "<=",
+            Ge => ">=",
             Or => &"|",
             And => &"&",
             Xor => &"^",
@@ -79,28 +107,111 @@ impl Ops {
             Assign => &"=",
         }
     }
+
+    pub const ALL: [Ops; 17] = [
+        Ops::Plus,
+        Ops::Minus,
+        Ops::Mult,
+        Ops::Div,
+        Ops::Mod,
+        Ops::Eq,
+        Ops::Neq,
+        Ops::Lt,
+        Ops::Gt,
+        Ops::Le,
+        Ops::Ge,
+        Ops::Assign,
+        Ops::Or,
+        Ops::And,
+        Ops::Xor,
+        Ops::Negate,
+        Ops::Colon,
+    ];
+}
+
+#[derive(Error, PartialEq, Debug)]
+#[error("Unknown operator: {0}")]
+pub struct UnknownOperator(pub String);
+
+// The spelling of each operator is only written down once, in as_str, going
+// the other way we just look for the operator spelled like that
+impl TryFrom<&str> for Ops {
+    type Error = UnknownOperator;
+
+    fn try_from(text: &str) -> Result<Self, Self::Error> {
+        Ops::ALL
+            .into_iter()
+            .find(|op| op.as_str() == text)
+            .ok_or_else(|| UnknownOperator(text.to_string()))
+    }
+}
+
+impl TryFrom<char> for Ops {
+    type Error = UnknownOperator;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        Ops::try_from(c.encode_utf8(&mut [0; 4]) as &str)
+    }
 }
 
 // For strings with no whitespace, need to be able to find out
 // if I should lex the entire string, or break it apart into slices
 // If the string contains multiple single char tokens, we return true.
+// Those starting an operator might turn out to be the first of two,
+// see operator_len.
 impl<'src> Token<'src> {
-    fn is_single_char_token(c: char) -> bool {
+    pub fn is_single_char_token(c: char) -> bool {
         match c {
-            '+' | '-' | '*' | '/' | ';' | ',' | '(' | ')' | '<' | '>' | '|' | '&' | '^'
-            | ':' => true,
+            '+' | '-' | '*' | '/' | '%' | ';' | ',' | '(' | ')' | '[' | ']' | '<' | '>' | '|'
+            | '&' | '^' | ':' | '`' | '=' | '!' => true,
 
             _ => false,
         }
     }
 }
 
+// How much of the start of a slice is the one token, where that's a single
+// char token. Maximal munch, two chars are taken if they spell an operator,
+// "<=" is less or equal, never less then assign, otherwise just the one.
+fn operator_len(slice: &str) -> usize {
+    let two_char_op = slice
+        .get(..2)
+        .is_some_and(|text| compound_assign(text).is_some() || Ops::try_from(text).is_ok());
+
+    if two_char_op {
+        2
+    } else {
+        slice.chars().next().map_or(0, char::len_utf8)
+    }
+}
+
+// The arithmetic operators that can be combined with an assignment, "x += 1"
+// being short for "x = x + 1"
+fn compound_assign(text: &str) -> Option<Ops> {
+    match text {
+        "+=" => Some(Ops::Plus),
+        "-=" => Some(Ops::Minus),
+        "*=" => Some(Ops::Mult),
+        "/=" => Some(Ops::Div),
+        "%=" => Some(Ops::Mod),
+        _ => None,
+    }
+}
+
 // Taking any given string slice, and producing a token for it,
 // used in Lex trait implementation for str.
 #[inline(always)]
 fn tokenize(string: &str) -> Token {
     use Token::*;
 
+    if let Ok(op) = Ops::try_from(string) {
+        return Operator(op);
+    }
+
+    if let Some(op) = compound_assign(string) {
+        return CompoundAssign(op);
+    }
+
     match string {
         // Keywords
         "def" => FuncDef,
@@ -108,63 +219,257 @@ fn tokenize(string: &str) -> Token {
         "if" => If,
         "then" => Then,
         "else" => Else,
+        "elif" => Elif,
         "for" => For,
         "in" => In,
         "unary" => UnaryOverload,
         "binary" => BinaryOverload,
         "var" => Var,
-
-        // Operators
-        "+" => Operator(Ops::Plus),
-        "-" => Operator(Ops::Minus),
-        "*" => Operator(Ops::Mult),
-        "/" => Operator(Ops::Div),
-        "==" => Operator(Ops::Eq),
-        "!=" => Operator(Ops::Neq),
-        "<" => Operator(Ops::Lt),
-        ">" => Operator(Ops::Gt),
-        "!" => Operator(Ops::Negate),
-        "|" => Operator(Ops::Or),
-        "&" => Operator(Ops::And),
-        "^" => Operator(Ops::Xor),
-        ":" => Operator(Ops::Colon),
-        "=" => Operator(Ops::Assign),
+        "const" => Const,
+        "decl" => Decl,
+        "while" => While,
 
         // Parenthesis
         "(" => OpenParen,
         ")" => ClosedParen,
 
+        // Brackets, for function attributes
+        "[" => OpenBracket,
+        "]" => ClosedBracket,
+
         //Delimiters
         "," => Comma,
         ";" => Semicolon,
+        "..." => Ellipsis,
+
+        // C99 style hexadecimal floats, "0x1.8p3" is 1.5 * 2^3, for writing
+        // down exactly the number meant, the exponent is a must
+        text if is_hex_float(text) => match parse_hex_float(text) {
+            Some(num) => Number(num),
+            None => Error(text),
+        },
+
+        // Numbers start with a digit, or a dot then a digit. Checked before
+        // parsing, as parse::<f64>() also takes words like "inf" and "NaN",
+        // which are identifiers to us. Anything else starting like a number,
+        // "1.2.3" or "2x", is an error, rather than some wrong number.
+        text if starts_like_number(text) => match text.parse::<f64>() {
+            Ok(num) => Number(num),
+            Err(_) => Error(text),
+        },
 
         // Everything else
         text => {
-            if let Ok(num) = text.parse::<f64>() {
-                Number(num)
+            if is_identifier(text) {
+                Identifier(text)
             } else {
-                if text.chars().nth(0).unwrap().is_alphabetic() {
-                    Identifier(text)
-                } else {
-                    Unknown(text)
-                }
+                Unknown(text)
+            }
+        }
+    }
+}
+
+fn is_hex_float(text: &str) -> bool {
+    text.starts_with("0x") || text.starts_with("0X")
+}
+
+// The hex digits, with an optional point somewhere among them, then a "p"
+// and a power of two in decimal. Exact, unless there are more digits than
+// fit in a double, in which case it's rounded like any other literal.
+fn parse_hex_float(text: &str) -> Option<f64> {
+    let (mantissa, exponent) = text[2..].split_once(['p', 'P'])?;
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    let digits = || whole.chars().chain(fraction.chars());
+
+    if digits().next().is_none() || !digits().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut exponent = exponent.parse::<i32>().ok()?;
+
+    // Digits past what a u64 holds would only be rounded away, each is
+    // still worth another four bits of magnitude if it's before the point
+    let mut value = 0u64;
+
+    for (i, digit) in digits().enumerate() {
+        let fractional = i >= whole.len();
+
+        if value >> 60 != 0 {
+            if !fractional {
+                exponent = exponent.saturating_add(4);
+            }
+        } else {
+            value = value << 4 | digit.to_digit(16).unwrap() as u64;
+
+            if fractional {
+                exponent = exponent.saturating_sub(4);
+            }
+        }
+    }
+
+    Some(scale_by_power_of_two(value as f64, exponent))
+}
+
+// x * 2^exp, in steps small enough that each power of two is a normal double
+fn scale_by_power_of_two(mut x: f64, mut exp: i32) -> f64 {
+    let power = |exp: i32| f64::from_bits(((exp + 1023) as u64) << 52);
+
+    while exp > 1023 {
+        x *= power(1023);
+        exp -= 1023;
+
+        if x.is_infinite() {
+            return x;
+        }
+    }
+
+    while exp < -1022 {
+        x *= power(-1022);
+        exp += 1022;
+
+        if x == 0.0 {
+            return x;
+        }
+    }
+
+    x * power(exp)
+}
+
+fn starts_like_number(text: &str) -> bool {
+    let digits = text.strip_prefix('.').unwrap_or(text);
+    digits.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// Where in a chunk the first single char token is, that isn't the sign of
+// an exponent, "1e-3", or the hex float "0x1p-3", is one number, not a
+// subtraction. A decimal one needs a digit after the sign, "1e-x" is still
+// the bad number "1e" minus x.
+fn split_point(slice: &str) -> Option<usize> {
+    let hex = is_hex_float(slice);
+    let decimal = !hex && starts_like_number(slice);
+
+    slice
+        .char_indices()
+        .find(|&(i, c)| {
+            let exponent_sign = matches!(c, '+' | '-')
+                && ((hex && slice[..i].ends_with(['p', 'P']))
+                    || (decimal
+                        && is_decimal_mantissa(&slice[..i])
+                        && slice[i + 1..].starts_with(|c: char| c.is_ascii_digit())));
+
+            Token::is_single_char_token(c) && !exponent_sign
+        })
+        .map(|(i, _)| i)
+}
+
+// Digits, maybe with a point among them, then the "e" of an exponent
+fn is_decimal_mantissa(text: &str) -> bool {
+    text.strip_suffix(['e', 'E'])
+        .is_some_and(|digits| digits.chars().all(|c| c.is_ascii_digit() || c == '.'))
+}
+
+// How long the block comment the source starts with is, up to and including
+// the "*/" closing it. They nest, each "/*" inside needs a "*/" of its own,
+// so a block commented out can have comments in it already. None when the
+// source runs out before the last one is closed.
+fn block_comment_len(src: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = 0;
+
+    while idx < src.len() {
+        if src[idx..].starts_with("/*") {
+            depth += 1;
+            idx += 2;
+        } else if src[idx..].starts_with("*/") {
+            depth -= 1;
+            idx += 2;
+
+            if depth == 0 {
+                return Some(idx);
             }
+        } else {
+            idx += src[idx..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+
+    None
+}
+
+// Breaks the source up into whitespace separated chunks, much like
+// SplitWhitespace, except that a '#' starts a comment running to the
+// end of the line, which is given back whole as a single chunk. Block
+// comments, "/*" to "*/", are whole chunks too, however many lines they
+// span. String literals are also whole chunks, quotes included, spaces
+// and all.
+#[derive(Debug, Clone)]
+pub struct Chunks<'src> {
+    rest: &'src str,
+}
+
+impl<'src> Iterator for Chunks<'src> {
+    type Item = &'src str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let src = self.rest.trim_start();
+
+        if src.is_empty() {
+            self.rest = src;
+            return None;
         }
+
+        let end = if src.starts_with('#') {
+            src.find('\n').unwrap_or(src.len())
+        } else if src.starts_with("/*") {
+            // Unterminated, like strings, it runs to the end of the source
+            block_comment_len(src).unwrap_or(src.len())
+        } else if let Some(quoted) = src.strip_prefix('"') {
+            // Unterminated, it runs to the end of the source
+            quoted.find('"').map_or(src.len(), |end| end + 2)
+        } else {
+            let end = src
+                .find(|c: char| c.is_whitespace() || c == '#' || c == '"')
+                .unwrap_or(src.len());
+
+            src[..end].find("/*").unwrap_or(end)
+        };
+
+        let (chunk, rest) = src.split_at(end);
+        self.rest = rest;
+
+        Some(chunk.trim_end())
     }
 }
 
+// Identifiers follow Unicode's rules (UAX #31), so names like π or naïve
+// work, but symbols like emoji do not
+pub(crate) fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+
+    chars.next().is_some_and(is_xid_start) && chars.all(is_xid_continue)
+}
+
 // Our iterator adapter for producing Kaleidoscope tokens,
-// the only iterator "I" we really use here is SplitWhitespace, but
+// the only iterator "I" we really use here is Chunks, but
 // so it is a bit needless to make this generic, but just following
 // typical iterator adapter nature.
 //
 // The iterator I must produce string slices &str, but if it produces
 // a slice with multiple tokens in it, we take the first token from it,
 // then store the latter part of slice in leftover_slice
-#[derive(Debug)]
+//
+// Comments are skipped, unless lexing losslessly, then they are given
+// back as comment tokens. These still borrow from the source, so where
+// a comment sits in the source can always be recovered from its slice.
+//
+// Tokens can be cloned to snapshot where we are, the parser can try one
+// parse on a clone and carry on from the original if it fails. Cloning is
+// cheap, nothing is copied but a couple of slices borrowed from the source.
+#[derive(Debug, Clone)]
 pub struct Tokens<'src, I> {
     iter: I,
     leftover_slice: Option<&'src str>,
+    lossless: bool,
 }
 
 impl<'src, I> Iterator for Tokens<'src, I>
@@ -174,24 +479,94 @@ where
     type Item = Token<'src>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_slice().map(|(token, _slice)| token)
+    }
+}
+
+impl<'src, I> Tokens<'src, I>
+where
+    I: Iterator<Item = &'src str>,
+{
+    // The next token, along with the slice of the source it was lexed from
+    fn next_with_slice(&mut self) -> Option<(Token<'src>, &'src str)> {
         let mut slice = self.leftover_slice.take().or_else(|| self.iter.next())?;
 
+        while slice.starts_with('#') || slice.starts_with("/*") {
+            // Never closed, and so an error, even when comments are skipped
+            if slice.starts_with("/*") && block_comment_len(slice).is_none() {
+                return Some((Token::Unknown(slice), slice));
+            }
+
+            if self.lossless {
+                return Some((Token::Comment(slice), slice));
+            }
+
+            slice = self.iter.next()?;
+        }
+
+        // Backtick escaped identifiers, everything up to the closing backtick
+        // is taken verbatim as the name, so keywords like `if` can be used as
+        // identifiers. Empty or unterminated quotes are unknown tokens.
+        if let Some(unquoted) = slice.strip_prefix('`') {
+            let Some(end) = unquoted.find('`') else {
+                return Some((Token::Unknown(slice), slice));
+            };
+
+            let (quoted, rest) = slice.split_at(end + 2);
+
+            if !rest.is_empty() {
+                self.leftover_slice.replace(rest);
+            }
+
+            return match &quoted[1..quoted.len() - 1] {
+                "" => Some((Token::Unknown(quoted), quoted)),
+                name => Some((Token::Identifier(name), quoted)),
+            };
+        }
+
+        // Chunks always hands strings over whole, so the quotes are at either end
+        if slice.starts_with('"') {
+            let token = match slice.len() > 1 && slice.ends_with('"') {
+                true => Token::Str(&slice[1..slice.len() - 1]),
+                false => Token::Unknown(slice),
+            };
+
+            return Some((token, slice));
+        }
+
         if slice.len() > 1 {
-            if let Some(pos) = slice.find(Token::is_single_char_token) {
+            if let Some(pos) = split_point(slice) {
                 if pos != 0 {
                     let (immed, rest) = slice.split_at(pos);
                     slice = immed;
                     self.leftover_slice.replace(rest);
                 } else {
-                    let (immed, rest) = slice.split_at(1);
+                    let (immed, rest) = slice.split_at(operator_len(slice));
                     slice = immed;
-                    self.leftover_slice.replace(rest);
+
+                    if !rest.is_empty() {
+                        self.leftover_slice.replace(rest);
+                    }
                 }
             }
         }
 
-        Some(tokenize(slice))
+        Some((tokenize(slice), slice))
+    }
+}
+
+// Every token along with the byte range of the source it came from, comments
+// included, for tools like editors that need to know exactly where each one is
+pub fn lex_all_spanned(src_code: &str) -> Vec<(Token<'_>, Range<usize>)> {
+    let mut tokens = src_code.lex_lossless();
+    let mut spanned = vec![];
+
+    while let Some((token, slice)) = tokens.next_with_slice() {
+        let span = Span::of(src_code, slice);
+        spanned.push((token, span.start..span.end));
     }
+
+    spanned
 }
 
 // We can apply this trait to produce the iterator for
@@ -199,13 +574,25 @@ where
 // source code we can.
 // let source_code = read_source_code();
 // let tokens: Vec<Token> = source_code.lex().collect()
+//
+// Use lex_lossless instead to also get comments back as tokens,
+// for tools like the formatter that must not throw them away.
 pub trait Lex {
-    fn lex(&self) -> Tokens<SplitWhitespace>;
+    fn lex(&self) -> Tokens<'_, Chunks<'_>>;
+
+    fn lex_lossless(&self) -> Tokens<'_, Chunks<'_>>;
 }
 
 impl Lex for str {
-    fn lex(&self) -> Tokens<SplitWhitespace> {
-        Tokens::new(self.split_whitespace())
+    fn lex(&self) -> Tokens<'_, Chunks<'_>> {
+        Tokens::new(Chunks { rest: self })
+    }
+
+    fn lex_lossless(&self) -> Tokens<'_, Chunks<'_>> {
+        Tokens {
+            lossless: true,
+            ..Tokens::new(Chunks { rest: self })
+        }
     }
 }
 
@@ -214,6 +601,7 @@ impl<'src, I> Tokens<'src, I> {
         Self {
             iter,
             leftover_slice: None,
+            lossless: false,
         }
     }
 }
@@ -256,6 +644,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lexing_unicode_identifiers() {
+        let input = " π naïve Δx_1 ";
+
+        assert_eq!(
+            input.lex().collect::<Vec<Token>>(),
+            vec![Identifier("π"), Identifier("naïve"), Identifier("Δx_1")]
+        );
+
+        let input = " 😀 x😀 ";
+
+        assert_eq!(
+            input.lex().collect::<Vec<Token>>(),
+            vec![Unknown("😀"), Unknown("x😀")]
+        );
+    }
+
     #[test]
     fn lexing_operators() {
         let input = " + - * / ";
@@ -349,6 +754,158 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lexing_escaped_identifiers() {
+        let mut input = " `if` + `then`(`x`) ";
+        let mut tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                Identifier("if"),
+                Operator(Plus),
+                Identifier("then"),
+                OpenParen,
+                Identifier("x"),
+                ClosedParen,
+            ]
+        );
+
+        // Missing closing backtick
+        input = " `if + 2 ";
+        tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![Unknown("`if"), Operator(Plus), Number(2.0)]
+        );
+    }
+
+    #[test]
+    fn lexing_function_attributes() {
+        let input = " def [inline,cold] f(x) ";
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                FuncDef,
+                OpenBracket,
+                Identifier("inline"),
+                Comma,
+                Identifier("cold"),
+                ClosedBracket,
+                Identifier("f"),
+                OpenParen,
+                Identifier("x"),
+                ClosedParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_comments() {
+        let input = "# a comment\ndef f(x) # another\n  x+1#no space\n";
+
+        assert_eq!(
+            input.lex().collect::<Vec<Token>>(),
+            vec![
+                FuncDef,
+                Identifier("f"),
+                OpenParen,
+                Identifier("x"),
+                ClosedParen,
+                Identifier("x"),
+                Operator(Plus),
+                Number(1.0),
+            ]
+        );
+
+        assert_eq!(
+            input.lex_lossless().collect::<Vec<Token>>(),
+            vec![
+                Comment("# a comment"),
+                FuncDef,
+                Identifier("f"),
+                OpenParen,
+                Identifier("x"),
+                ClosedParen,
+                Comment("# another"),
+                Identifier("x"),
+                Operator(Plus),
+                Number(1.0),
+                Comment("#no space"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_block_comments() {
+        assert_eq!(
+            "x /* half */ * 2".lex().collect::<Vec<Token>>(),
+            vec![Identifier("x"), Operator(Mult), Number(2.0)]
+        );
+
+        // Spanning lines, and stuck to the tokens around it
+        let input = "def f(x)/* the\n  body */x+1";
+
+        assert_eq!(
+            input.lex_lossless().collect::<Vec<Token>>(),
+            vec![
+                FuncDef,
+                Identifier("f"),
+                OpenParen,
+                Identifier("x"),
+                ClosedParen,
+                Comment("/* the\n  body */"),
+                Identifier("x"),
+                Operator(Plus),
+                Number(1.0),
+            ]
+        );
+
+        // The lines it spans still count for where the next token is
+        let (_, range) = lex_all_spanned(input)[6].clone();
+        assert_eq!(range, 24..25);
+        assert_eq!(input[..range.start].matches('\n').count(), 1);
+
+        assert_eq!(
+            "/* a /* b */ c # d */ 1"
+                .lex_lossless()
+                .collect::<Vec<Token>>(),
+            vec![Comment("/* a /* b */ c # d */"), Number(1.0)]
+        );
+
+        // The inner one is closed, the outer never is
+        assert_eq!(
+            "1 /* a /* b */ c\n2".lex().collect::<Vec<Token>>(),
+            vec![Number(1.0), Unknown("/* a /* b */ c\n2")]
+        );
+    }
+
+    #[test]
+    fn lexing_variadic_prototypes() {
+        let input = " extern printf(fmt, ...) extern f(...) ";
+
+        assert_eq!(
+            input.lex().collect::<Vec<Token>>(),
+            vec![
+                Extern,
+                Identifier("printf"),
+                OpenParen,
+                Identifier("fmt"),
+                Comma,
+                Ellipsis,
+                ClosedParen,
+                Extern,
+                Identifier("f"),
+                OpenParen,
+                Ellipsis,
+                ClosedParen,
+            ]
+        );
+    }
+
     #[test]
     fn lexing_function_defs() {
         let mut input = " def myCalculation(arg1 arg2) ";
@@ -374,4 +931,212 @@ mod tests {
             vec![FuncDef, Identifier(&"noParamsCall"), OpenParen, ClosedParen,]
         );
     }
+
+    #[test]
+    fn cloning_tokens() {
+        let mut tokens = "def f(x) x + 1".lex().peekable();
+        assert_eq!(tokens.next(), Some(FuncDef));
+
+        // Advancing a snapshot leaves the original where it was
+        let mut snapshot = tokens.clone();
+        assert_eq!(snapshot.by_ref().count(), 7);
+        assert_eq!(snapshot.next(), None);
+
+        assert_eq!(tokens.next(), Some(Identifier("f")));
+        assert_eq!(tokens.count(), 6);
+    }
+
+    #[test]
+    fn lexing_with_spans() {
+        // 'π' takes two bytes, and 'é' two more
+        let input = "def π(x) `é` + 1; # done";
+
+        assert_eq!(
+            lex_all_spanned(input),
+            vec![
+                (FuncDef, 0..3),
+                (Identifier("π"), 4..6),
+                (OpenParen, 6..7),
+                (Identifier("x"), 7..8),
+                (ClosedParen, 8..9),
+                (Identifier("é"), 10..14),
+                (Operator(Plus), 15..16),
+                (Number(1.0), 17..18),
+                (Semicolon, 18..19),
+                (Comment("# done"), 20..26),
+            ]
+        );
+
+        for (_token, range) in lex_all_spanned(input) {
+            assert!(input.is_char_boundary(range.start) && input.is_char_boundary(range.end));
+        }
+    }
+
+    #[test]
+    fn lexing_malformed_numbers() {
+        assert_eq!(
+            "1.2.3 .5 2x inf NaN".lex().collect::<Vec<Token>>(),
+            vec![
+                Error("1.2.3"),
+                Number(0.5),
+                Error("2x"),
+                Identifier("inf"),
+                Identifier("NaN"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_exponents() {
+        assert_eq!(
+            "1e-3 2.5E+2-1e2 x-1 1e-x".lex().collect::<Vec<Token>>(),
+            vec![
+                Number(0.001),
+                Number(250.0),
+                Operator(Minus),
+                Number(100.0),
+                Identifier("x"),
+                Operator(Minus),
+                Number(1.0),
+                Error("1e"),
+                Operator(Minus),
+                Identifier("x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_hex_floats() {
+        assert_eq!(
+            "0x1.8p3 0X.8P1 0x1p-2+0xAp+1 0xfp0"
+                .lex()
+                .collect::<Vec<Token>>(),
+            vec![
+                Number(12.0),
+                Number(1.0),
+                Number(0.25),
+                Operator(Plus),
+                Number(20.0),
+                Number(15.0),
+            ]
+        );
+
+        // Bit for bit, there's no decimal rounding in between
+        assert_eq!(
+            "0x1.fffffffffffffp1023".lex().next(),
+            Some(Number(f64::MAX))
+        );
+        assert_eq!("0x1p-1074".lex().next(), Some(Number(f64::from_bits(1))));
+
+        assert_eq!(
+            "0x1.8 0xp1 0x1.gp2 0x1p".lex().collect::<Vec<Token>>(),
+            vec![
+                Error("0x1.8"),
+                Error("0xp1"),
+                Error("0x1.gp2"),
+                Error("0x1p"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_strings() {
+        assert_eq!(
+            "len(\"a b # c\")+\"\" \"open".lex().collect::<Vec<Token>>(),
+            vec![
+                Identifier("len"),
+                OpenParen,
+                Str("a b # c"),
+                ClosedParen,
+                Operator(Plus),
+                Str(""),
+                Unknown("\"open"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_compound_assignments() {
+        assert_eq!(
+            "x += 1; y%=x-=2 % 3".lex().collect::<Vec<Token>>(),
+            vec![
+                Identifier("x"),
+                CompoundAssign(Plus),
+                Number(1.0),
+                Semicolon,
+                Identifier("y"),
+                CompoundAssign(Mod),
+                Identifier("x"),
+                CompoundAssign(Minus),
+                Number(2.0),
+                Operator(Mod),
+                Number(3.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn munching_operators() {
+        assert_eq!("<=".lex().collect::<Vec<Token>>(), vec![Operator(Le)]);
+        assert_eq!(
+            "< =".lex().collect::<Vec<Token>>(),
+            vec![Operator(Lt), Operator(Assign)]
+        );
+
+        // Two chars are taken only when they spell an operator together
+        assert_eq!(
+            "x<=y==!z;a=-1".lex().collect::<Vec<Token>>(),
+            vec![
+                Identifier("x"),
+                Operator(Le),
+                Identifier("y"),
+                Operator(Eq),
+                Operator(Negate),
+                Identifier("z"),
+                Semicolon,
+                Identifier("a"),
+                Operator(Assign),
+                Operator(Minus),
+                Number(1.0),
+            ]
+        );
+
+        assert_eq!(
+            "a!=b>=c".lex().collect::<Vec<Token>>(),
+            vec![
+                Identifier("a"),
+                Operator(Neq),
+                Identifier("b"),
+                Operator(Ge),
+                Identifier("c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_le_and_ge() {
+        assert_eq!(
+            "a <= b".lex().collect::<Vec<Token>>(),
+            vec![Identifier("a"), Operator(Le), Identifier("b")]
+        );
+        assert_eq!(
+            "a >= b".lex().collect::<Vec<Token>>(),
+            vec![Identifier("a"), Operator(Ge), Identifier("b")]
+        );
+    }
+
+    #[test]
+    fn converting_operators() {
+        assert_eq!(Ops::try_from("+"), Ok(Plus));
+        assert_eq!(Ops::try_from("<="), Ok(Le));
+        assert_eq!(Ops::try_from('+'), Ok(Plus));
+
+        assert_eq!(Ops::try_from("@"), Err(UnknownOperator("@".to_string())));
+        assert_eq!(Ops::try_from('@'), Err(UnknownOperator("@".to_string())));
+
+        // Every operator converts back from its own spelling
+        for op in Ops::ALL {
+            assert_eq!(Ops::try_from(op.as_str()), Ok(op));
+        }
+    }
 }
@@ -0,0 +1,163 @@
+use alloc::{format, string::ToString, vec, vec::Vec};
+
+use unicode_ident::is_xid_continue;
+
+use crate::frontend::{
+    diagnostics::{Diagnostic, Span},
+    lexer::{lex_all_spanned, Token},
+};
+
+// Problems that show in the tokens alone, without parsing anything, for
+// editors to flag as soon as they're typed. Text the lexer couldn't make
+// sense of, and numbers that are broken, are errors. Numbers that are fine
+// but look like a typo, and indentation mixing tabs with spaces, are only
+// warnings.
+pub fn lint_tokens(src_code: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    for (token, range) in lex_all_spanned(src_code) {
+        let span = Span {
+            start: range.start,
+            end: range.end,
+        };
+
+        match token {
+            Token::Unknown(text) if text.starts_with('"') => diagnostics.push(Diagnostic::error(
+                "Unterminated string literal".to_string(),
+                span,
+            )),
+
+            Token::Unknown(text) if text.starts_with("/*") => diagnostics.push(Diagnostic::error(
+                "Unterminated block comment".to_string(),
+                span,
+            )),
+
+            // Pointing at the first character no name could have in it, the
+            // rest of the token is some identifier it's stuck to
+            Token::Unknown(text) => {
+                let (offset, stray) = text
+                    .char_indices()
+                    .find(|(_, c)| !is_xid_continue(*c))
+                    .unwrap_or((0, text.chars().next().unwrap_or(' ')));
+
+                let start = span.start + offset;
+
+                diagnostics.push(Diagnostic::error(
+                    format!("Stray character '{stray}'"),
+                    Span {
+                        start,
+                        end: start + stray.len_utf8(),
+                    },
+                ));
+            }
+
+            Token::Error(text) => diagnostics.push(Diagnostic::error(
+                format!("Malformed number literal {text}"),
+                span,
+            )),
+
+            Token::Number(_) => {
+                let text = &src_code[range];
+
+                if let Some(message) = suspicious_number(text) {
+                    diagnostics.push(Diagnostic::warning(format!("{message}: {text}"), span));
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    diagnostics.extend(mixed_indentation(src_code));
+    diagnostics.sort_by_key(|diag| diag.span.start);
+
+    diagnostics
+}
+
+fn suspicious_number(text: &str) -> Option<&'static str> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        return None;
+    }
+
+    let digits = text.split(['.', 'e', 'E']).next().unwrap_or(text);
+
+    if digits.len() > 1 && digits.starts_with('0') {
+        // C would read this as octal, we don't
+        Some("Leading zero in number, it is not octal")
+    } else if text.ends_with('.') {
+        Some("Number ends in a dot")
+    } else {
+        None
+    }
+}
+
+// Lines whose indentation has both tabs and spaces in it, which line up
+// differently from one editor to the next
+fn mixed_indentation(src_code: &str) -> Vec<Diagnostic> {
+    src_code
+        .split('\n')
+        .filter_map(|line| {
+            let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+
+            (indent.contains(' ') && indent.contains('\t')).then(|| {
+                Diagnostic::warning(
+                    "Indentation mixes tabs and spaces".to_string(),
+                    Span::of(src_code, indent),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::diagnostics::Severity;
+
+    fn messages(src: &str) -> Vec<(Severity, String, usize)> {
+        lint_tokens(src)
+            .into_iter()
+            .map(|diag| (diag.severity, diag.message, diag.span.start))
+            .collect()
+    }
+
+    #[test]
+    fn linting_tokens() {
+        assert!(lint_tokens("def f(x) x * 2.5 + 0x1p3; # fine @ in comments").is_empty());
+        assert!(lint_tokens("/* and @ in /* these */ */ 1;").is_empty());
+
+        assert_eq!(
+            messages("1;\n/* never /* closed */"),
+            vec![(Severity::Error, "Unterminated block comment".to_string(), 3)]
+        );
+
+        assert_eq!(
+            messages("x + @;\nf(a@b) + 1.2.3;"),
+            vec![
+                (Severity::Error, "Stray character '@'".to_string(), 4),
+                (Severity::Error, "Stray character '@'".to_string(), 10),
+                (
+                    Severity::Error,
+                    "Malformed number literal 1.2.3".to_string(),
+                    16
+                ),
+            ]
+        );
+
+        assert_eq!(
+            messages("def f(x)\n \tx + 007;"),
+            vec![
+                (
+                    Severity::Warning,
+                    "Indentation mixes tabs and spaces".to_string(),
+                    9
+                ),
+                (
+                    Severity::Warning,
+                    "Leading zero in number, it is not octal: 007".to_string(),
+                    15
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use alloc::collections::VecDeque;
+
+use crate::frontend::lexer::Token;
+
+// What the parser reads tokens through, like Peekable, but able to look
+// further than the one token ahead, up to however many it was made with.
+// Tokens are pulled from the lexer only as far as something has looked,
+// the ones looked at but not yet taken wait in the buffer.
+//
+// The grammar is still LL(1) almost everywhere, calls included, they're a
+// postfix on whatever expression came before. The extra token is for the
+// few spots where what comes after the next one decides the production, like
+// "extern void f(x)", where void is only a qualifier if a name follows it.
+#[derive(Debug, Clone)]
+pub struct LookaheadBuffer<'src, I> {
+    iter: I,
+    buffer: VecDeque<Token<'src>>,
+    capacity: usize,
+    preserve_parens: bool, // The one option about the tree, not the tokens, see ParserOptions
+}
+
+pub const DEFAULT_LOOKAHEAD: usize = 2;
+
+impl<'src, I> LookaheadBuffer<'src, I>
+where
+    I: Iterator<Item = Token<'src>>,
+{
+    pub fn new(iter: I) -> Self {
+        Self::with_capacity(iter, DEFAULT_LOOKAHEAD)
+    }
+
+    pub fn with_capacity(iter: I, capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "FATAL: a lookahead buffer must hold at least one token"
+        );
+
+        Self {
+            iter,
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            preserve_parens: false,
+        }
+    }
+
+    // Whether the parser keeps the parens it reads as ParenExprs
+    pub fn preserving_parens(mut self, preserve: bool) -> Self {
+        self.preserve_parens = preserve;
+        self
+    }
+
+    pub fn preserves_parens(&self) -> bool {
+        self.preserve_parens
+    }
+
+    pub fn peek(&mut self) -> Option<&Token<'src>> {
+        self.peek_n(0)
+    }
+
+    // The token n ahead of the next one, so peek_n(0) is peek(). Looking past
+    // the capacity is a bug in the parser, not in what's being parsed.
+    pub fn peek_n(&mut self, n: usize) -> Option<&Token<'src>> {
+        assert!(
+            n < self.capacity,
+            "FATAL: peeked {} ahead with a lookahead of only {}",
+            n + 1,
+            self.capacity
+        );
+
+        while self.buffer.len() <= n {
+            self.buffer.push_back(self.iter.next()?);
+        }
+
+        self.buffer.get(n)
+    }
+
+    pub fn next_if(&mut self, func: impl FnOnce(&Token<'src>) -> bool) -> Option<Token<'src>> {
+        if func(self.peek()?) {
+            self.next()
+        } else {
+            None
+        }
+    }
+
+    // How many tokens have been pulled from the lexer, but not yet taken
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Runs one parse with f, like parse_expression, and hands back the tokens
+    // it left along with what it gave, peeked ones included. Nothing has to
+    // be left, or not, so a driver can parse a statement at a time:
+    //
+    // let (expr, rest) = "1; 2".lex().lookahead().parse_with_remainder(parse_expression);
+    pub fn parse_with_remainder<T>(mut self, f: impl FnOnce(&mut Self) -> T) -> (T, Self) {
+        let parsed = f(&mut self);
+
+        (parsed, self)
+    }
+}
+
+impl<'src, I> Iterator for LookaheadBuffer<'src, I>
+where
+    I: Iterator<Item = Token<'src>>,
+{
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buffer.pop_front().or_else(|| self.iter.next())
+    }
+}
+
+// So any token iterator can be wrapped as easily as with .peekable()
+pub trait Lookahead<'src>: Iterator<Item = Token<'src>> + Sized {
+    fn lookahead(self) -> LookaheadBuffer<'src, Self> {
+        LookaheadBuffer::new(self)
+    }
+}
+
+impl<'src, I: Iterator<Item = Token<'src>>> Lookahead<'src> for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::lexer::{Lex, Ops};
+
+    #[test]
+    fn peeking_ahead() {
+        let mut tokens = "f(x) + y".lex().lookahead();
+
+        assert_eq!(tokens.peek_n(1), Some(&Token::OpenParen));
+        assert_eq!(tokens.peek(), Some(&Token::Identifier("f")));
+        assert_eq!(tokens.buffered(), 2);
+
+        // Taking tokens goes through the buffer first, in order
+        assert_eq!(tokens.next(), Some(Token::Identifier("f")));
+        assert_eq!(tokens.next(), Some(Token::OpenParen));
+
+        assert_eq!(tokens.next_if(|t| matches!(t, Token::Comma)), None);
+        assert_eq!(tokens.next(), Some(Token::Identifier("x")));
+
+        assert_eq!(tokens.peek_n(1), Some(&Token::Operator(Ops::Plus)));
+        assert_eq!(tokens.by_ref().count(), 3);
+        assert_eq!(tokens.peek_n(1), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn peeking_past_capacity() {
+        let mut tokens = LookaheadBuffer::with_capacity("a b c".lex(), 2);
+        tokens.peek_n(2);
+    }
+}
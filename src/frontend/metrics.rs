@@ -0,0 +1,169 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+
+use crate::frontend::ast::ASTExpr;
+
+// Some simple measures of how big and complicated an expression is, for
+// checking a transform shrank the tree, or refusing programs that are too
+// much. Every node counts as one, whatever kind it is, the depth of a lone
+// leaf is one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AstMetrics {
+    pub node_count: usize,
+    pub max_depth: usize,
+    pub call_count: usize,
+}
+
+pub fn ast_metrics(node: &ASTExpr) -> AstMetrics {
+    let mut metrics = AstMetrics::default();
+    tally(node, 1, &mut metrics);
+
+    metrics
+}
+
+// How many nodes of each kind there are in the tree, by ASTExpr::kind,
+// kinds that don't appear at all are left out
+pub fn count_kinds(node: &ASTExpr, kinds: &mut BTreeMap<&'static str, usize>) {
+    let mut stack = vec![node];
+
+    while let Some(node) = stack.pop() {
+        *kinds.entry(node.kind()).or_insert(0) += 1;
+        stack.extend(node.children());
+    }
+}
+
+fn tally(node: &ASTExpr, depth: usize, metrics: &mut AstMetrics) {
+    use ASTExpr::*;
+
+    metrics.node_count += 1;
+    metrics.max_depth = metrics.max_depth.max(depth);
+
+    let depth = depth + 1;
+
+    match node {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        UnaryExpr { operand, .. } | ParenExpr(operand) => tally(operand, depth, metrics),
+
+        BinaryExpr { left, right, .. } => {
+            tally(left, depth, metrics);
+            tally(right, depth, metrics);
+        }
+
+        CallExpr { args, .. } => {
+            metrics.call_count += 1;
+            args.iter().for_each(|arg| tally(arg, depth, metrics));
+        }
+
+        IndirectCallExpr { callee, args } => {
+            metrics.call_count += 1;
+            tally(callee, depth, metrics);
+            args.iter().for_each(|arg| tally(arg, depth, metrics));
+        }
+
+        ArrayExpr(elems) => elems.iter().for_each(|elem| tally(elem, depth, metrics)),
+
+        IndexExpr { base, index } => {
+            tally(base, depth, metrics);
+            tally(index, depth, metrics);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            tally(cond, depth, metrics);
+            tally(then_branch, depth, metrics);
+
+            for (elif_cond, elif_branch) in elifs.iter() {
+                tally(elif_cond, depth, metrics);
+                tally(elif_branch, depth, metrics);
+            }
+
+            tally(else_branch, depth, metrics);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            tally(start, depth, metrics);
+            tally(end, depth, metrics);
+            tally(step, depth, metrics);
+            tally(body, depth, metrics);
+        }
+
+        WhileExpr { cond, body } => {
+            tally(cond, depth, metrics);
+            tally(body, depth, metrics);
+        }
+
+        VarExpr { var_names, body } => {
+            var_names
+                .iter()
+                .filter_map(|(_, init)| init.as_ref())
+                .for_each(|init| tally(init, depth, metrics));
+
+            tally(body, depth, metrics);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{
+        lexer::Lex, lookahead::Lookahead, parser::parse_top_level_expr, transform::fold_constants,
+    };
+
+    #[test]
+    fn measuring_trees() {
+        let mut func = parse_top_level_expr(&mut "2 + 3 * 4;".lex().lookahead()).unwrap();
+
+        assert_eq!(
+            ast_metrics(&func.body),
+            AstMetrics {
+                node_count: 5,
+                max_depth: 3,
+                call_count: 0,
+            }
+        );
+
+        fold_constants(&mut func);
+        assert_eq!(ast_metrics(&func.body).node_count, 1);
+
+        let func = parse_top_level_expr(&mut "f(g(1), x) + 1;".lex().lookahead()).unwrap();
+
+        assert_eq!(
+            ast_metrics(&func.body),
+            AstMetrics {
+                node_count: 6,
+                max_depth: 4,
+                call_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn counting_kinds() {
+        let func = parse_top_level_expr(&mut "f(g(1), x) + 1;".lex().lookahead()).unwrap();
+
+        let mut kinds = BTreeMap::new();
+        count_kinds(&func.body, &mut kinds);
+
+        assert_eq!(
+            kinds.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("BinaryExpr", 1),
+                ("CallExpr", 2),
+                ("NumberExpr", 2),
+                ("VariableExpr", 1)
+            ]
+        );
+    }
+}
@@ -1,3 +1,17 @@
+// The lexer and parser, and what they need, only rely on alloc, the rest
+// of the frontend leans on the backend or on std for its output
 pub mod ast;
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod dot;
+#[cfg(feature = "std")]
+pub mod formatter;
 pub mod lexer;
+pub mod lint;
+pub mod lookahead;
+pub mod metrics;
 pub mod parser;
+#[cfg(feature = "std")]
+pub mod sema;
+#[cfg(feature = "std")]
+pub mod transform;
@@ -1,106 +1,709 @@
-use std::collections::HashMap;
-use std::iter::Peekable;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt::Write;
+use core::ops::Deref;
 
-use mut_static::MutStatic;
+use spin::RwLock;
 use thiserror::Error;
 
 use crate::frontend::{
     ast::*,
-    lexer::{Ops, Token},
+    lexer::{is_identifier, lex_all_spanned, Ops, Token},
+    lookahead::{Lookahead, LookaheadBuffer},
 };
 
+// Where an operator may appear, in front of its operand (prefix), between
+// two of them (infix), or either, like minus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixity {
+    Prefix,
+    Infix,
+    Both,
+}
+
+impl Fixity {
+    pub fn allows_prefix(self) -> bool {
+        matches!(self, Fixity::Prefix | Fixity::Both)
+    }
+
+    pub fn allows_infix(self) -> bool {
+        matches!(self, Fixity::Infix | Fixity::Both)
+    }
+
+    // An operator overloaded both ways ends up usable both ways
+    fn merge(self, other: Fixity) -> Fixity {
+        if self == other {
+            self
+        } else {
+            Fixity::Both
+        }
+    }
+}
+
+// Which way a chain of binary operators of the same precedence groups,
+// "a - b - c" is "(a - b) - c" as minus is left associative. Right
+// associative ones group the other way, "a ^ b ^ c" being "a ^ (b ^ c)".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+impl Assoc {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Assoc::Left => "left",
+            Assoc::Right => "right",
+        }
+    }
+}
+
+// What we know about an operator, prefix only operators have no use for a
+// precedence, theirs is -1 so they never bind anything as a binary operator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpInfo {
+    pub precedence: i32,
+    pub fixity: Fixity,
+    pub assoc: Assoc,
+}
+
+impl OpInfo {
+    fn infix(precedence: i32) -> Self {
+        Self {
+            precedence,
+            fixity: Fixity::Infix,
+            assoc: Assoc::Left,
+        }
+    }
+
+    fn both(precedence: i32) -> Self {
+        Self {
+            fixity: Fixity::Both,
+            ..Self::infix(precedence)
+        }
+    }
+}
+
+// Ways registering an operator can go wrong, before the table is touched
+#[derive(Error, PartialEq, Debug)]
+pub enum OpError {
+    #[error("An operator needs a symbol")]
+    EmptySymbol,
+
+    #[error("{0} is no operator, only those the lexer knows can be registered")]
+    UnknownOperator(String),
+
+    #[error("Precedence {0} is out of range, it must be from 1 to {MAX_PRECEDENCE}")]
+    PrecedenceOutOfRange(i32),
+
+    #[error("Operator {op} is builtin, its precedence can only be changed with --allow-override", op = .0.as_str())]
+    BuiltinOverride(Ops),
+
+    // Otherwise "a op b other c" would group both ways at once
+    #[error("Operator {op} can't be {assoc} associative, {other} has the same precedence but is not", op = .op.as_str(), assoc = .assoc.as_str(), other = .other.as_str())]
+    MixedAssociativity { op: Ops, assoc: Assoc, other: Ops },
+}
+
+// The operators the language defines itself, the rest have no meaning
+// until a program overloads them
+fn is_builtin(operator: Ops) -> bool {
+    use Ops::*;
+
+    matches!(
+        operator,
+        Plus | Minus | Mult | Div | Mod | Eq | Neq | Lt | Gt | Le | Ge | Assign
+    )
+}
+
+// The operators and what we know of each, see OP_PRECEDENCE. Reading it is
+// just reading the map, registering goes through here to keep it consistent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperatorTable {
+    ops: BTreeMap<Ops, OpInfo>,
+
+    // Lets a registration change the precedence or associativity of a builtin
+    pub allow_override: bool,
+}
+
+impl OperatorTable {
+    fn builtin() -> Self {
+        let mut ops = BTreeMap::new();
+        ops.insert(Ops::Assign, OpInfo::infix(2));
+        ops.insert(Ops::Plus, OpInfo::both(20));
+        ops.insert(Ops::Minus, OpInfo::both(20));
+        ops.insert(Ops::Mult, OpInfo::infix(40));
+        ops.insert(Ops::Div, OpInfo::infix(40));
+        ops.insert(Ops::Mod, OpInfo::infix(40));
+        ops.insert(Ops::Eq, OpInfo::infix(50));
+        ops.insert(Ops::Neq, OpInfo::infix(50));
+        ops.insert(Ops::Gt, OpInfo::infix(50));
+        ops.insert(Ops::Lt, OpInfo::infix(50));
+        ops.insert(Ops::Le, OpInfo::infix(50));
+        ops.insert(Ops::Ge, OpInfo::infix(50));
+
+        // Power, like in math, "2 ^ 3 ^ 2" is "2 ^ (3 ^ 2)". Binding tighter
+        // than any overload can, none ever shares its precedence, so none
+        // has to be right associative to go with it.
+        let pow = OpInfo::infix(POW_PRECEDENCE);
+        ops.insert(
+            Ops::Xor,
+            OpInfo {
+                assoc: Assoc::Right,
+                ..pow
+            },
+        );
+
+        Self {
+            ops,
+            allow_override: false,
+        }
+    }
+
+    // Registers a binary operator, for hosts setting up the language before
+    // parsing anything, the same as a "def binary" overload does:
+    //
+    // OP_PRECEDENCE.write().register_operator("^", 60, Assoc::Right)?;
+    //
+    // Registering an operator again replaces its precedence and associativity.
+    pub fn register_operator(
+        &mut self,
+        sym: &str,
+        precedence: i32,
+        assoc: Assoc,
+    ) -> Result<(), OpError> {
+        if sym.is_empty() {
+            return Err(OpError::EmptySymbol);
+        }
+
+        let operator = Ops::try_from(sym).map_err(|unknown| OpError::UnknownOperator(unknown.0))?;
+
+        self.register_binary(operator, precedence, assoc)
+    }
+
+    // A "def binary" overload, always left associative. Overloading ^ would
+    // take power away from every program parsed after, so like changing a
+    // builtin that takes --allow-override, hosts can still register it.
+    fn register_overload(&mut self, operator: Ops, precedence: i32) -> Result<(), OpError> {
+        if operator == Ops::Xor && !self.allow_override {
+            return Err(OpError::BuiltinOverride(operator));
+        }
+
+        self.register_binary(operator, precedence, Assoc::Left)
+    }
+
+    fn register_binary(
+        &mut self,
+        operator: Ops,
+        precedence: i32,
+        assoc: Assoc,
+    ) -> Result<(), OpError> {
+        if !(1..=MAX_PRECEDENCE).contains(&precedence) {
+            return Err(OpError::PrecedenceOutOfRange(precedence));
+        }
+
+        // Registering a builtin just as it already is changes nothing
+        if let Some(info) = self.ops.get(&operator) {
+            let changed = info.precedence != precedence || info.assoc != assoc;
+
+            if is_builtin(operator) && changed && !self.allow_override {
+                return Err(OpError::BuiltinOverride(operator));
+            }
+        }
+
+        let clash = self.ops.iter().find(|(other, info)| {
+            **other != operator
+                && info.fixity.allows_infix()
+                && info.precedence == precedence
+                && info.assoc != assoc
+        });
+
+        if let Some((other, _)) = clash {
+            return Err(OpError::MixedAssociativity {
+                op: operator,
+                assoc,
+                other: *other,
+            });
+        }
+
+        let info = self.record(operator, Fixity::Infix);
+        info.precedence = precedence;
+        info.assoc = assoc;
+
+        Ok(())
+    }
+
+    // Puts what we know of an operator back to info, from before something
+    // that registered it, none meaning it wasn't there at all
+    pub(crate) fn restore(&mut self, operator: Ops, info: Option<OpInfo>) {
+        match info {
+            Some(info) => self.ops.insert(operator, info),
+            None => self.ops.remove(&operator),
+        };
+    }
+
+    // Records an overload of an operator, keeping whatever fixity it already had
+    fn record(&mut self, operator: Ops, fixity: Fixity) -> &mut OpInfo {
+        let info = self.ops.entry(operator).or_insert(OpInfo {
+            precedence: -1,
+            fixity,
+            assoc: Assoc::Left,
+        });
+
+        info.fixity = info.fixity.merge(fixity);
+        info
+    }
+}
+
+impl Deref for OperatorTable {
+    type Target = BTreeMap<Ops, OpInfo>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ops
+    }
+}
+
 // One of the few global variables I will use here, where the
-// tutorial uses many. This is just a hash table of operators
+// tutorial uses many. This is just a table of operators
 // to their precedence, used in binorph parsing. In the C++
-// tutorial, this variable is called "BinopPrecedence"
+// tutorial, this variable is called "BinopPrecedence". It also
+// records the fixity of each, overloads add to it as they're parsed,
+// an operator missing from it has never been defined at all.
+// Kept sorted by operator, so anything walking it sees the same order
+// every run, whatever order the overloads were registered in.
 lazy_static! {
-    pub static ref OP_PRECEDENCE: MutStatic<HashMap<Ops, i32>> = {
-        let mut map = HashMap::new();
-        map.insert(Ops::Assign, 2);
-        map.insert(Ops::Plus, 20);
-        map.insert(Ops::Minus, 20);
-        map.insert(Ops::Mult, 40);
-        map.insert(Ops::Div, 40);
-        map.insert(Ops::Eq, 50);
-        map.insert(Ops::Neq, 50);
-        map.insert(Ops::Gt, 50);
-        map.insert(Ops::Lt, 50);
-        map.into()
-    };
+    pub static ref OP_PRECEDENCE: RwLock<OperatorTable> = RwLock::new(OperatorTable::builtin());
+}
+
+// The highest precedence an overload can have, anything above is rejected
+pub const MAX_PRECEDENCE: i32 = 1000;
+
+// That of ^, power, above anything an overload can have
+pub const POW_PRECEDENCE: i32 = MAX_PRECEDENCE + 1;
+
+// The operator table as text, one operator a line, for debugging overloads
+pub fn dump_precedence() -> String {
+    dump_precedence_table(&OP_PRECEDENCE.read())
+}
+
+fn dump_precedence_table(table: &BTreeMap<Ops, OpInfo>) -> String {
+    let mut out = String::new();
+
+    for (op, info) in table.iter() {
+        writeln!(
+            out,
+            "{:<2} {:>3} {:?}",
+            op.as_str(),
+            info.precedence,
+            info.fixity
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+// The operator table for people learning it, loosest binding last, like:
+//
+// operator  precedence  associativity
+// *                 40  left
+// +                 20  left
+//
+// Binary operators are left associative, "a - b - c" is "(a - b) - c",
+// unless registered otherwise. Prefix only operators don't bind as binary
+// ones at all, so they come last, with no precedence.
+pub fn show_precedence() -> String {
+    show_precedence_table(&OP_PRECEDENCE.read())
+}
+
+fn show_precedence_table(table: &BTreeMap<Ops, OpInfo>) -> String {
+    let mut ops: Vec<_> = table.iter().collect();
+
+    // Stable, so operators of the same precedence stay in the table's order
+    ops.sort_by_key(|(_, info)| match info.fixity.allows_infix() {
+        true => -(info.precedence as i64),
+        false => i64::MAX,
+    });
+
+    let mut out = String::from("operator  precedence  associativity\n");
+
+    for (op, info) in ops {
+        let (precedence, associativity) = match info.fixity.allows_infix() {
+            true => (info.precedence.to_string(), info.assoc.as_str()),
+            false => (String::from("-"), "prefix"),
+        };
+
+        writeln!(out, "{:<8}  {precedence:>10}  {associativity}", op.as_str()).unwrap();
+    }
+
+    out
+}
+
+// Just f64::ceil, which lives in std rather than core, so done by hand
+fn round_up(precedence: f64) -> i32 {
+    let truncated = precedence as i32;
+
+    if (truncated as f64) < precedence {
+        truncated.saturating_add(1)
+    } else {
+        truncated
+    }
+}
+
+fn fixity_of(operator: Ops) -> Option<Fixity> {
+    OP_PRECEDENCE.read().get(&operator).map(|info| info.fixity)
+}
+
+fn assoc_of(operator: Ops) -> Assoc {
+    OP_PRECEDENCE
+        .read()
+        .get(&operator)
+        .map_or(Assoc::Left, |info| info.assoc)
 }
 
 // Few errors here to character what went wrong during the
 // parsing process.
 #[derive(Error, PartialEq, Debug)]
 pub enum ParserError<'src> {
-    #[error("Unexpected token: {0:?}")]
+    #[error("error[{code}]: Unexpected token: {0:?}", code = self.code())]
     UnexpectedToken(Token<'src>),
 
-    #[error("Reached end of input expecting more")]
-    UnexpectedEOI,
+    // What could have come next, where the input ended
+    #[error("error[{code}]: Reached end of input expecting {list}", list = .expected.join(" or "), code = self.code())]
+    UnexpectedEOI { expected: Vec<&'static str> },
 
-    #[error("Expected token: {0:?}")]
+    #[error("error[{code}]: Expected token: {0:?}", code = self.code())]
     ExpectedToken(&'static str),
 
-    #[error("Unary operator signatures need one argument")]
+    #[error("error[{code}]: Unary operator signatures need one argument", code = self.code())]
     BadOverloadedUnaryOp,
 
-    #[error("Binary operator signatures require two arguments & positive number for precedence")]
+    #[error("error[{code}]: Binary operator signatures require two arguments & positive number for precedence", code = self.code())]
     BadOverloadedBinaryOp,
+
+    #[error("error[{code}]: Unknown function attribute: {0}", code = self.code())]
+    UnknownAttribute(&'src str),
+
+    #[error("error[{code}]: Invalid number literal: {0}", code = self.code())]
+    InvalidNumber(&'src str),
+
+    #[error("error[{code}]: Parameter {0} given more than once", code = self.code())]
+    DuplicateParameter(&'src str),
+
+    #[error("error[{code}]: Operator {op} is binary only, it can't be used as a prefix", op = .0.as_str(), code = self.code())]
+    NotAPrefixOperator(Ops),
+
+    #[error("error[{code}]: Operator {op} is prefix only, it can't be used as a binary operator", op = .0.as_str(), code = self.code())]
+    NotABinaryOperator(Ops),
+
+    #[error("error[{code}]: Only a variable can be assigned to with {op}=", op = .0.as_str(), code = self.code())]
+    BadCompoundAssignment(Ops),
+
+    #[error("error[{code}]: {0}", code = self.code())]
+    BadOperatorRegistration(OpError),
+
+    #[error("error[{code}]: Function attributes {first} and {second} can't be given together", first = .0.as_str(), second = .1.as_str(), code = self.code())]
+    ConflictingAttributes(FnAttr, FnAttr),
+
+    #[error("error[{code}]: Operator {op} has no precedence, it needs a def binary{op} first", op = .0.as_str(), code = self.code())]
+    UndefinedBinaryOperator(Ops),
+
+    #[error("error[{code}]: Offset {0} is past the end of the source, or in the middle of a character", code = self.code())]
+    BadOffset(usize),
+}
+
+impl ParserError<'_> {
+    fn eoi(expected: &[&'static str]) -> Self {
+        ParserError::UnexpectedEOI {
+            expected: expected.to_vec(),
+        }
+    }
+
+    // A stable code for each kind of error, for tools to match on, the
+    // messages themselves are free to change
+    pub fn code(&self) -> &'static str {
+        use ParserError::*;
+
+        match self {
+            UnexpectedToken(_) => "E0001",
+            UnexpectedEOI { .. } => "E0002",
+            ExpectedToken(_) => "E0003",
+            BadOverloadedUnaryOp => "E0004",
+            BadOverloadedBinaryOp => "E0005",
+            UnknownAttribute(_) => "E0006",
+            InvalidNumber(_) => "E0007",
+            DuplicateParameter(_) => "E0008",
+            NotAPrefixOperator(_) => "E0009",
+            NotABinaryOperator(_) => "E0010",
+            BadCompoundAssignment(_) => "E0011",
+            BadOperatorRegistration(_) => "E0012",
+            ConflictingAttributes(..) => "E0013",
+            UndefinedBinaryOperator(_) => "E0014",
+            BadOffset(_) => "E0015",
+        }
+    }
+}
+
+// Ways the language can be bent for a particular use, all off by default.
+// They're applied to the tokens on their way in, before any lookahead:
+//
+// let mut tokens = options.lookahead(src.lex());
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParserOptions {
+    // For math heavy code, a number right before an identifier or an open
+    // paren multiplies it, so "2x" and "3(x + 1)" are "2 * x" and
+    // "3 * (x + 1)", with the same precedence as if the "*" were written
+    pub implicit_mult: bool,
+
+    // Keep "(a + b)" as a ParenExpr around the a + b, rather than just the
+    // a + b, for tools like the formatter that give back what was written.
+    // Nothing else cares, a ParenExpr evaluates to whatever is inside it.
+    pub preserve_parens: bool,
+}
+
+impl ParserOptions {
+    // The tokens with the options applied, ready for the parser, which is
+    // told about preserve_parens through the buffer
+    pub fn lookahead<'src, I>(self, tokens: I) -> LookaheadBuffer<'src, OptionTokens<'src, I>>
+    where
+        I: Iterator<Item = Token<'src>>,
+    {
+        self.apply(tokens)
+            .lookahead()
+            .preserving_parens(self.preserve_parens)
+    }
+
+    pub fn apply<'src, I>(self, tokens: I) -> OptionTokens<'src, I>
+    where
+        I: Iterator<Item = Token<'src>>,
+    {
+        OptionTokens {
+            iter: tokens,
+            options: self,
+            pending: VecDeque::new(),
+            after_number: false,
+            in_binary_proto: false,
+        }
+    }
+}
+
+// The tokens, with any the options imply added in. The parser never knows
+// the difference between a "*" written out and one put in here.
+#[derive(Debug, Clone)]
+pub struct OptionTokens<'src, I> {
+    iter: I,
+    options: ParserOptions,
+    pending: VecDeque<Token<'src>>,
+    after_number: bool,
+    in_binary_proto: bool, // The number in "def binary| 5 (a b)" is a precedence
+}
+
+impl<'src, I> Iterator for OptionTokens<'src, I>
+where
+    I: Iterator<Item = Token<'src>>,
+{
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.pending.pop_front().or_else(|| self.iter.next())?;
+
+        if !self.options.implicit_mult {
+            return Some(token);
+        }
+
+        // The lexer sees "2x" as a badly formed number, it's really two tokens
+        let token = match token {
+            Token::Error(text) => match split_coefficient(text) {
+                Some((num, name)) => {
+                    self.pending.push_back(Token::Identifier(name));
+                    Token::Number(num)
+                }
+                None => token,
+            },
+            _ => token,
+        };
+
+        if self.after_number && matches!(token, Token::Identifier(_) | Token::OpenParen) {
+            self.after_number = false;
+            self.pending.push_front(token);
+
+            return Some(Token::Operator(Ops::Mult));
+        }
+
+        self.after_number = match token {
+            Token::BinaryOverload => {
+                self.in_binary_proto = true;
+                false
+            }
+            Token::Number(_) => !core::mem::take(&mut self.in_binary_proto),
+            _ => false,
+        };
+
+        Some(token)
+    }
+}
+
+// The longest number the text starts with, and the identifier after it
+fn split_coefficient(text: &str) -> Option<(f64, &str)> {
+    text.char_indices()
+        .rev()
+        .filter(|(i, _)| *i > 0)
+        .find_map(|(i, _)| {
+            let (num, name) = text.split_at(i);
+            num.parse::<f64>()
+                .ok()
+                .filter(|_| is_identifier(name))
+                .map(|num| (num, name))
+        })
 }
 
-/// external ::= 'extern' prototype
+/// external ::= 'extern' 'void'? prototype
 pub fn parse_extern<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> Result<Box<Prototype<'src>>, ParserError<'src>> {
     // Swallow the 'extern' keyword, parse as prototype
     let _extern = tokens.next();
-    parse_prototype(tokens)
+
+    // "void" is only a qualifier when a name follows it, otherwise it's the
+    // name, so "extern void(x)" still declares a function called void
+    let void = matches!(tokens.peek(), Some(Token::Identifier("void")))
+        && matches!(tokens.peek_n(1), Some(Token::Identifier(_)));
+
+    if void {
+        let _void = tokens.next();
+    }
+
+    let mut proto = parse_prototype(tokens)?;
+
+    if let Prototype::FunctionProto { void: is_void, .. } = &mut *proto {
+        *is_void = void;
+    }
+
+    Ok(proto)
+}
+
+/// declaration ::= 'decl' prototype
+///
+/// A forward declaration, for calling a function before its definition,
+/// like mutually recursive ones. Unlike an extern, the function is one of
+/// the module's own, so it isn't exported.
+pub fn parse_decl<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> Result<Box<Prototype<'src>>, ParserError<'src>> {
+    let _decl = tokens.next();
+
+    let mut proto = parse_prototype(tokens)?;
+
+    if let Prototype::FunctionProto { decl, .. } = &mut *proto {
+        *decl = true;
+    }
+
+    Ok(proto)
+}
+
+// Swallows the next token if it's the one expected, pred tells. Where the
+// input ended instead, it's UnexpectedEOI, so the REPL waits on more lines.
+fn expect_token<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+    expected: &'static str,
+    pred: impl FnOnce(&Token<'src>) -> bool,
+) -> Result<Token<'src>, ParserError<'src>> {
+    match tokens.peek() {
+        Some(_) => tokens
+            .next_if(pred)
+            .ok_or(ParserError::ExpectedToken(expected)),
+        None => Err(ParserError::eoi(&[expected])),
+    }
+}
+
+/// constdef ::= 'const' id '=' expression
+///
+/// Whether the value really is constant is for codegen to decide, it can
+/// name constants defined before it, which only codegen knows the value of.
+pub fn parse_const<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> Result<Box<ConstDef<'src>>, ParserError<'src>> {
+    let _const = tokens.next();
+
+    let name = match tokens.next() {
+        Some(Token::Identifier(name)) => name,
+        Some(_) => return Err(ParserError::ExpectedToken("<identifier>")),
+        None => return Err(ParserError::eoi(&["<identifier>"])),
+    };
+
+    expect_token(tokens, "=", |t| matches!(t, Token::Operator(Ops::Assign)))?;
+
+    let value = parse_expression(tokens)?;
+
+    Ok(Box::new(ConstDef { name, value }))
 }
 
 /// prototype
-///   ::= id '(' id* ')'
+///   ::= id '(' id* (','? '...')? ')'
 pub fn parse_prototype<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> Result<Box<Prototype<'src>>, ParserError<'src>> {
     match tokens.next() {
         Some(Token::Identifier(name)) => {
-            let _ = tokens
-                .next_if(|t| matches!(t, Token::OpenParen))
-                .ok_or(ParserError::ExpectedToken(&"("))?;
+            expect_token(tokens, "(", |t| matches!(t, Token::OpenParen))?;
 
             let mut args = vec![];
 
-            while let Some(Token::Identifier(s)) = tokens.peek() {
-                args.push(*s);
-                let _ = tokens.next();
+            // Two params of the same name would have the second shadow the first
+            while let Some(Token::Identifier(s)) =
+                tokens.next_if(|t| matches!(t, Token::Identifier(_)))
+            {
+                if args.contains(&s) {
+                    return Err(ParserError::DuplicateParameter(s));
+                }
+
+                args.push(s);
             }
 
-            let _ = tokens
-                .next_if(|t| matches!(t, Token::ClosedParen))
-                .ok_or(ParserError::ExpectedToken(&")"))?;
+            // A trailing ellipsis makes it variadic, like C we allow a comma before it
+            let variadic = match tokens.next_if(|t| matches!(t, Token::Comma)) {
+                Some(_comma) => {
+                    expect_token(tokens, "...", |t| matches!(t, Token::Ellipsis))?;
+                    true
+                }
+                None => tokens.next_if(|t| matches!(t, Token::Ellipsis)).is_some(),
+            };
+
+            expect_token(tokens, ")", |t| matches!(t, Token::ClosedParen))?;
 
-            Ok(Box::new(Prototype::FunctionProto { name, args }))
+            Ok(Box::new(Prototype::FunctionProto {
+                name,
+                args,
+                variadic,
+                void: false,
+                decl: false,
+            }))
         }
 
         Some(Token::UnaryOverload) => {
-            let Some(Token::Operator(operator)) = tokens.next() else {
-                return Err(ParserError::ExpectedToken("!/&/|/^/:"));
+            let operator = match tokens.next() {
+                Some(Token::Operator(operator)) => operator,
+                Some(_) => return Err(ParserError::ExpectedToken("!/&/|/^/: or a builtin")),
+                None => return Err(ParserError::eoi(&["operator"])),
             };
 
             // swallow open parenthesis
-            let _ = tokens
-                .next_if(|t| matches!(t, Token::OpenParen))
-                .ok_or(ParserError::ExpectedToken(&"("))?;
+            expect_token(tokens, "(", |t| matches!(t, Token::OpenParen))?;
 
-            let Some(Token::Identifier(arg)) = tokens.next() else {
-                return Err(ParserError::BadOverloadedUnaryOp);
+            let arg = match tokens.next() {
+                Some(Token::Identifier(arg)) => arg,
+                Some(_) => return Err(ParserError::BadOverloadedUnaryOp),
+                None => return Err(ParserError::eoi(&["argument"])),
             };
 
             // swallow closed parenthesis
-            let _ = tokens
-                .next_if(|t| matches!(t, Token::ClosedParen))
-                .ok_or(ParserError::ExpectedToken(&")"))?;
+            expect_token(tokens, ")", |t| matches!(t, Token::ClosedParen))?;
+
+            OP_PRECEDENCE.write().record(operator, Fixity::Prefix);
 
             Ok(Box::new(Prototype::OverloadedUnaryOpProto {
                 operator,
@@ -109,72 +712,307 @@ pub fn parse_prototype<'src>(
         }
 
         Some(Token::BinaryOverload) => {
-            let Some(Token::Operator(operator)) = tokens.next() else {
-                return Err(ParserError::ExpectedToken("!/&/|/^/:"));
+            let operator = match tokens.next() {
+                Some(Token::Operator(operator)) => operator,
+                Some(_) => {
+                    return Err(ParserError::ExpectedToken(
+                        "!/&/|/:, or ^ or a builtin with --allow-override",
+                    ))
+                }
+                None => return Err(ParserError::eoi(&["operator"])),
             };
 
-            let Some(Token::Number(precedence)) = tokens.next() else {
-                return Err(ParserError::BadOverloadedBinaryOp);
+            let precedence = match tokens.next() {
+                Some(Token::Number(precedence)) => precedence,
+                Some(_) => return Err(ParserError::BadOverloadedBinaryOp),
+                None => return Err(ParserError::eoi(&["precedence"])),
             };
 
-            let mut precedence_map = OP_PRECEDENCE.write().unwrap();
+            // Out of range is an error, the same as registering it any other way
+            let precedence = round_up(precedence);
 
-            precedence_map.insert(operator, precedence.ceil() as i32);
+            OP_PRECEDENCE
+                .write()
+                .register_overload(operator, precedence)
+                .map_err(ParserError::BadOperatorRegistration)?;
 
             // swallow open parenthesis
-            let _ = tokens
-                .next_if(|t| matches!(t, Token::OpenParen))
-                .ok_or(ParserError::ExpectedToken(&"("))?;
-
-            let (Some(Token::Identifier(lhs)), Some(Token::Identifier(rhs))) =
-                (tokens.next(), tokens.next())
-            else {
-                return Err(ParserError::BadOverloadedUnaryOp);
+            expect_token(tokens, "(", |t| matches!(t, Token::OpenParen))?;
+
+            let (lhs, rhs) = match (tokens.next(), tokens.next()) {
+                (Some(Token::Identifier(lhs)), Some(Token::Identifier(rhs))) => (lhs, rhs),
+                (None, _) | (Some(Token::Identifier(_)), None) => {
+                    return Err(ParserError::eoi(&["argument"]))
+                }
+                _ => return Err(ParserError::BadOverloadedUnaryOp),
             };
 
+            if lhs == rhs {
+                return Err(ParserError::DuplicateParameter(rhs));
+            }
+
             // swallow closed parenthesis
-            let _ = tokens
-                .next_if(|t| matches!(t, Token::ClosedParen))
-                .ok_or(ParserError::ExpectedToken(&")"))?;
+            expect_token(tokens, ")", |t| matches!(t, Token::ClosedParen))?;
 
             Ok(Box::new(Prototype::OverloadedBinaryOpProto {
                 operator,
-                precedence: precedence.ceil() as i32,
+                precedence,
                 args: (lhs, rhs),
             }))
         }
 
         Some(unexpected) => Err(ParserError::UnexpectedToken(unexpected)),
-        None => Err(ParserError::UnexpectedEOI),
+        None => Err(ParserError::eoi(&["function name", "unary", "binary"])),
     }
 }
 
-/// definition ::= 'def' prototype expression
+/// definition ::= 'def' attributes? prototype expression
 pub fn parse_definition<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> Result<Box<Function<'src>>, ParserError<'src>> {
     // swallow the def keyword
     let _def = tokens.next();
 
-    // try to parse prototype and body
+    // try to parse attributes, prototype and body
+    let attrs = parse_attributes(tokens)?;
     let proto = parse_prototype(tokens)?;
     let body = parse_expression(tokens)?;
 
-    Ok(Box::new(Function { proto, body }))
+    Ok(Box::new(Function { proto, body, attrs }))
+}
+
+/// attributes ::= '[' id (',' id)* ']'
+///
+/// Optional, if there is no open bracket we have no attributes
+fn parse_attributes<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> Result<Vec<FnAttr>, ParserError<'src>> {
+    let mut attrs = vec![];
+
+    if tokens
+        .next_if(|t| matches!(t, Token::OpenBracket))
+        .is_none()
+    {
+        return Ok(attrs);
+    }
+
+    loop {
+        match tokens.next() {
+            Some(Token::Identifier(name)) => {
+                let attr = FnAttr::from_name(name).ok_or(ParserError::UnknownAttribute(name))?;
+
+                if let Some(&other) = attrs.iter().find(|other| attr.conflicts_with(**other)) {
+                    return Err(ParserError::ConflictingAttributes(other, attr));
+                }
+
+                attrs.push(attr);
+            }
+            Some(unexpected) => return Err(ParserError::UnexpectedToken(unexpected)),
+            None => return Err(ParserError::eoi(&["attribute"])),
+        }
+
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::ClosedBracket) => break,
+            Some(_) => return Err(ParserError::ExpectedToken("]")),
+            None => return Err(ParserError::eoi(&[",", "]"])),
+        }
+    }
+
+    Ok(attrs)
+}
+
+/// toplevel
+///   ::= definition
+///   ::= external
+///   ::= declaration
+///   ::= constdef
+///   ::= toplevelexpr
+pub fn parse_top_level<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> Result<TopLevel<'src>, ParserError<'src>> {
+    match tokens.peek() {
+        Some(Token::FuncDef) => parse_definition(tokens).map(TopLevel::Definition),
+        Some(Token::Extern) => parse_extern(tokens).map(TopLevel::Extern),
+        Some(Token::Decl) => parse_decl(tokens).map(TopLevel::Extern),
+        Some(Token::Const) => parse_const(tokens).map(TopLevel::Const),
+        Some(_top_level_token) => parse_top_level_expr(tokens).map(TopLevel::Expression),
+        None => Err(ParserError::eoi(&[
+            "def",
+            "extern",
+            "decl",
+            "const",
+            "expression",
+        ])),
+    }
+}
+
+// What has to come after each top level item before the next one starts.
+// Definitions and externs end where the next keyword starts either way, so
+// leaving off their ';' is never ambiguous, unlike "f(x) (1 + 2)".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TerminatorPolicy {
+    // Semicolons are only there to be skipped, anywhere
+    #[default]
+    Optional,
+
+    // Like most scripting languages, a top level expression must be ended
+    // with a ';', while definitions and externs can go without
+    AfterExpressions,
+}
+
+/// program ::= (toplevel | ';')*
+///
+/// Parses a whole program, stopping at the first error, with the semicolons
+/// between items required only as far as the policy says so.
+pub fn parse_program<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+    policy: TerminatorPolicy,
+) -> Result<Vec<TopLevel<'src>>, ParserError<'src>> {
+    let mut program = vec![];
+
+    while let Some(token) = tokens.peek() {
+        if let Token::Semicolon = token {
+            _ = tokens.next();
+            continue;
+        }
+
+        let item = parse_top_level(tokens)?;
+
+        if let (TerminatorPolicy::AfterExpressions, TopLevel::Expression(_)) = (policy, &item) {
+            let _ = tokens
+                .next_if(|t| matches!(t, Token::Semicolon))
+                .ok_or(ParserError::ExpectedToken(";"))?;
+        }
+
+        program.push(item);
+    }
+
+    Ok(program)
+}
+
+/// program ::= (toplevel | ';')*
+///
+/// Parses a whole program, but rather than giving up at the first error,
+/// records it and synchronizes by skipping ahead to the next semicolon,
+/// 'def', 'extern' or 'const', then carries on. This way we can report every
+/// error in a file at once.
+pub fn parse_program_recovering<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> (Vec<TopLevel<'src>>, Vec<ParserError<'src>>) {
+    let (program, errors, _suppressed) = parse_program_capped(tokens, usize::MAX);
+    (program, errors)
+}
+
+/// Just like parse_program_recovering, but only the first max_errors errors
+/// are kept, on badly broken input there could be hundreds. The rest are
+/// still recovered from, but only counted, giving back how many were left out.
+pub fn parse_program_capped<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+    max_errors: usize,
+) -> (Vec<TopLevel<'src>>, Vec<ParserError<'src>>, usize) {
+    let mut errors = vec![];
+    let mut suppressed = 0;
+
+    let program = parse_program_reporting(tokens, |err| {
+        if errors.len() < max_errors {
+            errors.push(err);
+        } else {
+            suppressed += 1;
+        }
+    });
+
+    (program, errors, suppressed)
+}
+
+/// The recovering parser underneath the others, handing each error to report
+/// the moment it's found. Before skipping ahead, so the tokens are still just
+/// as the parser left them, for whoever wants to know where it had got to.
+pub fn parse_program_reporting<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+    mut report: impl FnMut(ParserError<'src>),
+) -> Vec<TopLevel<'src>> {
+    let mut program = vec![];
+
+    while let Some(token) = tokens.peek() {
+        match token {
+            Token::Semicolon => {
+                _ = tokens.next();
+            }
+
+            _top_level_token => match parse_top_level(tokens) {
+                Ok(item) => program.push(item),
+                Err(err) => {
+                    report(err);
+
+                    while let Some(_skipped) = tokens.next_if(|t| {
+                        !matches!(
+                            t,
+                            Token::Semicolon
+                                | Token::FuncDef
+                                | Token::Extern
+                                | Token::Decl
+                                | Token::Const
+                        )
+                    }) {}
+                }
+            },
+        }
+    }
+
+    program
 }
 
 /// toplevelexpr ::= expression
 pub fn parse_top_level_expr<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> Result<Box<Function<'src>>, ParserError<'src>> {
     let expr = parse_expression(tokens)?;
 
     let proto = Box::new(Prototype::FunctionProto {
         name: &"__anonymous_expr",
         args: vec![],
+        variadic: false,
+        void: false,
+        decl: false,
     });
 
-    Ok(Box::new(Function { proto, body: expr }))
+    Ok(Box::new(Function {
+        proto,
+        body: expr,
+        attrs: vec![],
+    }))
+}
+
+// Parses a single expression out of the middle of some source, starting at
+// byte offset start, for hosts embedding expressions in text of their own.
+// Along with the expression comes the offset just past its last token, where
+// the host picks up again. Only the tokens the parser took count, whatever it
+// merely peeked at to know the expression was over is left where it is.
+pub fn parse_expression_at(
+    src: &str,
+    start: usize,
+) -> Result<(Box<ASTExpr<'_>>, usize), ParserError<'_>> {
+    let rest = src.get(start..).ok_or(ParserError::BadOffset(start))?;
+
+    let spanned: Vec<_> = lex_all_spanned(rest)
+        .into_iter()
+        .filter(|(token, _)| !matches!(token, Token::Comment(_)))
+        .collect();
+
+    let mut source = spanned.iter().map(|(token, _)| *token);
+    let mut tokens = LookaheadBuffer::new(source.by_ref());
+
+    let expr = parse_expression(&mut tokens)?;
+    let buffered = tokens.buffered();
+
+    let consumed = spanned.len() - source.len() - buffered;
+    let end = match consumed {
+        0 => start,
+        n => start + spanned[n - 1].1.end,
+    };
+
+    Ok((expr, end))
 }
 
 // Small alias for fallible returns of parsing expressions
@@ -184,32 +1022,47 @@ type ExprParseResult<'src> = Result<Box<ASTExpr<'src>>, ParserError<'src>>;
 ///   ::= identifierexpr
 ///   ::= numberexpr
 ///   ::= parenexpr
+///   ::= arrayexpr
 fn parse_primary<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     match tokens.peek() {
-        Some(Token::Identifier(_)) => parse_identifier_expr(tokens),
+        // A call, if followed by an open paren, is for parse_postfix to make
+        Some(Token::Identifier(_)) => parse_variable_expr(tokens),
 
         Some(Token::Number(_)) => parse_number_expr(tokens),
 
+        Some(Token::Error(text)) => Err(ParserError::InvalidNumber(text)),
+
+        Some(Token::Str(text)) => {
+            let text = *text;
+            let _ = tokens.next();
+
+            Ok(Box::new(ASTExpr::StringExpr(text)))
+        }
+
         Some(Token::OpenParen) => parse_paren_expr(tokens),
 
+        Some(Token::OpenBracket) => parse_array_expr(tokens),
+
         Some(Token::If) => parse_if_expr(tokens),
 
         Some(Token::For) => parse_for_loop_expression(tokens),
 
+        Some(Token::While) => parse_while_expression(tokens),
+
         Some(Token::Var) => parse_var_expression(tokens),
 
         Some(unexpected) => Err(ParserError::UnexpectedToken(*unexpected)),
 
-        None => Err(ParserError::UnexpectedEOI),
+        None => Err(ParserError::eoi(&["expression"])),
     }
 }
 
 /// varexpr ::= 'var' identifier ('=' expression)?
 //              (',' identifier ('=' expression)?)* 'in' expression
 fn parse_var_expression<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     // Swallow the var keyword
     let _ = tokens.next();
@@ -218,8 +1071,10 @@ fn parse_var_expression<'src>(
 
     // Loop over the list of comma delimited variables with possible initializers
     loop {
-        let Some(Token::Identifier(name)) = tokens.next() else {
-            return Err(ParserError::ExpectedToken("<identifier>"));
+        let name = match tokens.next() {
+            Some(Token::Identifier(name)) => name,
+            Some(_) => return Err(ParserError::ExpectedToken("<identifier>")),
+            None => return Err(ParserError::eoi(&["<identifier>"])),
         };
 
         // If there is an assignment operator following, it has an initializer,
@@ -240,9 +1095,7 @@ fn parse_var_expression<'src>(
     }
 
     // Check for the "in" keyword, should be there before body
-    tokens
-        .next_if(|t| matches!(t, Token::In))
-        .ok_or(ParserError::ExpectedToken(&"in"))?;
+    expect_token(tokens, "in", |t| matches!(t, Token::In))?;
 
     let body = parse_expression(tokens)?;
 
@@ -251,22 +1104,73 @@ fn parse_var_expression<'src>(
 
 /// unary
 ///   ::= primary
+///   ::= ('-' | '+') unary
 ///   ::= '!' unary
 fn parse_unary<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     if let Some(Token::Operator(op)) = tokens.next_if(|t| matches!(t, Token::Operator(_))) {
+        // Operators never defined are let through, it's for codegen to complain
+        if fixity_of(op).is_some_and(|fixity| !fixity.allows_prefix()) {
+            return Err(ParserError::NotAPrefixOperator(op));
+        }
+
+        // The lexer never produces negative numbers, "-3" is a minus then a 3,
+        // and it stays a unary expression here, a program may overload
+        // "unary -". Codegen and eval_const know when it isn't, and fold it.
         let operand = parse_unary(tokens)?;
 
         Ok(Box::new(ASTExpr::UnaryExpr { op, operand }))
     } else {
-        parse_primary(tokens)
+        parse_postfix(tokens)
     }
 }
 
-/// forexpr ::= 'for' identifier '=' expression ',' expression (',' expr)? 'in' expression
+/// postfixexpr ::= primary ('(' expression* ')' | '[' expression ']')*
+///
+/// Calls and indexes apply to whatever comes before them, working left to
+/// right, so "a[i][j]" indexes what "a[i]" gives, and "f(x)(y)" calls what
+/// "f(x)" returns. Literals are never functions, so a paren right after one
+/// isn't taken to be a call.
+fn parse_postfix<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    use ASTExpr::*;
+
+    let mut expr = parse_primary(tokens)?;
+
+    loop {
+        match tokens.peek() {
+            Some(Token::OpenParen)
+                if !matches!(
+                    unparenthesized(&expr),
+                    NumberExpr(_) | StringExpr(_) | ArrayExpr(_)
+                ) =>
+            {
+                expr = parse_call_expr(tokens, expr)?;
+            }
+
+            Some(Token::OpenBracket) => {
+                let _open_bracket = tokens.next();
+                let index = parse_expression(tokens)?;
+
+                let _ = tokens
+                    .next_if(|t| matches!(t, Token::ClosedBracket))
+                    .ok_or(ParserError::ExpectedToken("]"))?;
+
+                expr = Box::new(IndexExpr { base: expr, index });
+            }
+
+            _ => break,
+        }
+    }
+
+    Ok(expr)
+}
+
+/// forexpr ::= 'for' identifier '=' expression ',' expression (',' expr)? 'in' expression
 fn parse_for_loop_expression<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     let Some(Token::For) = tokens.next() else {
         return Err(ParserError::ExpectedToken(&"for"));
@@ -312,9 +1216,29 @@ fn parse_for_loop_expression<'src>(
     }))
 }
 
-/// ifexpr ::= 'if' expression 'then' expression 'else' expression
+/// whileexpr ::= 'while' expression 'in' expression
+///
+/// The condition is checked before each time around, so unlike a for loop,
+/// the body may never run at all. Like a for loop, it gives 0.
+fn parse_while_expression<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    let _while = tokens.next();
+
+    let cond = parse_expression(tokens)?;
+
+    let Some(Token::In) = tokens.next() else {
+        return Err(ParserError::ExpectedToken("in"));
+    };
+
+    let body = parse_expression(tokens)?;
+
+    Ok(Box::new(ASTExpr::WhileExpr { cond, body }))
+}
+
+/// ifexpr ::= 'if' expression 'then' expression ('elif' expression 'then' expression)* 'else' expression
 fn parse_if_expr<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     let Some(Token::If) = tokens.next() else {
         return Err(ParserError::ExpectedToken(&"if"));
@@ -328,6 +1252,18 @@ fn parse_if_expr<'src>(
 
     let then_branch = parse_expression(tokens)?;
 
+    let mut elifs = vec![];
+
+    while let Some(Token::Elif) = tokens.next_if(|t| matches!(t, Token::Elif)) {
+        let elif_cond = parse_expression(tokens)?;
+
+        let Some(Token::Then) = tokens.next() else {
+            return Err(ParserError::ExpectedToken(&"then"));
+        };
+
+        elifs.push((elif_cond, parse_expression(tokens)?));
+    }
+
     let Some(Token::Else) = tokens.next() else {
         return Err(ParserError::ExpectedToken(&"else"));
     };
@@ -337,13 +1273,14 @@ fn parse_if_expr<'src>(
     Ok(Box::new(ASTExpr::IfExpr {
         cond,
         then_branch,
+        elifs,
         else_branch,
     }))
 }
 
 /// numberexpr ::= number
 fn parse_number_expr<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     if let Some(Token::Number(num)) = tokens.next() {
         Ok(Box::new(ASTExpr::NumberExpr(num)))
@@ -352,48 +1289,99 @@ fn parse_number_expr<'src>(
     }
 }
 
-/// identifierexpr
-///   ::= identifier
-///   ::= identifier '(' expression* ')'
-fn parse_identifier_expr<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+/// variableexpr ::= identifier
+fn parse_variable_expr<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
-    let name = match tokens.next() {
-        Some(Token::Identifier(name)) => name,
-        _unexpected => panic!("Expected"),
+    let Some(Token::Identifier(name)) = tokens.next() else {
+        panic!("FATAL: misuse of of this function in recursive descent!")
     };
 
-    // Call Expression
-    if let Some(Token::OpenParen) = tokens.peek() {
-        let _open_paren = tokens.next();
+    Ok(Box::new(ASTExpr::VariableExpr(name)))
+}
 
-        let mut args = vec![];
+/// callexpr ::= postfixexpr '(' expression* ')'
+///
+/// Calling a plain name, even "(f)(x)", is an ordinary call, anything else
+/// gives the function pointer to call.
+fn parse_call_expr<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+    callee: Box<ASTExpr<'src>>,
+) -> ExprParseResult<'src> {
+    let Some(Token::OpenParen) = tokens.next() else {
+        panic!("FATAL: misuse of of this function in recursive descent!")
+    };
 
-        loop {
-            if let Some(Token::ClosedParen) = tokens.peek() {
-                break;
-            }
+    let mut args = vec![];
 
-            parse_expression(tokens).map(|arg_expr| args.push(arg_expr))?;
+    loop {
+        match tokens.peek() {
+            Some(Token::ClosedParen) => break,
+            None => return Err(ParserError::eoi(&["expression", ")"])),
+            Some(_) => {}
+        }
 
-            if let Some(Token::Comma) = tokens.peek() {
-                tokens.next();
-                continue;
-            }
+        parse_expression(tokens).map(|arg_expr| args.push(arg_expr))?;
+
+        if let Some(Token::Comma) = tokens.peek() {
+            tokens.next();
+            continue;
         }
+    }
 
-        let _closed_paren = tokens.next();
+    let _closed_paren = tokens.next();
 
-        Ok(Box::new(ASTExpr::CallExpr { callee: name, args }))
-    } else {
-        // Variable Expression
-        Ok(Box::new(ASTExpr::VariableExpr(name)))
+    match unparenthesized(&callee) {
+        &ASTExpr::VariableExpr(name) => Ok(Box::new(ASTExpr::CallExpr { callee: name, args })),
+        _ => Ok(Box::new(ASTExpr::IndirectCallExpr { callee, args })),
     }
 }
 
+// Whatever is inside any parens kept with preserve_parens, they're only
+// there for the formatter, so "(f)(x)" is still a call of f
+fn unparenthesized<'a, 'src>(mut expr: &'a ASTExpr<'src>) -> &'a ASTExpr<'src> {
+    while let ASTExpr::ParenExpr(inner) = expr {
+        expr = inner;
+    }
+
+    expr
+}
+
+/// arrayexpr ::= '[' (expression (',' expression)*)? ']'
+///
+/// Unlike the arguments of a call, the commas can't be left out, "[1 -2]"
+/// would otherwise be an array of one element, 1 - 2.
+fn parse_array_expr<'src>(
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    let _open_bracket = tokens.next();
+
+    let mut elems = vec![];
+
+    if tokens
+        .next_if(|t| matches!(t, Token::ClosedBracket))
+        .is_some()
+    {
+        return Ok(Box::new(ASTExpr::ArrayExpr(elems)));
+    }
+
+    loop {
+        elems.push(parse_expression(tokens)?);
+
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::ClosedBracket) => break,
+            Some(_) => return Err(ParserError::ExpectedToken(",/]")),
+            None => return Err(ParserError::eoi(&[",", "]"])),
+        }
+    }
+
+    Ok(Box::new(ASTExpr::ArrayExpr(elems)))
+}
+
 /// parenexpr ::= '(' expression ')'
 fn parse_paren_expr<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     // Swallow the open parenthesis
     let _paren = tokens.next();
@@ -401,11 +1389,16 @@ fn parse_paren_expr<'src>(
     // Parse the expression inside it
     let expr = parse_expression(tokens);
 
-    // Should be a closed parenthesis following it.
+    // Should be a closed parenthesis following it. Usually the parens have
+    // done their job with that, the tree's shape already has the grouping.
     match tokens.next() {
+        Some(Token::ClosedParen) if tokens.preserves_parens() => {
+            Ok(Box::new(ASTExpr::ParenExpr(expr?)))
+        }
         Some(Token::ClosedParen) => expr,
         Some(unexpected) => Err(ParserError::UnexpectedToken(unexpected)),
-        None => Err(ParserError::UnexpectedEOI),
+        // Ending inside the expression, it knows better what should've come
+        None => expr.and(Err(ParserError::eoi(&["operator", ")"]))),
     }
 }
 
@@ -413,7 +1406,7 @@ fn parse_paren_expr<'src>(
 ///   ::= primary binoprhs
 ///
 fn parse_expression<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
     // Be sure we handle the case where either the lhs has unary
     // operator, or rhs, or both.
@@ -423,117 +1416,801 @@ fn parse_expression<'src>(
 }
 
 // Small helper method to fetch the precedence of operator
-// from hash table. If the token is not an operator,
+// from the table. If the token is not an operator,
 // default to -1. Tutorial names this GetTokPrecedence
-fn get_token_precedence(token: Token) -> i32 {
-    if let Token::Operator(operator) = token {
-        OP_PRECEDENCE.read().unwrap()[&operator]
-    } else {
-        -1
-    }
+fn get_token_precedence<'src>(token: Token) -> Result<i32, ParserError<'src>> {
+    let operator = match token {
+        Token::Operator(operator) => operator,
+        Token::CompoundAssign(_) => Ops::Assign,
+        _ => return Ok(-1),
+    };
+
+    // An operator the lexer knows, but no program has given a meaning yet
+    OP_PRECEDENCE
+        .read()
+        .get(&operator)
+        .map(|info| info.precedence)
+        .ok_or(ParserError::UndefinedBinaryOperator(operator))
 }
 
 /// binoprhs
 ///   ::= ('+' primary)*
 fn parse_binop_rhs<'src>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+    tokens: &mut LookaheadBuffer<'src, impl Iterator<Item = Token<'src>>>,
     mut lhs: Box<ASTExpr<'src>>,
     expr_prec: i32,
 ) -> ExprParseResult<'src> {
     loop {
+        // Running out of input right after an operand just ends the expression,
+        // so "1+1" alone on a line of the REPL is complete
         let tok_prec = match tokens.peek().copied() {
-            Some(token) => get_token_precedence(token),
-            None => return Err(ParserError::UnexpectedEOI),
+            Some(Token::Operator(op)) if fixity_of(op) == Some(Fixity::Prefix) => {
+                return Err(ParserError::NotABinaryOperator(op));
+            }
+            Some(token) => get_token_precedence(token)?,
+            None => return Ok(lhs),
+        };
+
+        if tok_prec < expr_prec {
+            return Ok(lhs);
+        }
+
+        let (op, compound) = match tokens.next() {
+            Some(Token::Operator(op)) => (op, false),
+            Some(Token::CompoundAssign(op)) => (op, true),
+            _ => panic!("FATAL: misuse of of this function in recursive descent!"),
+        };
+
+        if compound && !matches!(*lhs, ASTExpr::VariableExpr(_)) {
+            return Err(ParserError::BadCompoundAssignment(op));
+        }
+
+        // In chapter 6, we changed this from parse_primary to parse_unary
+        // handle the lhs case where it might be attached to unary operator
+        let mut rhs = parse_unary(tokens)?;
+
+        let next_prec = match tokens.peek().copied() {
+            Some(token) => get_token_precedence(token)?,
+            None => -1,
+        };
+
+        // A right associative operator takes the rest of a chain of the same
+        // precedence as its rhs, operators of one precedence all group alike
+        let right_assoc = assoc_of(if compound { Ops::Assign } else { op }) == Assoc::Right;
+
+        if tok_prec < next_prec || (right_assoc && tok_prec == next_prec) {
+            let min_prec = match right_assoc {
+                true => tok_prec,
+                false => tok_prec.saturating_add(1),
+            };
+
+            rhs = parse_binop_rhs(tokens, rhs, min_prec)?;
+        }
+
+        // "x += e" becomes "x = x + e", with only a variable allowed on the
+        // left, having it twice still only evaluates it once, it's a load
+        lhs = if compound {
+            let right = Box::new(ASTExpr::BinaryExpr {
+                op,
+                left: lhs.clone(),
+                right: rhs,
+            });
+
+            Box::new(ASTExpr::BinaryExpr {
+                op: Ops::Assign,
+                left: lhs,
+                right,
+            })
+        } else {
+            Box::new(ASTExpr::BinaryExpr {
+                op,
+                left: lhs,
+                right: rhs,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead};
+
+    use super::*;
+    use ASTExpr::*;
+    use Ops::*;
+
+    #[test]
+    fn parsing_primary_expressions() {
+        let mut tokens = " 23.2 ".lex().lookahead();
+        let mut res = parse_primary(&mut tokens);
+
+        assert_eq!(res, Ok(Box::new(NumberExpr(23.2))));
+
+        tokens = " myVariable ".lex().lookahead();
+        res = parse_primary(&mut tokens);
+
+        assert_eq!(res, Ok(Box::new(VariableExpr(&"myVariable"))));
+
+        tokens = " (400.5 - 323.10) ".lex().lookahead();
+        res = parse_primary(&mut tokens);
+
+        assert_eq!(
+            res,
+            Ok(Box::new(BinaryExpr {
+                op: Minus,
+                left: Box::new(NumberExpr(400.5)),
+                right: Box::new(NumberExpr(323.10)),
+            }))
+        );
+
+        tokens = " squareNums(2) ".lex().lookahead();
+        res = parse_postfix(&mut tokens);
+
+        assert_eq!(
+            res,
+            Ok(Box::new(CallExpr {
+                callee: &"squareNums",
+                args: vec![Box::new(NumberExpr(2.0))]
+            }))
+        );
+
+        tokens = " multiParams(6, x, (2 + 2)) ".lex().lookahead();
+        res = parse_postfix(&mut tokens);
+
+        assert_eq!(
+            res,
+            Ok(Box::new(CallExpr {
+                callee: &"multiParams",
+                args: vec![
+                    Box::new(NumberExpr(6.0)),
+                    Box::new(VariableExpr(&"x")),
+                    Box::new(BinaryExpr {
+                        op: Plus,
+                        left: Box::new(NumberExpr(2.0)),
+                        right: Box::new(NumberExpr(2.0)),
+                    })
+                ]
+            }))
+        );
+    }
+
+    #[test]
+    fn parsing_expression_at_end_of_input() {
+        let mut tokens = " 1 + 2 ".lex().lookahead();
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Ok(Box::new(BinaryExpr {
+                op: Plus,
+                left: Box::new(NumberExpr(1.0)),
+                right: Box::new(NumberExpr(2.0)),
+            }))
+        );
+
+        // A trailing operator still needs more
+        let mut tokens = " 1 + ".lex().lookahead();
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Err(ParserError::eoi(&["expression"]))
+        );
+    }
+
+    #[test]
+    fn parsing_duplicate_parameters() {
+        assert_eq!(
+            parse_definition(&mut " def f(x x) x; ".lex().lookahead()),
+            Err(ParserError::DuplicateParameter("x"))
+        );
+
+        assert!(parse_definition(&mut " def f(x y) x; ".lex().lookahead()).is_ok());
+
+        // Operator overloads can't repeat theirs either
+        assert_eq!(
+            parse_definition(&mut " def binary : 1 (a a) a; ".lex().lookahead()),
+            Err(ParserError::DuplicateParameter("a"))
+        );
+    }
+
+    #[test]
+    fn checking_operator_fixity() {
+        // Once overloaded as unary only, ! can't go between two operands
+        assert!(parse_definition(&mut " def unary ! (v) 0 - v; ".lex().lookahead()).is_ok());
+        assert!(parse_expression(&mut " ! 1 + 2 ".lex().lookahead()).is_ok());
+
+        assert_eq!(
+            parse_expression(&mut " 1 ! 2 ".lex().lookahead()),
+            Err(ParserError::NotABinaryOperator(Negate))
+        );
+
+        // The builtins that are binary only can't be used as a prefix
+        assert_eq!(
+            parse_expression(&mut " 1 + * 2 ".lex().lookahead()),
+            Err(ParserError::NotAPrefixOperator(Mult))
+        );
+
+        // Minus is fine either way
+        assert!(parse_expression(&mut " -1 - -x ".lex().lookahead()).is_ok());
+    }
+
+    #[test]
+    fn dumping_precedence_in_order() {
+        let table = OP_PRECEDENCE.read().clone();
+
+        // The same operators inserted the other way round dump the same
+        let reversed = table
+            .iter()
+            .rev()
+            .map(|(op, info)| (*op, *info))
+            .collect::<BTreeMap<_, _>>();
+
+        let dump = dump_precedence_table(&table);
+        assert_eq!(dump, dump_precedence_table(&reversed));
+        assert_eq!(dump, dump_precedence_table(&table));
+
+        assert!(dump.starts_with("+   20 Both\n-   20 Both\n*   40 Infix\n"));
+    }
+
+    #[test]
+    fn showing_precedence() {
+        let mut table = OP_PRECEDENCE.read().clone();
+        let shown = show_precedence_table(&table);
+
+        assert!(shown.starts_with("operator  precedence  associativity\n"));
+        assert!(shown.contains("\n*                 40  left\n"));
+        assert!(shown.contains("\n+                 20  left\n"));
+
+        // Tightest first, other tests may have added overloads, but in order all the same
+        let precedences: Vec<i32> = shown
+            .lines()
+            .filter(|line| line.ends_with("left"))
+            .map(|line| {
+                line[8..]
+                    .split_whitespace()
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(precedences.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(shown.contains("\n=                  2  left\n"));
+
+        // Overloads show up with the rest, the prefix only ones last
+        table.register_operator("|", 45, Assoc::Left).unwrap();
+        table.record(Ops::Negate, Fixity::Prefix);
+
+        let shown = show_precedence_table(&table);
+        assert!(shown.contains("\n|                 45  left\n"));
+        assert!(shown.ends_with("\n!                  -  prefix\n"));
+    }
+
+    #[test]
+    fn registering_operators() {
+        let mut table = OP_PRECEDENCE.read().clone();
+
+        assert_eq!(table.register_operator("^", 60, Assoc::Right), Ok(()));
+        assert_eq!(table[&Ops::Xor].precedence, 60);
+        assert_eq!(table[&Ops::Xor].assoc, Assoc::Right);
+        assert!(show_precedence_table(&table).contains("\n^                 60  right\n"));
+
+        // Another right associative one can join it, a left associative one can't
+        assert_eq!(table.register_operator(":", 60, Assoc::Right), Ok(()));
+        assert_eq!(
+            table.register_operator("|", 60, Assoc::Left),
+            Err(OpError::MixedAssociativity {
+                op: Ops::Or,
+                assoc: Assoc::Left,
+                other: Ops::Xor,
+            })
+        );
+
+        assert_eq!(
+            table.register_operator("", 10, Assoc::Left),
+            Err(OpError::EmptySymbol)
+        );
+        assert_eq!(
+            table.register_operator("@", 10, Assoc::Left),
+            Err(OpError::UnknownOperator("@".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejecting_out_of_range_precedence() {
+        let mut table = OP_PRECEDENCE.read().clone();
+        let before = table.clone();
+
+        assert_eq!(
+            table.register_operator("|", 0, Assoc::Left),
+            Err(OpError::PrecedenceOutOfRange(0))
+        );
+        assert_eq!(
+            table.register_operator("|", MAX_PRECEDENCE + 1, Assoc::Left),
+            Err(OpError::PrecedenceOutOfRange(MAX_PRECEDENCE + 1))
+        );
+
+        // Nothing was registered
+        assert_eq!(table, before);
+    }
+
+    #[test]
+    fn overriding_builtin_operators() {
+        let mut table = OP_PRECEDENCE.read().clone();
+
+        assert_eq!(
+            table.register_operator("+", 60, Assoc::Left),
+            Err(OpError::BuiltinOverride(Plus))
+        );
+        assert_eq!(
+            table.register_operator("+", 20, Assoc::Right),
+            Err(OpError::BuiltinOverride(Plus))
+        );
+
+        // As it already is changes nothing, so is fine
+        assert_eq!(table.register_operator("+", 20, Assoc::Left), Ok(()));
+
+        table.allow_override = true;
+        assert_eq!(table.register_operator("+", 60, Assoc::Left), Ok(()));
+        assert_eq!(table[&Plus].precedence, 60);
+        assert_eq!(table[&Plus].fixity, Fixity::Both);
+
+        // The parser registers overloads the same way
+        let err = parse_definition(&mut " def binary+ 60 (a b) a; ".lex().lookahead());
+        assert_eq!(
+            err.unwrap_err(),
+            ParserError::BadOperatorRegistration(OpError::BuiltinOverride(Plus))
+        );
+    }
+
+    #[test]
+    fn overloading_next_to_power() {
+        // Nothing overloaded is as tight as ^, so no associativity to clash with
+        let mut table = OP_PRECEDENCE.read().clone();
+        assert_eq!(table.register_overload(Ops::Or, 60), Ok(()));
+        assert_eq!(table.register_overload(Ops::Or, MAX_PRECEDENCE), Ok(()));
+
+        // ^ itself stays power, for every program after this one too
+        let src = " def binary^ 10 (a b) a; ";
+        assert_eq!(
+            parse_definition(&mut src.lex().lookahead()).unwrap_err(),
+            ParserError::BadOperatorRegistration(OpError::BuiltinOverride(Ops::Xor))
+        );
+
+        let table = OP_PRECEDENCE.read();
+        assert_eq!(table[&Ops::Xor].precedence, POW_PRECEDENCE);
+        assert_eq!(table[&Ops::Xor].assoc, Assoc::Right);
+
+        // Which says so where the operator is missing
+        let err = parse_definition(&mut " def binary 10 (a b) a; ".lex().lookahead()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("or ^ or a builtin with --allow-override"));
+    }
+
+    #[test]
+    fn parsing_with_remainder() {
+        let (expr, mut rest) = "1; 2"
+            .lex()
+            .lookahead()
+            .parse_with_remainder(parse_expression);
+        assert_eq!(expr, Ok(Box::new(NumberExpr(1.0))));
+
+        // The parse peeked at the ; to see the expression was over, it's still there
+        assert_eq!(rest.buffered(), 1);
+        assert_eq!(rest.next(), Some(Token::Semicolon));
+
+        let (expr, mut rest) = rest.parse_with_remainder(parse_expression);
+        assert_eq!(expr, Ok(Box::new(NumberExpr(2.0))));
+        assert_eq!(rest.next(), None);
+    }
+
+    #[test]
+    fn limiting_overload_precedence() {
+        // Rejected rather than taken as the most there can be
+        for src in [
+            " def binary : 2147483647 (a b) a; ",
+            " def binary : 1e20 (a b) a; ",
+        ] {
+            assert!(matches!(
+                parse_definition(&mut src.lex().lookahead()),
+                Err(ParserError::BadOperatorRegistration(
+                    OpError::PrecedenceOutOfRange(_)
+                ))
+            ));
+        }
+
+        let src = format!(" def binary : {MAX_PRECEDENCE} (a b) a; ");
+        let Ok(func) = parse_definition(&mut src.lex().lookahead()) else {
+            panic!("FATAL: failed to parse overload");
         };
 
-        if tok_prec < expr_prec {
-            return Ok(lhs);
-        }
+        assert!(matches!(
+            *func.proto,
+            Prototype::OverloadedBinaryOpProto {
+                precedence: MAX_PRECEDENCE,
+                ..
+            }
+        ));
+
+        assert!(parse_expression(&mut " 1 : 2 : 3 * 4 ".lex().lookahead()).is_ok());
+    }
+
+    #[test]
+    fn multiplying_implicitly() {
+        let implicit = ParserOptions {
+            implicit_mult: true,
+            ..ParserOptions::default()
+        };
+
+        let parse = |src: &'static str, options: ParserOptions| {
+            parse_expression(&mut options.lookahead(src.lex()))
+        };
+
+        let explicit = ParserOptions::default();
+
+        assert_eq!(parse(" 2x ", implicit), parse(" 2 * x ", explicit));
+        assert_eq!(
+            parse(" 3(x + 1) ", implicit),
+            parse(" 3 * (x + 1) ", explicit)
+        );
+
+        // Binding as tight as a written out "*" would
+        assert_eq!(
+            parse(" 1 + 2x * y - 1.5e2z ", implicit),
+            parse(" 1 + 2 * x * y - 150 * z ", explicit)
+        );
+
+        assert_eq!(
+            parse(" 2x ", explicit),
+            Err(ParserError::InvalidNumber("2x"))
+        );
+
+        // The precedence of a binary overload isn't multiplying anything
+        let src = " def binary : 5 (a b) 2a; ";
+        assert!(parse_definition(&mut implicit.apply(src.lex()).lookahead()).is_ok());
+    }
+
+    #[test]
+    fn preserving_parens() {
+        let preserving = ParserOptions {
+            preserve_parens: true,
+            ..ParserOptions::default()
+        };
+
+        let sum = ASTExpr::BinaryExpr {
+            op: Ops::Plus,
+            left: Box::new(ASTExpr::VariableExpr("a")),
+            right: Box::new(ASTExpr::VariableExpr("b")),
+        };
+
+        assert_eq!(
+            parse_expression(&mut preserving.lookahead(" (a + b) ".lex())),
+            Ok(Box::new(ASTExpr::ParenExpr(Box::new(sum.clone()))))
+        );
+
+        assert_eq!(
+            parse_expression(&mut ParserOptions::default().lookahead(" (a + b) ".lex())),
+            Ok(Box::new(sum))
+        );
+
+        // Still the same grouping, only now with the parens in the tree too
+        let Ok(expr) = parse_expression(&mut preserving.lookahead(" ((a)) * 2 ".lex())) else {
+            panic!("FATAL: failed to parse with parens preserved");
+        };
+
+        let ASTExpr::BinaryExpr { left, .. } = *expr else {
+            panic!("FATAL: expected a product");
+        };
+
+        assert_eq!(
+            *left,
+            ASTExpr::ParenExpr(Box::new(ASTExpr::ParenExpr(Box::new(
+                ASTExpr::VariableExpr("a")
+            ))))
+        );
+        // Calling a name in parens is still calling it, and a number in parens
+        // is no more a function than one without
+        assert_eq!(
+            parse_expression(&mut preserving.lookahead(" (f)(x) ".lex())),
+            Ok(Box::new(ASTExpr::CallExpr {
+                callee: "f",
+                args: vec![Box::new(ASTExpr::VariableExpr("x"))]
+            }))
+        );
+
+        assert_eq!(
+            parse_expression(&mut preserving.lookahead(" (2)(x) ".lex())),
+            Ok(Box::new(ASTExpr::ParenExpr(Box::new(ASTExpr::NumberExpr(
+                2.0
+            )))))
+        );
+    }
+
+    #[test]
+    fn terminating_top_level_items() {
+        let parse = |src: &'static str, policy| parse_program(&mut src.lex().lookahead(), policy);
+
+        // Definitions and externs end themselves just fine
+        let src = " extern sin(x) def f(x) sin(x) def g(x) f(x) * 2 g(1); ";
+        let Ok(program) = parse(src, TerminatorPolicy::AfterExpressions) else {
+            panic!("FATAL: failed to parse program");
+        };
+
+        assert_eq!(program.len(), 4);
+        assert!(matches!(program[3], TopLevel::Expression(_)));
+
+        // But not expressions, the start of the next one could be their end
+        for src in [" f(1) f(2); ", " def f(x) x f(1) ", " 1 + 2 def f(x) x "] {
+            assert_eq!(
+                parse(src, TerminatorPolicy::AfterExpressions),
+                Err(ParserError::ExpectedToken(";"))
+            );
+            assert!(parse(src, TerminatorPolicy::Optional).is_ok());
+        }
+    }
+
+    #[test]
+    fn parsing_array_literals() {
+        assert_eq!(
+            parse_expression(&mut " [1, 2, 3] ".lex().lookahead()),
+            Ok(Box::new(ArrayExpr(vec![
+                Box::new(NumberExpr(1.0)),
+                Box::new(NumberExpr(2.0)),
+                Box::new(NumberExpr(3.0)),
+            ])))
+        );
+
+        assert_eq!(
+            parse_expression(&mut " [] ".lex().lookahead()),
+            Ok(Box::new(ArrayExpr(vec![])))
+        );
+
+        assert_eq!(
+            parse_expression(&mut " [1 2] ".lex().lookahead()),
+            Err(ParserError::ExpectedToken(",/]"))
+        );
+
+        // Nesting, and elements as any expression
+        assert!(parse_expression(&mut " [[x + 1], f(2)] ".lex().lookahead()).is_ok());
+    }
+
+    #[test]
+    fn parsing_index_expressions() {
+        assert_eq!(
+            parse_expression(&mut " a[0] ".lex().lookahead()),
+            Ok(Box::new(IndexExpr {
+                base: Box::new(VariableExpr("a")),
+                index: Box::new(NumberExpr(0.0)),
+            }))
+        );
+
+        // Chained, the leftmost index is innermost
+        assert_eq!(
+            parse_expression(&mut " a[i][j] ".lex().lookahead()),
+            Ok(Box::new(IndexExpr {
+                base: Box::new(IndexExpr {
+                    base: Box::new(VariableExpr("a")),
+                    index: Box::new(VariableExpr("i")),
+                }),
+                index: Box::new(VariableExpr("j")),
+            }))
+        );
+
+        // Binding tighter than any operator, prefix ones included
+        assert_eq!(
+            parse_expression(&mut " -a[0] * [1, 2][i + 1] ".lex().lookahead()),
+            Ok(Box::new(BinaryExpr {
+                op: Mult,
+                left: Box::new(UnaryExpr {
+                    op: Minus,
+                    operand: Box::new(IndexExpr {
+                        base: Box::new(VariableExpr("a")),
+                        index: Box::new(NumberExpr(0.0)),
+                    }),
+                }),
+                right: Box::new(IndexExpr {
+                    base: Box::new(ArrayExpr(vec![
+                        Box::new(NumberExpr(1.0)),
+                        Box::new(NumberExpr(2.0)),
+                    ])),
+                    index: Box::new(BinaryExpr {
+                        op: Plus,
+                        left: Box::new(VariableExpr("i")),
+                        right: Box::new(NumberExpr(1.0)),
+                    }),
+                }),
+            }))
+        );
+
+        assert_eq!(
+            parse_expression(&mut " a[0 ".lex().lookahead()),
+            Err(ParserError::ExpectedToken("]"))
+        );
+    }
+
+    #[test]
+    fn parsing_postfix_operators() {
+        assert_eq!(
+            parse_expression(&mut " (f)(x) ".lex().lookahead()),
+            parse_expression(&mut " f(x) ".lex().lookahead())
+        );
+
+        assert_eq!(
+            parse_expression(&mut " a[0](1) ".lex().lookahead()),
+            Ok(Box::new(IndirectCallExpr {
+                callee: Box::new(IndexExpr {
+                    base: Box::new(VariableExpr("a")),
+                    index: Box::new(NumberExpr(0.0)),
+                }),
+                args: vec![Box::new(NumberExpr(1.0))],
+            }))
+        );
+
+        // A function returned by a call, then indexing what that one returns
+        assert_eq!(
+            parse_expression(&mut " f(1)(2)[3] ".lex().lookahead()),
+            Ok(Box::new(IndexExpr {
+                base: Box::new(IndirectCallExpr {
+                    callee: Box::new(CallExpr {
+                        callee: "f",
+                        args: vec![Box::new(NumberExpr(1.0))],
+                    }),
+                    args: vec![Box::new(NumberExpr(2.0))],
+                }),
+                index: Box::new(NumberExpr(3.0)),
+            }))
+        );
 
-        let Some(Token::Operator(op)) = tokens.next() else {
-            panic!("FATAL: misuse of of this function in recursive descent!")
-        };
+        // Numbers can't be called, this is just the 2
+        let mut tokens = " 2 (x) ".lex().lookahead();
+        assert_eq!(parse_expression(&mut tokens), Ok(Box::new(NumberExpr(2.0))));
+        assert_eq!(tokens.next(), Some(Token::OpenParen));
+    }
 
-        // In chapter 6, we changed this from parse_primary to parse_unary
-        // handle the lhs case where it might be attached to unary operator
-        let mut rhs = parse_unary(tokens)?;
+    #[test]
+    fn parsing_expressions_at_offsets() {
+        let (expr, end) = parse_expression_at("1+2 rest", 0).unwrap();
 
-        let next_prec = match tokens.peek().copied() {
-            Some(token) => get_token_precedence(token),
-            None => return Err(ParserError::UnexpectedEOI),
-        };
+        assert_eq!(
+            expr,
+            Box::new(BinaryExpr {
+                op: Plus,
+                left: Box::new(NumberExpr(1.0)),
+                right: Box::new(NumberExpr(2.0)),
+            })
+        );
+        assert_eq!(end, 3);
 
-        if tok_prec < next_prec {
-            rhs = parse_binop_rhs(tokens, rhs, tok_prec + 1)?;
-        }
+        // The offset is into the whole source, not from where it started
+        let src = "let y = x * (2 + 3) # why\n; done";
+        let (_, end) = parse_expression_at(src, 8).unwrap();
+        assert_eq!(&src[end..], " # why\n; done");
 
-        lhs = Box::new(ASTExpr::BinaryExpr {
-            op,
-            left: lhs,
-            right: rhs,
-        })
+        assert_eq!(
+            parse_expression_at("1 +", 0),
+            Err(ParserError::eoi(&["expression"]))
+        );
+
+        // Offsets that aren't the start of a character in the source at all
+        assert_eq!(parse_expression_at("1", 2), Err(ParserError::BadOffset(2)));
+        assert_eq!(
+            parse_expression_at("é + 1", 1),
+            Err(ParserError::BadOffset(1))
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::frontend::lexer::Lex;
+    #[test]
+    fn desugaring_compound_assignments() {
+        assert_eq!(
+            parse_expression(&mut " x += 1 ".lex().lookahead()),
+            parse_expression(&mut " x = x + 1 ".lex().lookahead())
+        );
 
-    use super::*;
-    use ASTExpr::*;
-    use Ops::*;
+        // The whole right side is the operand, as if it were in parentheses
+        assert_eq!(
+            parse_expression(&mut " x *= y + 2 ".lex().lookahead()),
+            parse_expression(&mut " x = x * (y + 2) ".lex().lookahead())
+        );
+
+        assert_eq!(
+            parse_expression(&mut " 1 += x ".lex().lookahead()),
+            Err(ParserError::BadCompoundAssignment(Plus))
+        );
+    }
 
     #[test]
-    fn parsing_primary_expressions() {
-        let mut tokens = " 23.2 ".lex().peekable();
-        let mut res = parse_primary(&mut tokens);
+    fn parsing_invalid_numbers() {
+        let mut tokens = " 1.2.3 + x ".lex().lookahead();
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Err(ParserError::InvalidNumber("1.2.3"))
+        );
 
-        assert_eq!(res, Ok(Box::new(NumberExpr(23.2))));
+        let mut tokens = " -2x ".lex().lookahead();
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Err(ParserError::InvalidNumber("2x"))
+        );
 
-        tokens = " myVariable ".lex().peekable();
-        res = parse_primary(&mut tokens);
+        // A hex float has to have its exponent
+        let mut tokens = " 0x1.8p3 + 0x1.8 ".lex().lookahead();
+        assert_eq!(parse_primary(&mut tokens), Ok(Box::new(NumberExpr(12.0))));
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Err(ParserError::InvalidNumber("0x1.8"))
+        );
+    }
 
-        assert_eq!(res, Ok(Box::new(VariableExpr(&"myVariable"))));
+    #[test]
+    fn using_undefined_operators() {
+        // Nothing gives & a meaning, so there's no precedence to parse it with
+        let mut tokens = " x & 1 ".lex().lookahead();
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Err(ParserError::UndefinedBinaryOperator(Ops::And))
+        );
 
-        tokens = " (400.5 - 323.10) ".lex().peekable();
-        res = parse_primary(&mut tokens);
+        tokens = " (x + 1) & y ".lex().lookahead();
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Err(ParserError::UndefinedBinaryOperator(Ops::And))
+        );
+    }
 
+    #[test]
+    fn parsing_signed_literals() {
+        let mut tokens = " -3 ".lex().lookahead();
         assert_eq!(
-            res,
-            Ok(Box::new(BinaryExpr {
+            parse_unary(&mut tokens),
+            Ok(Box::new(UnaryExpr {
                 op: Minus,
-                left: Box::new(NumberExpr(400.5)),
-                right: Box::new(NumberExpr(323.10)),
+                operand: Box::new(NumberExpr(3.0)),
             }))
         );
 
-        tokens = " squareNums(2) ".lex().peekable();
-        res = parse_primary(&mut tokens);
+        tokens = " +2.5 ".lex().lookahead();
+        assert_eq!(
+            parse_unary(&mut tokens),
+            Ok(Box::new(UnaryExpr {
+                op: Plus,
+                operand: Box::new(NumberExpr(2.5)),
+            }))
+        );
 
+        tokens = " -x ".lex().lookahead();
         assert_eq!(
-            res,
-            Ok(Box::new(CallExpr {
-                callee: &"squareNums",
-                args: vec![Box::new(NumberExpr(2.0))]
+            parse_unary(&mut tokens),
+            Ok(Box::new(UnaryExpr {
+                op: Minus,
+                operand: Box::new(VariableExpr("x")),
             }))
         );
 
-        tokens = " multiParams(6, x, (2 + 2)) ".lex().peekable();
-        res = parse_primary(&mut tokens);
+        // Each sign is its own unary expression
+        tokens = " - -3 ".lex().lookahead();
+        assert_eq!(
+            parse_unary(&mut tokens),
+            Ok(Box::new(UnaryExpr {
+                op: Minus,
+                operand: Box::new(UnaryExpr {
+                    op: Minus,
+                    operand: Box::new(NumberExpr(3.0)),
+                }),
+            }))
+        );
 
+        // Binary minus is untouched, the sign applies to the right operand only
+        tokens = " 2 - -3; ".lex().lookahead();
         assert_eq!(
-            res,
-            Ok(Box::new(CallExpr {
-                callee: &"multiParams",
-                args: vec![
-                    Box::new(NumberExpr(6.0)),
-                    Box::new(VariableExpr(&"x")),
-                    Box::new(BinaryExpr {
-                        op: Plus,
-                        left: Box::new(NumberExpr(2.0)),
-                        right: Box::new(NumberExpr(2.0)),
-                    })
-                ]
+            parse_expression(&mut tokens),
+            Ok(Box::new(BinaryExpr {
+                op: Minus,
+                left: Box::new(NumberExpr(2.0)),
+                right: Box::new(UnaryExpr {
+                    op: Minus,
+                    operand: Box::new(NumberExpr(3.0)),
+                }),
             }))
         );
     }
@@ -542,7 +2219,7 @@ mod tests {
     fn binary_expression_precedence() {
         // Left takes precedence, precedence here should be
         // (((1+2)-3)+4)
-        let mut tokens = " 1 + 2 - 3 + 4;".lex().peekable();
+        let mut tokens = " 1 + 2 - 3 + 4;".lex().lookahead();
         let mut expr_ast = parse_expression(&mut tokens);
 
         assert_eq!(
@@ -564,7 +2241,7 @@ mod tests {
 
         // The last binary expression " y * z " should take precedence,
         // (x + (y * z))
-        tokens = " x + y * z; ".lex().peekable();
+        tokens = " x + y * z; ".lex().lookahead();
         expr_ast = parse_expression(&mut tokens);
 
         assert_eq!(
@@ -583,7 +2260,7 @@ mod tests {
         // But parenthesis can be enforce  explicit binary expression
         // precedence ((x + y) * z)
 
-        tokens = " (x+y)*z;".lex().peekable();
+        tokens = " (x+y)*z;".lex().lookahead();
         expr_ast = parse_expression(&mut tokens);
 
         assert_eq!(
@@ -601,7 +2278,7 @@ mod tests {
 
         // Here the division expression in middle should take precedence,
         // ((2 + (10 / 5)) - 3)
-        tokens = " 2 + 10 / 5 - 3; ".lex().peekable();
+        tokens = " 2 + 10 / 5 - 3; ".lex().lookahead();
         expr_ast = parse_expression(&mut tokens);
 
         assert_eq!(
@@ -624,7 +2301,7 @@ mod tests {
 
     #[test]
     fn parsing_functions() {
-        let mut tokens = "def func1(x y) x * y;".lex().peekable();
+        let mut tokens = "def func1(x y) x * y;".lex().lookahead();
         let mut func_ast = parse_definition(&mut tokens);
 
         assert_eq!(
@@ -632,17 +2309,21 @@ mod tests {
             Ok(Box::new(Function {
                 proto: Box::new(Prototype::FunctionProto {
                     name: &"func1",
-                    args: vec![&"x", &"y"]
+                    args: vec![&"x", &"y"],
+                    variadic: false,
+                    void: false,
+                    decl: false,
                 }),
                 body: Box::new(BinaryExpr {
                     op: Mult,
                     left: Box::new(VariableExpr(&"x")),
                     right: Box::new(VariableExpr(&"y")),
-                },)
+                },),
+                attrs: vec![],
             }))
         );
 
-        tokens = "def alwaysReturnOne ( ) 1;".lex().peekable();
+        tokens = "def alwaysReturnOne ( ) 1;".lex().lookahead();
         func_ast = parse_definition(&mut tokens);
 
         assert_eq!(
@@ -650,15 +2331,19 @@ mod tests {
             Ok(Box::new(Function {
                 proto: Box::new(Prototype::FunctionProto {
                     name: &"alwaysReturnOne",
-                    args: vec![]
+                    args: vec![],
+                    variadic: false,
+                    void: false,
+                    decl: false,
                 }),
                 body: Box::new(NumberExpr(1.0)),
+                attrs: vec![],
             }))
         );
 
         tokens = "def func2 (base mid upper) base*mid + upper;"
             .lex()
-            .peekable();
+            .lookahead();
         func_ast = parse_definition(&mut tokens);
 
         assert_eq!(
@@ -666,7 +2351,10 @@ mod tests {
             Ok(Box::new(Function {
                 proto: Box::new(Prototype::FunctionProto {
                     name: &"func2",
-                    args: vec![&"base", &"mid", &"upper"]
+                    args: vec![&"base", &"mid", &"upper"],
+                    variadic: false,
+                    void: false,
+                    decl: false,
                 }),
                 body: Box::new(BinaryExpr {
                     op: Plus,
@@ -676,14 +2364,377 @@ mod tests {
                         right: Box::new(VariableExpr(&"mid")),
                     }),
                     right: Box::new(VariableExpr(&"upper")),
-                })
+                }),
+                attrs: vec![],
+            }))
+        );
+    }
+
+    #[test]
+    fn parsing_const_definitions() {
+        let mut tokens = "const TAU = 2 * PI; TAU;".lex().lookahead();
+
+        assert_eq!(
+            parse_top_level(&mut tokens),
+            Ok(TopLevel::Const(Box::new(ConstDef {
+                name: "TAU",
+                value: Box::new(BinaryExpr {
+                    op: Mult,
+                    left: Box::new(NumberExpr(2.0)),
+                    right: Box::new(VariableExpr("PI")),
+                }),
+            })))
+        );
+
+        assert_eq!(
+            parse_top_level(&mut "const = 1;".lex().lookahead()),
+            Err(ParserError::ExpectedToken("<identifier>"))
+        );
+        assert_eq!(
+            parse_top_level(&mut "const N 1;".lex().lookahead()),
+            Err(ParserError::ExpectedToken("="))
+        );
+    }
+
+    #[test]
+    fn parsing_zero_arg_calls() {
+        let mut tokens = "def f() 42; f();".lex().lookahead();
+
+        assert_eq!(
+            parse_definition(&mut tokens),
+            Ok(Box::new(Function {
+                proto: Box::new(Prototype::FunctionProto {
+                    name: "f",
+                    args: vec![],
+                    variadic: false,
+                    void: false,
+                    decl: false,
+                }),
+                body: Box::new(NumberExpr(42.0)),
+                attrs: vec![],
+            }))
+        );
+        assert_eq!(tokens.next(), Some(Token::Semicolon));
+
+        // The ) ends the call before any argument is looked for, and is taken
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Ok(Box::new(CallExpr {
+                callee: "f",
+                args: vec![],
+            }))
+        );
+        assert_eq!(tokens.next(), Some(Token::Semicolon));
+        assert_eq!(tokens.next(), None);
+
+        // A comma with nothing in front of it is no empty argument
+        assert_eq!(
+            parse_expression(&mut " f(,) ".lex().lookahead()),
+            Err(ParserError::UnexpectedToken(Token::Comma))
+        );
+
+        // Calls of what a zero argument call gives are just as empty
+        assert_eq!(
+            parse_expression(&mut " f()() ".lex().lookahead()),
+            Ok(Box::new(IndirectCallExpr {
+                callee: Box::new(CallExpr {
+                    callee: "f",
+                    args: vec![],
+                }),
+                args: vec![],
+            }))
+        );
+    }
+
+    #[test]
+    fn parsing_declarations() {
+        let (program, errors) =
+            parse_program_recovering(&mut "decl g(x); def f(x) g(x);".lex().lookahead());
+        assert!(errors.is_empty());
+
+        assert_eq!(
+            program[0],
+            TopLevel::Extern(Box::new(Prototype::FunctionProto {
+                name: "g",
+                args: vec!["x"],
+                variadic: false,
+                void: false,
+                decl: true,
+            }))
+        );
+        assert!(!parse_extern(&mut "extern g(x)".lex().lookahead())
+            .unwrap()
+            .is_decl());
+
+        // Recovery picks up again at the next decl
+        let (program, errors) =
+            parse_program_recovering(&mut "decl (x) decl h(x)".lex().lookahead());
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&program[..], [TopLevel::Extern(proto)] if proto.is_decl()));
+    }
+
+    #[test]
+    fn parsing_signed_arguments() {
+        let call = |src: &'static str| {
+            parse_top_level_expr(&mut src.lex().lookahead())
+                .unwrap()
+                .body
+        };
+
+        assert_eq!(
+            call("f(-1)"),
+            Box::new(CallExpr {
+                callee: "f",
+                args: vec![Box::new(UnaryExpr {
+                    op: Minus,
+                    operand: Box::new(NumberExpr(1.0)),
+                })],
+            })
+        );
+
+        assert_eq!(
+            call("f(a, -b)"),
+            Box::new(CallExpr {
+                callee: "f",
+                args: vec![
+                    Box::new(VariableExpr("a")),
+                    Box::new(UnaryExpr {
+                        op: Minus,
+                        operand: Box::new(VariableExpr("b")),
+                    }),
+                ],
+            })
+        );
+
+        assert_eq!(
+            call("f(-1 + 2, 1e-3)"),
+            Box::new(CallExpr {
+                callee: "f",
+                args: vec![
+                    Box::new(BinaryExpr {
+                        op: Plus,
+                        left: Box::new(UnaryExpr {
+                            op: Minus,
+                            operand: Box::new(NumberExpr(1.0)),
+                        }),
+                        right: Box::new(NumberExpr(2.0)),
+                    }),
+                    Box::new(NumberExpr(0.001)),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn expecting_at_end_of_input() {
+        let parse = |src: &'static str| parse_top_level_expr(&mut src.lex().lookahead());
+
+        let err = parse("(1 +").unwrap_err();
+        assert_eq!(err, ParserError::eoi(&["expression"]));
+        assert_eq!(
+            err.to_string(),
+            "error[E0002]: Reached end of input expecting expression"
+        );
+
+        assert_eq!(
+            parse("(1 + 2").unwrap_err(),
+            ParserError::eoi(&["operator", ")"])
+        );
+        assert_eq!(
+            parse("f(1").unwrap_err(),
+            ParserError::eoi(&["expression", ")"])
+        );
+        assert_eq!(parse("[1, 2").unwrap_err(), ParserError::eoi(&[",", "]"]));
+
+        let err = parse_definition(&mut "def".lex().lookahead()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error[E0002]: Reached end of input expecting function name or unary or binary"
+        );
+
+        // Cut short anywhere in a prototype, var or const, not just before it
+        let def = |src: &'static str| parse_definition(&mut src.lex().lookahead()).unwrap_err();
+        assert_eq!(def("def f"), ParserError::eoi(&["("]));
+        assert_eq!(def("def f(x"), ParserError::eoi(&[")"]));
+        assert_eq!(def("def f(x,"), ParserError::eoi(&["..."]));
+        assert_eq!(def("def unary ! ("), ParserError::eoi(&["argument"]));
+        assert_eq!(def("def binary |"), ParserError::eoi(&["precedence"]));
+
+        assert_eq!(
+            parse("var").unwrap_err(),
+            ParserError::eoi(&["<identifier>"])
+        );
+        assert_eq!(parse("var x = 1").unwrap_err(), ParserError::eoi(&["in"]));
+
+        let constdef = |src: &'static str| parse_const(&mut src.lex().lookahead()).unwrap_err();
+        assert_eq!(constdef("const"), ParserError::eoi(&["<identifier>"]));
+        assert_eq!(constdef("const n"), ParserError::eoi(&["="]));
+
+        // Anything else there is still the wrong token
+        assert_eq!(def("def f x"), ParserError::ExpectedToken("("));
+    }
+
+    #[test]
+    fn parsing_void_externs() {
+        let (program, errors) =
+            parse_program_recovering(&mut "extern void print(x); print(1);".lex().lookahead());
+        assert!(errors.is_empty());
+
+        assert_eq!(
+            program[0],
+            TopLevel::Extern(Box::new(Prototype::FunctionProto {
+                name: "print",
+                args: vec!["x"],
+                variadic: false,
+                void: true,
+                decl: false,
+            }))
+        );
+
+        // Without a name after it, void is the name
+        let proto = parse_extern(&mut "extern void(x)".lex().lookahead()).unwrap();
+        assert!(!proto.is_void());
+        assert_eq!(proto.get_name(), "void");
+    }
+
+    #[test]
+    fn parsing_variadic_prototypes() {
+        let proto = parse_extern(&mut "extern printf(fmt, ...)".lex().lookahead()).unwrap();
+        assert_eq!(
+            *proto,
+            Prototype::FunctionProto {
+                name: "printf",
+                args: vec!["fmt"],
+                variadic: true,
+                void: false,
+                decl: false,
+            }
+        );
+
+        let proto = parse_extern(&mut "extern f(...)".lex().lookahead()).unwrap();
+        assert!(proto.is_variadic() && proto.get_num_params() == 0);
+
+        let proto = parse_extern(&mut "extern g(x y)".lex().lookahead()).unwrap();
+        assert!(!proto.is_variadic());
+
+        assert_eq!(
+            parse_extern(&mut "extern h(x,)".lex().lookahead()),
+            Err(ParserError::ExpectedToken("..."))
+        );
+    }
+
+    #[test]
+    fn parsing_function_attributes() {
+        let func = parse_definition(&mut "def [inline] hot(x) x*x;".lex().lookahead()).unwrap();
+        assert_eq!(func.attrs, vec![FnAttr::Inline]);
+        assert_eq!(func.proto.get_name(), "hot");
+
+        let func = parse_definition(&mut "def [noinline, cold] f(x) x;".lex().lookahead()).unwrap();
+        assert_eq!(func.attrs, vec![FnAttr::NoInline, FnAttr::Cold]);
+
+        assert_eq!(
+            parse_definition(&mut "def [fast] f(x) x;".lex().lookahead()),
+            Err(ParserError::UnknownAttribute("fast"))
+        );
+
+        assert_eq!(
+            parse_definition(&mut "def [inline f(x) x;".lex().lookahead()),
+            Err(ParserError::ExpectedToken("]"))
+        );
+
+        assert_eq!(
+            parse_definition(&mut "def [inline, noinline] f(x) x;".lex().lookahead()),
+            Err(ParserError::ConflictingAttributes(
+                FnAttr::Inline,
+                FnAttr::NoInline
+            ))
+        );
+        assert_eq!(
+            parse_definition(&mut "def [cold, inline, hot] f(x) x;".lex().lookahead()),
+            Err(ParserError::ConflictingAttributes(
+                FnAttr::Cold,
+                FnAttr::Hot
+            ))
+        );
+    }
+
+    #[test]
+    fn identifiers_borrow_from_source() {
+        // Every name in the tree is a slice of the source itself, no matter how
+        // many times an identifier repeats, it is never copied into a new String
+        let src = "def f(x) x + g(x) * x;";
+        let func = parse_definition(&mut src.lex().lookahead()).unwrap();
+
+        let src_range = src.as_bytes().as_ptr_range();
+        let borrowed = |name: &str| src_range.contains(&name.as_ptr());
+
+        let Prototype::FunctionProto { name, ref args, .. } = *func.proto else {
+            panic!("Expected a function prototype");
+        };
+        assert!(borrowed(name) && borrowed(args[0]));
+
+        let BinaryExpr { left, right, .. } = *func.body else {
+            panic!("Expected a binary expression body");
+        };
+        let (VariableExpr(first_x), BinaryExpr { left: call, .. }) = (*left, *right) else {
+            panic!("Unexpected body structure");
+        };
+        let CallExpr { callee, .. } = *call else {
+            panic!("Expected call expression");
+        };
+
+        assert!(borrowed(first_x) && borrowed(callee));
+    }
+
+    #[test]
+    fn recovering_from_errors() {
+        let mut tokens = "def f(x) x + 1; def (y) y; 4 * ); f(2);".lex().lookahead();
+        let (program, errors) = parse_program_recovering(&mut tokens);
+
+        assert_eq!(
+            errors,
+            vec![
+                ParserError::UnexpectedToken(Token::OpenParen),
+                ParserError::UnexpectedToken(Token::ClosedParen),
+            ]
+        );
+
+        // The good definition and top level call both survive
+        assert_eq!(program.len(), 2);
+        assert!(matches!(program[0], TopLevel::Definition(_)));
+        assert!(matches!(program[1], TopLevel::Expression(_)));
+    }
+
+    #[test]
+    fn parsing_elif_chains() {
+        let mut tokens = " if a then 1 elif b then 2 elif c then 3 else 4; "
+            .lex()
+            .lookahead();
+
+        assert_eq!(
+            parse_if_expr(&mut tokens),
+            Ok(Box::new(IfExpr {
+                cond: Box::new(VariableExpr("a")),
+                then_branch: Box::new(NumberExpr(1.0)),
+                elifs: vec![
+                    (Box::new(VariableExpr("b")), Box::new(NumberExpr(2.0))),
+                    (Box::new(VariableExpr("c")), Box::new(NumberExpr(3.0))),
+                ],
+                else_branch: Box::new(NumberExpr(4.0)),
             }))
         );
+
+        // The final else can't be left off
+        let mut tokens = " if a then 1 elif b then 2; ".lex().lookahead();
+        assert_eq!(
+            parse_if_expr(&mut tokens),
+            Err(ParserError::ExpectedToken("else"))
+        );
     }
 
     #[test]
     fn parsing_if_then_else_expressions() {
-        let mut tokens = " if pred then x+1 else x-1; ".lex().peekable();
+        let mut tokens = " if pred then x+1 else x-1; ".lex().lookahead();
         let if_expr = parse_if_expr(&mut tokens);
 
         assert_eq!(
@@ -695,6 +2746,7 @@ mod tests {
                     left: Box::new(VariableExpr(&"x")),
                     right: Box::new(NumberExpr(1.0)),
                 }),
+                elifs: vec![],
                 else_branch: Box::new(BinaryExpr {
                     op: Minus,
                     left: Box::new(VariableExpr(&"x")),
@@ -703,4 +2755,30 @@ mod tests {
             }))
         );
     }
+
+    #[test]
+    fn error_codes() {
+        let errors = [
+            ParserError::UnexpectedToken(Token::Comma),
+            ParserError::eoi(&[]),
+            ParserError::ExpectedToken("("),
+            ParserError::BadOverloadedUnaryOp,
+            ParserError::BadOverloadedBinaryOp,
+            ParserError::UnknownAttribute("fast"),
+            ParserError::InvalidNumber("1.2.3"),
+            ParserError::DuplicateParameter("x"),
+            ParserError::NotAPrefixOperator(Ops::Mult),
+            ParserError::NotABinaryOperator(Ops::Negate),
+        ];
+
+        let codes = errors.iter().map(|err| err.code()).collect::<HashSet<_>>();
+        assert_eq!(codes.len(), errors.len());
+
+        for err in errors.iter() {
+            assert!(!err.code().is_empty());
+            assert!(err
+                .to_string()
+                .starts_with(&format!("error[{}]: ", err.code())));
+        }
+    }
 }
@@ -0,0 +1,570 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use thiserror::Error;
+
+use crate::backend::llvm_backend::is_intrinsic;
+use crate::frontend::{
+    ast::{ASTExpr, Prototype, TopLevel},
+    diagnostics::{Diagnostic, Span},
+    transform::references,
+};
+
+// Errors found when analyzing a whole program before we go on
+// to generate any IR for it.
+#[derive(Error, PartialEq, Debug)]
+pub enum SemaError<'src> {
+    #[error("Call to undefined function {0}")]
+    UndefinedFunction(&'src str),
+}
+
+// Semantic analysis over an entire program, done in two passes. The first
+// collects the name of every function that is defined or declared (extern),
+// anywhere in the program. The second walks every body looking for calls
+// to names not found in the first pass. Because we collect first, a function
+// may call itself, or one defined later on, so recursion and mutual recursion
+// are both fine.
+pub fn check_program<'src>(program: &[TopLevel<'src>]) -> Vec<SemaError<'src>> {
+    let known_fns = program
+        .iter()
+        .filter_map(|item| match item {
+            TopLevel::Definition(func) | TopLevel::Expression(func) => Some(func.proto.get_name()),
+            TopLevel::Extern(proto) => Some(proto.get_name()),
+            TopLevel::Const(_) => None,
+        })
+        .collect::<HashSet<String>>();
+
+    let mut errors = vec![];
+
+    for item in program.iter() {
+        if let TopLevel::Definition(func) | TopLevel::Expression(func) = item {
+            check_calls(
+                &func.body,
+                &known_fns,
+                &func.proto.get_params(),
+                &mut errors,
+            );
+        }
+    }
+
+    errors
+}
+
+// Recursively walk an expression, flagging any call to a function we don't know about,
+// builtins like floor are always known. Calls to a local in scope are fine too,
+// those go through whatever function pointer it holds
+fn check_calls<'src>(
+    expr: &ASTExpr<'src>,
+    known_fns: &HashSet<String>,
+    locals: &[&'src str],
+    errors: &mut Vec<SemaError<'src>>,
+) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        UnaryExpr { operand, .. } | ParenExpr(operand) => {
+            check_calls(operand, known_fns, locals, errors)
+        }
+
+        BinaryExpr { left, right, .. } => {
+            check_calls(left, known_fns, locals, errors);
+            check_calls(right, known_fns, locals, errors);
+        }
+
+        CallExpr { callee, args } => {
+            if !locals.contains(callee) && !known_fns.contains(*callee) && !is_intrinsic(callee) {
+                errors.push(SemaError::UndefinedFunction(callee));
+            }
+
+            args.iter()
+                .for_each(|arg| check_calls(arg, known_fns, locals, errors));
+        }
+
+        IndirectCallExpr { callee, args } => {
+            check_calls(callee, known_fns, locals, errors);
+            args.iter()
+                .for_each(|arg| check_calls(arg, known_fns, locals, errors));
+        }
+
+        ArrayExpr(elems) => elems
+            .iter()
+            .for_each(|elem| check_calls(elem, known_fns, locals, errors)),
+
+        IndexExpr { base, index } => {
+            check_calls(base, known_fns, locals, errors);
+            check_calls(index, known_fns, locals, errors);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            check_calls(cond, known_fns, locals, errors);
+            check_calls(then_branch, known_fns, locals, errors);
+
+            for (elif_cond, elif_branch) in elifs.iter() {
+                check_calls(elif_cond, known_fns, locals, errors);
+                check_calls(elif_branch, known_fns, locals, errors);
+            }
+
+            check_calls(else_branch, known_fns, locals, errors);
+        }
+
+        // The loop variable is in scope for all but the start
+        ForLoopExpr {
+            varname,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            check_calls(start, known_fns, locals, errors);
+
+            let locals = [locals, &[*varname]].concat();
+            check_calls(end, known_fns, &locals, errors);
+            check_calls(step, known_fns, &locals, errors);
+            check_calls(body, known_fns, &locals, errors);
+        }
+
+        WhileExpr { cond, body } => {
+            check_calls(cond, known_fns, locals, errors);
+            check_calls(body, known_fns, locals, errors);
+        }
+
+        // Each binding is in scope for the initializers after it
+        VarExpr { var_names, body } => {
+            let mut locals = locals.to_vec();
+
+            for (name, init) in var_names.iter() {
+                if let Some(init) = init {
+                    check_calls(init, known_fns, &locals, errors);
+                }
+
+                locals.push(name);
+            }
+
+            check_calls(body, known_fns, &locals, errors);
+        }
+    }
+}
+
+// Everything found about the program, the errors of check_program along
+// with warnings, which don't stop compilation. Needs the source the program
+// was parsed from, to work out where in it each problem is.
+pub fn diagnose<'src>(src_code: &'src str, program: &[TopLevel<'src>]) -> Vec<Diagnostic> {
+    let mut diagnostics = check_program(program)
+        .into_iter()
+        .map(|err| match err {
+            SemaError::UndefinedFunction(callee) => {
+                Diagnostic::error(err.to_string(), Span::of(src_code, callee))
+            }
+        })
+        .collect::<Vec<Diagnostic>>();
+
+    for item in program.iter() {
+        if let TopLevel::Definition(func) | TopLevel::Expression(func) = item {
+            let mut unused = vec![];
+            find_unused_vars(&func.body, &mut unused);
+
+            diagnostics.extend(unused.into_iter().map(|name| {
+                Diagnostic::warning(format!("Unused variable {name}"), Span::of(src_code, name))
+            }));
+        }
+    }
+
+    diagnostics
+}
+
+// Recursively walk an expression, collecting var bindings that are never used,
+// not by the body, nor the initializers after them
+fn find_unused_vars<'src>(expr: &ASTExpr<'src>, unused: &mut Vec<&'src str>) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        UnaryExpr { operand, .. } | ParenExpr(operand) => find_unused_vars(operand, unused),
+
+        BinaryExpr { left, right, .. } => {
+            find_unused_vars(left, unused);
+            find_unused_vars(right, unused);
+        }
+
+        CallExpr { args, .. } => args.iter().for_each(|arg| find_unused_vars(arg, unused)),
+
+        IndirectCallExpr { callee, args } => {
+            find_unused_vars(callee, unused);
+            args.iter().for_each(|arg| find_unused_vars(arg, unused));
+        }
+
+        ArrayExpr(elems) => elems.iter().for_each(|elem| find_unused_vars(elem, unused)),
+
+        IndexExpr { base, index } => {
+            find_unused_vars(base, unused);
+            find_unused_vars(index, unused);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            find_unused_vars(cond, unused);
+            find_unused_vars(then_branch, unused);
+
+            for (elif_cond, elif_branch) in elifs.iter() {
+                find_unused_vars(elif_cond, unused);
+                find_unused_vars(elif_branch, unused);
+            }
+
+            find_unused_vars(else_branch, unused);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            find_unused_vars(start, unused);
+            find_unused_vars(end, unused);
+            find_unused_vars(step, unused);
+            find_unused_vars(body, unused);
+        }
+
+        WhileExpr { cond, body } => {
+            find_unused_vars(cond, unused);
+            find_unused_vars(body, unused);
+        }
+
+        VarExpr { var_names, body } => {
+            for (idx, (name, init)) in var_names.iter().enumerate() {
+                if let Some(init) = init {
+                    find_unused_vars(init, unused);
+                }
+
+                let used = references(body, name)
+                    || var_names[idx + 1..]
+                        .iter()
+                        .filter_map(|(_, init)| init.as_ref())
+                        .any(|init| references(init, name));
+
+                if !used {
+                    unused.push(name);
+                }
+            }
+
+            find_unused_vars(body, unused);
+        }
+    }
+}
+
+// Each function defined in the program, to the functions its body calls,
+// operator overloads included, as using one is a call too, and so is naming
+// one as a value, for it to be called through later. Kept sorted, so
+// anything walking the graph, like ordering codegen, does so the same way
+// every run. A call through a local isn't to any function in particular,
+// so it's left out, mutual recursion shows up as a cycle.
+pub type CallGraph = BTreeMap<String, BTreeSet<String>>;
+
+pub fn call_graph(program: &[TopLevel]) -> CallGraph {
+    let defined = program
+        .iter()
+        .filter_map(|item| match item {
+            TopLevel::Definition(func) => Some(func.proto.get_name()),
+            _ => None,
+        })
+        .collect::<BTreeSet<String>>();
+
+    let mut graph = CallGraph::new();
+
+    for item in program.iter() {
+        if let TopLevel::Definition(func) = item {
+            let callees = graph.entry(func.proto.get_name()).or_default();
+            collect_calls(&func.body, &defined, &func.proto.get_params(), callees);
+        }
+    }
+
+    graph
+}
+
+// Definitions that nothing calls, besides themselves, for --warn-unused-functions.
+// Calls from top level expressions count, but there's no following them out
+// from some root, so a dead helper only called by another dead one is fine.
+// The entry point and main are called from outside the program, externs
+// are someone else's. Operator overloads are left out too, they extend the
+// language for whatever comes after, used or not.
+pub fn find_unused_functions<'src>(
+    src_code: &'src str,
+    program: &[TopLevel<'src>],
+    entry: Option<&str>,
+) -> Vec<Diagnostic> {
+    let graph = call_graph(program);
+    let defined = graph.keys().cloned().collect::<BTreeSet<String>>();
+
+    let mut used = BTreeSet::new();
+
+    for (caller, callees) in graph.iter() {
+        used.extend(callees.iter().filter(|callee| *callee != caller).cloned());
+    }
+
+    for item in program.iter() {
+        if let TopLevel::Expression(func) = item {
+            collect_calls(&func.body, &defined, &[], &mut used);
+        }
+    }
+
+    program
+        .iter()
+        .filter_map(|item| match item {
+            TopLevel::Definition(func) => match *func.proto {
+                Prototype::FunctionProto { name, .. } => Some(name),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|name| !used.contains(*name) && *name != "main" && Some(*name) != entry)
+        .map(|name| {
+            let message = format!("Function {name} is never called");
+            Diagnostic::warning(message, Span::of(src_code, name))
+        })
+        .collect()
+}
+
+// Scoped just like check_calls, so a local shadows a function of the same name
+fn collect_calls<'src>(
+    expr: &ASTExpr<'src>,
+    defined: &BTreeSet<String>,
+    locals: &[&'src str],
+    callees: &mut BTreeSet<String>,
+) {
+    use ASTExpr::*;
+
+    let mut overload = |name: String| {
+        if defined.contains(&name) {
+            callees.insert(name);
+        }
+    };
+
+    match expr {
+        VariableExpr(name) if !locals.contains(name) && defined.contains(*name) => {
+            callees.insert(name.to_string());
+        }
+
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        ParenExpr(inner) => collect_calls(inner, defined, locals, callees),
+
+        UnaryExpr { op, operand } => {
+            overload(format!("unary{}", op.as_str()));
+            collect_calls(operand, defined, locals, callees);
+        }
+
+        BinaryExpr { op, left, right } => {
+            overload(format!("binary{}", op.as_str()));
+            collect_calls(left, defined, locals, callees);
+            collect_calls(right, defined, locals, callees);
+        }
+
+        CallExpr { callee, args } => {
+            if !locals.contains(callee) {
+                callees.insert(callee.to_string());
+            }
+
+            args.iter()
+                .for_each(|arg| collect_calls(arg, defined, locals, callees));
+        }
+
+        // Whatever function is called through the pointer, it's not known
+        // until it's run, only what's called to get at the pointer is
+        IndirectCallExpr { callee, args } => {
+            collect_calls(callee, defined, locals, callees);
+            args.iter()
+                .for_each(|arg| collect_calls(arg, defined, locals, callees));
+        }
+
+        ArrayExpr(elems) => elems
+            .iter()
+            .for_each(|elem| collect_calls(elem, defined, locals, callees)),
+
+        IndexExpr { base, index } => {
+            collect_calls(base, defined, locals, callees);
+            collect_calls(index, defined, locals, callees);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            collect_calls(cond, defined, locals, callees);
+            collect_calls(then_branch, defined, locals, callees);
+
+            for (elif_cond, elif_branch) in elifs.iter() {
+                collect_calls(elif_cond, defined, locals, callees);
+                collect_calls(elif_branch, defined, locals, callees);
+            }
+
+            collect_calls(else_branch, defined, locals, callees);
+        }
+
+        ForLoopExpr {
+            varname,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            collect_calls(start, defined, locals, callees);
+
+            let locals = [locals, &[*varname]].concat();
+            collect_calls(end, defined, &locals, callees);
+            collect_calls(step, defined, &locals, callees);
+            collect_calls(body, defined, &locals, callees);
+        }
+
+        WhileExpr { cond, body } => {
+            collect_calls(cond, defined, locals, callees);
+            collect_calls(body, defined, locals, callees);
+        }
+
+        VarExpr { var_names, body } => {
+            let mut locals = locals.to_vec();
+
+            for (name, init) in var_names.iter() {
+                if let Some(init) = init {
+                    collect_calls(init, defined, &locals, callees);
+                }
+
+                locals.push(name);
+            }
+
+            collect_calls(body, defined, &locals, callees);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_program_recovering};
+
+    fn parse(src: &str) -> Vec<TopLevel<'_>> {
+        let (program, errors) = parse_program_recovering(&mut src.lex().lookahead());
+        assert!(errors.is_empty());
+
+        program
+    }
+
+    #[test]
+    fn undefined_function_call() {
+        let program = parse("def bar(x) foo(x) + 1;");
+
+        assert_eq!(
+            check_program(&program),
+            vec![SemaError::UndefinedFunction("foo")]
+        );
+
+        // Top level expressions are checked too, externs count as declared
+        let program = parse("extern sin(x); sin(2) + foo(3);");
+
+        assert_eq!(
+            check_program(&program),
+            vec![SemaError::UndefinedFunction("foo")]
+        );
+    }
+
+    #[test]
+    fn recursion_is_allowed() {
+        let program = parse("def fact(n) if n < 2 then 1 else n * fact(n - 1);");
+        assert_eq!(check_program(&program), vec![]);
+
+        let program = parse("def a() b(); def b() a();");
+        assert_eq!(check_program(&program), vec![]);
+    }
+
+    #[test]
+    fn calling_locals() {
+        let program = parse("def apply(f x) f(x); def g(x) var h = x in h(1) + x(2);");
+        assert_eq!(check_program(&program), vec![]);
+
+        // Out of scope, the name is a function again
+        let program = parse("def g(x) (var h = x in h(1)) + h(2);");
+
+        assert_eq!(
+            check_program(&program),
+            vec![SemaError::UndefinedFunction("h")]
+        );
+    }
+
+    #[test]
+    fn warning_on_unused_vars() {
+        let src = "def f(x) var a = 1, b = 2 in a + foo(x);";
+        let diagnostics = diagnose(src, &parse(src));
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::error(
+                    "Call to undefined function foo".to_string(),
+                    Span { start: 33, end: 36 }
+                ),
+                Diagnostic::warning("Unused variable b".to_string(), Span { start: 20, end: 21 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn extracting_call_graph() {
+        let graph = call_graph(&parse("def a() b(); def b() a(); a();"));
+
+        assert_eq!(
+            graph,
+            CallGraph::from([
+                ("a".to_string(), BTreeSet::from(["b".to_string()])),
+                ("b".to_string(), BTreeSet::from(["a".to_string()])),
+            ])
+        );
+
+        // Overloads used are called, locals called through aren't functions
+        let graph = call_graph(&parse(
+            "def binary| 5 (a b) a; def f(g x) g(x) | sin(x); extern sin(x); def h() 1;",
+        ));
+
+        assert_eq!(
+            graph["f"],
+            BTreeSet::from(["binary|".to_string(), "sin".to_string()])
+        );
+        assert!(graph["h"].is_empty() && !graph.contains_key("sin"));
+    }
+
+    #[test]
+    fn finding_unused_functions() {
+        let src = "def helper(x) x * 2; def dead(x) dead(x - 1); def f(x) helper(x);";
+        let unused = find_unused_functions(src, &parse(src), Some("f"));
+
+        assert_eq!(
+            unused,
+            vec![Diagnostic::warning(
+                "Function dead is never called".to_string(),
+                Span { start: 25, end: 29 }
+            )]
+        );
+
+        // Called from the top level, passed as a value, main, and externs
+        let src = "extern sin(x); def id(f) f; def g(x) x; def h(x) x; def main() id(g)(1); h(2);";
+        assert_eq!(find_unused_functions(src, &parse(src), None), vec![]);
+    }
+
+    #[test]
+    fn builtins_are_known() {
+        let program = parse("def f(x) floor(x) + ceil(x) + round(x) + trunc(x);");
+        assert_eq!(check_program(&program), vec![]);
+    }
+}
@@ -0,0 +1,704 @@
+use std::collections::HashMap;
+
+use crate::backend::llvm_backend::const_builtin;
+use crate::frontend::{
+    ast::{ASTExpr, Function},
+    lexer::Ops,
+};
+
+// Dead code elimination on the tree itself, before LLVM ever sees it.
+// Drops var bindings whose variables are never referenced, as long as the
+// initializer has no side effects. A var left with no bindings at all is
+// replaced by its body. LLVM does this too of course, but doing it here
+// also helps anything else that walks the tree.
+pub fn eliminate_dead_vars(func: &mut Function) {
+    eliminate_dead_vars_expr(&mut func.body);
+}
+
+fn eliminate_dead_vars_expr(expr: &mut ASTExpr) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        UnaryExpr { operand, .. } | ParenExpr(operand) => eliminate_dead_vars_expr(operand),
+
+        BinaryExpr { left, right, .. } => {
+            eliminate_dead_vars_expr(left);
+            eliminate_dead_vars_expr(right);
+        }
+
+        CallExpr { args, .. } => args
+            .iter_mut()
+            .for_each(|arg| eliminate_dead_vars_expr(arg)),
+
+        IndirectCallExpr { callee, args } => {
+            eliminate_dead_vars_expr(callee);
+            args.iter_mut()
+                .for_each(|arg| eliminate_dead_vars_expr(arg));
+        }
+
+        ArrayExpr(elems) => elems
+            .iter_mut()
+            .for_each(|elem| eliminate_dead_vars_expr(elem)),
+
+        IndexExpr { base, index } => {
+            eliminate_dead_vars_expr(base);
+            eliminate_dead_vars_expr(index);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            eliminate_dead_vars_expr(cond);
+            eliminate_dead_vars_expr(then_branch);
+
+            for (elif_cond, elif_branch) in elifs.iter_mut() {
+                eliminate_dead_vars_expr(elif_cond);
+                eliminate_dead_vars_expr(elif_branch);
+            }
+
+            eliminate_dead_vars_expr(else_branch);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            eliminate_dead_vars_expr(start);
+            eliminate_dead_vars_expr(end);
+            eliminate_dead_vars_expr(step);
+            eliminate_dead_vars_expr(body);
+        }
+
+        WhileExpr { cond, body } => {
+            eliminate_dead_vars_expr(cond);
+            eliminate_dead_vars_expr(body);
+        }
+
+        VarExpr { var_names, body } => {
+            var_names
+                .iter_mut()
+                .filter_map(|(_, init)| init.as_mut())
+                .for_each(|init| eliminate_dead_vars_expr(init));
+
+            eliminate_dead_vars_expr(body);
+
+            // Go backwards, a binding is only used by the body or the
+            // initializers of the bindings after it
+            let mut idx = var_names.len();
+            while idx > 0 {
+                idx -= 1;
+
+                let (name, init) = &var_names[idx];
+
+                let used = references(body, name)
+                    || var_names[idx + 1..]
+                        .iter()
+                        .filter_map(|(_, init)| init.as_ref())
+                        .any(|init| references(init, name));
+
+                let pure = init.as_ref().is_none_or(|init| is_pure(init));
+
+                if !used && pure {
+                    var_names.remove(idx);
+                }
+            }
+
+            if var_names.is_empty() {
+                let body = std::mem::replace(body, Box::new(NumberExpr(0.0)));
+                *expr = *body;
+            }
+        }
+    }
+}
+
+// Does the expression mention the variable anywhere? Inner scopes that shadow
+// the name are counted too, which is conservative but always safe.
+pub(crate) fn references(expr: &ASTExpr, name: &str) -> bool {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) => false,
+
+        VariableExpr(var) => *var == name,
+
+        UnaryExpr { operand, .. } | ParenExpr(operand) => references(operand, name),
+
+        BinaryExpr { left, right, .. } => references(left, name) || references(right, name),
+
+        // Calling through a local uses it as much as passing it does
+        CallExpr { callee, args } => {
+            *callee == name || args.iter().any(|arg| references(arg, name))
+        }
+
+        IndirectCallExpr { callee, args } => {
+            references(callee, name) || args.iter().any(|arg| references(arg, name))
+        }
+
+        ArrayExpr(elems) => elems.iter().any(|elem| references(elem, name)),
+
+        IndexExpr { base, index } => references(base, name) || references(index, name),
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            references(cond, name)
+                || references(then_branch, name)
+                || elifs.iter().any(|(elif_cond, elif_branch)| {
+                    references(elif_cond, name) || references(elif_branch, name)
+                })
+                || references(else_branch, name)
+        }
+
+        ForLoopExpr {
+            varname,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            *varname == name
+                || references(start, name)
+                || references(end, name)
+                || references(step, name)
+                || references(body, name)
+        }
+
+        WhileExpr { cond, body } => references(cond, name) || references(body, name),
+
+        VarExpr { var_names, body } => {
+            var_names.iter().any(|(var, init)| {
+                *var == name || init.as_ref().is_some_and(|init| references(init, name))
+            }) || references(body, name)
+        }
+    }
+}
+
+// Can the expression be thrown away without anyone noticing? Calls might
+// do anything, as might the user defined operators, which are calls too,
+// and assignments obviously change things. Everything else is pure.
+fn is_pure(expr: &ASTExpr) -> bool {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => true,
+
+        ParenExpr(inner) => is_pure(inner),
+
+        BinaryExpr { op, left, right } => {
+            matches!(
+                op,
+                Ops::Plus
+                    | Ops::Minus
+                    | Ops::Mult
+                    | Ops::Div
+                    | Ops::Mod
+                    | Ops::Eq
+                    | Ops::Neq
+                    | Ops::Lt
+                    | Ops::Gt
+                    | Ops::Le
+                    | Ops::Ge
+            ) && is_pure(left)
+                && is_pure(right)
+        }
+
+        ArrayExpr(elems) => elems.iter().all(|elem| is_pure(elem)),
+
+        IndexExpr { base, index } => is_pure(base) && is_pure(index),
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            is_pure(cond)
+                && is_pure(then_branch)
+                && elifs
+                    .iter()
+                    .all(|(elif_cond, elif_branch)| is_pure(elif_cond) && is_pure(elif_branch))
+                && is_pure(else_branch)
+        }
+
+        UnaryExpr { .. }
+        | CallExpr { .. }
+        | IndirectCallExpr { .. }
+        | ForLoopExpr { .. }
+        | WhileExpr { .. }
+        | VarExpr { .. } => false,
+    }
+}
+
+// Constant folding on the tree, any builtin binary operator applied to two
+// number literals is replaced by its result, working from the leaves up, so
+// "2 + 3 * 4" turns into just 14. Comparisons fold to 1 or 0, like codegen.
+// Unary operators are left alone, a user may have overloaded those, so is ^,
+// which is only power while no "binary^" is defined, see eval_const.
+pub fn fold_constants(func: &mut Function) {
+    fold_constants_expr(&mut func.body);
+}
+
+// What a builtin binary operator gives on two numbers, None for the rest,
+// which are overloads, or assignment, and ^, which may be either
+pub fn fold_binary(op: Ops, lhs: f64, rhs: f64) -> Option<f64> {
+    let truth = |b: bool| if b { 1.0 } else { 0.0 };
+
+    match op {
+        Ops::Plus => Some(lhs + rhs),
+        Ops::Minus => Some(lhs - rhs),
+        Ops::Mult => Some(lhs * rhs),
+        Ops::Div => Some(lhs / rhs),
+        Ops::Mod => Some(lhs % rhs),
+        Ops::Eq => Some(truth(lhs == rhs)),
+        Ops::Neq => Some(truth(lhs != rhs && !lhs.is_nan() && !rhs.is_nan())),
+        Ops::Lt => Some(truth(lhs < rhs)),
+        Ops::Gt => Some(truth(lhs > rhs)),
+        Ops::Le => Some(truth(lhs <= rhs)),
+        Ops::Ge => Some(truth(lhs >= rhs)),
+        _ => None,
+    }
+}
+
+// How many times over codegen multiplies out base ^ exponent, a constant
+// exponent that is, see LLVMContext::build_pow. Only a whole one up to max,
+// for any other it calls llvm.pow.
+pub fn pow_unroll(exponent: f64, max: u32) -> Option<u32> {
+    (exponent.fract() == 0.0 && (0.0..=max as f64).contains(&exponent)).then_some(exponent as u32)
+}
+
+// base ^ n multiplied out like codegen does it, rounding after each multiply
+// in the same order, which pow doesn't, so the last bit can differ from it
+pub fn unrolled_pow(base: f64, n: u32) -> f64 {
+    match n {
+        0 => 1.0,
+        n => (1..n).fold(base, |product, _| product * base),
+    }
+}
+
+// The value of a constant's expression, worked out at compile time, from
+// numbers, the constants defined before it, builtin operators, and builtins
+// like len. Anything that could only be known by running the program, like
+// a call, makes it no constant at all. Whether a function of some name, an
+// operator overload, exists is up to the caller. Powers are worked out as
+// codegen would, multiplying out those up to max_pow_unroll.
+pub fn eval_const(
+    expr: &ASTExpr,
+    consts: &HashMap<String, f64>,
+    is_defined: &dyn Fn(&str) -> bool,
+    max_pow_unroll: u32,
+) -> Option<f64> {
+    use ASTExpr::*;
+
+    let eval = |expr: &ASTExpr| eval_const(expr, consts, is_defined, max_pow_unroll);
+
+    match expr {
+        NumberExpr(num) => Some(*num),
+
+        VariableExpr(name) => consts.get(*name).copied(),
+
+        ParenExpr(inner) => eval(inner),
+
+        UnaryExpr { op, operand } if !is_defined(&format!("unary{}", op.as_str())) => match op {
+            Ops::Minus => eval(operand).map(|num| -num),
+            Ops::Plus => eval(operand),
+            _ => None,
+        },
+
+        // ^ is only power while it isn't overloaded
+        BinaryExpr { op: Ops::Xor, .. } if is_defined("binary^") => None,
+
+        BinaryExpr {
+            op: Ops::Xor,
+            left,
+            right,
+        } => {
+            let (base, exponent) = (eval(left)?, eval(right)?);
+
+            Some(match pow_unroll(exponent, max_pow_unroll) {
+                Some(n) => unrolled_pow(base, n),
+                None => base.powf(exponent),
+            })
+        }
+
+        BinaryExpr { op, left, right } => fold_binary(*op, eval(left)?, eval(right)?),
+
+        CallExpr { callee, args } => match (const_builtin(callee), args.as_slice()) {
+            (Some(builtin), [arg]) => match **arg {
+                StringExpr(text) => Some(builtin(text)),
+                _ => None,
+            },
+            _ => None,
+        },
+
+        _ => None,
+    }
+}
+
+fn fold_constants_expr(expr: &mut ASTExpr) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        UnaryExpr { operand, .. } => fold_constants_expr(operand),
+
+        // "(2 + 3) * 4" should fold all the way, the parens mustn't get in between
+        ParenExpr(inner) => {
+            fold_constants_expr(inner);
+
+            if let NumberExpr(num) = **inner {
+                *expr = NumberExpr(num);
+            }
+        }
+
+        BinaryExpr { op, left, right } => {
+            fold_constants_expr(left);
+            fold_constants_expr(right);
+
+            if let (NumberExpr(lhs), NumberExpr(rhs)) = (&**left, &**right) {
+                if let Some(num) = fold_binary(*op, *lhs, *rhs) {
+                    *expr = NumberExpr(num);
+                }
+            }
+        }
+
+        CallExpr { callee, args } => {
+            args.iter_mut().for_each(|arg| fold_constants_expr(arg));
+
+            // Builtins like len, on a literal, are as good as a number already
+            if let (Some(builtin), [arg]) = (const_builtin(callee), args.as_slice()) {
+                if let StringExpr(text) = **arg {
+                    *expr = NumberExpr(builtin(text));
+                }
+            }
+        }
+
+        IndirectCallExpr { callee, args } => {
+            fold_constants_expr(callee);
+            args.iter_mut().for_each(|arg| fold_constants_expr(arg));
+        }
+
+        ArrayExpr(elems) => elems.iter_mut().for_each(|elem| fold_constants_expr(elem)),
+
+        IndexExpr { base, index } => {
+            fold_constants_expr(base);
+            fold_constants_expr(index);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            fold_constants_expr(cond);
+            fold_constants_expr(then_branch);
+
+            for (elif_cond, elif_branch) in elifs.iter_mut() {
+                fold_constants_expr(elif_cond);
+                fold_constants_expr(elif_branch);
+            }
+
+            fold_constants_expr(else_branch);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            fold_constants_expr(start);
+            fold_constants_expr(end);
+            fold_constants_expr(step);
+            fold_constants_expr(body);
+        }
+
+        WhileExpr { cond, body } => {
+            fold_constants_expr(cond);
+            fold_constants_expr(body);
+        }
+
+        VarExpr { var_names, body } => {
+            var_names
+                .iter_mut()
+                .filter_map(|(_, init)| init.as_mut())
+                .for_each(|init| fold_constants_expr(init));
+
+            fold_constants_expr(body);
+        }
+    }
+}
+
+// Rewrites every for loop into the while loop it amounts to, showing how
+// little the for loop really adds. "for i = start, end, step in body" is
+//
+// var i = start in
+//   var `for.more` = 1 in
+//     while `for.more` in
+//       var `for.body` = body, `for.step` = step, `for.end` = end,
+//           `for.next` = (i = i + `for.step`) in
+//         `for.more` = `for.end`
+//
+// The body runs once before end is first checked, then step and end are
+// worked out with i as the body left it, just as the for loop has them. The
+// var of i scopes it to the loop, shadowing any i outside it, like the for
+// loop does. The other names are the quoted ones above, backticks and all,
+// which no identifier can hold, quoted or not, so none of them can clash
+// with a name in the body.
+pub fn desugar_for(func: &mut Function) {
+    desugar_for_expr(&mut func.body);
+}
+
+fn desugar_for_expr(expr: &mut ASTExpr) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+
+        UnaryExpr { operand, .. } | ParenExpr(operand) => desugar_for_expr(operand),
+
+        BinaryExpr { left, right, .. } => {
+            desugar_for_expr(left);
+            desugar_for_expr(right);
+        }
+
+        CallExpr { args, .. } => args.iter_mut().for_each(|arg| desugar_for_expr(arg)),
+
+        IndirectCallExpr { callee, args } => {
+            desugar_for_expr(callee);
+            args.iter_mut().for_each(|arg| desugar_for_expr(arg));
+        }
+
+        ArrayExpr(elems) => elems.iter_mut().for_each(|elem| desugar_for_expr(elem)),
+
+        IndexExpr { base, index } => {
+            desugar_for_expr(base);
+            desugar_for_expr(index);
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            elifs,
+            else_branch,
+        } => {
+            desugar_for_expr(cond);
+            desugar_for_expr(then_branch);
+
+            for (elif_cond, elif_branch) in elifs.iter_mut() {
+                desugar_for_expr(elif_cond);
+                desugar_for_expr(elif_branch);
+            }
+
+            desugar_for_expr(else_branch);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            desugar_for_expr(start);
+            desugar_for_expr(end);
+            desugar_for_expr(step);
+            desugar_for_expr(body);
+
+            let ForLoopExpr {
+                varname,
+                start,
+                end,
+                step,
+                body,
+            } = std::mem::replace(expr, NumberExpr(0.0))
+            else {
+                unreachable!("FATAL: matched a for loop above");
+            };
+
+            *expr = *lower_for(varname, start, end, step, body);
+        }
+
+        WhileExpr { cond, body } => {
+            desugar_for_expr(cond);
+            desugar_for_expr(body);
+        }
+
+        VarExpr { var_names, body } => {
+            var_names
+                .iter_mut()
+                .filter_map(|(_, init)| init.as_mut())
+                .for_each(|init| desugar_for_expr(init));
+
+            desugar_for_expr(body);
+        }
+    }
+}
+
+fn lower_for<'src>(
+    varname: &'src str,
+    start: Box<ASTExpr<'src>>,
+    end: Box<ASTExpr<'src>>,
+    step: Box<ASTExpr<'src>>,
+    body: Box<ASTExpr<'src>>,
+) -> Box<ASTExpr<'src>> {
+    use ASTExpr::*;
+
+    let var = |name| Box::new(VariableExpr(name));
+    let assign = |name, value| {
+        Box::new(BinaryExpr {
+            op: Ops::Assign,
+            left: var(name),
+            right: value,
+        })
+    };
+
+    let next = Box::new(BinaryExpr {
+        op: Ops::Plus,
+        left: var(varname),
+        right: var("`for.step`"),
+    });
+
+    let iteration = Box::new(VarExpr {
+        var_names: vec![
+            ("`for.body`", Some(body)),
+            ("`for.step`", Some(step)),
+            ("`for.end`", Some(end)),
+            ("`for.next`", Some(assign(varname, next))),
+        ],
+        body: assign("`for.more`", var("`for.end`")),
+    });
+
+    let looped = Box::new(VarExpr {
+        var_names: vec![("`for.more`", Some(Box::new(NumberExpr(1.0))))],
+        body: Box::new(WhileExpr {
+            cond: var("`for.more`"),
+            body: iteration,
+        }),
+    });
+
+    Box::new(VarExpr {
+        var_names: vec![(varname, Some(start))],
+        body: looped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, lookahead::Lookahead, parser::parse_definition};
+
+    fn dce(src: &str) -> Box<ASTExpr<'_>> {
+        let mut func = parse_definition(&mut src.lex().lookahead()).unwrap();
+        eliminate_dead_vars(&mut func);
+
+        func.body
+    }
+
+    fn body(src: &str) -> Box<ASTExpr<'_>> {
+        parse_definition(&mut src.lex().lookahead()).unwrap().body
+    }
+
+    #[test]
+    fn removing_unused_vars() {
+        // b is never used, a is
+        assert_eq!(
+            dce("def f(x) var a = x * 2, b = 3 in a + 1;"),
+            body("def f(x) var a = x * 2 in a + 1;")
+        );
+
+        // Nothing left, just the body
+        assert_eq!(dce("def f(x) var a = 1 in x;"), body("def f(x) x;"));
+
+        // Used by a later initializer only, still needed
+        assert_eq!(
+            dce("def f(x) var a = 1, b = a in b;"),
+            body("def f(x) var a = 1, b = a in b;")
+        );
+    }
+
+    #[test]
+    fn keeping_side_effects() {
+        // The initializers call a function and assign, so they stay
+        assert_eq!(
+            dce("def f(x) var a = g(x) in x;"),
+            body("def f(x) var a = g(x) in x;")
+        );
+
+        assert_eq!(
+            dce("def f(x) var a = x = 2 in x;"),
+            body("def f(x) var a = x = 2 in x;")
+        );
+    }
+
+    fn fold(src: &str) -> Box<ASTExpr<'_>> {
+        let mut func = parse_definition(&mut src.lex().lookahead()).unwrap();
+        fold_constants(&mut func);
+
+        func.body
+    }
+
+    #[test]
+    fn folding_constants() {
+        assert_eq!(fold("def f(x) 2 + 3 * 4;"), body("def f(x) 14;"));
+        assert_eq!(fold("def f(x) x * (1 < 2);"), body("def f(x) x * 1;"));
+
+        // Only the constant parts of an expression fold
+        assert_eq!(fold("def f(x) x + 2 * 3;"), body("def f(x) x + 6;"));
+        assert_eq!(fold("def f(x) (x + 2) * 3;"), body("def f(x) (x + 2) * 3;"));
+
+        // So do the compile time builtins
+        assert_eq!(fold("def f(x) len(\"hello\") + 1;"), body("def f(x) 6;"));
+        assert_eq!(fold("def f(x) len(x);"), body("def f(x) len(x);"));
+
+        // ^ may yet be overloaded, so it's left to codegen
+        assert_eq!(fold("def f(x) 2 ^ 3;"), body("def f(x) 2 ^ 3;"));
+    }
+
+    #[test]
+    fn desugaring_for_loops() {
+        use crate::backend::interpreter::Interpreter;
+        use crate::frontend::ast::TopLevel;
+        use crate::frontend::{metrics::count_kinds, parser::parse_program_recovering};
+
+        // The step and end see what the body did to i, the i outside the
+        // loop is untouched by it, and the body always runs at least once
+        let src = "def sum(n) var total = 0 in (for i = 0, i < n, 1 in total = total + i) + total;
+                   def f(x) var i = 100 in (for i = 0, i < 3 in i = i + 1) + i + x;
+                   def empty() var runs = 0 in (for i = 0, 0 in runs = runs + 1) + runs;
+                   def g() var `for.end` = 5 in
+                     (for i = 0, i < 3 in `for.end` = `for.end` + 1) + `for.end`;
+                   sum(10); f(1); empty(); g();";
+
+        let (program, _) = parse_program_recovering(&mut src.lex().lookahead());
+        let expected = Interpreter::new().run(&program).unwrap();
+        assert_eq!(expected, vec![55.0, 101.0, 1.0, 9.0]);
+
+        let (mut program, _) = parse_program_recovering(&mut src.lex().lookahead());
+        let mut kinds = std::collections::BTreeMap::new();
+
+        for item in program.iter_mut() {
+            if let TopLevel::Definition(func) | TopLevel::Expression(func) = item {
+                desugar_for(func);
+                count_kinds(&func.body, &mut kinds);
+            }
+        }
+
+        assert!(!kinds.contains_key("ForLoopExpr"));
+        assert_eq!(kinds["WhileExpr"], 4);
+        assert_eq!(Interpreter::new().run(&program), Ok(expected));
+    }
+}
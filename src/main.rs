@@ -1,20 +1,81 @@
 use std::fs::read_to_string;
 use std::process::exit;
 
-#[macro_use]
-extern crate lazy_static;
-
-mod backend;
-mod cli;
-mod frontend;
-mod repl;
-mod compile;
-
 use clap::Parser;
 use inkwell::targets;
 
+use kaleidrs::frontend::{lexer::Lex, lookahead::Lookahead};
+use kaleidrs::{backend, check, cli, compile, frontend, repl};
+
 fn main() {
     let cli = cli::Cli::parse();
+    frontend::parser::OP_PRECEDENCE.write().allow_override = cli.allow_override;
+
+    if cli.list_passes {
+        backend::passes::list_passes(&mut std::io::stdout()).unwrap();
+        exit(0);
+    }
+
+    // Parsing the file registers its overloads, the rest of what's in it
+    // doesn't matter, nor do any syntax errors
+    if cli.show_precedence {
+        if let Some(ref file_path) = cli.file {
+            let Ok(src_code) = read_to_string(file_path) else {
+                eprintln!("File not found, please make sure it exists!");
+                exit(-1);
+            };
+
+            let _ = frontend::parser::parse_program_recovering(&mut src_code.lex().lookahead());
+        }
+
+        print!("{}", frontend::parser::show_precedence());
+        exit(0);
+    }
+
+    // Syntax checking only needs the frontend, do it before bothering with LLVM
+    if cli.check {
+        let file_path = cli.file.as_ref().unwrap();
+
+        let Ok(src_code) = read_to_string(file_path) else {
+            eprintln!("File not found, please make sure it exists!");
+            exit(-1);
+        };
+
+        let (errors, suppressed) = check::check_src(&src_code, cli.max_errors);
+        check::report_errors(
+            &src_code,
+            &errors,
+            suppressed,
+            cli.error_format,
+            &mut std::io::stderr(),
+        )
+        .unwrap();
+
+        exit(if errors.is_empty() && suppressed == 0 {
+            0
+        } else {
+            1
+        });
+    }
+
+    if cli.fmt {
+        let file_path = cli.file.as_ref().unwrap();
+
+        let Ok(src_code) = read_to_string(file_path) else {
+            eprintln!("File not found, please make sure it exists!");
+            exit(-1);
+        };
+
+        match frontend::formatter::format_source(&src_code) {
+            Ok(formatted) => print!("{formatted}"),
+            Err(err) => {
+                eprintln!("{}", err);
+                exit(1);
+            }
+        }
+
+        exit(0);
+    }
 
     let target_config = targets::InitializationConfig::default();
 
@@ -26,11 +87,21 @@ fn main() {
     // If a positional argument of file was passed, then the program runs in compile mode,
     // taking that file and compiling it to an object/assembly file
     if let Some(ref file_path) = cli.file {
-        match read_to_string(file_path) {
-            Ok(src_code) => {
-                compile::compile_src(&src_code, &cli)
-                    .expect("Failed to compile to object");
-                exit(0);
+        let read = std::iter::once(file_path)
+            .chain(cli.linked_files.iter())
+            .map(read_to_string)
+            .collect::<Result<Vec<_>, _>>();
+
+        match read {
+            Ok(srcs) => {
+                let linked = srcs[1..].iter().map(String::as_str).collect::<Vec<_>>();
+
+                // As JSON, the error was already reported as a diagnostic
+                match compile::compile_src(&srcs[0], &linked, &cli) {
+                    Ok(()) => exit(0),
+                    Err(_) if cli.error_format == cli::ErrorFormat::Json => exit(1),
+                    Err(err) => panic!("Failed to compile to object: {err:?}"),
+                }
             }
             Err(_) => {
                 eprintln!("File not found, please make sure it exists!");
@@ -43,6 +114,12 @@ fn main() {
     if cli.inspect_tree {
         repl::ast_parser_driver();
     } else {
-        repl::llvm_ir_gen_driver(cli.opt_level, &cli.passes);
+        repl::llvm_ir_gen_driver(
+            cli.opt_level,
+            &cli.passes,
+            cli.explain_opt,
+            cli.repl_format,
+            !cli.repl_single_line,
+        );
     }
 }
@@ -1,10 +1,14 @@
-use std::io::Write;
+use std::io::{self, BufRead, Write};
 
 use crate::{
-    cli::OptLevel,
+    cli::{OptLevel, ReplFormat},
     frontend::{
         lexer::{Lex, Token},
-        parser::{parse_definition, parse_extern, parse_top_level_expr},
+        lookahead::Lookahead,
+        parser::{
+            parse_decl, parse_definition, parse_extern, parse_top_level_expr, ParserError,
+            OP_PRECEDENCE,
+        },
     },
 };
 
@@ -22,7 +26,7 @@ pub fn ast_parser_driver() {
         std::io::stdout().flush().unwrap();
         let _ = std::io::stdin().read_line(&mut input_buf);
 
-        let mut tokens = input_buf.lex().peekable();
+        let mut tokens = input_buf.lex().lookahead();
 
         match tokens.peek() {
             None => continue,
@@ -33,7 +37,7 @@ pub fn ast_parser_driver() {
                     dbg!(ast);
                 }
                 Err(err) => {
-                    eprintln!("Error: {}", err);
+                    eprintln!("{}", err);
                     _ = tokens.next();
                 }
             },
@@ -44,7 +48,18 @@ pub fn ast_parser_driver() {
                     dbg!(ast);
                 }
                 Err(err) => {
-                    eprintln!("Error: {}", err);
+                    eprintln!("{}", err);
+                    _ = tokens.next();
+                }
+            },
+
+            Some(Token::Decl) => match parse_decl(&mut tokens) {
+                Ok(ast) => {
+                    println!("Parsed a declaration.");
+                    dbg!(ast);
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
                     _ = tokens.next();
                 }
             },
@@ -59,7 +74,7 @@ pub fn ast_parser_driver() {
                     dbg!(ast);
                 }
                 Err(err) => {
-                    eprintln!("Error on top-level: {}", err);
+                    eprintln!("{}", err);
                     _ = tokens.next();
                 }
             },
@@ -70,50 +85,177 @@ pub fn ast_parser_driver() {
     }
 }
 
-pub fn llvm_ir_gen_driver(opt_level: OptLevel, passes: &str) {
+// What an expression evaluated to, as the REPL prints it. Rust's own
+// formatting never goes scientific, so 1e300 would be a line of 301 digits.
+pub fn format_f64(num: f64, format: ReplFormat) -> String {
+    match format {
+        ReplFormat::Sci => format!("{num:e}"),
+        ReplFormat::Fixed => format!("{num}"),
+        ReplFormat::Auto => {
+            let magnitude = num.abs();
+            let tiny = magnitude != 0.0 && magnitude < 1e-5;
+
+            if magnitude.is_finite() && (magnitude >= 1e15 || tiny) {
+                format!("{num:e}")
+            } else {
+                format!("{num}")
+            }
+        }
+    }
+}
+
+// Whether the input so far stops in the middle of something, like a def
+// with no body yet or "1 +", which the next line may well finish
+fn awaits_more(src: &str) -> bool {
+    let mut tokens = src.lex().lookahead();
+
+    let err = match tokens.peek() {
+        None | Some(Token::Semicolon) => return false,
+        Some(Token::FuncDef) => {
+            // The def of an operator registers it as its prototype is parsed,
+            // it's put back after, else one never finished would leave it
+            // defined. Only its own operator, the one a def can register.
+            let mut ahead = src.lex().skip(1);
+            let overloaded = match (ahead.next(), ahead.next()) {
+                (Some(Token::UnaryOverload | Token::BinaryOverload), Some(Token::Operator(op))) => {
+                    Some((op, OP_PRECEDENCE.read().get(&op).copied()))
+                }
+                _ => None,
+            };
+
+            let err = parse_definition(&mut tokens).err();
+
+            if let Some((op, info)) = overloaded {
+                OP_PRECEDENCE.write().restore(op, info);
+            }
+
+            err
+        }
+        Some(Token::Extern) => parse_extern(&mut tokens).err(),
+        Some(Token::Decl) => parse_decl(&mut tokens).err(),
+        Some(_top_level_token) => parse_top_level_expr(&mut tokens).err(),
+    };
+
+    matches!(err, Some(ParserError::UnexpectedEOI { .. }))
+}
+
+pub fn llvm_ir_gen_driver(
+    opt_level: OptLevel,
+    passes: &str,
+    explain_opt: bool,
+    repl_format: ReplFormat,
+    multiline: bool,
+) {
+    let stdin = io::stdin().lock();
+    let stdout = io::stdout().lock();
+
+    llvm_ir_gen_driver_with(
+        stdin,
+        stdout,
+        opt_level,
+        passes,
+        explain_opt,
+        repl_format,
+        multiline,
+    )
+    .expect("Failed to read or write the REPL's input/output");
+}
+
+// The JIT REPL proper, reading lines of input until it ends or "quit" is
+// entered. Separate from the real stdin/stdout, so tests can drive it with
+// buffers in memory. Errors and IR dumps still go to stderr.
+//
+// When multiline, input that ends before what it started is done, say a def
+// with no body, is continued onto the next line rather than reported. A
+// blank line gives up on continuing, and has the input parsed as it is.
+pub fn llvm_ir_gen_driver_with(
+    mut input: impl BufRead,
+    mut output: impl Write,
+    opt_level: OptLevel,
+    passes: &str,
+    explain_opt: bool,
+    repl_format: ReplFormat,
+    multiline: bool,
+) -> io::Result<()> {
     let context = inkwell::context::Context::create();
 
     let sesh_ctx = LLVMContext::new(&context, opt_level);
     let mut input_buf = String::new();
 
     loop {
-        print!("Ready >> ");
-        std::io::stdout().flush().unwrap();
-        let _ = std::io::stdin().read_line(&mut input_buf);
+        match input_buf.is_empty() {
+            true => write!(output, "Ready >> ")?,
+            false => write!(output, "   ... >> ")?,
+        }
+        output.flush()?;
+
+        let line_start = input_buf.len();
+
+        if input.read_line(&mut input_buf)? == 0 || input_buf.trim() == "quit" {
+            return Ok(());
+        }
+
+        let blank_line = input_buf[line_start..].trim().is_empty();
+
+        if multiline && !blank_line && awaits_more(&input_buf) {
+            continue;
+        }
 
-        let mut tokens = input_buf.lex().peekable();
+        let mut tokens = input_buf.lex().lookahead();
 
         match tokens.peek() {
-            None => continue,
+            None => {
+                std::mem::drop(tokens);
+                input_buf.clear();
+                continue;
+            }
 
             Some(Token::FuncDef) => match parse_definition(&mut tokens) {
                 Ok(ast) => {
-                    println!("Parsed a function definition.");
+                    writeln!(output, "Parsed a function definition.")?;
                     match ast.codegen(&sesh_ctx) {
                         Ok(_ir) => {
 
-                            sesh_ctx.run_passes(passes);
+                            if explain_opt {
+                                write!(output, "{}", sesh_ctx.run_passes_explained(passes))?;
+                            } else {
+                                sesh_ctx.run_passes(passes);
+                            }
                             sesh_ctx.dump_module();
                         }
-                        Err(e) => eprintln!("Backend error: {}", e),
+                        Err(e) => eprintln!("{}", e),
                     }
                 }
                 Err(err) => {
-                    eprintln!("Frontend Error: {}", err);
+                    eprintln!("{}", err);
                     _ = tokens.next();
                 }
             },
 
             Some(Token::Extern) => match parse_extern(&mut tokens) {
                 Ok(ast) => {
-                    println!("Parsed an extern.");
+                    writeln!(output, "Parsed an extern.")?;
                     match ast.codegen(&sesh_ctx) {
                         Ok(_ir) => sesh_ctx.dump_module(),
-                        Err(e) => eprintln!("Backend error: {}", e),
+                        Err(e) => eprintln!("{}", e),
                     }
                 }
                 Err(err) => {
-                    eprintln!("Frontend Error: {}", err);
+                    eprintln!("{}", err);
+                    _ = tokens.next();
+                }
+            },
+
+            Some(Token::Decl) => match parse_decl(&mut tokens) {
+                Ok(ast) => {
+                    writeln!(output, "Parsed a declaration.")?;
+                    match ast.codegen(&sesh_ctx) {
+                        Ok(_ir) => sesh_ctx.dump_module(),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
                     _ = tokens.next();
                 }
             },
@@ -124,10 +266,14 @@ pub fn llvm_ir_gen_driver(opt_level: OptLevel, passes: &str) {
 
             Some(_top_level_token) => match parse_top_level_expr(&mut tokens) {
                 Ok(ast) => {
-                    println!("Parsed a top level expression.");
+                    writeln!(output, "Parsed a top level expression.")?;
                     match ast.codegen(&sesh_ctx) {
                         Ok(_ir) => {
-                            sesh_ctx.run_passes(passes);
+                            if explain_opt {
+                                write!(output, "{}", sesh_ctx.run_passes_explained(passes))?;
+                            } else {
+                                sesh_ctx.run_passes(passes);
+                            }
                             sesh_ctx.dump_module();
 
                             unsafe {
@@ -135,16 +281,17 @@ pub fn llvm_ir_gen_driver(opt_level: OptLevel, passes: &str) {
                                     .jit_eval()
                                     .expect("Failed to JIT top level pression into function!");
 
-                                println!("Jit compiled and evaluated to: {res}");
+                                let res = format_f64(res, repl_format);
+                                writeln!(output, "Jit compiled and evaluated to: {res}")?;
                             }
                         }
-                        Err(e) => eprintln!("Backend error: {}", e),
+                        Err(e) => eprintln!("{}", e),
                     }
 
                     sesh_ctx.delete_top_level_expr();
                 }
                 Err(err) => {
-                    eprintln!("Frontend Error: {}", err);
+                    eprintln!("{}", err);
                     _ = tokens.next();
                 }
             },
@@ -154,3 +301,92 @@ pub fn llvm_ir_gen_driver(opt_level: OptLevel, passes: &str) {
         input_buf.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use inkwell::targets::{InitializationConfig, Target};
+
+    use super::*;
+    use crate::backend::passes::DEFAULT_PASSES;
+    use crate::frontend::lexer::Ops;
+
+    #[test]
+    fn driving_the_repl() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let mut output = vec![];
+        llvm_ir_gen_driver_with(
+            "1+1\nquit\n1+2\n".as_bytes(),
+            &mut output,
+            OptLevel::O2,
+            DEFAULT_PASSES,
+            false,
+            ReplFormat::Auto,
+            true,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("evaluated to: 2"));
+
+        // Nothing after quit is run
+        assert!(!output.contains("evaluated to: 3"));
+    }
+
+    #[test]
+    fn continuing_lines() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+
+        let mut output = vec![];
+        llvm_ir_gen_driver_with(
+            "def f(x)\n  x+1\nf(2)\n1 +\n\n(2 *\n 3)\ndef g(\nx) x\ng(7)\n".as_bytes(),
+            &mut output,
+            OptLevel::O2,
+            DEFAULT_PASSES,
+            false,
+            ReplFormat::Auto,
+            true,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Ready >>    ... >> Parsed a function definition."));
+        assert!(output.contains("evaluated to: 3"));
+
+        // The blank line gave up on "1 +", which was an error, never evaluated
+        assert!(!output.contains("evaluated to: 1\n"));
+        assert!(output.contains("evaluated to: 6"));
+
+        // A prototype split over lines, the open parenthesis ends the first
+        assert!(output.contains("evaluated to: 7"));
+    }
+
+    #[test]
+    fn awaiting_operator_defs() {
+        // No other test makes ! binary, so any precedence it has is left by us
+        let is_binary = || {
+            OP_PRECEDENCE
+                .read()
+                .get(&Ops::Negate)
+                .is_some_and(|info| info.precedence > 0)
+        };
+
+        assert!(awaits_more("def binary ! 7 (a b)\n"));
+        assert!(!is_binary());
+
+        assert!(!awaits_more("def binary ! 7 (a b) a\n"));
+        assert!(!is_binary());
+    }
+
+    #[test]
+    fn formatting_results() {
+        assert_eq!(format_f64(1e20, ReplFormat::Sci), "1e20");
+        assert_eq!(format_f64(1e20, ReplFormat::Fixed), "100000000000000000000");
+        assert_eq!(format_f64(1e20, ReplFormat::Auto), "1e20");
+
+        // Everyday numbers stay as they were
+        assert_eq!(format_f64(2.0, ReplFormat::Auto), "2");
+        assert_eq!(format_f64(0.25, ReplFormat::Auto), "0.25");
+        assert_eq!(format_f64(0.25, ReplFormat::Sci), "2.5e-1");
+    }
+}